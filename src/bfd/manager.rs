@@ -0,0 +1,362 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use super::packet::{ControlPacket, State};
+use super::session::BfdSession;
+use crate::neighbor;
+use crate::speaker;
+
+pub const BFD_SOURCE_PORT_MIN: u16 = 49152;
+pub const BFD_TTL: u32 = 255;
+
+struct Peer {
+    session: BfdSession,
+    socket: UdpSocket,
+    port: u16,
+    neighbor: Arc<neighbor::NeighborCell>,
+}
+
+/// Binds the per-session transmit socket. RFC 5881 requires a source port in the range
+/// 49152-65535 and a TTL/Hop Limit of 255.
+async fn bind_tx_socket(local: IpAddr, peer: IpAddr) -> Result<UdpSocket> {
+    let source = match (local, peer) {
+        (IpAddr::V4(l), IpAddr::V4(_)) if !l.is_unspecified() => local,
+        (IpAddr::V6(l), IpAddr::V6(_)) if !l.is_unspecified() => local,
+        (_, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (_, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    for port in BFD_SOURCE_PORT_MIN..=u16::MAX {
+        if let Ok(socket) = UdpSocket::bind(SocketAddr::new(source, port)).await {
+            match peer {
+                IpAddr::V4(_) => socket.set_ttl(BFD_TTL)?,
+                IpAddr::V6(_) => set_unicast_hops_v6(&socket, BFD_TTL)?,
+            }
+            return Ok(socket);
+        }
+    }
+    Err(anyhow!("No free BFD source port for {}", peer))
+}
+
+fn set_unicast_hops_v6(socket: &UdpSocket, hops: u32) -> std::io::Result<()> {
+    set_int_option(
+        socket,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_UNICAST_HOPS,
+        hops as libc::c_int,
+    )
+}
+
+fn set_int_option(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    // SAFETY: the fd is owned by `socket` for the duration of the call and the option value
+    // points to a properly sized c_int.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Binds the socket receiving the control packets of one address family, with the TTL/Hop
+/// Limit of every packet reported so that [`recv_with_ttl`] can check it. IPv6 sockets are
+/// IPv6 only, so that an IPv4 socket can share the port.
+pub(super) async fn bind_rx_socket(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    match addr {
+        SocketAddr::V4(_) => set_int_option(&socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1)?,
+        SocketAddr::V6(_) => {
+            set_int_option(&socket, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?
+        }
+    }
+    Ok(socket)
+}
+
+/// Receives a packet with its source and the TTL/Hop Limit it arrived with, none when the
+/// kernel did not report it.
+pub(super) async fn recv_with_ttl(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<u8>)> {
+    socket
+        .async_io(Interest::READABLE, || recvmsg_ttl(socket, buf))
+        .await
+}
+
+fn recvmsg_ttl(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<u8>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: zeroed sockaddr_storage and msghdr are valid values of these C structs.
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    // SAFETY: every buffer `msg` points to outlives the call and its lengths are their sizes.
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut ttl = None;
+    // SAFETY: the control messages are walked with the libc macros within msg_controllen, and
+    // the TTL and Hop Limit are c_int values.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let (level, kind) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if (level == libc::IPPROTO_IP && kind == libc::IP_TTL)
+                || (level == libc::IPPROTO_IPV6 && kind == libc::IPV6_HOPLIMIT)
+            {
+                let value = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                ttl = u8::try_from(value).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((len as usize, sockaddr_to_std(&addr)?, ttl))
+}
+
+fn sockaddr_to_std(addr: &libc::sockaddr_storage) -> std::io::Result<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a sockaddr_in.
+            let a =
+                unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                u16::from_be(a.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a sockaddr_in6.
+            let a =
+                unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(a.sin6_addr.s6_addr),
+                u16::from_be(a.sin6_port),
+                a.sin6_flowinfo,
+                a.sin6_scope_id,
+            )))
+        }
+        family => Err(std::io::Error::other(format!(
+            "Unexpected address family {}",
+            family
+        ))),
+    }
+}
+
+/// Local address of the sessions with `peer`, the first configured one of its address family or
+/// the unspecified address when there is none.
+pub(super) fn local_ip_for(local_ips: &[IpAddr], peer: IpAddr) -> IpAddr {
+    local_ips
+        .iter()
+        .copied()
+        .find(|ip| ip.is_ipv4() == peer.is_ipv4())
+        .unwrap_or(match peer {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        })
+}
+
+/// Receives the control packets of one listener and hands the valid ones to the manager. A
+/// failed receive only loses that packet.
+async fn receive_packets(listener: UdpSocket, tx: mpsc::Sender<(ControlPacket, SocketAddr)>) {
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, src, ttl) = match recv_with_ttl(&listener, &mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Failed to receive BFD packet: {}", e);
+                continue;
+            }
+        };
+        // RFC 5881 section 5, single-hop packets that may have been forwarded are discarded,
+        // which keeps off-link hosts from spoofing them.
+        if ttl != Some(BFD_TTL as u8) {
+            log::debug!("Discarding BFD packet from {} with TTL {:?}", src, ttl);
+            continue;
+        }
+        match ControlPacket::try_from(&buf[..len]) {
+            Ok(pkt) => {
+                if tx.send((pkt, src)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => log::debug!("Discarding BFD packet from {}: {}", src, e),
+        }
+    }
+}
+
+fn state_changed(peer: &Peer, previous: State, state: State) {
+    log::info!(
+        "BFD session with {} changed from {:?} to {:?} (diag {:?})",
+        peer.session.peer,
+        previous,
+        state,
+        peer.session.local_diag
+    );
+    peer.neighbor.set_bfd_state(state);
+    let status = peer.neighbor.status();
+    if previous == State::Up && state == State::Down && status.is_established() {
+        // Handed over apart, so that a busy session does not hold up the BFD timers.
+        if let Some(tx) = status.tx {
+            let diag = peer.session.local_diag;
+            tokio::spawn(async move {
+                let _ = tx.send(neighbor::Event::BfdDown(diag)).await;
+            });
+        }
+    }
+}
+
+async fn send(peer: &mut Peer, now: Instant) {
+    let pkt: Vec<u8> = peer.session.packet().into();
+    let dst = SocketAddr::new(peer.session.peer, peer.port);
+    if let Err(e) = peer.socket.send_to(&pkt, dst).await {
+        log::debug!("Failed to send BFD packet to {}: {}", dst, e);
+    }
+    peer.session.schedule_tx(now);
+}
+
+/// Runs every configured BFD session. Packets are received on a socket per address family and
+/// demultiplexed by Your Discriminator, or by source address while the remote discriminator is
+/// unknown.
+pub async fn bfd_mgr(speaker: Arc<speaker::BGPSpeaker>) -> Result<()> {
+    let (bfd_config, neighbors) = (speaker.bfd.clone(), speaker.neighbors.clone());
+
+    let mut peers: Vec<Peer> = vec![];
+    for nb in neighbors {
+        let remote_ip = match (nb.status().bfd, nb.remote_ip) {
+            (true, Some(ip)) => ip,
+            _ => continue,
+        };
+        let mut discr = fastrand::u32(1..);
+        while peers.iter().any(|p| p.session.local_discr == discr) {
+            discr = fastrand::u32(1..);
+        }
+        let socket = bind_tx_socket(local_ip_for(&speaker.local_ips, remote_ip), remote_ip)
+            .await
+            .with_context(|| format!("Failed to bind BFD socket for {}", remote_ip))?;
+        nb.set_bfd_state(State::Down);
+        log::info!(
+            "Starting BFD session with {} (discriminator {})",
+            remote_ip,
+            discr
+        );
+        peers.push(Peer {
+            session: BfdSession::new(remote_ip, discr, &bfd_config),
+            socket,
+            port: bfd_config.port,
+            neighbor: nb,
+        });
+    }
+
+    if peers.is_empty() {
+        return Ok(());
+    }
+
+    let (tx, mut rx) = mpsc::channel(64);
+    for ipv4 in [true, false] {
+        let Some(peer) = peers.iter().find(|p| p.session.peer.is_ipv4() == ipv4) else {
+            continue;
+        };
+        let socket_addr = SocketAddr::new(
+            local_ip_for(&speaker.local_ips, peer.session.peer),
+            bfd_config.port,
+        );
+        let listener = bind_rx_socket(socket_addr)
+            .await
+            .with_context(|| format!("Failed to bind BFD listener to {}", socket_addr))?;
+        tokio::spawn(receive_packets(listener, tx.clone()));
+    }
+
+    let by_discr: HashMap<u32, usize> = peers
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.session.local_discr, i))
+        .collect();
+
+    loop {
+        let deadline = peers
+            .iter()
+            .map(|p| p.session.next_deadline())
+            .min()
+            .expect("BUG: BFD manager runs with at least one session");
+
+        tokio::select! {
+            Some((pkt, src)) = rx.recv() => {
+                let src_ip = src.ip().to_canonical();
+                let idx = if pkt.your_discriminator != 0 {
+                    by_discr.get(&pkt.your_discriminator).copied()
+                } else {
+                    peers.iter().position(|p| p.session.peer == src_ip)
+                };
+                let peer = match idx {
+                    Some(i) if peers[i].session.peer == src_ip => &mut peers[i],
+                    _ => {
+                        log::debug!("Discarding BFD packet from unknown peer {}", src);
+                        continue;
+                    }
+                };
+                let now = Instant::now();
+                let previous = peer.session.state;
+                if let Some(state) = peer.session.receive(&pkt, now) {
+                    state_changed(peer, previous, state);
+                }
+                if peer.session.needs_immediate_tx() {
+                    send(peer, now).await;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline) => {}
+        }
+
+        let now = Instant::now();
+        for peer in peers.iter_mut() {
+            let previous = peer.session.state;
+            if let Some(state) = peer.session.expire(now) {
+                state_changed(peer, previous, state);
+            }
+            if peer.session.next_tx <= now {
+                send(peer, now).await;
+            }
+        }
+    }
+}
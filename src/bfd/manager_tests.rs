@@ -0,0 +1,54 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+use super::manager::{bind_rx_socket, local_ip_for, recv_with_ttl, BFD_TTL};
+
+async fn send_with_ttl(dst: SocketAddr, ttl: u32) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    socket.set_ttl(ttl).unwrap();
+    socket.send_to(&[0u8; 24], dst).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_received_ttl_reported() {
+    let listener = bind_rx_socket(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .unwrap();
+    let dst = listener.local_addr().unwrap();
+    let mut buf = [0u8; 64];
+
+    send_with_ttl(dst, BFD_TTL).await;
+    let (len, src, ttl) = recv_with_ttl(&listener, &mut buf).await.unwrap();
+    assert_eq!(len, 24);
+    assert_eq!(src.ip(), Ipv4Addr::LOCALHOST);
+    assert_eq!(ttl, Some(255));
+
+    // A packet that crossed a router arrives with a lower TTL, and is to be discarded
+    send_with_ttl(dst, 254).await;
+    let (_, _, ttl) = recv_with_ttl(&listener, &mut buf).await.unwrap();
+    assert_eq!(ttl, Some(254));
+}
+
+#[tokio::test]
+async fn test_rx_sockets_share_port_across_families() {
+    let v4 = bind_rx_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+        .await
+        .unwrap();
+    let port = v4.local_addr().unwrap().port();
+    bind_rx_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_local_ip_for_peer_family() {
+    let v4: IpAddr = "192.0.2.1".parse().unwrap();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let local_ips = [v6, v4];
+    assert_eq!(local_ip_for(&local_ips, "192.0.2.2".parse().unwrap()), v4);
+    assert_eq!(local_ip_for(&local_ips, "2001:db8::2".parse().unwrap()), v6);
+    assert_eq!(
+        local_ip_for(&[v4], "2001:db8::2".parse().unwrap()),
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    );
+}
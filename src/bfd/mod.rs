@@ -0,0 +1,13 @@
+mod manager;
+mod packet;
+mod session;
+
+#[cfg(test)]
+mod manager_tests;
+#[cfg(test)]
+mod packet_tests;
+#[cfg(test)]
+mod session_tests;
+
+pub use manager::bfd_mgr;
+pub use packet::{Diagnostic, State};
@@ -0,0 +1,183 @@
+use byteorder::{BigEndian, WriteBytesExt};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::io::Cursor;
+use thiserror::Error;
+
+pub const BFD_VERSION: u8 = 1;
+pub const BFD_CONTROL_PACKET_LENGTH: usize = 24;
+
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum State {
+    AdminDown = 0,
+    #[default]
+    Down,
+    Init,
+    Up,
+}
+
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Diagnostic {
+    #[default]
+    NoDiag = 0,
+    ControlDetectionTimeExpired,
+    EchoFunctionFailed,
+    NeighborSignaledSessionDown,
+    ForwardingPlaneReset,
+    PathDown,
+    ConcatenatedPathDown,
+    AdministrativelyDown,
+    ReverseConcatenatedPathDown,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BfdPacketError {
+    #[error("Packet too short: got {0}, minimum {BFD_CONTROL_PACKET_LENGTH}")]
+    TooShort(usize),
+
+    #[error("Unsupported BFD version: {0}")]
+    InvalidVersion(u8),
+
+    #[error("Invalid length field: {length} (received {received} bytes)")]
+    InvalidLength { length: usize, received: usize },
+
+    #[error("Invalid diagnostic code: {0}")]
+    InvalidDiagnostic(u8),
+
+    #[error("Detect multiplier is zero")]
+    ZeroDetectMult,
+
+    #[error("Multipoint bit is set")]
+    Multipoint,
+
+    #[error("My Discriminator is zero")]
+    ZeroMyDiscriminator,
+
+    #[error("Your Discriminator is zero in state {0:?}")]
+    ZeroYourDiscriminator(State),
+
+    #[error("Authentication is not supported")]
+    AuthenticationUnsupported,
+}
+
+/// BFD Control packet, RFC 5880 section 4.1. Intervals are in microseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ControlPacket {
+    pub diag: Diagnostic,
+    pub state: State,
+    pub poll: bool,
+    pub final_: bool,
+    pub control_plane_independent: bool,
+    pub demand: bool,
+    pub detect_mult: u8,
+    pub my_discriminator: u32,
+    pub your_discriminator: u32,
+    pub desired_min_tx_interval: u32,
+    pub required_min_rx_interval: u32,
+    pub required_min_echo_rx_interval: u32,
+}
+
+impl From<ControlPacket> for Vec<u8> {
+    fn from(val: ControlPacket) -> Self {
+        let mut buf = Cursor::new(Vec::with_capacity(BFD_CONTROL_PACKET_LENGTH));
+        let mut flags = (val.state as u8) << 6;
+        if val.poll {
+            flags |= 0b0010_0000;
+        }
+        if val.final_ {
+            flags |= 0b0001_0000;
+        }
+        if val.control_plane_independent {
+            flags |= 0b0000_1000;
+        }
+        if val.demand {
+            flags |= 0b0000_0010;
+        }
+        buf.write_u8((BFD_VERSION << 5) | (val.diag as u8 & 0x1f))
+            .unwrap();
+        buf.write_u8(flags).unwrap();
+        buf.write_u8(val.detect_mult).unwrap();
+        buf.write_u8(BFD_CONTROL_PACKET_LENGTH as u8).unwrap();
+        buf.write_u32::<BigEndian>(val.my_discriminator).unwrap();
+        buf.write_u32::<BigEndian>(val.your_discriminator).unwrap();
+        buf.write_u32::<BigEndian>(val.desired_min_tx_interval)
+            .unwrap();
+        buf.write_u32::<BigEndian>(val.required_min_rx_interval)
+            .unwrap();
+        buf.write_u32::<BigEndian>(val.required_min_echo_rx_interval)
+            .unwrap();
+        buf.into_inner()
+    }
+}
+
+impl TryFrom<&[u8]> for ControlPacket {
+    type Error = BfdPacketError;
+
+    /// Parses and validates a received packet following the checks of RFC 5880 section 6.8.6.
+    fn try_from(src: &[u8]) -> Result<Self, Self::Error> {
+        if src.len() < BFD_CONTROL_PACKET_LENGTH {
+            return Err(BfdPacketError::TooShort(src.len()));
+        }
+
+        let version = src[0] >> 5;
+        if version != BFD_VERSION {
+            return Err(BfdPacketError::InvalidVersion(version));
+        }
+        let diag = Diagnostic::from_u8(src[0] & 0x1f)
+            .ok_or(BfdPacketError::InvalidDiagnostic(src[0] & 0x1f))?;
+
+        let flags = src[1];
+        let state = State::from_u8(flags >> 6).expect("BUG: two bits always map to a BFD state");
+        if flags & 0b0000_0100 != 0 {
+            return Err(BfdPacketError::AuthenticationUnsupported);
+        }
+        if flags & 0b0000_0001 != 0 {
+            return Err(BfdPacketError::Multipoint);
+        }
+
+        let detect_mult = src[2];
+        if detect_mult == 0 {
+            return Err(BfdPacketError::ZeroDetectMult);
+        }
+
+        let length = src[3] as usize;
+        if length < BFD_CONTROL_PACKET_LENGTH || length > src.len() {
+            return Err(BfdPacketError::InvalidLength {
+                length,
+                received: src.len(),
+            });
+        }
+
+        let word = |i: usize| {
+            let mut v = [0u8; 4];
+            v.copy_from_slice(&src[i..i + 4]);
+            u32::from_be_bytes(v)
+        };
+
+        let my_discriminator = word(4);
+        if my_discriminator == 0 {
+            return Err(BfdPacketError::ZeroMyDiscriminator);
+        }
+        let your_discriminator = word(8);
+        if your_discriminator == 0 && !matches!(state, State::Down | State::AdminDown) {
+            return Err(BfdPacketError::ZeroYourDiscriminator(state));
+        }
+
+        Ok(ControlPacket {
+            diag,
+            state,
+            poll: flags & 0b0010_0000 != 0,
+            final_: flags & 0b0001_0000 != 0,
+            control_plane_independent: flags & 0b0000_1000 != 0,
+            demand: flags & 0b0000_0010 != 0,
+            detect_mult,
+            my_discriminator,
+            your_discriminator,
+            desired_min_tx_interval: word(12),
+            required_min_rx_interval: word(16),
+            required_min_echo_rx_interval: word(20),
+        })
+    }
+}
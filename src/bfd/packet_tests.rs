@@ -0,0 +1,113 @@
+use super::packet::*;
+
+fn sample() -> ControlPacket {
+    ControlPacket {
+        diag: Diagnostic::ControlDetectionTimeExpired,
+        state: State::Up,
+        poll: true,
+        detect_mult: 3,
+        my_discriminator: 0x01020304,
+        your_discriminator: 0x0a0b0c0d,
+        desired_min_tx_interval: 300_000,
+        required_min_rx_interval: 200_000,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_control_packet_serialization_valid() {
+    let bytes: Vec<u8> = sample().into();
+
+    assert_eq!(bytes.len(), BFD_CONTROL_PACKET_LENGTH);
+    assert_eq!(bytes[0], (1 << 5) | 1);
+    assert_eq!(bytes[1], 0b1110_0000);
+    assert_eq!(bytes[2], 3);
+    assert_eq!(bytes[3], 24);
+    assert_eq!(bytes[4..8], [0x01, 0x02, 0x03, 0x04]);
+    assert_eq!(bytes[8..12], [0x0a, 0x0b, 0x0c, 0x0d]);
+    assert_eq!(bytes[12..16], 300_000u32.to_be_bytes());
+    assert_eq!(bytes[16..20], 200_000u32.to_be_bytes());
+    assert_eq!(bytes[20..24], [0, 0, 0, 0]);
+}
+
+#[test]
+fn test_control_packet_roundtrip_valid() {
+    let bytes: Vec<u8> = sample().into();
+    let parsed = ControlPacket::try_from(bytes.as_slice()).unwrap();
+
+    assert_eq!(parsed, sample());
+}
+
+#[test]
+fn test_control_packet_too_short_invalid() {
+    let bytes: Vec<u8> = sample().into();
+
+    assert_eq!(
+        ControlPacket::try_from(&bytes[..20]),
+        Err(BfdPacketError::TooShort(20))
+    );
+}
+
+#[test]
+fn test_control_packet_bad_version_invalid() {
+    let mut bytes: Vec<u8> = sample().into();
+    bytes[0] = (2 << 5) | (bytes[0] & 0x1f);
+
+    assert_eq!(
+        ControlPacket::try_from(bytes.as_slice()),
+        Err(BfdPacketError::InvalidVersion(2))
+    );
+}
+
+#[test]
+fn test_control_packet_zero_detect_mult_invalid() {
+    let mut bytes: Vec<u8> = sample().into();
+    bytes[2] = 0;
+
+    assert_eq!(
+        ControlPacket::try_from(bytes.as_slice()),
+        Err(BfdPacketError::ZeroDetectMult)
+    );
+}
+
+#[test]
+fn test_control_packet_multipoint_and_auth_invalid() {
+    let mut bytes: Vec<u8> = sample().into();
+    bytes[1] |= 0b0000_0001;
+    assert_eq!(
+        ControlPacket::try_from(bytes.as_slice()),
+        Err(BfdPacketError::Multipoint)
+    );
+
+    let mut bytes: Vec<u8> = sample().into();
+    bytes[1] |= 0b0000_0100;
+    assert_eq!(
+        ControlPacket::try_from(bytes.as_slice()),
+        Err(BfdPacketError::AuthenticationUnsupported)
+    );
+}
+
+#[test]
+fn test_control_packet_discriminators() {
+    let mut pkt = sample();
+    pkt.my_discriminator = 0;
+    let bytes: Vec<u8> = pkt.into();
+    assert_eq!(
+        ControlPacket::try_from(bytes.as_slice()),
+        Err(BfdPacketError::ZeroMyDiscriminator)
+    );
+
+    let mut pkt = sample();
+    pkt.your_discriminator = 0;
+    let bytes: Vec<u8> = pkt.into();
+    assert_eq!(
+        ControlPacket::try_from(bytes.as_slice()),
+        Err(BfdPacketError::ZeroYourDiscriminator(State::Up))
+    );
+
+    let mut pkt = sample();
+    pkt.state = State::Down;
+    pkt.your_discriminator = 0;
+    let bytes: Vec<u8> = pkt.into();
+    assert!(ControlPacket::try_from(bytes.as_slice()).is_ok());
+}
@@ -0,0 +1,208 @@
+use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
+
+use super::packet::{ControlPacket, Diagnostic, State};
+use crate::config;
+
+/// While a session is not Up, RFC 5880 section 6.8.3 requires a transmit interval of at least
+/// one second.
+const BFD_SLOW_TX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Single-hop asynchronous mode session state (RFC 5880 section 6.8.1).
+#[derive(Debug, Clone)]
+pub struct BfdSession {
+    pub peer: IpAddr,
+    pub state: State,
+    pub remote_state: State,
+    pub local_discr: u32,
+    pub remote_discr: u32,
+    pub local_diag: Diagnostic,
+    pub desired_min_tx: Duration,
+    pub required_min_rx: Duration,
+    pub detect_mult: u8,
+    pub remote_desired_min_tx: Duration,
+    pub remote_min_rx: Duration,
+    pub remote_detect_mult: u8,
+    pub next_tx: Instant,
+    pub detect_deadline: Option<Instant>,
+    poll_pending: bool,
+    final_pending: bool,
+}
+
+impl BfdSession {
+    pub fn new(peer: IpAddr, local_discr: u32, config: &config::BfdConfig) -> Self {
+        BfdSession {
+            peer,
+            state: State::Down,
+            remote_state: State::Down,
+            local_discr,
+            remote_discr: 0,
+            local_diag: Diagnostic::NoDiag,
+            desired_min_tx: Duration::from_millis(config.min_tx as u64),
+            required_min_rx: Duration::from_millis(config.min_rx as u64),
+            detect_mult: config.multiplier,
+            remote_desired_min_tx: Duration::ZERO,
+            remote_min_rx: Duration::from_micros(1),
+            remote_detect_mult: 0,
+            next_tx: Instant::now(),
+            detect_deadline: None,
+            poll_pending: false,
+            final_pending: false,
+        }
+    }
+
+    fn effective_desired_min_tx(&self) -> Duration {
+        if self.state == State::Up {
+            self.desired_min_tx
+        } else {
+            self.desired_min_tx.max(BFD_SLOW_TX_INTERVAL)
+        }
+    }
+
+    /// Interval between two periodic Control packets, before jitter. `None` when the remote
+    /// system asked us not to send periodic packets.
+    pub fn tx_interval(&self) -> Option<Duration> {
+        if self.remote_min_rx.is_zero() {
+            return None;
+        }
+        Some(self.effective_desired_min_tx().max(self.remote_min_rx))
+    }
+
+    /// Asynchronous mode detection time, RFC 5880 section 6.8.4.
+    pub fn detection_time(&self) -> Duration {
+        self.required_min_rx.max(self.remote_desired_min_tx) * self.remote_detect_mult as u32
+    }
+
+    /// Earliest point in time at which the session needs attention.
+    pub fn next_deadline(&self) -> Instant {
+        match self.detect_deadline {
+            Some(d) => d.min(self.next_tx),
+            None => self.next_tx,
+        }
+    }
+
+    pub fn schedule_tx(&mut self, now: Instant) {
+        let interval = match self.tx_interval() {
+            Some(i) => i,
+            None => {
+                self.next_tx = now + BFD_SLOW_TX_INTERVAL;
+                return;
+            }
+        };
+        // Reduce the interval by 0-25%, or 10-25% with a multiplier of one (section 6.8.7).
+        let min_jitter = if self.detect_mult == 1 { 10 } else { 0 };
+        let jitter = fastrand::u32(min_jitter..=25);
+        self.next_tx = now + interval * (100 - jitter) / 100;
+    }
+
+    pub fn packet(&mut self) -> ControlPacket {
+        let final_ = self.final_pending;
+        self.final_pending = false;
+        ControlPacket {
+            diag: self.local_diag,
+            state: self.state,
+            poll: self.poll_pending && !final_,
+            final_,
+            detect_mult: self.detect_mult,
+            my_discriminator: self.local_discr,
+            your_discriminator: self.remote_discr,
+            desired_min_tx_interval: self.effective_desired_min_tx().as_micros() as u32,
+            required_min_rx_interval: self.required_min_rx.as_micros() as u32,
+            ..Default::default()
+        }
+    }
+
+    /// Whether a reply must be sent right away, without waiting for the periodic timer.
+    pub fn needs_immediate_tx(&self) -> bool {
+        self.final_pending
+    }
+
+    fn set_state(&mut self, state: State, diag: Diagnostic) {
+        if state == State::Up && self.desired_min_tx < BFD_SLOW_TX_INTERVAL {
+            // Switching to the configured fast rate is a parameter change and needs a Poll
+            // Sequence (section 6.8.3).
+            self.poll_pending = true;
+        }
+        if state == State::Down {
+            self.poll_pending = false;
+        }
+        self.state = state;
+        self.local_diag = diag;
+    }
+
+    /// Processes a validated Control packet following RFC 5880 section 6.8.6. Returns the new
+    /// local state when it changed.
+    pub fn receive(&mut self, pkt: &ControlPacket, now: Instant) -> Option<State> {
+        let previous = self.state;
+
+        self.remote_discr = pkt.my_discriminator;
+        self.remote_state = pkt.state;
+        self.remote_desired_min_tx = Duration::from_micros(pkt.desired_min_tx_interval as u64);
+        self.remote_min_rx = Duration::from_micros(pkt.required_min_rx_interval as u64);
+        self.remote_detect_mult = pkt.detect_mult;
+
+        if pkt.final_ {
+            self.poll_pending = false;
+        }
+        if pkt.poll {
+            self.final_pending = true;
+        }
+
+        if self.state == State::AdminDown {
+            return None;
+        }
+
+        if pkt.state == State::AdminDown {
+            if self.state != State::Down {
+                self.set_state(State::Down, Diagnostic::NeighborSignaledSessionDown);
+            }
+        } else {
+            match self.state {
+                State::Down => match pkt.state {
+                    State::Down => self.set_state(State::Init, Diagnostic::NoDiag),
+                    State::Init => self.set_state(State::Up, Diagnostic::NoDiag),
+                    _ => {}
+                },
+                State::Init => {
+                    if matches!(pkt.state, State::Init | State::Up) {
+                        self.set_state(State::Up, Diagnostic::NoDiag);
+                    }
+                }
+                State::Up => {
+                    if pkt.state == State::Down {
+                        self.set_state(State::Down, Diagnostic::NeighborSignaledSessionDown);
+                    }
+                }
+                State::AdminDown => {}
+            }
+        }
+
+        self.detect_deadline = match self.state {
+            State::Init | State::Up => Some(now + self.detection_time()),
+            _ => None,
+        };
+
+        if self.state != previous {
+            Some(self.state)
+        } else {
+            None
+        }
+    }
+
+    /// Checks the detection timer. Returns the new local state when the session went down.
+    pub fn expire(&mut self, now: Instant) -> Option<State> {
+        match self.detect_deadline {
+            Some(deadline) if deadline <= now => {
+                self.detect_deadline = None;
+                if matches!(self.state, State::Init | State::Up) {
+                    self.set_state(State::Down, Diagnostic::ControlDetectionTimeExpired);
+                    self.remote_discr = 0;
+                    Some(self.state)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use super::packet::*;
+use super::session::BfdSession;
+use crate::config::BfdConfig;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::time::{Duration, Instant};
+
+fn session() -> BfdSession {
+    BfdSession::new(
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        1,
+        &BfdConfig::default(),
+    )
+}
+
+fn remote(state: State) -> ControlPacket {
+    ControlPacket {
+        state,
+        detect_mult: 3,
+        my_discriminator: 42,
+        your_discriminator: if state == State::Down { 0 } else { 1 },
+        desired_min_tx_interval: 100_000,
+        required_min_rx_interval: 100_000,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_three_way_handshake() {
+    let now = Instant::now();
+    let mut s = session();
+
+    assert_eq!(s.receive(&remote(State::Down), now), Some(State::Init));
+    assert_eq!(s.remote_discr, 42);
+    assert_eq!(s.receive(&remote(State::Up), now), Some(State::Up));
+    assert_eq!(s.receive(&remote(State::Up), now), None);
+
+    let mut s = session();
+    assert_eq!(s.receive(&remote(State::Init), now), Some(State::Up));
+}
+
+#[test]
+fn test_remote_down_and_admin_down() {
+    let now = Instant::now();
+    let mut s = session();
+    s.receive(&remote(State::Init), now);
+
+    assert_eq!(s.receive(&remote(State::Down), now), Some(State::Down));
+    assert_eq!(s.local_diag, Diagnostic::NeighborSignaledSessionDown);
+
+    let mut s = session();
+    s.receive(&remote(State::Init), now);
+    assert_eq!(s.receive(&remote(State::AdminDown), now), Some(State::Down));
+}
+
+#[test]
+fn test_detection_time_expiry() {
+    let now = Instant::now();
+    let mut s = session();
+    s.receive(&remote(State::Init), now);
+
+    // max(300ms local rx, 100ms remote tx) * 3
+    assert_eq!(s.detection_time(), Duration::from_millis(900));
+    assert_eq!(s.expire(now + Duration::from_millis(899)), None);
+    assert_eq!(
+        s.expire(now + Duration::from_millis(900)),
+        Some(State::Down)
+    );
+    assert_eq!(s.local_diag, Diagnostic::ControlDetectionTimeExpired);
+    assert_eq!(s.remote_discr, 0);
+}
+
+#[test]
+fn test_tx_interval_slow_until_up() {
+    let now = Instant::now();
+    let mut s = session();
+
+    assert_eq!(s.tx_interval(), Some(Duration::from_secs(1)));
+    s.receive(&remote(State::Init), now);
+    assert_eq!(s.tx_interval(), Some(Duration::from_millis(300)));
+
+    let pkt = s.packet();
+    assert!(pkt.poll);
+    assert_eq!(pkt.desired_min_tx_interval, 300_000);
+}
+
+#[test]
+fn test_poll_answered_with_final() {
+    let now = Instant::now();
+    let mut s = session();
+    let mut pkt = remote(State::Down);
+    pkt.poll = true;
+
+    s.receive(&pkt, now);
+    assert!(s.needs_immediate_tx());
+    let reply = s.packet();
+    assert!(reply.final_);
+    assert!(!reply.poll);
+    assert!(!s.needs_immediate_tx());
+}
+
+#[test]
+fn test_schedule_tx_jitter() {
+    let now = Instant::now();
+    let mut s = session();
+    s.receive(&remote(State::Init), now);
+
+    for _ in 0..100 {
+        s.schedule_tx(now);
+        let interval = s.next_tx - now;
+        assert!(interval >= Duration::from_millis(225));
+        assert!(interval <= Duration::from_millis(300));
+    }
+}
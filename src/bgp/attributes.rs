@@ -16,10 +16,14 @@ pub enum OriginType {
     Incomplete,
 }
 
+// Named after the RFC 4271 and RFC 5065 segment types
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Eq, Clone, FromPrimitive, PartialEq, Hash)]
 pub enum ASPATHSegmentType {
     AsSet = 1,
     AsSequence,
+    AsConfedSequence,
+    AsConfedSet,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -29,12 +33,22 @@ pub struct ASPATHSegment {
 }
 
 impl ASPATHSegment {
+    /// Length counted by the decision process, confederation segments do not count.
     pub fn len(&self) -> usize {
         match &self.segment_type {
             ASPATHSegmentType::AsSequence => self.as_list.len(),
             ASPATHSegmentType::AsSet => 1,
+            ASPATHSegmentType::AsConfedSequence | ASPATHSegmentType::AsConfedSet => 0,
         }
     }
+
+    /// Member ASes of the confederation the path went through.
+    pub fn is_confed(&self) -> bool {
+        matches!(
+            self.segment_type,
+            ASPATHSegmentType::AsConfedSequence | ASPATHSegmentType::AsConfedSet
+        )
+    }
 }
 
 impl From<ASPATHSegment> for Vec<u8> {
@@ -56,9 +70,34 @@ pub type Aspath = Vec<ASPATHSegment>;
 
 pub trait Flatten {
     fn flatten_aspath(&self) -> Vec<u16>;
+    /// Textual form of the path, e.g. `(65010) 65001 65002 {65003,65004}`, with AS_SET members
+    /// in braces, AS_CONFED_SEQUENCE in parentheses and AS_CONFED_SET in brackets.
+    fn aspath_string(&self) -> String;
 }
 
 impl Flatten for Aspath {
+    fn aspath_string(&self) -> String {
+        self.iter()
+            .map(|segment| {
+                let asns = segment.as_list.iter().map(|asn| asn.to_string());
+                match segment.segment_type {
+                    ASPATHSegmentType::AsSequence => asns.collect::<Vec<_>>().join(" "),
+                    ASPATHSegmentType::AsSet => {
+                        format!("{{{}}}", asns.collect::<Vec<_>>().join(","))
+                    }
+                    ASPATHSegmentType::AsConfedSequence => {
+                        format!("({})", asns.collect::<Vec<_>>().join(" "))
+                    }
+                    ASPATHSegmentType::AsConfedSet => {
+                        format!("[{}]", asns.collect::<Vec<_>>().join(","))
+                    }
+                }
+            })
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     fn flatten_aspath(&self) -> Vec<u16> {
         let mut v: Vec<u16> = vec![];
         for segment in self {
@@ -103,9 +142,9 @@ pub enum PathAttributeValue {
     LocalPref(u32),
     AtomicAggregate,
     Aggregator(AggregatorValue),
-    Community,
-    OriginatorId,
-    ClusterList,
+    Community(Vec<u32>),
+    OriginatorId(Ipv4Addr),
+    ClusterList(Vec<u32>),
     Dpa,
     Advertiser,
     RcidPathClusterId,
@@ -174,8 +213,8 @@ impl PathAttribute {
         PathAttribute {
             type_code: PathAttributeType::LocalPref,
             value: PathAttributeValue::LocalPref(pref),
-            optional: true,
-            transitive: false,
+            optional: false,
+            transitive: true,
             partial: false,
             extended_length: false,
         }
@@ -195,6 +234,39 @@ impl PathAttribute {
         }
     }
 
+    pub fn communities(communities: Vec<u32>) -> Self {
+        PathAttribute {
+            type_code: PathAttributeType::Community,
+            value: PathAttributeValue::Community(communities),
+            optional: true,
+            transitive: true,
+            partial: false,
+            extended_length: false,
+        }
+    }
+
+    pub fn originator_id(originator_id: Ipv4Addr) -> Self {
+        PathAttribute {
+            type_code: PathAttributeType::OriginatorId,
+            value: PathAttributeValue::OriginatorId(originator_id),
+            optional: true,
+            transitive: false,
+            partial: false,
+            extended_length: false,
+        }
+    }
+
+    pub fn cluster_list(cluster_list: Vec<u32>) -> Self {
+        PathAttribute {
+            type_code: PathAttributeType::ClusterList,
+            value: PathAttributeValue::ClusterList(cluster_list),
+            optional: true,
+            transitive: false,
+            partial: false,
+            extended_length: false,
+        }
+    }
+
     pub fn mp_reachable(af: AddressFamily, nh: IpAddr, nlris: Vec<Nlri>) -> Self {
         PathAttribute {
             type_code: PathAttributeType::MPReachableNLRI,
@@ -222,8 +294,10 @@ impl PathAttribute {
     }
 }
 
-impl From<Vec<u8>> for PathAttribute {
-    fn from(src: Vec<u8>) -> Self {
+impl TryFrom<Vec<u8>> for PathAttribute {
+    type Error = BgpValidationError;
+
+    fn try_from(src: Vec<u8>) -> Result<Self, Self::Error> {
         let mask = src[0];
 
         let mask = mask >> 4;
@@ -260,8 +334,13 @@ impl From<Vec<u8>> for PathAttribute {
                 let mut offset = 0;
 
                 while total_len > 0 {
-                    let segment_type: ASPATHSegmentType =
-                        FromPrimitive::from_u8(src[i + offset]).unwrap();
+                    let segment_type: ASPATHSegmentType = FromPrimitive::from_u8(src[i + offset])
+                        .ok_or_else(|| {
+                        BgpValidationError::MalformedAsPath(format!(
+                            "unknown segment type {}",
+                            src[i + offset]
+                        ))
+                    })?;
                     let as_list_len = src[i + offset + 1] as usize;
                     // let mut as_list = Box::<Vec<u16>>::new(vec![]);
                     let mut as_list = vec![];
@@ -314,9 +393,46 @@ impl From<Vec<u8>> for PathAttribute {
                 };
                 PathAttributeValue::Aggregator(ag)
             }
-            PathAttributeType::Community => PathAttributeValue::Community,
-            PathAttributeType::OriginatorId => PathAttributeValue::OriginatorId,
-            PathAttributeType::ClusterList => PathAttributeValue::ClusterList,
+            PathAttributeType::Community => {
+                let start = if extended_length { 4 } else { 3 };
+                // RFC 4271 section 6.3, communities are 4 octets each
+                let len = src.len() - start;
+                if !len.is_multiple_of(4) {
+                    return Err(BgpValidationError::InvalidPathAttributeLength(len));
+                }
+                let communities = src[start..]
+                    .chunks_exact(4)
+                    .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect();
+                PathAttributeValue::Community(communities)
+            }
+            PathAttributeType::OriginatorId => {
+                let start = if extended_length { 4 } else { 3 };
+                // RFC 4456 section 8, the ORIGINATOR_ID is a 4 octet router ID
+                let len = src.len() - start;
+                if len != 4 {
+                    return Err(BgpValidationError::InvalidPathAttributeLength(len));
+                }
+                PathAttributeValue::OriginatorId(Ipv4Addr::new(
+                    src[start],
+                    src[start + 1],
+                    src[start + 2],
+                    src[start + 3],
+                ))
+            }
+            PathAttributeType::ClusterList => {
+                let start = if extended_length { 4 } else { 3 };
+                // RFC 4456 section 8, cluster IDs are 4 octets each
+                let len = src.len() - start;
+                if !len.is_multiple_of(4) {
+                    return Err(BgpValidationError::InvalidPathAttributeLength(len));
+                }
+                let cluster_list = src[start..]
+                    .chunks_exact(4)
+                    .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect();
+                PathAttributeValue::ClusterList(cluster_list)
+            }
             PathAttributeType::Dpa => PathAttributeValue::Dpa,
             PathAttributeType::Advertiser => PathAttributeValue::Advertiser,
             PathAttributeType::RcidPathClusterId => PathAttributeValue::RcidPathClusterId,
@@ -329,14 +445,14 @@ impl From<Vec<u8>> for PathAttribute {
             PathAttributeType::ExtCommunities => PathAttributeValue::ExtCommunities,
         };
 
-        PathAttribute {
+        Ok(PathAttribute {
             optional,
             transitive,
             partial,
             extended_length,
             type_code,
             value,
-        }
+        })
     }
 }
 
@@ -380,14 +496,21 @@ impl From<PathAttribute> for Vec<u8> {
                     .write_u32::<BigEndian>(value.aggregator.into())
                     .unwrap();
             }
-            PathAttributeValue::Community => {
+            PathAttributeValue::Community(value) => {
                 code = 8;
+                for c in value {
+                    bufval.write_u32::<BigEndian>(c).unwrap();
+                }
             }
-            PathAttributeValue::OriginatorId => {
+            PathAttributeValue::OriginatorId(value) => {
                 code = 9;
+                bufval.write_u32::<BigEndian>(value.into()).unwrap();
             }
-            PathAttributeValue::ClusterList => {
+            PathAttributeValue::ClusterList(value) => {
                 code = 10;
+                for c in value {
+                    bufval.write_u32::<BigEndian>(c).unwrap();
+                }
             }
             PathAttributeValue::Dpa => {
                 code = 11;
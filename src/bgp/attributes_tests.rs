@@ -52,6 +52,58 @@ fn test_aspath_flatten_valid() {
     assert_eq!(flattened, vec![65000, 65001, 65002, 65003]);
 }
 
+#[test]
+fn test_aspath_string_valid() {
+    let aspath: Aspath = vec![
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsSequence,
+            as_list: vec![65001, 65002],
+        },
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsSet,
+            as_list: vec![65003, 65004],
+        },
+    ];
+
+    assert_eq!(aspath.aspath_string(), "65001 65002 {65003,65004}");
+    assert_eq!(Aspath::new().aspath_string(), "");
+}
+
+#[test]
+fn test_aspath_confed_segments() {
+    let aspath: Aspath = vec![
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsConfedSequence,
+            as_list: vec![65010, 65011],
+        },
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsConfedSet,
+            as_list: vec![65012, 65013],
+        },
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsSequence,
+            as_list: vec![65001],
+        },
+    ];
+    assert_eq!(
+        aspath.aspath_string(),
+        "(65010 65011) [65012,65013] 65001"
+    );
+    assert_eq!(aspath.iter().map(|s| s.len()).sum::<usize>(), 1);
+    assert!(aspath[0].is_confed() && aspath[1].is_confed() && !aspath[2].is_confed());
+
+    let attr = PathAttribute::aspath(aspath.clone());
+    let bytes: Vec<u8> = attr.into();
+    assert_eq!(
+        bytes[3..],
+        [3, 2, 0xFD, 0xF2, 0xFD, 0xF3, 4, 2, 0xFD, 0xF4, 0xFD, 0xF5, 2, 1, 0xFD, 0xE9]
+    );
+    assert_eq!(
+        PathAttribute::try_from(bytes).unwrap().value,
+        PathAttributeValue::AsPath(aspath)
+    );
+}
+
 #[test]
 fn test_path_attribute_origin_valid() {
     let attr = PathAttribute::origin(OriginType::Igp);
@@ -114,10 +166,92 @@ fn test_path_attribute_local_pref_valid() {
 
     assert_eq!(attr.type_code, PathAttributeType::LocalPref);
     assert_eq!(attr.value, PathAttributeValue::LocalPref(pref));
-    assert!(attr.optional);
-    assert!(!attr.transitive);
+    assert!(!attr.optional);
+    assert!(attr.transitive);
     assert!(!attr.partial);
     assert!(!attr.extended_length);
+
+    // Well-known discretionary
+    let bytes: Vec<u8> = attr.into();
+    assert_eq!(bytes, [0x40, 5, 4, 0, 0, 0, 100]);
+}
+
+#[test]
+fn test_path_attribute_communities_valid() {
+    let communities = vec![0xFDE8_0064, 0xFFFF_FF01];
+    let attr = PathAttribute::communities(communities.clone());
+
+    assert_eq!(attr.type_code, PathAttributeType::Community);
+    assert!(attr.optional);
+    assert!(attr.transitive);
+
+    let bytes: Vec<u8> = attr.clone().into();
+    assert_eq!(
+        bytes,
+        vec![0xC0, 8, 8, 0xFD, 0xE8, 0x00, 0x64, 0xFF, 0xFF, 0xFF, 0x01]
+    );
+    assert_eq!(PathAttribute::try_from(bytes).unwrap(), attr);
+}
+
+#[test]
+fn test_path_attribute_communities_length_error() {
+    let bytes = vec![0xC0, 8, 6, 0xFD, 0xE8, 0x00, 0x64, 0xFF, 0xFF];
+    let err = PathAttribute::try_from(bytes).unwrap_err();
+
+    assert!(matches!(
+        err,
+        BgpValidationError::InvalidPathAttributeLength(6)
+    ));
+    assert_eq!(
+        err.to_notification_codes(),
+        (
+            ErrorCode::UpdateMessage,
+            UpdateSubCode::AttributeLengthError as u8
+        )
+    );
+}
+
+#[test]
+fn test_path_attribute_originator_id_length_error() {
+    for bytes in [
+        vec![0x80, 9, 3, 192, 0, 2],
+        vec![0x80, 9, 5, 192, 0, 2, 1, 0],
+    ] {
+        let len = bytes.len() - 3;
+        let err = PathAttribute::try_from(bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            BgpValidationError::InvalidPathAttributeLength(l) if l == len
+        ));
+    }
+}
+
+#[test]
+fn test_path_attribute_cluster_list_length_error() {
+    let bytes = vec![0x80, 10, 6, 192, 0, 2, 1, 192, 0];
+    let err = PathAttribute::try_from(bytes).unwrap_err();
+
+    assert!(matches!(
+        err,
+        BgpValidationError::InvalidPathAttributeLength(6)
+    ));
+}
+
+#[test]
+fn test_path_attribute_originator_id_and_cluster_list() {
+    let attr = PathAttribute::originator_id(Ipv4Addr::new(192, 0, 2, 1));
+    assert!(attr.optional);
+    assert!(!attr.transitive);
+    let bytes: Vec<u8> = attr.clone().into();
+    assert_eq!(bytes, vec![0x80, 9, 4, 192, 0, 2, 1]);
+    assert_eq!(PathAttribute::try_from(bytes).unwrap(), attr);
+
+    let attr = PathAttribute::cluster_list(vec![0x0A00_0001, 0x0A00_0002]);
+    assert!(attr.optional);
+    assert!(!attr.transitive);
+    let bytes: Vec<u8> = attr.clone().into();
+    assert_eq!(bytes, vec![0x80, 10, 8, 10, 0, 0, 1, 10, 0, 0, 2]);
+    assert_eq!(PathAttribute::try_from(bytes).unwrap(), attr);
 }
 
 #[test]
@@ -151,7 +285,7 @@ fn test_path_attribute_transitive_check_valid() {
 fn test_path_attribute_from_empty_bytes_invalid() {
     let empty_bytes: Vec<u8> = vec![];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = empty_bytes.into();
+        let _attr = PathAttribute::try_from(empty_bytes);
     })
     .expect_err("Should panic on empty input");
 }
@@ -160,7 +294,7 @@ fn test_path_attribute_from_empty_bytes_invalid() {
 fn test_path_attribute_from_insufficient_bytes_invalid() {
     let insufficient_bytes: Vec<u8> = vec![0x40];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = insufficient_bytes.into();
+        let _attr = PathAttribute::try_from(insufficient_bytes);
     })
     .expect_err("Should panic on insufficient input");
 }
@@ -169,7 +303,7 @@ fn test_path_attribute_from_insufficient_bytes_invalid() {
 fn test_path_attribute_from_invalid_type_code_invalid() {
     let invalid_bytes: Vec<u8> = vec![0x40, 255, 1, 0];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = invalid_bytes.into();
+        let _attr = PathAttribute::try_from(invalid_bytes);
     })
     .expect_err("Should panic on invalid type code");
 }
@@ -178,7 +312,7 @@ fn test_path_attribute_from_invalid_type_code_invalid() {
 fn test_path_attribute_origin_invalid_value() {
     let invalid_bytes: Vec<u8> = vec![0x40, 1, 1, 3];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = invalid_bytes.into();
+        let _attr = PathAttribute::try_from(invalid_bytes);
     })
     .expect_err("Should panic on invalid origin value");
 }
@@ -187,7 +321,7 @@ fn test_path_attribute_origin_invalid_value() {
 fn test_path_attribute_nexthop_insufficient_bytes_invalid() {
     let invalid_bytes: Vec<u8> = vec![0x40, 3, 3, 192, 0, 2];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = invalid_bytes.into();
+        let _attr = PathAttribute::try_from(invalid_bytes);
     })
     .expect_err("Should panic on insufficient bytes for nexthop");
 }
@@ -196,12 +330,18 @@ fn test_path_attribute_nexthop_insufficient_bytes_invalid() {
 fn test_path_attribute_aspath_invalid_segment_type() {
     let invalid_bytes: Vec<u8> = vec![
         0x40, 2, 4,
-        3, 1, 0xFD, 0xE8,
+        5, 1, 0xFD, 0xE8,
     ];
-    std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = invalid_bytes.into();
-    })
-    .expect_err("Should panic on invalid AS_PATH segment type");
+    let err = PathAttribute::try_from(invalid_bytes).unwrap_err();
+
+    assert!(matches!(err, BgpValidationError::MalformedAsPath(_)));
+    assert_eq!(
+        err.to_notification_codes(),
+        (
+            ErrorCode::UpdateMessage,
+            UpdateSubCode::MalformedASPATH as u8
+        )
+    );
 }
 
 #[test]
@@ -211,7 +351,7 @@ fn test_path_attribute_aspath_inconsistent_length_invalid() {
         2, 2, 0xFD, 0xE8,
     ];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = invalid_bytes.into();
+        let _attr = PathAttribute::try_from(invalid_bytes);
     })
     .expect_err("Should panic on inconsistent AS_PATH length");
 }
@@ -220,7 +360,7 @@ fn test_path_attribute_aspath_inconsistent_length_invalid() {
 fn test_path_attribute_med_insufficient_bytes_invalid() {
     let invalid_bytes: Vec<u8> = vec![0x80, 4, 2, 0x00, 0x64];
     std::panic::catch_unwind(|| {
-        let _attr: PathAttribute = invalid_bytes.into();
+        let _attr = PathAttribute::try_from(invalid_bytes);
     })
     .expect_err("Should panic on insufficient bytes for MED");
 }
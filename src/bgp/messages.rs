@@ -272,7 +272,7 @@ impl TryFrom<Vec<u8>> for BGPUpdateMessage {
                     if i + 3 + atn > src.len() {
                         break;
                     }
-                    n = src[i..i + 3 + atn].to_vec().into();
+                    n = src[i..i + 3 + atn].to_vec().try_into()?;
                     used += 3 + atn;
                     i += 3 + atn;
                 }
@@ -286,7 +286,7 @@ impl TryFrom<Vec<u8>> for BGPUpdateMessage {
                     if i + 4 + atn > src.len() {
                         break;
                     }
-                    n = src[i..i + 4 + atn].to_vec().into();
+                    n = src[i..i + 4 + atn].to_vec().try_into()?;
                     used += 4 + atn;
                     i += 4 + atn;
                 }
@@ -15,6 +15,15 @@ pub struct Nlri {
     pub net: IpNet,
 }
 
+impl Nlri {
+    pub fn afi(&self) -> Afi {
+        match self.net {
+            IpNet::V4(_) => Afi::Ipv4,
+            IpNet::V6(_) => Afi::Ipv6,
+        }
+    }
+}
+
 pub struct Ipv4Octets {
     pub octets: Vec<u8>,
 }
@@ -27,11 +36,7 @@ impl From<Nlri> for Vec<u8> {
     fn from(val: Nlri) -> Self {
         let mut buf = Cursor::new(vec![]);
         buf.write_u8(val.net.prefix_len()).unwrap();
-        let afi = match val.net {
-            IpNet::V4(_) => &Afi::Ipv4,
-            IpNet::V6(_) => &Afi::Ipv6,
-        };
-        let blen = prefix_bytes(val.net.prefix_len(), afi).unwrap(); // This should never fail for valid IpNet
+        let blen = prefix_bytes(val.net.prefix_len(), &val.afi()).unwrap(); // This should never fail for valid IpNet
         match val.net {
             IpNet::V4(v) => {
                 let addrv4: u32 = v.network().into();
@@ -54,6 +59,12 @@ impl From<Nlri> for IpNet {
     }
 }
 
+impl From<IpNet> for Nlri {
+    fn from(net: IpNet) -> Self {
+        Nlri { net }
+    }
+}
+
 impl From<&Nlri> for IpNet {
     fn from(val: &Nlri) -> Self {
         val.net
@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod attributes_tests {
     use super::super::attributes::*;
+    use super::super::types::*;
     use std::net::Ipv4Addr;
     include!("../bgp/attributes_tests.rs");
 }
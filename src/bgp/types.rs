@@ -85,6 +85,21 @@ pub enum UpdateSubCode {
     MalformedASPATH = 11,
 }
 
+/// NOTIFICATION Cease subcodes, RFC 4486.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum CeaseSubCode {
+    MaximumNumberOfPrefixesReached = 1,
+    AdministrativeShutdown = 2,
+    PeerDeconfigured = 3,
+    AdministrativeReset = 4,
+    ConnectionRejected = 5,
+    OtherConfigurationChange = 6,
+    ConnectionCollisionResolution = 7,
+    OutOfResources = 8,
+}
+
 #[derive(Error, Debug)]
 pub enum BgpValidationError {
     #[error("Message too short: got {actual}, minimum {minimum}")]
@@ -1,7 +1,8 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::io::prelude::*;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use crate::bgp;
@@ -10,11 +11,18 @@ use serde_derive::Deserialize;
 pub const BGP_DEFAULT_PORT: u16 = 179;
 pub const BGP_DEFAULT_HOLD_TIME: u16 = 3;
 pub const BGP_DEFAULT_LOCAL_IP: &str = "[::]:0";
+pub const BFD_DEFAULT_PORT: u16 = 3784;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub asn: u16,
     pub rid: Ipv4Addr,
+    /// Cluster of the route reflector, the router ID by default. Reflectors of the same
+    /// cluster share it.
+    #[serde(default)]
+    pub cluster_id: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub confederation: Option<Confederation>,
     #[serde(default)]
     pub localips: Option<Vec<IpAddr>>,
     #[serde(default)]
@@ -25,6 +33,27 @@ pub struct Config {
     pub families: Option<Vec<bgp::AddressFamily>>,
     #[serde(default)]
     pub neighbors: Option<Vec<Neighbor>>,
+    /// Prefixes the speaker originates, e.g. `10.0.0.0/24`.
+    #[serde(default)]
+    pub networks: Vec<String>,
+    #[serde(default)]
+    pub bfd: BfdConfig,
+    #[serde(default)]
+    pub damping: Option<DampingConfig>,
+    #[serde(default)]
+    pub prefix_lists: Vec<PrefixList>,
+    #[serde(default)]
+    pub as_path_lists: Vec<AsPathList>,
+    #[serde(default)]
+    pub route_maps: Vec<RouteMap>,
+    #[serde(default)]
+    pub best_path: BestPathConfig,
+    #[serde(default)]
+    pub fib: FibConfig,
+    #[serde(default)]
+    pub mrai: MraiConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
 }
 
 pub fn read_config(path: &PathBuf) -> Result<Config> {
@@ -62,6 +91,88 @@ pub fn read_config(path: &PathBuf) -> Result<Config> {
         Some(vec![a])
     });
 
+    if matches!(
+        config.fib.backend,
+        FibBackendType::File | FibBackendType::Socket
+    ) && config.fib.path.is_none()
+    {
+        return Err(anyhow!(
+            "The {:?} FIB backend needs a path",
+            config.fib.backend
+        ));
+    }
+
+    if let Some(network) = config
+        .networks
+        .iter()
+        .find(|n| n.parse::<ipnet::IpNet>().is_err())
+    {
+        return Err(anyhow!("Invalid network {}", network));
+    }
+
+    if config.bfd.multiplier == 0 || config.bfd.min_tx == 0 || config.bfd.min_rx == 0 {
+        return Err(anyhow!(
+            "The BFD multiplier and intervals cannot be 0 (multiplier {}, min_tx {}, min_rx {})",
+            config.bfd.multiplier,
+            config.bfd.min_tx,
+            config.bfd.min_rx
+        ));
+    }
+
+    if let Some(damping) = &config.damping {
+        if damping.half_life == 0 {
+            return Err(anyhow!("The damping half_life cannot be 0"));
+        }
+        if damping.reuse >= damping.suppress {
+            return Err(anyhow!(
+                "The damping reuse threshold {} is not below the suppress threshold {}",
+                damping.reuse,
+                damping.suppress
+            ));
+        }
+        if damping.ceiling() <= damping.suppress as f64 {
+            return Err(anyhow!(
+                "The damping max_suppress_time {} caps the penalty at {:.0}, not above the suppress threshold {}",
+                damping.max_suppress_time,
+                damping.ceiling(),
+                damping.suppress
+            ));
+        }
+    }
+
+    if config.mrai.jitter > 100 {
+        return Err(anyhow!(
+            "The MRAI jitter is a percentage, {} is over 100",
+            config.mrai.jitter
+        ));
+    }
+
+    if let Some(n) = config
+        .neighbors
+        .iter()
+        .flatten()
+        .find(|n| n.local_as.is_some() && n.asn == config.asn)
+    {
+        return Err(anyhow!(
+            "Neighbor {} is in AS {}, local_as only applies to eBGP neighbors",
+            n.ip,
+            config.asn
+        ));
+    }
+
+    if let Some(n) = config
+        .neighbors
+        .iter()
+        .flatten()
+        .find(|n| n.route_reflector_client && n.asn != config.asn)
+    {
+        return Err(anyhow!(
+            "Neighbor {} is not in AS {}, it cannot be a route reflector client",
+            n.ip,
+            config.asn
+        ));
+    }
+
     Ok(config)
 }
 
@@ -99,4 +210,566 @@ pub struct Neighbor {
     pub max_retry_count: Option<u16>,
     #[serde(default = "default_exponential_backoff")]
     pub exponential_backoff: bool,
+    #[serde(default)]
+    pub bfd: bool,
+    #[serde(default)]
+    pub max_prefix: Vec<MaxPrefix>,
+    #[serde(default)]
+    pub damp_peer_oscillations: Option<PeerDamping>,
+    #[serde(default)]
+    pub policies: Vec<NeighborPolicy>,
+    /// Local preference given to every path received from the neighbor, higher wins. It is
+    /// the first step of the decision process and never advertised.
+    #[serde(default)]
+    pub weight: u32,
+    /// MinRouteAdvertisementInterval in seconds, the session type default when unset.
+    #[serde(default)]
+    pub mrai: Option<u16>,
+    /// Keeps the routes received from the neighbor as they were before the import policy, so
+    /// that a policy change can be applied without resetting the session.
+    #[serde(default)]
+    pub soft_reconfiguration_inbound: bool,
+    /// Reflects the iBGP routes of other neighbors to this one and its routes to every iBGP
+    /// neighbor (RFC 4456).
+    #[serde(default)]
+    pub route_reflector_client: bool,
+    #[serde(default)]
+    pub local_as: Option<LocalAs>,
+    /// Replaces the AS of the neighbor with the local AS in the paths advertised to it, for
+    /// sites sharing an AS behind a transit.
+    #[serde(default)]
+    pub as_override: bool,
+    /// Number of times the local AS may appear in the paths received from the neighbor.
+    #[serde(default)]
+    pub allowas_in: u8,
+}
+
+/// AS presented to an eBGP neighbor instead of the local AS, e.g. while migrating from it.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LocalAs {
+    pub asn: u16,
+    /// Does not prepend `asn` to the paths received from the neighbor.
+    #[serde(default)]
+    pub no_prepend: bool,
+    /// Prepends only `asn` to the paths advertised to the neighbor, without the local AS.
+    #[serde(default)]
+    pub replace_as: bool,
+}
+
+/// Confederation (RFC 5065) the local AS is a member of. External neighbors see it as a single
+/// AS, `identifier`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Confederation {
+    pub identifier: u16,
+    /// Other member ASes, whose neighbors exchange routes keeping the next hop and LOCAL_PREF.
+    #[serde(default)]
+    pub members: Vec<u16>,
+}
+
+/// Route maps applied to the routes received from (`import`) and advertised to (`export`) a
+/// neighbor for one address family.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NeighborPolicy {
+    pub afi: bgp::Afi,
+    pub safi: bgp::Safi,
+    #[serde(default)]
+    pub import: Option<String>,
+    #[serde(default)]
+    pub export: Option<String>,
+}
+
+impl NeighborPolicy {
+    pub fn family(&self) -> bgp::AddressFamily {
+        bgp::AddressFamily {
+            afi: self.afi,
+            safi: self.safi,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    #[default]
+    Permit,
+    Deny,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrefixList {
+    pub name: String,
+    #[serde(default)]
+    pub entries: Vec<PrefixListEntry>,
+}
+
+/// Matches `prefix` and its more specifics whose length is between `ge` and `le`. Without
+/// either bound only the exact prefix matches.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrefixListEntry {
+    #[serde(default)]
+    pub action: PolicyAction,
+    pub prefix: String,
+    #[serde(default)]
+    pub ge: Option<u8>,
+    #[serde(default)]
+    pub le: Option<u8>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AsPathList {
+    pub name: String,
+    #[serde(default)]
+    pub entries: Vec<AsPathListEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AsPathListEntry {
+    #[serde(default)]
+    pub action: PolicyAction,
+    /// AS path regular expression, e.g. `^65001_` or `_65000-65099$`.
+    pub regex: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouteMap {
+    pub name: String,
+    #[serde(default)]
+    pub entries: Vec<RouteMapEntry>,
+}
+
+/// Route map entries are evaluated in order. The first entry whose match clauses all hold
+/// decides, and routes matching no entry are rejected.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouteMapEntry {
+    #[serde(default)]
+    pub action: PolicyAction,
+    #[serde(default, rename = "match")]
+    pub match_: RouteMapMatch,
+    #[serde(default)]
+    pub set: RouteMapSet,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RouteMapMatch {
+    #[serde(default)]
+    pub prefix_list: Option<String>,
+    /// Address or prefix containing the next hop.
+    #[serde(default)]
+    pub next_hop: Option<String>,
+    #[serde(default)]
+    pub as_path_contains: Option<u16>,
+    #[serde(default)]
+    pub as_path_list: Option<String>,
+    /// One of `igp`, `egp` or `incomplete`.
+    #[serde(default)]
+    pub origin: Option<String>,
+    #[serde(default)]
+    pub med: Option<u32>,
+    #[serde(default)]
+    pub local_pref: Option<u32>,
+    /// `ASN:VALUE` or a well-known community name such as `no-export`.
+    #[serde(default)]
+    pub community: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RouteMapSet {
+    #[serde(default)]
+    pub local_pref: Option<u32>,
+    #[serde(default)]
+    pub med: Option<u32>,
+    #[serde(default)]
+    pub next_hop: Option<IpAddr>,
+    #[serde(default)]
+    pub community: Option<Vec<String>>,
+    /// Add to the communities of the route instead of replacing them.
+    #[serde(default)]
+    pub community_additive: bool,
+    #[serde(default)]
+    pub as_path_prepend: Vec<u16>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+}
+
+fn default_idle_hold_time() -> u16 {
+    5
+}
+
+fn default_max_idle_hold_time() -> u16 {
+    300
+}
+
+fn default_idle_hold_stable_time() -> u16 {
+    300
+}
+
+/// RFC 4271 DampPeerOscillations: the idle hold time doubles after every session failure, up
+/// to `max_idle_hold_time`, and halves for every `stable_time` seconds spent Established.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PeerDamping {
+    #[serde(default = "default_idle_hold_time")]
+    pub idle_hold_time: u16,
+    #[serde(default = "default_max_idle_hold_time")]
+    pub max_idle_hold_time: u16,
+    #[serde(default = "default_idle_hold_stable_time")]
+    pub stable_time: u16,
+}
+
+fn default_max_prefix_warning_threshold() -> u8 {
+    75
+}
+
+/// Upper bound on the number of prefixes accepted from a neighbor for one address family.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MaxPrefix {
+    pub afi: bgp::Afi,
+    pub safi: bgp::Safi,
+    pub limit: u32,
+    /// Percentage of `limit` at which a warning is logged.
+    #[serde(default = "default_max_prefix_warning_threshold")]
+    pub warning_threshold: u8,
+    #[serde(default)]
+    pub warning_only: bool,
+    /// Seconds after which the session is restarted once torn down, never when unset.
+    #[serde(default)]
+    pub restart_interval: Option<u16>,
+}
+
+impl MaxPrefix {
+    pub fn family(&self) -> bgp::AddressFamily {
+        bgp::AddressFamily {
+            afi: self.afi,
+            safi: self.safi,
+        }
+    }
+}
+
+fn default_bfd_port() -> u16 {
+    BFD_DEFAULT_PORT
+}
+
+fn default_bfd_min_tx() -> u32 {
+    300
+}
+
+fn default_bfd_min_rx() -> u32 {
+    300
+}
+
+fn default_bfd_multiplier() -> u8 {
+    3
+}
+
+/// Knobs of the best path decision process.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BestPathConfig {
+    /// Compare MED between paths from different neighbor ASes.
+    #[serde(default)]
+    pub always_compare_med: bool,
+    /// Select the best path of every neighbor AS before comparing them, so that the result
+    /// does not depend on the order paths were received in.
+    #[serde(default)]
+    pub deterministic_med: bool,
+    /// Number of equal-cost paths installed in the FIB when the best path is learned over eBGP.
+    #[serde(default = "default_maximum_paths")]
+    pub maximum_paths_ebgp: usize,
+    /// Number of equal-cost paths installed in the FIB when the best path is learned over iBGP.
+    #[serde(default = "default_maximum_paths")]
+    pub maximum_paths_ibgp: usize,
+    /// Paths with AS paths of the same length but different content are also equal.
+    #[serde(default)]
+    pub multipath_relax: bool,
+}
+
+impl Default for BestPathConfig {
+    fn default() -> Self {
+        BestPathConfig {
+            always_compare_med: false,
+            deterministic_med: false,
+            maximum_paths_ebgp: default_maximum_paths(),
+            maximum_paths_ibgp: default_maximum_paths(),
+            multipath_relax: false,
+        }
+    }
+}
+
+fn default_maximum_paths() -> usize {
+    1
+}
+
+/// Where the best paths are installed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FibBackendType {
+    /// The kernel routing table.
+    #[default]
+    Netlink,
+    /// An in-memory table, for route servers and tests.
+    Memory,
+    /// A JSON file rewritten after every change.
+    File,
+    /// A Unix socket the JSON table is written to after every change.
+    Socket,
+    /// An FPM server, for userspace dataplanes.
+    Fpm,
+}
+
+/// Payload of the FPM messages.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FpmEncoding {
+    #[default]
+    Netlink,
+    Protobuf,
+}
+
+/// Connection to the FPM server of the `fpm` backend.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FpmConfig {
+    #[serde(default = "default_fpm_address")]
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub encoding: FpmEncoding,
+}
+
+impl Default for FpmConfig {
+    fn default() -> Self {
+        FpmConfig {
+            address: default_fpm_address(),
+            encoding: FpmEncoding::default(),
+        }
+    }
+}
+
+fn default_fpm_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 2620))
+}
+
+/// Kernel table, metric, protocol number and preferred source address (RTA_PREFSRC) of the
+/// installed routes. Unset fields are inherited.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KernelRoute {
+    #[serde(default)]
+    pub table: Option<u32>,
+    #[serde(default)]
+    pub metric: Option<u32>,
+    #[serde(default)]
+    pub protocol: Option<u8>,
+    #[serde(default)]
+    pub prefsrc: Option<IpAddr>,
+}
+
+/// How the routes of an address family are installed. The first rule whose prefix list permits
+/// a prefix overrides the settings of the family for it.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FibFamily {
+    pub afi: bgp::Afi,
+    pub safi: bgp::Safi,
+    #[serde(flatten)]
+    pub route: KernelRoute,
+    #[serde(default)]
+    pub rules: Vec<FibRule>,
+}
+
+impl FibFamily {
+    pub fn family(&self) -> bgp::AddressFamily {
+        bgp::AddressFamily {
+            afi: self.afi,
+            safi: self.safi,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FibRule {
+    pub prefix_list: String,
+    #[serde(flatten)]
+    pub route: KernelRoute,
+}
+
+/// Kernel routing table synchronization.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FibConfig {
+    #[serde(default)]
+    pub backend: FibBackendType,
+    /// File or socket of the `file` and `socket` backends.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub fpm: FpmConfig,
+    #[serde(default)]
+    pub families: Vec<FibFamily>,
+    /// Seconds after startup before the BGP routes left over by a previous run, and not learned
+    /// again since, are removed from the kernel.
+    #[serde(default = "default_stale_routes_time")]
+    pub stale_routes_time: u16,
+    /// Resolve BGP next hops through the default route.
+    #[serde(default)]
+    pub resolve_via_default: bool,
+    /// Resolve BGP next hops through routes installed by BGP.
+    #[serde(default)]
+    pub resolve_via_bgp: bool,
+    /// Kernel table BGP next hops are resolved in, the main table by default.
+    #[serde(default)]
+    pub resolve_table: Option<u32>,
+}
+
+impl Default for FibConfig {
+    fn default() -> Self {
+        FibConfig {
+            backend: FibBackendType::default(),
+            path: None,
+            fpm: FpmConfig::default(),
+            families: vec![],
+            stale_routes_time: default_stale_routes_time(),
+            resolve_via_default: false,
+            resolve_via_bgp: false,
+            resolve_table: None,
+        }
+    }
+}
+
+fn default_stale_routes_time() -> u16 {
+    120
+}
+
+/// Timers shared by every BFD session, in milliseconds.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BfdConfig {
+    #[serde(default = "default_bfd_port")]
+    pub port: u16,
+    #[serde(default = "default_bfd_min_tx")]
+    pub min_tx: u32,
+    #[serde(default = "default_bfd_min_rx")]
+    pub min_rx: u32,
+    #[serde(default = "default_bfd_multiplier")]
+    pub multiplier: u8,
+}
+
+impl Default for BfdConfig {
+    fn default() -> Self {
+        BfdConfig {
+            port: default_bfd_port(),
+            min_tx: default_bfd_min_tx(),
+            min_rx: default_bfd_min_rx(),
+            multiplier: default_bfd_multiplier(),
+        }
+    }
+}
+
+fn default_damping_half_life() -> u32 {
+    900
+}
+
+fn default_damping_reuse() -> u32 {
+    750
+}
+
+fn default_damping_suppress() -> u32 {
+    6000
+}
+
+fn default_damping_max_suppress_time() -> u32 {
+    3600
+}
+
+fn default_damping_withdraw_penalty() -> u32 {
+    1000
+}
+
+fn default_damping_attribute_change_penalty() -> u32 {
+    500
+}
+
+/// RFC 4271 MinRouteAdvertisementInterval defaults in seconds. Each interval is shortened by a
+/// random amount of up to `jitter` percent.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MraiConfig {
+    #[serde(default = "default_mrai_ebgp")]
+    pub ebgp: u16,
+    #[serde(default = "default_mrai_ibgp")]
+    pub ibgp: u16,
+    #[serde(default = "default_mrai_jitter")]
+    pub jitter: u8,
+}
+
+impl Default for MraiConfig {
+    fn default() -> Self {
+        MraiConfig {
+            ebgp: default_mrai_ebgp(),
+            ibgp: default_mrai_ibgp(),
+            jitter: default_mrai_jitter(),
+        }
+    }
+}
+
+fn default_mrai_ebgp() -> u16 {
+    30
+}
+
+fn default_mrai_ibgp() -> u16 {
+    5
+}
+
+fn default_mrai_jitter() -> u8 {
+    25
+}
+
+/// gRPC API settings.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiConfig {
+    /// How long a neighbor session is given to hand over its Adj-RIBs-In, in milliseconds.
+    #[serde(default = "default_adj_rib_timeout")]
+    pub adj_rib_timeout: u64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            adj_rib_timeout: default_adj_rib_timeout(),
+        }
+    }
+}
+
+fn default_adj_rib_timeout() -> u64 {
+    1000
+}
+
+/// Route flap damping parameters. Times are in seconds and the defaults follow the RFC 7196
+/// recommendations.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DampingConfig {
+    #[serde(default = "default_damping_half_life")]
+    pub half_life: u32,
+    #[serde(default = "default_damping_reuse")]
+    pub reuse: u32,
+    #[serde(default = "default_damping_suppress")]
+    pub suppress: u32,
+    #[serde(default = "default_damping_max_suppress_time")]
+    pub max_suppress_time: u32,
+    #[serde(default = "default_damping_withdraw_penalty")]
+    pub withdraw_penalty: u32,
+    #[serde(default = "default_damping_attribute_change_penalty")]
+    pub attribute_change_penalty: u32,
+}
+
+impl DampingConfig {
+    /// Highest penalty a path can reach, so that it is never suppressed for longer than
+    /// `max_suppress_time`.
+    pub fn ceiling(&self) -> f64 {
+        self.reuse as f64 * 2f64.powf(self.max_suppress_time as f64 / self.half_life as f64)
+    }
+}
+
+impl Default for DampingConfig {
+    fn default() -> Self {
+        DampingConfig {
+            half_life: default_damping_half_life(),
+            reuse: default_damping_reuse(),
+            suppress: default_damping_suppress(),
+            max_suppress_time: default_damping_max_suppress_time(),
+            withdraw_penalty: default_damping_withdraw_penalty(),
+            attribute_change_penalty: default_damping_attribute_change_penalty(),
+        }
+    }
 }
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::bgp;
+use crate::config::DampingConfig;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DampingInfo {
+    pub penalty: f64,
+    pub flaps: u32,
+    pub suppressed: bool,
+    last_update: Instant,
+}
+
+impl DampingInfo {
+    fn new(now: Instant) -> Self {
+        DampingInfo {
+            penalty: 0.0,
+            flaps: 0,
+            suppressed: false,
+            last_update: now,
+        }
+    }
+}
+
+/// Per-path route flap damping state (RFC 2439), keyed by prefix and peer router ID.
+#[derive(Debug, Clone)]
+pub struct Damping {
+    config: Option<DampingConfig>,
+    entries: HashMap<(bgp::Nlri, u32), DampingInfo>,
+}
+
+impl Damping {
+    pub fn new(config: Option<DampingConfig>) -> Self {
+        Damping {
+            config,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn decay(config: &DampingConfig, info: &mut DampingInfo, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(info.last_update)
+            .as_secs_f64();
+        info.penalty *= 2f64.powf(-elapsed / config.half_life as f64);
+        info.last_update = now;
+    }
+
+    fn penalize(&mut self, nlri: bgp::Nlri, peer: u32, penalty: u32, now: Instant) -> bool {
+        let config = match &self.config {
+            Some(c) => c,
+            None => return false,
+        };
+        let info = self
+            .entries
+            .entry((nlri, peer))
+            .or_insert_with(|| DampingInfo::new(now));
+        Damping::decay(config, info, now);
+        info.penalty = (info.penalty + penalty as f64).min(config.ceiling());
+        info.flaps += 1;
+        if info.penalty > config.suppress as f64 {
+            if !info.suppressed {
+                log::info!(
+                    "Suppressing {:?} from {} (penalty {:.0})",
+                    nlri,
+                    std::net::Ipv4Addr::from(peer),
+                    info.penalty
+                );
+            }
+            info.suppressed = true;
+        }
+        info.suppressed
+    }
+
+    /// Records a withdrawal of the path.
+    pub fn withdrawn(&mut self, nlri: bgp::Nlri, peer: u32, now: Instant) {
+        let penalty = self
+            .config
+            .as_ref()
+            .map(|c| c.withdraw_penalty)
+            .unwrap_or(0);
+        self.penalize(nlri, peer, penalty, now);
+    }
+
+    /// Records an advertisement of the path and returns whether it is suppressed. Only
+    /// re-advertisements with different attributes are penalized.
+    pub fn advertised(
+        &mut self,
+        nlri: bgp::Nlri,
+        peer: u32,
+        attributes_changed: bool,
+        now: Instant,
+    ) -> bool {
+        if attributes_changed {
+            let penalty = self
+                .config
+                .as_ref()
+                .map(|c| c.attribute_change_penalty)
+                .unwrap_or(0);
+            return self.penalize(nlri, peer, penalty, now);
+        }
+        self.is_suppressed(nlri, peer, now)
+    }
+
+    pub fn is_suppressed(&mut self, nlri: bgp::Nlri, peer: u32, now: Instant) -> bool {
+        let config = match &self.config {
+            Some(c) => c,
+            None => return false,
+        };
+        match self.entries.get_mut(&(nlri, peer)) {
+            None => false,
+            Some(info) => {
+                Damping::decay(config, info, now);
+                info.suppressed
+            }
+        }
+    }
+
+    /// Decays every entry, returns the paths that became usable again and forgets the ones
+    /// whose penalty is negligible. Suppressed paths are only released here, so that the RIB
+    /// sees every transition.
+    pub fn reuse(&mut self, now: Instant) -> Vec<(bgp::Nlri, u32)> {
+        let config = match &self.config {
+            Some(c) => c,
+            None => return vec![],
+        };
+        let mut reused = vec![];
+        for (key, info) in self.entries.iter_mut() {
+            Damping::decay(config, info, now);
+            if info.suppressed && info.penalty < config.reuse as f64 {
+                info.suppressed = false;
+                log::info!("Reusing {:?} (penalty {:.0})", key.0, info.penalty);
+                reused.push(*key);
+            }
+        }
+        let forget = config.reuse as f64 / 2.0;
+        self.entries
+            .retain(|_, info| info.suppressed || info.penalty >= forget);
+        reused
+    }
+
+    /// Forgets the history of `nlri`, or of every prefix, and returns the paths that were
+    /// suppressed.
+    pub fn clear(&mut self, nlri: Option<bgp::Nlri>) -> Vec<(bgp::Nlri, u32)> {
+        let mut cleared = vec![];
+        self.entries.retain(|key, info| {
+            if nlri.is_some_and(|n| n != key.0) {
+                return true;
+            }
+            if info.suppressed {
+                cleared.push(*key);
+            }
+            false
+        });
+        cleared
+    }
+
+    /// Time until a suppressed path can be reused.
+    pub fn reuse_in(&self, info: &DampingInfo) -> Option<Duration> {
+        let config = self.config.as_ref()?;
+        if !info.suppressed {
+            return None;
+        }
+        let half_lives = (info.penalty / config.reuse as f64).log2().max(0.0);
+        Some(Duration::from_secs_f64(
+            half_lives * config.half_life as f64,
+        ))
+    }
+
+    /// Snapshot of the damping history, decayed to `now`.
+    pub fn entries(&mut self, now: Instant) -> Vec<(bgp::Nlri, u32, DampingInfo)> {
+        let config = match &self.config {
+            Some(c) => c,
+            None => return vec![],
+        };
+        self.entries
+            .iter_mut()
+            .map(|((nlri, peer), info)| {
+                Damping::decay(config, info, now);
+                (*nlri, *peer, info.clone())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,119 @@
+use crate::bgp::Nlri;
+use crate::config::DampingConfig;
+use crate::damping::*;
+use std::time::{Duration, Instant};
+
+fn nlri() -> Nlri {
+    Nlri {
+        net: "10.0.0.0/24".parse().unwrap(),
+    }
+}
+
+fn damping() -> Damping {
+    Damping::new(Some(DampingConfig::default()))
+}
+
+#[test]
+fn test_damping_disabled() {
+    let mut d = Damping::new(None);
+    let now = Instant::now();
+
+    for _ in 0..10 {
+        d.withdrawn(nlri(), 1, now);
+    }
+    assert!(!d.advertised(nlri(), 1, true, now));
+    assert!(d.entries(now).is_empty());
+}
+
+#[test]
+fn test_damping_suppress_after_flaps() {
+    let mut d = damping();
+    let now = Instant::now();
+
+    for _ in 0..6 {
+        d.withdrawn(nlri(), 1, now);
+        assert!(!d.advertised(nlri(), 1, false, now));
+    }
+    d.withdrawn(nlri(), 1, now);
+    assert!(d.advertised(nlri(), 1, false, now));
+    assert!(!d.is_suppressed(nlri(), 2, now));
+
+    let entries = d.entries(now);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].2.flaps, 7);
+}
+
+#[test]
+fn test_damping_attribute_change_penalty() {
+    let mut d = damping();
+    let now = Instant::now();
+
+    d.advertised(nlri(), 1, true, now);
+    d.advertised(nlri(), 1, false, now);
+    let entries = d.entries(now);
+    assert_eq!(entries[0].2.penalty, 500.0);
+    assert_eq!(entries[0].2.flaps, 1);
+}
+
+#[test]
+fn test_damping_half_life_decay() {
+    let mut d = damping();
+    let now = Instant::now();
+
+    d.withdrawn(nlri(), 1, now);
+    let entries = d.entries(now + Duration::from_secs(900));
+    assert!((entries[0].2.penalty - 500.0).abs() < 0.01);
+}
+
+#[test]
+fn test_damping_reuse() {
+    let mut d = damping();
+    let now = Instant::now();
+
+    for _ in 0..7 {
+        d.withdrawn(nlri(), 1, now);
+    }
+    assert!(d.is_suppressed(nlri(), 1, now));
+    assert!(d.reuse(now).is_empty());
+
+    let info = d.entries(now)[0].2.clone();
+    let reuse_in = d.reuse_in(&info).unwrap();
+    assert!(d.reuse(now + reuse_in - Duration::from_secs(1)).is_empty());
+    assert_eq!(
+        d.reuse(now + reuse_in + Duration::from_secs(1)),
+        vec![(nlri(), 1)]
+    );
+    assert!(!d.is_suppressed(nlri(), 1, now + reuse_in + Duration::from_secs(1)));
+}
+
+#[test]
+fn test_damping_max_suppress_time() {
+    let mut d = damping();
+    let now = Instant::now();
+
+    for _ in 0..100 {
+        d.withdrawn(nlri(), 1, now);
+    }
+    let info = d.entries(now)[0].2.clone();
+    assert!(d.reuse_in(&info).unwrap() <= Duration::from_secs(3600));
+    assert_eq!(d.reuse(now + Duration::from_secs(3601)), vec![(nlri(), 1)]);
+}
+
+#[test]
+fn test_damping_clear() {
+    let mut d = damping();
+    let now = Instant::now();
+    let other = Nlri {
+        net: "10.0.1.0/24".parse().unwrap(),
+    };
+
+    for _ in 0..7 {
+        d.withdrawn(nlri(), 1, now);
+        d.withdrawn(other, 1, now);
+    }
+    assert_eq!(d.clear(Some(nlri())), vec![(nlri(), 1)]);
+    assert!(!d.is_suppressed(nlri(), 1, now));
+    assert!(d.is_suppressed(other, 1, now));
+    assert_eq!(d.clear(None), vec![(other, 1)]);
+    assert!(d.entries(now).is_empty());
+}
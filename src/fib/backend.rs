@@ -0,0 +1,71 @@
+use anyhow::Result;
+use netlink_packet_route::link::LinkMessage;
+use netlink_packet_route::route::RouteMessage;
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use super::{FibChange, FibEntry, FileBackend, FpmBackend, MemoryBackend, NetlinkBackend};
+use crate::bgp::AddressFamily;
+use crate::config::{self, FibBackendType};
+
+/// Table the best paths are installed in.
+#[tonic::async_trait]
+pub trait FibBackend: Debug + Send {
+    /// Name of the backend, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Routes of `af` in the table, the BGP ones and those BGP next hops resolve through.
+    async fn routes(&mut self, af: &AddressFamily) -> Result<Vec<FibEntry>>;
+
+    /// Applies `changes` to the table. Changes that fail on their own are logged and skipped.
+    async fn apply(&mut self, changes: Vec<FibChange>) -> Result<()>;
+
+    /// Whether BGP next hops are resolved through the routes of the table. When they are not,
+    /// every next hop is reachable directly.
+    fn resolves_next_hops(&self) -> bool;
+
+    /// Route of a kernel route notification, none when it is skipped.
+    async fn notified_route(&mut self, msg: RouteMessage) -> Option<FibEntry> {
+        Some(FibEntry::parse(msg))
+    }
+
+    /// Follows a kernel link notification, `removed` when the link is gone.
+    async fn link_changed(&mut self, _link: LinkMessage, _removed: bool) {}
+}
+
+/// Backends of `families` selected by `config`. The families share the netlink, file, socket or
+/// FPM connection of the backends other than `memory`.
+pub fn backends(
+    config: &config::FibConfig,
+    families: &[AddressFamily],
+) -> Result<HashMap<AddressFamily, Box<dyn FibBackend>>> {
+    let path = || {
+        config
+            .path
+            .clone()
+            .expect("FIB backend path not configured")
+    };
+    let new_backend: Box<dyn Fn() -> Box<dyn FibBackend>> = match config.backend {
+        FibBackendType::Netlink => {
+            let netlink = NetlinkBackend::new()?;
+            Box::new(move || Box::new(netlink.clone()))
+        }
+        FibBackendType::Memory => Box::new(|| Box::new(MemoryBackend::new())),
+        FibBackendType::File => {
+            let file = FileBackend::file(path());
+            Box::new(move || Box::new(file.clone()))
+        }
+        FibBackendType::Socket => {
+            let socket = FileBackend::socket(path());
+            Box::new(move || Box::new(socket.clone()))
+        }
+        FibBackendType::Fpm => {
+            let fpm = FpmBackend::new(config.fpm.address, config.fpm.encoding);
+            Box::new(move || Box::new(fpm.clone()))
+        }
+    };
+    Ok(families
+        .iter()
+        .map(|af| (af.clone(), new_backend()))
+        .collect())
+}
@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use serde_derive::Serialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+
+use super::{apply_change, family_routes, FibBackend, FibChange, FibEntry, Gateway, RouteTable};
+use crate::bgp::AddressFamily;
+
+/// Routes of every address family and the connection they are written to.
+#[derive(Debug, Default)]
+struct Table {
+    routes: RouteTable,
+    stream: Option<UnixStream>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    routes: Vec<Route<'a>>,
+}
+
+#[derive(Serialize)]
+struct Route<'a> {
+    prefix: String,
+    next_hops: &'a [Gateway],
+    table: u32,
+    metric: Option<u32>,
+    protocol: u8,
+    prefsrc: Option<IpAddr>,
+}
+
+/// Table written as a JSON document after every change, to a file or to the Unix socket of
+/// another process. Clones share the same table.
+#[derive(Debug, Clone)]
+pub struct FileBackend {
+    path: PathBuf,
+    socket: bool,
+    table: Arc<Mutex<Table>>,
+}
+
+impl FileBackend {
+    /// Writes the table to `path`, the file is replaced at once.
+    pub fn file(path: PathBuf) -> Self {
+        FileBackend {
+            path,
+            socket: false,
+            table: Arc::new(Mutex::new(Table::default())),
+        }
+    }
+
+    /// Writes the table to the Unix socket at `path`, one document per line. The connection is
+    /// opened again after a failure.
+    pub fn socket(path: PathBuf) -> Self {
+        FileBackend {
+            socket: true,
+            ..FileBackend::file(path)
+        }
+    }
+
+    async fn write(&self, table: &mut Table) -> Result<()> {
+        let mut routes: Vec<Route> = table
+            .routes
+            .values()
+            .flatten()
+            .filter_map(|fe| {
+                Some(Route {
+                    prefix: fe.prefix?.to_string(),
+                    next_hops: &fe.next_hops,
+                    table: fe.table,
+                    metric: fe.metric,
+                    protocol: fe.proto.into(),
+                    prefsrc: fe.prefsrc,
+                })
+            })
+            .collect();
+        routes.sort_by(|a, b| (&a.prefix, a.table, a.metric).cmp(&(&b.prefix, b.table, b.metric)));
+        let document = Document { routes };
+        let mut json = serde_json::to_vec(&document).context("Failed to encode the FIB")?;
+        json.push(b'\n');
+
+        if !self.socket {
+            let tmp = PathBuf::from(format!("{}.tmp", self.path.display()));
+            tokio::fs::write(&tmp, &json)
+                .await
+                .with_context(|| format!("Failed to write {}", tmp.display()))?;
+            return tokio::fs::rename(&tmp, &self.path)
+                .await
+                .with_context(|| format!("Failed to replace {}", self.path.display()));
+        }
+
+        let mut stream = match table.stream.take() {
+            Some(stream) => stream,
+            None => UnixStream::connect(&self.path)
+                .await
+                .with_context(|| format!("Failed to connect to {}", self.path.display()))?,
+        };
+        stream
+            .write_all(&json)
+            .await
+            .with_context(|| format!("Failed to write to {}", self.path.display()))?;
+        table.stream = Some(stream);
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl FibBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        if self.socket {
+            "socket"
+        } else {
+            "file"
+        }
+    }
+
+    async fn routes(&mut self, af: &AddressFamily) -> Result<Vec<FibEntry>> {
+        Ok(family_routes(&self.table.lock().await.routes, af))
+    }
+
+    async fn apply(&mut self, changes: Vec<FibChange>) -> Result<()> {
+        let mut table = self.table.lock().await;
+        for change in &changes {
+            apply_change(&mut table.routes, change);
+        }
+        self.write(&mut table).await
+    }
+
+    fn resolves_next_hops(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use prost::Message;
+use rtnetlink::packet_core::{
+    NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_CREATE, NLM_F_REPLACE, NLM_F_REQUEST,
+};
+use rtnetlink::packet_route::RouteNetlinkMessage;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Weak};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+use super::netlink::{delete_message, route_message};
+use super::{apply_change, family_routes, FibBackend, FibChange, FibEntry, RouteTable};
+use crate::bgp::AddressFamily;
+use crate::config::FpmEncoding;
+
+pub(super) mod pb {
+    pub mod qpb {
+        tonic::include_proto!("qpb");
+    }
+    pub mod fpm {
+        tonic::include_proto!("fpm");
+    }
+}
+
+use pb::{fpm, qpb};
+
+const FPM_PROTO_VERSION: u8 = 1;
+const FPM_MSG_TYPE_NETLINK: u8 = 1;
+const FPM_MSG_TYPE_PROTOBUF: u8 = 2;
+/// Version, message type and length, the length covers the header.
+const FPM_HEADER_LEN: usize = 4;
+
+/// Interval at which the connection to the FPM server is checked and opened again.
+const FPM_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Routes of every address family and the connection to the FPM server.
+#[derive(Debug, Default)]
+struct Table {
+    routes: RouteTable,
+    stream: Option<TcpStream>,
+}
+
+/// Installs the routes through the Forwarding Plane Manager protocol of FRR, as a client of an
+/// FPM server. The server is sent every route again after a reconnection. Clones share the same
+/// connection.
+#[derive(Debug, Clone)]
+pub struct FpmBackend {
+    address: SocketAddr,
+    encoding: FpmEncoding,
+    table: Arc<Mutex<Table>>,
+}
+
+impl FpmBackend {
+    /// Connects to the FPM server at `address` in the background.
+    pub fn new(address: SocketAddr, encoding: FpmEncoding) -> Self {
+        let backend = FpmBackend {
+            address,
+            encoding,
+            table: Arc::new(Mutex::new(Table::default())),
+        };
+        let table = Arc::downgrade(&backend.table);
+        tokio::spawn(keep_connected(address, encoding, table));
+        backend
+    }
+}
+
+/// Opens the connection again when the server closes it, until the backend is dropped.
+async fn keep_connected(address: SocketAddr, encoding: FpmEncoding, table: Weak<Mutex<Table>>) {
+    while let Some(table) = table.upgrade() {
+        {
+            let mut table = table.lock().await;
+            if let Some(stream) = &table.stream {
+                // The server sends nothing, reading tells whether it is still there.
+                let mut buf = [0; 64];
+                match stream.try_read(&mut buf) {
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Ok(n) if n > 0 => {}
+                    _ => {
+                        log::warn!("FPM server {} closed the connection", address);
+                        table.stream = None;
+                    }
+                }
+            }
+            if table.stream.is_none() {
+                if let Err(e) = connect(address, encoding, &mut table).await {
+                    log::debug!("{:#}", e);
+                }
+            }
+        }
+        drop(table);
+        tokio::time::sleep(FPM_RECONNECT_INTERVAL).await;
+    }
+}
+
+/// Connects to the FPM server and sends it every route.
+async fn connect(address: SocketAddr, encoding: FpmEncoding, table: &mut Table) -> Result<()> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .with_context(|| format!("Failed to connect to FPM server {}", address))?;
+    stream.set_nodelay(true)?;
+    let mut frames = vec![];
+    for entry in table.routes.values().flatten() {
+        frames.extend(frame(encoding, &FibChange::Add(entry.clone()))?);
+    }
+    stream
+        .write_all(&frames)
+        .await
+        .with_context(|| format!("Failed to send the routes to FPM server {}", address))?;
+    log::info!(
+        "Connected to FPM server {}, sent {} routes",
+        address,
+        table.routes.values().map(Vec::len).sum::<usize>()
+    );
+    table.stream = Some(stream);
+    Ok(())
+}
+
+/// FPM message for `change`.
+fn frame(encoding: FpmEncoding, change: &FibChange) -> Result<Vec<u8>> {
+    let (entry, add) = match change {
+        FibChange::Add(entry) | FibChange::Replace(entry) => (entry, true),
+        FibChange::Delete(entry) => (entry, false),
+    };
+    let prefix = entry
+        .prefix
+        .ok_or_else(|| anyhow!("Route without prefix"))?;
+    let (msg_type, payload) = match encoding {
+        FpmEncoding::Netlink => (FPM_MSG_TYPE_NETLINK, netlink_payload(entry, add)?),
+        FpmEncoding::Protobuf => (
+            FPM_MSG_TYPE_PROTOBUF,
+            protobuf_payload(prefix, entry, add).encode_to_vec(),
+        ),
+    };
+    let len = u16::try_from(FPM_HEADER_LEN + payload.len())
+        .map_err(|_| anyhow!("FPM message for {} too long", prefix))?;
+    let mut frame = Vec::with_capacity(len as usize);
+    frame.push(FPM_PROTO_VERSION);
+    frame.push(msg_type);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend(payload);
+    Ok(frame)
+}
+
+fn netlink_payload(entry: &FibEntry, add: bool) -> Result<Vec<u8>> {
+    let (payload, flags) = if add {
+        let message = route_message(entry)
+            .ok_or_else(|| anyhow!("No gateway of the address family of the route"))?;
+        (
+            RouteNetlinkMessage::NewRoute(message),
+            NLM_F_REQUEST | NLM_F_CREATE | NLM_F_REPLACE,
+        )
+    } else {
+        let message = delete_message(entry).ok_or_else(|| anyhow!("Route without prefix"))?;
+        (RouteNetlinkMessage::DelRoute(message), NLM_F_REQUEST)
+    };
+    let mut header = NetlinkHeader::default();
+    header.flags = flags;
+    let mut message = NetlinkMessage::new(header, NetlinkPayload::from(payload));
+    message.finalize();
+    let mut buf = vec![0; message.buffer_len()];
+    message.serialize(&mut buf);
+    Ok(buf)
+}
+
+fn protobuf_payload(prefix: IpNet, entry: &FibEntry, add: bool) -> fpm::Message {
+    let address_family = match prefix {
+        IpNet::V4(_) => qpb::AddressFamily::Ipv4,
+        IpNet::V6(_) => qpb::AddressFamily::Ipv6,
+    } as i32;
+    let key = qpb::L3Prefix {
+        length: prefix.prefix_len() as u32,
+        bytes: match prefix.addr() {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        },
+    };
+    let sub_address_family = qpb::SubAddressFamily::Unicast as i32;
+    if add {
+        fpm::Message {
+            r#type: Some(fpm::message::Type::AddRoute as i32),
+            add_route: Some(fpm::AddRoute {
+                vrf_id: 0,
+                address_family,
+                sub_address_family,
+                key,
+                route_type: Some(fpm::RouteType::Normal as i32),
+                protocol: qpb::Protocol::Bgp as i32,
+                metric: entry.metric.unwrap_or(0) as i32,
+                nexthops: entry
+                    .next_hops
+                    .iter()
+                    .map(|g| fpm::Nexthop {
+                        if_id: g.oif.map(|index| qpb::IfIdentifier {
+                            index: Some(index as i32),
+                            name: None,
+                        }),
+                        address: Some(match g.addr {
+                            IpAddr::V4(addr) => qpb::L3Address {
+                                v4: Some(qpb::Ipv4Address { value: addr.into() }),
+                                v6: None,
+                            },
+                            IpAddr::V6(addr) => qpb::L3Address {
+                                v4: None,
+                                v6: Some(qpb::Ipv6Address {
+                                    bytes: addr.octets().to_vec(),
+                                }),
+                            },
+                        }),
+                    })
+                    .collect(),
+            }),
+            delete_route: None,
+        }
+    } else {
+        fpm::Message {
+            r#type: Some(fpm::message::Type::DeleteRoute as i32),
+            add_route: None,
+            delete_route: Some(fpm::DeleteRoute {
+                vrf_id: 0,
+                address_family,
+                sub_address_family,
+                key,
+            }),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FibBackend for FpmBackend {
+    fn name(&self) -> &'static str {
+        "fpm"
+    }
+
+    async fn routes(&mut self, af: &AddressFamily) -> Result<Vec<FibEntry>> {
+        let table = self.table.lock().await;
+        Ok(family_routes(&table.routes, af))
+    }
+
+    async fn apply(&mut self, changes: Vec<FibChange>) -> Result<()> {
+        let mut table = self.table.lock().await;
+        let mut frames = vec![];
+        for change in changes {
+            apply_change(&mut table.routes, &change);
+            match frame(self.encoding, &change) {
+                Ok(frame) => frames.extend(frame),
+                Err(e) => log::error!("Failed to encode the FPM message: {:#}", e),
+            }
+        }
+
+        // Without a connection, the routes are sent once connected.
+        let Some(stream) = table.stream.as_mut() else {
+            return connect(self.address, self.encoding, &mut table).await;
+        };
+        if let Err(e) = stream.write_all(&frames).await {
+            table.stream = None;
+            return Err(e)
+                .with_context(|| format!("Failed to write to FPM server {}", self.address));
+        }
+        Ok(())
+    }
+
+    fn resolves_next_hops(&self) -> bool {
+        false
+    }
+}
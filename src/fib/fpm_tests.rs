@@ -0,0 +1,141 @@
+use ipnet::IpNet;
+use prost::Message;
+use rtnetlink::packet_core::{NetlinkMessage, NetlinkPayload};
+use rtnetlink::packet_route::RouteNetlinkMessage;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::fpm::pb::{fpm, qpb};
+use super::*;
+use crate::bgp::Safi;
+use crate::config::{FibConfig, FpmEncoding};
+
+fn net(s: &str) -> IpNet {
+    s.parse().unwrap()
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+async fn fib(address: SocketAddr, encoding: FpmEncoding) -> Fib {
+    let af = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::NLRIUnicast,
+    };
+    let backend = Box::new(FpmBackend::new(address, encoding));
+    Fib::new(af, FibConfig::default(), InstallPolicy::default(), backend).await
+}
+
+/// Message type and payload of the next FPM message.
+async fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[0], 1);
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut payload = vec![0; len - header.len()];
+    stream.read_exact(&mut payload).await.unwrap();
+    (header[1], payload)
+}
+
+#[tokio::test]
+async fn test_fpm_netlink_add_and_delete() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut fib = fib(listener.local_addr().unwrap(), FpmEncoding::Netlink).await;
+
+    let desired = HashMap::from([(net("10.0.1.0/24"), vec![ip("192.0.2.1")])]);
+    fib.sync(desired).await;
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let (msg_type, payload) = read_frame(&mut stream).await;
+    assert_eq!(msg_type, 1);
+    let message = NetlinkMessage::<RouteNetlinkMessage>::deserialize(&payload).unwrap();
+    let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(route)) = message.payload else {
+        panic!("Not a new route: {:?}", message);
+    };
+    let route = FibEntry::parse(route);
+    assert_eq!(route.prefix, Some(net("10.0.1.0/24")));
+    assert_eq!(route.next_hops[0].addr, ip("192.0.2.1"));
+
+    fib.refresh().await;
+    fib.sync(HashMap::new()).await;
+    let (_, payload) = read_frame(&mut stream).await;
+    let message = NetlinkMessage::<RouteNetlinkMessage>::deserialize(&payload).unwrap();
+    let NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRoute(route)) = message.payload else {
+        panic!("Not a deleted route: {:?}", message);
+    };
+    assert_eq!(FibEntry::parse(route).prefix, Some(net("10.0.1.0/24")));
+}
+
+#[tokio::test]
+async fn test_fpm_protobuf_resync_on_connect() {
+    // Nothing listens yet
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+    let mut fib = fib(address, FpmEncoding::Protobuf).await;
+    fib.sync(HashMap::from([(net("10.0.1.0/24"), vec![ip("192.0.2.1")])]))
+        .await;
+
+    let listener = TcpListener::bind(address).await.unwrap();
+    fib.refresh().await;
+    fib.sync(HashMap::from([
+        (net("10.0.1.0/24"), vec![ip("192.0.2.1")]),
+        (net("10.0.2.0/24"), vec![ip("192.0.2.2")]),
+    ]))
+    .await;
+
+    // Both routes, the one added while disconnected included
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut prefixes = vec![];
+    for _ in 0..2 {
+        let (msg_type, payload) = read_frame(&mut stream).await;
+        assert_eq!(msg_type, 2);
+        let message = fpm::Message::decode(payload.as_slice()).unwrap();
+        assert_eq!(message.r#type, Some(fpm::message::Type::AddRoute as i32));
+        let route = message.add_route.unwrap();
+        assert_eq!(route.protocol, qpb::Protocol::Bgp as i32);
+        prefixes.push((route.key.length, route.key.bytes));
+    }
+    prefixes.sort();
+    assert_eq!(
+        prefixes,
+        vec![(24, vec![10, 0, 1, 0]), (24, vec![10, 0, 2, 0])]
+    );
+}
+
+#[tokio::test]
+async fn test_fpm_netlink_route_params() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut fib = fib(listener.local_addr().unwrap(), FpmEncoding::Netlink).await;
+    let entry = FibEntry::new(
+        net("10.0.1.0/24"),
+        vec![Gateway {
+            addr: ip("192.0.2.1"),
+            oif: None,
+        }],
+        &RouteParams {
+            table: 1000,
+            metric: Some(20),
+            protocol: RouteProtocol::from(200),
+            prefsrc: Some(ip("192.0.2.254")),
+        },
+    );
+    fib.backend
+        .apply(vec![FibChange::Add(entry.clone())])
+        .await
+        .unwrap();
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let (_, payload) = read_frame(&mut stream).await;
+    let message = NetlinkMessage::<RouteNetlinkMessage>::deserialize(&payload).unwrap();
+    let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(route)) = message.payload else {
+        panic!("Not a new route: {:?}", message);
+    };
+    let route = FibEntry::parse(route);
+    assert_eq!(
+        (route.table, route.metric, route.proto, route.prefsrc),
+        (entry.table, entry.metric, entry.proto, entry.prefsrc)
+    );
+}
@@ -0,0 +1,49 @@
+use anyhow::Result;
+
+use super::{apply_change, family_routes, FibBackend, FibChange, FibEntry, RouteTable};
+use crate::bgp::AddressFamily;
+
+/// Table kept in memory, for route servers and tests.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    routes: RouteTable,
+    resolve: bool,
+}
+
+impl MemoryBackend {
+    /// Empty table, every next hop is reachable directly.
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    /// Table holding `routes`, BGP next hops are resolved through them.
+    #[cfg(test)]
+    pub fn with_routes(routes: Vec<FibEntry>) -> Self {
+        MemoryBackend {
+            routes: super::route_table(routes),
+            resolve: true,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FibBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn routes(&mut self, af: &AddressFamily) -> Result<Vec<FibEntry>> {
+        Ok(family_routes(&self.routes, af))
+    }
+
+    async fn apply(&mut self, changes: Vec<FibChange>) -> Result<()> {
+        for change in &changes {
+            apply_change(&mut self.routes, change);
+        }
+        Ok(())
+    }
+
+    fn resolves_next_hops(&self) -> bool {
+        self.resolve
+    }
+}
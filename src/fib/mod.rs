@@ -0,0 +1,711 @@
+use anyhow::Result;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use netlink_packet_route::link::LinkMessage;
+use netlink_packet_route::route::{
+    RouteAddress, RouteAttribute, RouteHeader, RouteMessage, RouteProtocol,
+};
+use netlink_packet_route::AddressFamily as NetlinkAddressFamily;
+use serde_derive::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::bgp::{AddressFamily, Afi};
+use crate::config;
+use crate::policy;
+use crate::rib::{self};
+use crate::trie;
+
+mod backend;
+mod file;
+mod fpm;
+mod memory;
+mod netlink;
+
+#[cfg(test)]
+mod fpm_tests;
+
+pub use backend::{backends, FibBackend};
+pub use file::FileBackend;
+pub use fpm::FpmBackend;
+pub use memory::MemoryBackend;
+pub use netlink::NetlinkBackend;
+
+/// Immediate next hop of a route.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize)]
+pub struct Gateway {
+    pub addr: IpAddr,
+    /// Index of the output interface.
+    pub oif: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FibEntry {
+    pub prefix: Option<IpNet>,
+    /// Gateways of the route, sorted. More than one for a multipath route, none for a connected
+    /// one.
+    pub next_hops: Vec<Gateway>,
+    pub oif: Option<u32>,
+    pub dev: Option<String>,
+    pub metric: Option<u32>,
+    pub proto: RouteProtocol,
+    pub table: u32,
+    pub prefsrc: Option<IpAddr>,
+    /// Message the route was read from, none for the routes installed since.
+    pub rm: Option<RouteMessage>,
+}
+
+/// Routes by prefix, those of a prefix differ by table or metric.
+pub type RouteTable = trie::PrefixMap<IpNet, Vec<FibEntry>>;
+
+/// Table of `routes`, those without a prefix are left out.
+pub fn route_table(routes: Vec<FibEntry>) -> RouteTable {
+    let mut table = RouteTable::default();
+    for entry in routes {
+        if let Some(prefix) = entry.prefix {
+            let same_prefix = table.get_or_insert_with(prefix, Vec::new);
+            same_prefix.retain(|fe| !fe.same_route(&entry));
+            same_prefix.push(entry);
+        }
+    }
+    table
+}
+
+/// Where and how a route is installed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RouteParams {
+    pub table: u32,
+    pub metric: Option<u32>,
+    pub protocol: RouteProtocol,
+    pub prefsrc: Option<IpAddr>,
+}
+
+impl Default for RouteParams {
+    fn default() -> Self {
+        RouteParams {
+            table: RouteHeader::RT_TABLE_MAIN as u32,
+            metric: None,
+            protocol: RouteProtocol::Bgp,
+            prefsrc: None,
+        }
+    }
+}
+
+impl RouteParams {
+    /// These parameters with the fields set in `config` overridden.
+    fn with(self, config: &config::KernelRoute) -> Self {
+        RouteParams {
+            table: config.table.unwrap_or(self.table),
+            metric: config.metric.or(self.metric),
+            protocol: config.protocol.map_or(self.protocol, RouteProtocol::from),
+            prefsrc: config.prefsrc.or(self.prefsrc),
+        }
+    }
+}
+
+/// Parameters the routes of an address family are installed with, by prefix.
+#[derive(Debug, Clone, Default)]
+pub struct InstallPolicy {
+    default: RouteParams,
+    rules: Vec<(Arc<policy::PrefixList>, RouteParams)>,
+}
+
+impl InstallPolicy {
+    /// Policy of `af` in `config`, the defaults when the family is not configured.
+    pub fn new(
+        config: &config::FibConfig,
+        af: &AddressFamily,
+        policies: &policy::Policies,
+    ) -> Result<Self> {
+        let Some(family) = config.families.iter().find(|f| f.family() == *af) else {
+            return Ok(InstallPolicy::default());
+        };
+        let default = RouteParams::default().with(&family.route);
+        let rules = family
+            .rules
+            .iter()
+            .map(|r| {
+                Ok((
+                    policies.prefix_list(&r.prefix_list)?,
+                    default.with(&r.route),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(InstallPolicy { default, rules })
+    }
+
+    pub fn params(&self, prefix: &IpNet) -> RouteParams {
+        self.rules
+            .iter()
+            .find(|(pl, _)| pl.permits(prefix))
+            .map_or(self.default, |(_, params)| *params)
+    }
+
+    /// Whether the routes with protocol `proto` are installed by this policy.
+    pub fn installs(&self, proto: RouteProtocol) -> bool {
+        self.default.protocol == proto || self.rules.iter().any(|(_, p)| p.protocol == proto)
+    }
+
+    /// Whether the routes with protocol `proto` in `table` are installed by this policy.
+    fn installs_in(&self, proto: RouteProtocol, table: u32) -> bool {
+        std::iter::once(&self.default)
+            .chain(self.rules.iter().map(|(_, p)| p))
+            .any(|p| p.protocol == proto && p.table == table)
+    }
+}
+
+/// Change bringing the kernel routes in line with the Loc-RIB.
+#[derive(Debug, PartialEq)]
+pub enum FibChange {
+    Add(FibEntry),
+    Replace(FibEntry),
+    Delete(FibEntry),
+}
+
+/// Computes the changes turning the kernel `routes` into the `desired` ones, by prefix.
+///
+/// Only routes with a protocol the `policy` installs are replaced or deleted. Those left over by
+/// a previous run are only deleted with `sweep_stale`, routes `installed` by this one as soon as
+/// they are not desired anymore.
+pub fn reconcile(
+    routes: &RouteTable,
+    desired: &HashMap<IpNet, FibEntry>,
+    policy: &InstallPolicy,
+    installed: &HashSet<IpNet>,
+    sweep_stale: bool,
+) -> Vec<FibChange> {
+    let mut changes = vec![];
+    for (prefix, want) in desired {
+        let mut existing = routes
+            .get(prefix)
+            .into_iter()
+            .flatten()
+            .filter(|fe| fe.same_route(want));
+        let existing = existing
+            .clone()
+            .find(|fe| policy.installs(fe.proto))
+            .or_else(|| existing.next());
+        match existing {
+            Some(fe)
+                if fe.next_hops == want.next_hops
+                    && fe.proto == want.proto
+                    && fe.prefsrc == want.prefsrc => {}
+            Some(fe) if policy.installs(fe.proto) => changes.push(FibChange::Replace(want.clone())),
+            _ => changes.push(FibChange::Add(want.clone())),
+        }
+    }
+    for fe in routes.values().flatten() {
+        let prefix = match fe.prefix {
+            Some(prefix) if policy.installs(fe.proto) => prefix,
+            _ => continue,
+        };
+        let wanted = desired.get(&prefix).is_some_and(|want| want.same_route(fe));
+        if !wanted && (sweep_stale || installed.contains(&prefix)) {
+            changes.push(FibChange::Delete(fe.clone()));
+        }
+    }
+    changes
+}
+
+impl FibEntry {
+    /// Route described by `msg`, without resolving the name of its output interface.
+    pub fn parse(msg: RouteMessage) -> FibEntry {
+        let plen = msg.header.destination_prefix_length;
+        let prefix = msg.attributes.iter().find_map(|nla| {
+            if let RouteAttribute::Destination(v) = nla {
+                match v {
+                    RouteAddress::Inet(t) => Ipv4Net::new(*t, plen)
+                        .map_err(|e| log::warn!("Invalid IPv4 prefix: {}", e))
+                        .ok()
+                        .map(|net| net.into()),
+                    RouteAddress::Inet6(t) => Ipv6Net::new(*t, plen)
+                        .map_err(|e| log::warn!("Invalid IPv6 prefix: {}", e))
+                        .ok()
+                        .map(|net| net.into()),
+                    _ => None,
+                }
+            } else {
+                None
+            }
+        });
+        // The default route has no destination.
+        let prefix = prefix.or(match (plen, msg.header.address_family) {
+            (0, NetlinkAddressFamily::Inet) => Some(IpNet::V4(Ipv4Net::default())),
+            (0, NetlinkAddressFamily::Inet6) => Some(IpNet::V6(Ipv6Net::default())),
+            _ => None,
+        });
+        let gateway = |attributes: &[RouteAttribute]| {
+            attributes.iter().find_map(|nla| {
+                if let RouteAttribute::Gateway(v) = nla {
+                    match v {
+                        RouteAddress::Inet(t) => Some(IpAddr::V4(*t)),
+                        RouteAddress::Inet6(t) => Some(IpAddr::V6(*t)),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            })
+        };
+        let oif = msg.attributes.iter().find_map(|nla| {
+            if let RouteAttribute::Oif(v) = nla {
+                Some(*v)
+            } else {
+                None
+            }
+        });
+        let mut next_hops: Vec<Gateway> = gateway(&msg.attributes)
+            .map(|addr| Gateway { addr, oif })
+            .into_iter()
+            .collect();
+        for nla in &msg.attributes {
+            if let RouteAttribute::MultiPath(hops) = nla {
+                next_hops.extend(hops.iter().filter_map(|h| {
+                    gateway(&h.attributes).map(|addr| Gateway {
+                        addr,
+                        oif: Some(h.interface_index),
+                    })
+                }));
+            }
+        }
+        next_hops.sort();
+        let metric = msg.attributes.iter().find_map(|nla| {
+            if let RouteAttribute::Priority(v) = nla {
+                Some(*v)
+            } else {
+                None
+            }
+        });
+        let table = table_of(&msg);
+        let prefsrc = msg.attributes.iter().find_map(|nla| match nla {
+            RouteAttribute::PrefSource(RouteAddress::Inet(t)) => Some(IpAddr::V4(*t)),
+            RouteAttribute::PrefSource(RouteAddress::Inet6(t)) => Some(IpAddr::V6(*t)),
+            _ => None,
+        });
+        let proto = msg.header.protocol;
+        FibEntry {
+            prefix,
+            next_hops,
+            oif,
+            dev: None,
+            metric,
+            proto,
+            table,
+            prefsrc,
+            rm: Some(msg),
+        }
+    }
+
+    /// Route for `prefix` through `next_hops`, to install with `params`.
+    pub fn new(prefix: IpNet, next_hops: Vec<Gateway>, params: &RouteParams) -> FibEntry {
+        FibEntry {
+            prefix: Some(prefix),
+            next_hops,
+            metric: params.metric,
+            proto: params.protocol,
+            table: params.table,
+            prefsrc: params.prefsrc,
+            ..Default::default()
+        }
+    }
+
+    /// Whether both are the same kernel route, which may have changed in between.
+    fn same_route(&self, other: &FibEntry) -> bool {
+        self.prefix == other.prefix && self.metric == other.metric && self.table == other.table
+    }
+}
+
+/// Table of the route in `msg`. Tables above 255 only fit the attribute.
+fn table_of(msg: &RouteMessage) -> u32 {
+    msg.attributes
+        .iter()
+        .find_map(|nla| {
+            if let RouteAttribute::Table(v) = nla {
+                Some(*v)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(msg.header.table as u32)
+}
+
+fn in_family(prefix: &IpNet, af: &AddressFamily) -> bool {
+    matches!(
+        (prefix, af.afi),
+        (IpNet::V4(_), Afi::Ipv4) | (IpNet::V6(_), Afi::Ipv6)
+    )
+}
+
+/// Routes of `af`, for the backends keeping their own table.
+fn family_routes(routes: &RouteTable, af: &AddressFamily) -> Vec<FibEntry> {
+    routes
+        .iter()
+        .filter(|(prefix, _)| in_family(prefix, af))
+        .flat_map(|(_, routes)| routes.iter().cloned())
+        .collect()
+}
+
+/// Applies `change` to `routes`.
+fn apply_change(routes: &mut RouteTable, change: &FibChange) {
+    let (entry, add) = match change {
+        FibChange::Add(entry) | FibChange::Replace(entry) => (entry, true),
+        FibChange::Delete(entry) => (entry, false),
+    };
+    let Some(prefix) = entry.prefix else {
+        return;
+    };
+    let same_prefix = routes.get_or_insert_with(prefix, Vec::new);
+    same_prefix.retain(|fe| !fe.same_route(entry));
+    if add {
+        same_prefix.push(entry.clone());
+    } else if same_prefix.is_empty() {
+        routes.remove(&prefix);
+    }
+}
+
+/// How a BGP next hop is reached through the kernel routing table.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Resolution {
+    /// Most specific route covering the next hop.
+    pub prefix: IpNet,
+    /// Metric of that route. Routes without a priority, like connected ones, have a metric of 0.
+    pub metric: u32,
+    /// Immediate next hops towards the BGP next hop, the next hop itself when it is directly
+    /// connected.
+    pub gateways: Vec<Gateway>,
+}
+
+impl Resolution {
+    /// Resolution of a next hop reachable directly.
+    fn direct(addr: &IpAddr) -> Self {
+        Resolution {
+            prefix: IpNet::from(*addr),
+            metric: 0,
+            gateways: vec![Gateway {
+                addr: *addr,
+                oif: None,
+            }],
+        }
+    }
+}
+
+/// Sorted next hops of the paths to install for every prefix of the Loc-RIB.
+pub fn desired_routes(rib: &rib::Rib) -> HashMap<IpNet, Vec<IpAddr>> {
+    rib.iter()
+        .filter_map(|(n, a)| {
+            let mut next_hops: Vec<IpAddr> = a
+                .iter()
+                .filter(|a| a.multipath && !a.is_local())
+                .map(|a| a.attrs.next_hop)
+                .collect();
+            next_hops.sort();
+            (!next_hops.is_empty()).then(|| ((*n).into(), next_hops))
+        })
+        .collect()
+}
+
+/// Resolution of the next hops the FIB tracks, published by the FIB manager so that the decision
+/// process reads it without waiting on the FIB.
+#[derive(Debug, Clone, Default)]
+pub struct NextHops {
+    /// Whether every next hop is reachable directly, for the backends without IGP routes.
+    direct: bool,
+    resolutions: HashMap<IpAddr, Option<Resolution>>,
+}
+
+impl NextHops {
+    /// Resolution of the next hop `addr`, none when it is unreachable or not tracked yet.
+    pub fn get(&self, addr: &IpAddr) -> Option<Resolution> {
+        if self.direct {
+            return Some(Resolution::direct(addr));
+        }
+        self.resolutions.get(addr).cloned().flatten()
+    }
+
+    /// Whether the FIB tracks the next hop `addr`, and tells when its resolution changes.
+    pub fn tracks(&self, addr: &IpAddr) -> bool {
+        self.direct || self.resolutions.contains_key(addr)
+    }
+}
+
+#[derive(Debug)]
+pub struct Fib {
+    af: AddressFamily,
+    config: config::FibConfig,
+    policy: InstallPolicy,
+    backend: Box<dyn FibBackend>,
+    routes: RouteTable,
+    /// Prefixes installed since startup.
+    installed: HashSet<IpNet>,
+    /// Whether BGP routes left over by a previous run are deleted.
+    sweep_stale: bool,
+    /// Resolution of the next hops of the Loc-RIB paths.
+    next_hops: HashMap<IpAddr, Option<Resolution>>,
+}
+
+impl Fib {
+    pub async fn new(
+        af: AddressFamily,
+        config: config::FibConfig,
+        policy: InstallPolicy,
+        backend: Box<dyn FibBackend>,
+    ) -> Self {
+        let mut fib = Fib::with_routes(af, config, policy, backend, vec![]);
+        fib.refresh().await;
+        fib
+    }
+
+    /// Table of `backend` already known to hold `routes`.
+    pub fn with_routes(
+        af: AddressFamily,
+        config: config::FibConfig,
+        policy: InstallPolicy,
+        backend: Box<dyn FibBackend>,
+        routes: Vec<FibEntry>,
+    ) -> Self {
+        Fib {
+            af,
+            config,
+            policy,
+            backend,
+            routes: route_table(routes),
+            installed: HashSet::new(),
+            sweep_stale: false,
+            next_hops: HashMap::new(),
+        }
+    }
+
+    /// Ends the startup grace period, the next sync deletes the BGP routes of a previous run that
+    /// have not been learned again.
+    pub fn sweep_stale(&mut self) {
+        self.sweep_stale = true;
+    }
+
+    pub async fn refresh(&mut self) {
+        match self.backend.routes(&self.af).await {
+            Ok(routes) => self.routes = route_table(routes),
+            Err(e) => log::error!("Failed to read the {} routes: {:#}", self.backend.name(), e),
+        }
+    }
+
+    /// Routes of the table, as of the last refresh with the changes made since.
+    #[cfg(test)]
+    pub fn routes(&self) -> Vec<&FibEntry> {
+        self.routes.values().flatten().collect()
+    }
+
+    /// Whether `msg` notifies a change to a route of the protocol and table this FIB installs,
+    /// which it applied to its table when making it.
+    fn is_own(&self, msg: &RouteMessage) -> bool {
+        self.policy.installs_in(msg.header.protocol, table_of(msg))
+    }
+
+    /// Applies a route notification from the kernel and returns the prefix of the route, none
+    /// when it was skipped.
+    pub async fn route_added(&mut self, msg: RouteMessage) -> Option<IpNet> {
+        if self.is_own(&msg) {
+            return None;
+        }
+        let entry = self.backend.notified_route(msg).await?;
+        let prefix = entry.prefix;
+        apply_change(&mut self.routes, &FibChange::Add(entry));
+        prefix
+    }
+
+    /// Applies a route deletion notification from the kernel and returns the prefix of the
+    /// route, none when it was skipped.
+    pub fn route_deleted(&mut self, msg: RouteMessage) -> Option<IpNet> {
+        if self.is_own(&msg) {
+            return None;
+        }
+        let entry = FibEntry::parse(msg);
+        let prefix = entry.prefix;
+        apply_change(&mut self.routes, &FibChange::Delete(entry));
+        prefix
+    }
+
+    /// Applies a link notification from the kernel, and reads the routes again since those
+    /// through a link going down are flushed without notification.
+    pub async fn link_changed(&mut self, link: LinkMessage, removed: bool) {
+        self.backend.link_changed(link, removed).await;
+        self.refresh().await;
+    }
+
+    /// Installs the `desired` routes, prefixes with the BGP next hops of their paths, through the
+    /// immediate gateways these resolve to, and removes the BGP routes that are not desired
+    /// anymore. Returns the next hops resolving through the BGP routes whose resolution changed.
+    pub async fn sync(&mut self, desired: HashMap<IpNet, Vec<IpAddr>>) -> Vec<IpAddr> {
+        let desired: HashMap<IpNet, FibEntry> = desired
+            .into_iter()
+            .filter_map(|(prefix, next_hops)| {
+                let mut gateways: Vec<Gateway> = next_hops
+                    .into_iter()
+                    .filter_map(|nh| self.next_hop(nh))
+                    .flat_map(|r| r.gateways)
+                    .collect();
+                gateways.sort();
+                gateways.dedup();
+                let params = self.policy.params(&prefix);
+                (!gateways.is_empty()).then(|| (prefix, FibEntry::new(prefix, gateways, &params)))
+            })
+            .collect();
+
+        let changes = reconcile(
+            &self.routes,
+            &desired,
+            &self.policy,
+            &self.installed,
+            self.sweep_stale,
+        );
+        for change in &changes {
+            log::debug!("FIB change: {:?}", change);
+            match change {
+                FibChange::Add(entry) | FibChange::Replace(entry) => {
+                    self.installed.extend(entry.prefix);
+                }
+                // The route may have moved to another table or metric.
+                FibChange::Delete(entry) => {
+                    if let Some(prefix) = entry.prefix.filter(|p| !desired.contains_key(p)) {
+                        self.installed.remove(&prefix);
+                    }
+                }
+            }
+            apply_change(&mut self.routes, change);
+        }
+        if changes.is_empty() {
+            return vec![];
+        }
+        // The notifications of these changes are skipped, the next hops they cover are resolved
+        // again here.
+        let changed: Vec<IpNet> = if self.config.resolve_via_bgp {
+            changes
+                .iter()
+                .filter_map(|change| match change {
+                    FibChange::Add(entry)
+                    | FibChange::Replace(entry)
+                    | FibChange::Delete(entry) => entry.prefix,
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        if let Err(e) = self.backend.apply(changes).await {
+            log::error!(
+                "Failed to update the {} routes: {:#}",
+                self.backend.name(),
+                e
+            );
+            // Some changes may have been applied, the table is read again to know which.
+            self.refresh().await;
+            return self.update_next_hops(None);
+        }
+        let mut next_hops: Vec<IpAddr> = changed
+            .iter()
+            .flat_map(|prefix| self.update_next_hops(Some(prefix)))
+            .collect();
+        next_hops.sort();
+        next_hops.dedup();
+        next_hops
+    }
+
+    /// Resolves `addr` through the most specific route covering it, looked up in the prefix trie.
+    /// Only the routes of the main table, or the configured one, are used. The default route and
+    /// the routes installed by BGP are not used unless the configuration allows it.
+    ///
+    /// Resolution recurses through the covering route to its gateways, these are always directly
+    /// connected in the kernel table so a single level is enough.
+    ///
+    /// Backends without IGP routes reach every next hop directly.
+    pub fn resolve(&self, addr: &IpAddr) -> Option<Resolution> {
+        if !self.backend.resolves_next_hops() {
+            return Some(Resolution::direct(addr));
+        }
+        let table = self
+            .config
+            .resolve_table
+            .unwrap_or(RouteHeader::RT_TABLE_MAIN as u32);
+        self.routes
+            .less_specifics(&IpNet::from(*addr))
+            .into_iter()
+            .rev()
+            .filter(|(prefix, _)| prefix.prefix_len() > 0 || self.config.resolve_via_default)
+            .find_map(|(prefix, routes)| {
+                let fe = routes
+                    .iter()
+                    .filter(|fe| fe.table == table)
+                    .filter(|fe| {
+                        !self.policy.installs_in(fe.proto, fe.table) || self.config.resolve_via_bgp
+                    })
+                    .min_by_key(|fe| fe.metric.unwrap_or(0))?;
+                let gateways = if fe.next_hops.is_empty() {
+                    vec![Gateway {
+                        addr: *addr,
+                        oif: fe.oif,
+                    }]
+                } else {
+                    fe.next_hops.clone()
+                };
+                Some(Resolution {
+                    prefix: *prefix,
+                    metric: fe.metric.unwrap_or(0),
+                    gateways,
+                })
+            })
+    }
+
+    /// Resolution of the next hop `addr`, which is tracked from now on.
+    pub fn next_hop(&mut self, addr: IpAddr) -> Option<Resolution> {
+        if let Some(resolution) = self.next_hops.get(&addr) {
+            return resolution.clone();
+        }
+        let resolution = self.resolve(&addr);
+        self.next_hops.insert(addr, resolution.clone());
+        resolution
+    }
+
+    /// Resolves again the tracked next hops covered by the `changed` prefix, all of them when the
+    /// whole table may have changed, and returns those whose resolution changed.
+    pub fn update_next_hops(&mut self, changed: Option<&IpNet>) -> Vec<IpAddr> {
+        let mut updated = vec![];
+        let addrs: Vec<IpAddr> = self
+            .next_hops
+            .keys()
+            .filter(|addr| changed.is_none_or(|prefix| prefix.contains(*addr)))
+            .copied()
+            .collect();
+        for addr in addrs {
+            let resolution = self.resolve(&addr);
+            if self.next_hops.insert(addr, resolution.clone()) != Some(resolution) {
+                updated.push(addr);
+            }
+        }
+        updated
+    }
+
+    /// Stops tracking the next hops no path uses anymore.
+    pub fn retain_next_hops(&mut self, in_use: &HashSet<IpAddr>) {
+        self.next_hops.retain(|addr, _| in_use.contains(addr));
+    }
+
+    /// Tracks exactly the next hops `in_use` and returns those it did not track before.
+    pub fn track_next_hops(&mut self, in_use: &HashSet<IpAddr>) -> Vec<IpAddr> {
+        self.retain_next_hops(in_use);
+        let added: Vec<IpAddr> = in_use
+            .iter()
+            .filter(|addr| !self.next_hops.contains_key(addr))
+            .copied()
+            .collect();
+        for addr in &added {
+            self.next_hop(*addr);
+        }
+        added
+    }
+
+    /// Resolution of the tracked next hops, for the decision process.
+    pub fn next_hops(&self) -> NextHops {
+        NextHops {
+            direct: !self.backend.resolves_next_hops(),
+            resolutions: self.next_hops.clone(),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{bail, Context, Result};
+use futures::stream::TryStreamExt;
+use futures::stream::{self, StreamExt};
+use ipnet::IpNet;
+use netlink_packet_route::link::{LinkAttribute, LinkMessage};
+use netlink_packet_route::route::RouteNextHop;
+use netlink_packet_route::route::{RouteAttribute, RouteMessage};
+use rtnetlink::{new_connection, Handle, RouteMessageBuilder, RouteNextHopBuilder};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use super::{FibBackend, FibChange, FibEntry, Gateway};
+use crate::bgp::{AddressFamily, Afi};
+
+/// Sets the table, metric and protocol of `entry`.
+fn with_params<T>(builder: RouteMessageBuilder<T>, entry: &FibEntry) -> RouteMessageBuilder<T> {
+    let builder = builder.protocol(entry.proto).table_id(entry.table);
+    match entry.metric {
+        Some(metric) => builder.priority(metric),
+        None => builder,
+    }
+}
+
+fn multipath(gateways: &[Gateway]) -> Vec<RouteNextHop> {
+    gateways
+        .iter()
+        .filter_map(|g| {
+            let builder = match g.addr {
+                IpAddr::V4(_) => RouteNextHopBuilder::new_ipv4(),
+                IpAddr::V6(_) => RouteNextHopBuilder::new_ipv6(),
+            };
+            let builder = builder.via(g.addr).ok()?;
+            Some(match g.oif {
+                Some(oif) => builder.interface(oif),
+                None => builder,
+            })
+        })
+        .map(|b| b.build())
+        .collect()
+}
+
+/// Message installing `entry`, as a multipath route when it has several gateways. Gateways and
+/// preferred source of the other address family are left out, there is no message when no
+/// gateway is left.
+pub(super) fn route_message(entry: &FibEntry) -> Option<RouteMessage> {
+    let subnet = entry.prefix?;
+    let gateways: Vec<Gateway> = entry
+        .next_hops
+        .iter()
+        .filter(|g| g.addr.is_ipv4() == subnet.addr().is_ipv4())
+        .copied()
+        .collect();
+    let mut message = match (subnet, gateways.as_slice()) {
+        (_, []) => return None,
+        (IpNet::V4(t), gateways) => {
+            let mut builder = with_params(
+                RouteMessageBuilder::<Ipv4Addr>::new().destination_prefix(t.addr(), t.prefix_len()),
+                entry,
+            );
+            if let Some(IpAddr::V4(src)) = entry.prefsrc {
+                builder = builder.pref_source(src);
+            }
+            match gateways {
+                [Gateway {
+                    addr: IpAddr::V4(addr),
+                    ..
+                }] => builder.gateway(*addr).build(),
+                _ => builder.multipath(multipath(gateways)).build(),
+            }
+        }
+        (IpNet::V6(t), gateways) => {
+            let mut builder = with_params(
+                RouteMessageBuilder::<Ipv6Addr>::new().destination_prefix(t.addr(), t.prefix_len()),
+                entry,
+            );
+            if let Some(IpAddr::V6(src)) = entry.prefsrc {
+                builder = builder.pref_source(src);
+            }
+            match gateways {
+                [Gateway {
+                    addr: IpAddr::V6(addr),
+                    ..
+                }] => builder.gateway(*addr).build(),
+                _ => builder.multipath(multipath(gateways)).build(),
+            }
+        }
+    };
+    if let [Gateway { oif: Some(oif), .. }] = gateways.as_slice() {
+        message.attributes.push(RouteAttribute::Oif(*oif));
+    }
+    Some(message)
+}
+
+/// Message deleting `entry`.
+pub(super) fn delete_message(entry: &FibEntry) -> Option<RouteMessage> {
+    Some(match entry.prefix? {
+        IpNet::V4(t) => with_params(
+            RouteMessageBuilder::<Ipv4Addr>::new().destination_prefix(t.addr(), t.prefix_len()),
+            entry,
+        )
+        .build(),
+        IpNet::V6(t) => with_params(
+            RouteMessageBuilder::<Ipv6Addr>::new().destination_prefix(t.addr(), t.prefix_len()),
+            entry,
+        )
+        .build(),
+    })
+}
+
+/// Route operations sent to the kernel without waiting for the previous ones to complete.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// The kernel routing table, through rtnetlink. Clones share the same connection and the names of
+/// the interfaces, these are kept up to date from link notifications.
+#[derive(Debug, Clone)]
+pub struct NetlinkBackend {
+    handle: Handle,
+    links: Arc<Mutex<HashMap<u32, String>>>,
+}
+
+impl NetlinkBackend {
+    pub fn new() -> Result<Self> {
+        let (connection, handle, _) =
+            new_connection().context("Failed to create netlink connection")?;
+        tokio::spawn(connection);
+        Ok(NetlinkBackend {
+            handle,
+            links: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Reads the names of every interface.
+    async fn load_links(&self) -> Result<()> {
+        let mut links = self.handle.link().get().execute();
+        let mut names = HashMap::new();
+        while let Some(msg) = links
+            .try_next()
+            .await
+            .context("Failed to get link information")?
+        {
+            if let Some(name) = link_name(&msg) {
+                names.insert(msg.header.index, name);
+            }
+        }
+        *self.links.lock().await = names;
+        Ok(())
+    }
+
+    /// Name of the interface `index`, asked to the kernel when it is not known yet.
+    async fn link_name(&self, index: u32) -> Result<String> {
+        if let Some(name) = self.links.lock().await.get(&index) {
+            return Ok(name.clone());
+        }
+        let name = get_link_name(self.handle.clone(), index).await?;
+        self.links.lock().await.insert(index, name.clone());
+        Ok(name)
+    }
+
+    /// Route described by `msg`, with the name of its output interface.
+    async fn entry(&self, msg: RouteMessage) -> Option<FibEntry> {
+        let entry = FibEntry::parse(msg);
+        let dev = match entry.oif {
+            Some(dev_id) => match self.link_name(dev_id).await {
+                Ok(name) => Some(name),
+                Err(e) => {
+                    log::error!(
+                        "Failed to get link name for device {}: {:#} (route will be skipped)",
+                        dev_id,
+                        e
+                    );
+                    return None;
+                }
+            },
+            None => {
+                log::debug!("Route has no output interface specified");
+                None
+            }
+        };
+        Some(FibEntry { dev, ..entry })
+    }
+
+    /// Sends `change` to the kernel. Routes read from the kernel are deleted with the message
+    /// they were read from.
+    async fn execute(&self, change: FibChange) -> Result<()> {
+        let (entry, replace) = match change {
+            FibChange::Add(entry) => (entry, false),
+            FibChange::Replace(entry) => (entry, true),
+            FibChange::Delete(entry) => {
+                let Some(message) = entry.rm.clone().or_else(|| delete_message(&entry)) else {
+                    return Ok(());
+                };
+                return self
+                    .handle
+                    .route()
+                    .del(message)
+                    .execute()
+                    .await
+                    .with_context(|| format!("Failed to delete route {:?}", entry.prefix));
+            }
+        };
+        let Some(message) = route_message(&entry) else {
+            return Ok(());
+        };
+        let mut request = self.handle.route().add(message);
+        if replace {
+            request = request.replace();
+        }
+        request
+            .execute()
+            .await
+            .with_context(|| format!("Failed to install route {:?}", entry.prefix))
+    }
+}
+
+#[tonic::async_trait]
+impl FibBackend for NetlinkBackend {
+    fn name(&self) -> &'static str {
+        "netlink"
+    }
+
+    async fn routes(&mut self, af: &AddressFamily) -> Result<Vec<FibEntry>> {
+        if self.links.lock().await.is_empty() {
+            self.load_links().await?;
+        }
+        let mut routes = match af.afi {
+            Afi::Ipv4 => self
+                .handle
+                .route()
+                .get(RouteMessageBuilder::<Ipv4Addr>::new().build())
+                .execute(),
+            Afi::Ipv6 => self
+                .handle
+                .route()
+                .get(RouteMessageBuilder::<Ipv6Addr>::new().build())
+                .execute(),
+        };
+        let mut entries = vec![];
+        while let Some(route) = routes.try_next().await.unwrap_or_else(|e| {
+            log::error!("Failed to read route from netlink: {}", e);
+            None
+        }) {
+            entries.extend(self.entry(route).await);
+        }
+        Ok(entries)
+    }
+
+    async fn apply(&mut self, changes: Vec<FibChange>) -> Result<()> {
+        let total = changes.len();
+        let this = &*self;
+        let failed = stream::iter(changes)
+            .map(|change| this.execute(change))
+            .buffer_unordered(MAX_IN_FLIGHT)
+            .filter_map(|result| async move { result.err() })
+            .fold(0, |failed, e| async move {
+                log::error!("{:#}", e);
+                failed + 1
+            })
+            .await;
+        if failed > 0 {
+            bail!("{} of {} route changes failed", failed, total);
+        }
+        Ok(())
+    }
+
+    fn resolves_next_hops(&self) -> bool {
+        true
+    }
+
+    async fn notified_route(&mut self, msg: RouteMessage) -> Option<FibEntry> {
+        self.entry(msg).await
+    }
+
+    async fn link_changed(&mut self, link: LinkMessage, removed: bool) {
+        let mut links = self.links.lock().await;
+        match link_name(&link) {
+            Some(name) if !removed => links.insert(link.header.index, name),
+            _ => links.remove(&link.header.index),
+        };
+    }
+}
+
+fn link_name(msg: &LinkMessage) -> Option<String> {
+    msg.attributes.iter().find_map(|nla| {
+        if let LinkAttribute::IfName(v) = nla {
+            Some(v.clone())
+        } else {
+            None
+        }
+    })
+}
+
+async fn get_link_name(handle: Handle, index: u32) -> Result<String, anyhow::Error> {
+    let mut links = handle.link().get().match_index(index).execute();
+    let msg = links
+        .try_next()
+        .await
+        .context("Failed to get link information")?
+        .context("No link found with specified index")?;
+
+    link_name(&msg).context("Link has no interface name")
+}
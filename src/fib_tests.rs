@@ -0,0 +1,588 @@
+use ipnet::{IpNet, Ipv4Net};
+use netlink_packet_route::route::{RouteMessage, RouteProtocol};
+use rtnetlink::RouteMessageBuilder;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+use crate::bgp::{AddressFamily, Afi, Safi};
+use crate::config::FibConfig;
+use crate::fib::*;
+
+fn net(s: &str) -> IpNet {
+    s.parse().unwrap()
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn gw(s: &str) -> Gateway {
+    Gateway {
+        addr: ip(s),
+        oif: None,
+    }
+}
+
+fn route(prefix: &str, next_hops: &[&str], proto: RouteProtocol) -> FibEntry {
+    let params = RouteParams {
+        protocol: proto,
+        ..Default::default()
+    };
+    FibEntry::new(
+        net(prefix),
+        next_hops.iter().map(|nh| gw(nh)).collect(),
+        &params,
+    )
+}
+
+fn bgp(prefix: &str, next_hops: &[&str]) -> FibEntry {
+    route(prefix, next_hops, RouteProtocol::Bgp)
+}
+
+#[test]
+fn test_reconcile_add_and_replace() {
+    let routes = route_table(vec![
+        route("10.0.1.0/24", &["192.0.2.1"], RouteProtocol::Bgp),
+        route("10.0.2.0/24", &["192.0.2.1"], RouteProtocol::Bgp),
+        route("10.0.3.0/24", &["192.0.2.9"], RouteProtocol::Static),
+    ]);
+    let desired = HashMap::from([
+        (net("10.0.1.0/24"), bgp("10.0.1.0/24", &["192.0.2.1"])),
+        (
+            net("10.0.2.0/24"),
+            bgp("10.0.2.0/24", &["192.0.2.1", "192.0.2.2"]),
+        ),
+        (net("10.0.3.0/24"), bgp("10.0.3.0/24", &["192.0.2.1"])),
+        (net("10.0.4.0/24"), bgp("10.0.4.0/24", &["192.0.2.1"])),
+    ]);
+
+    let policy = InstallPolicy::default();
+    let mut changes = reconcile(&routes, &desired, &policy, &HashSet::new(), false);
+    changes.sort_by_key(|c| format!("{:?}", c));
+    assert_eq!(
+        changes,
+        vec![
+            // Routes from other protocols are left alone
+            FibChange::Add(bgp("10.0.3.0/24", &["192.0.2.1"])),
+            FibChange::Add(bgp("10.0.4.0/24", &["192.0.2.1"])),
+            FibChange::Replace(bgp("10.0.2.0/24", &["192.0.2.1", "192.0.2.2"])),
+        ]
+    );
+}
+
+#[test]
+fn test_reconcile_delete() {
+    let installed = route("10.0.1.0/24", &["192.0.2.1"], RouteProtocol::Bgp);
+    let stale = route("10.0.2.0/24", &["192.0.2.1"], RouteProtocol::Bgp);
+    let other = route("10.0.3.0/24", &["192.0.2.1"], RouteProtocol::Static);
+    let routes = route_table(vec![installed.clone(), stale.clone(), other]);
+    let desired = HashMap::new();
+    let policy = InstallPolicy::default();
+
+    // Routes from a previous run are kept during the grace period
+    let changes = reconcile(
+        &routes,
+        &desired,
+        &policy,
+        &HashSet::from([net("10.0.1.0/24")]),
+        false,
+    );
+    assert_eq!(changes, vec![FibChange::Delete(installed.clone())]);
+
+    let changes = reconcile(
+        &routes,
+        &desired,
+        &policy,
+        &HashSet::from([net("10.0.1.0/24")]),
+        true,
+    );
+    assert_eq!(
+        changes,
+        vec![FibChange::Delete(installed), FibChange::Delete(stale)]
+    );
+}
+
+fn message(prefix: &str, metric: Option<u32>) -> RouteMessage {
+    let net: Ipv4Net = prefix.parse().unwrap();
+    let builder = RouteMessageBuilder::<Ipv4Addr>::new()
+        .destination_prefix(net.addr(), net.prefix_len())
+        .gateway(Ipv4Addr::new(192, 0, 2, 254));
+    match metric {
+        Some(metric) => builder.priority(metric).build(),
+        None => builder.build(),
+    }
+}
+
+fn ipv4() -> AddressFamily {
+    AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::NLRIUnicast,
+    }
+}
+
+fn fib_with(config: FibConfig, routes: Vec<FibEntry>) -> Fib {
+    let af = ipv4();
+    let backend = Box::new(MemoryBackend::with_routes(routes.clone()));
+    Fib::with_routes(af, config, InstallPolicy::default(), backend, routes)
+}
+
+fn fib(routes: &[(&str, Option<u32>)]) -> Fib {
+    let routes = routes
+        .iter()
+        .map(|(prefix, metric)| FibEntry::parse(message(prefix, *metric)))
+        .collect();
+    fib_with(FibConfig::default(), routes)
+}
+
+#[test]
+fn test_resolve_most_specific() {
+    let fib = fib(&[
+        ("10.0.0.0/8", Some(10)),
+        ("10.1.0.0/16", Some(200)),
+        ("10.1.0.0/16", Some(100)),
+    ]);
+    assert_eq!(
+        fib.resolve(&ip("10.1.2.3")),
+        Some(Resolution {
+            prefix: net("10.1.0.0/16"),
+            metric: 100,
+            gateways: vec![gw("192.0.2.254")],
+        })
+    );
+    assert_eq!(fib.resolve(&ip("10.2.0.1")).map(|r| r.metric), Some(10));
+    assert_eq!(fib.resolve(&ip("192.168.0.1")), None);
+}
+
+#[test]
+fn test_next_hop_tracking() {
+    let mut fib = fib(&[("10.0.0.0/8", None), ("10.1.0.0/16", None)]);
+    assert!(fib.next_hop(ip("10.1.0.1")).is_some());
+    assert!(fib.next_hop(ip("10.2.0.1")).is_some());
+    assert_eq!(fib.next_hop(ip("192.168.0.1")), None);
+    assert!(fib.update_next_hops(None).is_empty());
+
+    let prefix = fib.route_deleted(message("10.1.0.0/16", None));
+    assert_eq!(fib.update_next_hops(prefix.as_ref()), vec![ip("10.1.0.1")]);
+    assert_eq!(
+        fib.next_hop(ip("10.1.0.1")).map(|r| r.prefix),
+        Some(net("10.0.0.0/8"))
+    );
+
+    let prefix = fib.route_deleted(message("10.0.0.0/8", None));
+    let mut changed = fib.update_next_hops(prefix.as_ref());
+    changed.sort();
+    assert_eq!(changed, vec![ip("10.1.0.1"), ip("10.2.0.1")]);
+    assert_eq!(fib.next_hop(ip("10.1.0.1")), None);
+}
+
+#[test]
+fn test_untracked_next_hops_not_reported() {
+    let mut fib = fib(&[("10.0.0.0/8", None)]);
+    fib.next_hop(ip("10.1.0.1"));
+    fib.next_hop(ip("10.2.0.1"));
+    fib.retain_next_hops(&HashSet::from([ip("10.1.0.1")]));
+
+    fib.route_deleted(message("10.0.0.0/8", None));
+    assert_eq!(fib.update_next_hops(None), vec![ip("10.1.0.1")]);
+}
+
+#[test]
+fn test_only_covered_next_hops_updated() {
+    let mut fib = fib(&[("10.1.0.0/16", None), ("10.2.0.0/16", None)]);
+    fib.next_hop(ip("10.1.0.1"));
+    fib.next_hop(ip("10.2.0.1"));
+
+    // The next hop 10.2.0.1 is not covered, so it is left as it was resolved.
+    let prefix = fib.route_deleted(message("10.1.0.0/16", None));
+    assert_eq!(prefix, Some(net("10.1.0.0/16")));
+    assert_eq!(fib.update_next_hops(prefix.as_ref()), vec![ip("10.1.0.1")]);
+}
+
+#[test]
+fn test_own_route_notifications_skipped() {
+    let mut fib = fib(&[("10.0.0.0/8", None)]);
+    fib.next_hop(ip("10.1.0.1"));
+
+    let mut msg = message("10.1.0.0/16", None);
+    msg.header.protocol = RouteProtocol::Bgp;
+    assert_eq!(fib.route_deleted(msg), None);
+    assert_eq!(
+        fib.next_hop(ip("10.1.0.1")).map(|r| r.prefix),
+        Some(net("10.0.0.0/8"))
+    );
+}
+
+#[test]
+fn test_parse_default_route() {
+    let msg = RouteMessageBuilder::<Ipv4Addr>::new()
+        .gateway(Ipv4Addr::new(192, 0, 2, 254))
+        .output_interface(2)
+        .build();
+    let fe = FibEntry::parse(msg);
+    assert_eq!(fe.prefix, Some(net("0.0.0.0/0")));
+    assert_eq!(
+        fe.next_hops,
+        vec![Gateway {
+            addr: ip("192.0.2.254"),
+            oif: Some(2)
+        }]
+    );
+}
+
+#[test]
+fn test_resolve_through_gateway_or_interface() {
+    let connected = FibEntry {
+        oif: Some(3),
+        ..route("192.0.2.0/24", &[], RouteProtocol::Kernel)
+    };
+    let fib = fib_with(
+        FibConfig::default(),
+        vec![
+            connected,
+            route("10.0.0.0/8", &["192.0.2.1"], RouteProtocol::Ospf),
+        ],
+    );
+
+    // A directly connected next hop is its own gateway
+    assert_eq!(
+        fib.resolve(&ip("192.0.2.7")).map(|r| r.gateways),
+        Some(vec![Gateway {
+            addr: ip("192.0.2.7"),
+            oif: Some(3)
+        }])
+    );
+    // Otherwise traffic goes through the gateway of the covering route
+    assert_eq!(
+        fib.resolve(&ip("10.1.2.3")).map(|r| r.gateways),
+        Some(vec![gw("192.0.2.1")])
+    );
+}
+
+#[test]
+fn test_resolve_excludes_default_and_bgp_routes() {
+    let routes = vec![
+        route("0.0.0.0/0", &["192.0.2.1"], RouteProtocol::Static),
+        route("10.0.0.0/8", &["192.0.2.2"], RouteProtocol::Static),
+        route("10.1.0.0/16", &["192.0.2.3"], RouteProtocol::Bgp),
+    ];
+    let fib = fib_with(FibConfig::default(), routes.clone());
+    assert_eq!(
+        fib.resolve(&ip("10.1.0.1")).map(|r| r.prefix),
+        Some(net("10.0.0.0/8"))
+    );
+    assert_eq!(fib.resolve(&ip("172.16.0.1")), None);
+
+    let fib = fib_with(
+        FibConfig {
+            resolve_via_default: true,
+            resolve_via_bgp: true,
+            ..Default::default()
+        },
+        routes,
+    );
+    assert_eq!(
+        fib.resolve(&ip("10.1.0.1")).map(|r| r.prefix),
+        Some(net("10.1.0.0/16"))
+    );
+    assert_eq!(
+        fib.resolve(&ip("172.16.0.1")).map(|r| r.gateways),
+        Some(vec![gw("192.0.2.1")])
+    );
+}
+
+#[test]
+fn test_resolve_in_main_table_only() {
+    let in_table = |prefix, next_hop, proto, table| {
+        let params = RouteParams {
+            protocol: proto,
+            table,
+            ..Default::default()
+        };
+        FibEntry::new(net(prefix), vec![gw(next_hop)], &params)
+    };
+    // BGP installs its routes with protocol 200 in table 100
+    let ours = RouteProtocol::from(200);
+    let routes = vec![
+        in_table("10.0.0.0/8", "192.0.2.1", RouteProtocol::Static, 254),
+        // Local, VRF or policy tables
+        in_table("10.1.0.0/16", "192.0.2.2", RouteProtocol::Kernel, 255),
+        in_table("10.1.2.0/24", "192.0.2.3", RouteProtocol::Static, 100),
+        in_table("10.2.0.0/16", "192.0.2.4", ours, 254),
+        in_table("10.2.1.0/24", "192.0.2.5", ours, 100),
+    ];
+    let fib = |config| {
+        let backend = Box::new(MemoryBackend::with_routes(routes.clone()));
+        Fib::with_routes(ipv4(), config, install_policy(), backend, routes.clone())
+    };
+
+    let main = fib(FibConfig::default());
+    assert_eq!(
+        main.resolve(&ip("10.1.2.3")).map(|r| r.gateways),
+        Some(vec![gw("192.0.2.1")])
+    );
+    // Only the routes BGP installed in its own table are left out
+    assert_eq!(
+        main.resolve(&ip("10.2.1.1")).map(|r| r.gateways),
+        Some(vec![gw("192.0.2.4")])
+    );
+
+    let table_100 = fib(FibConfig {
+        resolve_table: Some(100),
+        ..Default::default()
+    });
+    assert_eq!(
+        table_100.resolve(&ip("10.1.2.3")).map(|r| r.gateways),
+        Some(vec![gw("192.0.2.3")])
+    );
+    assert_eq!(table_100.resolve(&ip("10.2.1.1")), None);
+}
+
+#[tokio::test]
+async fn test_sync_memory_backend() {
+    let backend = Box::new(MemoryBackend::new());
+    let mut fib = Fib::new(
+        ipv4(),
+        FibConfig::default(),
+        InstallPolicy::default(),
+        backend,
+    )
+    .await;
+    assert!(fib.routes().is_empty());
+
+    // Without IGP routes, next hops are reached directly
+    let desired = HashMap::from([(net("10.0.1.0/24"), vec![ip("192.0.2.2"), ip("192.0.2.1")])]);
+    fib.sync(desired).await;
+    fib.refresh().await;
+    assert_eq!(fib.routes().len(), 1);
+    assert_eq!(fib.routes()[0].prefix, Some(net("10.0.1.0/24")));
+    assert_eq!(
+        fib.routes()[0].next_hops,
+        vec![gw("192.0.2.1"), gw("192.0.2.2")]
+    );
+
+    fib.sync(HashMap::new()).await;
+    fib.refresh().await;
+    assert!(fib.routes().is_empty());
+}
+
+#[tokio::test]
+async fn test_sync_tracks_changes_without_refresh() {
+    let backend = Box::new(MemoryBackend::new());
+    let mut fib = Fib::new(
+        ipv4(),
+        FibConfig::default(),
+        InstallPolicy::default(),
+        backend,
+    )
+    .await;
+
+    let desired = HashMap::from([(net("10.0.1.0/24"), vec![ip("192.0.2.1")])]);
+    fib.sync(desired.clone()).await;
+    fib.sync(desired).await;
+    assert_eq!(fib.routes().len(), 1);
+    assert_eq!(fib.routes()[0].next_hops, vec![gw("192.0.2.1")]);
+
+    fib.sync(HashMap::from([(net("10.0.1.0/24"), vec![ip("192.0.2.2")])]))
+        .await;
+    assert_eq!(fib.routes().len(), 1);
+    assert_eq!(fib.routes()[0].next_hops, vec![gw("192.0.2.2")]);
+}
+
+#[tokio::test]
+async fn test_file_backend_writes_json() {
+    let path = std::env::temp_dir().join(format!("ubgpd-fib-{}.json", std::process::id()));
+    let backend = Box::new(FileBackend::file(path.clone()));
+    let mut fib = Fib::new(
+        ipv4(),
+        FibConfig::default(),
+        InstallPolicy::default(),
+        backend,
+    )
+    .await;
+
+    let desired = HashMap::from([(net("10.0.1.0/24"), vec![ip("192.0.2.1")])]);
+    fib.sync(desired).await;
+    let document: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        document,
+        serde_json::json!({
+            "routes": [{
+                "prefix": "10.0.1.0/24",
+                "next_hops": [{"addr": "192.0.2.1", "oif": null}],
+                "table": 254,
+                "metric": null,
+                "protocol": 186,
+                "prefsrc": null,
+            }]
+        })
+    );
+}
+
+fn install_policy() -> InstallPolicy {
+    let config: FibConfig = toml::from_str(
+        r#"
+        [[families]]
+            afi = "Ipv4"
+            safi = "NLRIUnicast"
+            table = 100
+            protocol = 200
+            prefsrc = "192.0.2.254"
+            [[families.rules]]
+                prefix_list = "CUSTOMERS"
+                metric = 20
+        "#,
+    )
+    .unwrap();
+    let prefix_lists = vec![crate::config::PrefixList {
+        name: "CUSTOMERS".to_string(),
+        entries: vec![crate::config::PrefixListEntry {
+            action: crate::config::PolicyAction::Permit,
+            prefix: "10.0.0.0/8".to_string(),
+            ge: None,
+            le: Some(24),
+        }],
+    }];
+    let policies = crate::policy::Policies::new(&prefix_lists, &[], &[]).unwrap();
+    InstallPolicy::new(&config, &ipv4(), &policies).unwrap()
+}
+
+#[test]
+fn test_install_policy_params() {
+    let policy = install_policy();
+    let family = RouteParams {
+        table: 100,
+        metric: None,
+        protocol: RouteProtocol::from(200),
+        prefsrc: Some(ip("192.0.2.254")),
+    };
+    assert_eq!(policy.params(&net("172.16.0.0/16")), family);
+    // Rules only override what they set
+    assert_eq!(
+        policy.params(&net("10.0.1.0/24")),
+        RouteParams {
+            metric: Some(20),
+            ..family
+        }
+    );
+    assert!(policy.installs(RouteProtocol::from(200)));
+    assert!(!policy.installs(RouteProtocol::Bgp));
+}
+
+#[test]
+fn test_install_policy_unknown_prefix_list() {
+    let config: FibConfig = toml::from_str(
+        r#"
+        [[families]]
+            afi = "Ipv4"
+            safi = "NLRIUnicast"
+            [[families.rules]]
+                prefix_list = "MISSING"
+                table = 10
+        "#,
+    )
+    .unwrap();
+    let policies = crate::policy::Policies::default();
+    assert!(InstallPolicy::new(&config, &ipv4(), &policies).is_err());
+}
+
+#[test]
+fn test_reconcile_metric_change() {
+    let policy = install_policy();
+    let params = policy.params(&net("10.0.1.0/24"));
+    let old = FibEntry::new(
+        net("10.0.1.0/24"),
+        vec![gw("192.0.2.1")],
+        &RouteParams {
+            metric: None,
+            ..params
+        },
+    );
+    let routes = route_table(vec![old.clone()]);
+    let new = FibEntry::new(net("10.0.1.0/24"), vec![gw("192.0.2.1")], &params);
+    let desired = HashMap::from([(net("10.0.1.0/24"), new.clone())]);
+
+    // The metric is part of the kernel route key, the old route is not replaced
+    let changes = reconcile(
+        &routes,
+        &desired,
+        &policy,
+        &HashSet::from([net("10.0.1.0/24")]),
+        false,
+    );
+    assert_eq!(changes, vec![FibChange::Add(new), FibChange::Delete(old)]);
+}
+
+/// One million /32 out of 10.0.0.0/8, all through `next_hop`.
+fn million_routes(next_hop: IpAddr) -> HashMap<IpNet, Vec<IpAddr>> {
+    (0..1_000_000u32)
+        .map(|i| {
+            let prefix = IpNet::from(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)));
+            (prefix, vec![next_hop])
+        })
+        .collect()
+}
+
+/// Installs, syncs again and removes a full table. The timings are printed, run with
+/// `cargo test --release -- --ignored --nocapture bench_`.
+async fn bench_sync(mut fib: Fib, next_hop: IpAddr) {
+    let desired = million_routes(next_hop);
+
+    let start = Instant::now();
+    fib.sync(desired.clone()).await;
+    println!("{:?} to install 1M routes", start.elapsed());
+
+    let start = Instant::now();
+    fib.refresh().await;
+    println!("{:?} to read the table", start.elapsed());
+    assert_eq!(
+        fib.routes().iter().filter(|fe| fe.table == 4242).count(),
+        1_000_000
+    );
+
+    let start = Instant::now();
+    fib.sync(desired).await;
+    println!("{:?} to sync 1M unchanged routes", start.elapsed());
+
+    let start = Instant::now();
+    fib.sync(HashMap::new()).await;
+    println!("{:?} to remove 1M routes", start.elapsed());
+    fib.refresh().await;
+    assert_eq!(fib.routes().iter().filter(|fe| fe.table == 4242).count(), 0);
+}
+
+fn bench_config() -> (FibConfig, InstallPolicy) {
+    let config: FibConfig = toml::from_str(
+        r#"
+        [[families]]
+            afi = "Ipv4"
+            safi = "NLRIUnicast"
+            table = 4242
+        "#,
+    )
+    .unwrap();
+    let policies = crate::policy::Policies::default();
+    let policy = InstallPolicy::new(&config, &ipv4(), &policies).unwrap();
+    (config, policy)
+}
+
+#[tokio::test]
+#[ignore]
+async fn bench_sync_memory_1m_routes() {
+    let (config, policy) = bench_config();
+    let backend = Box::new(MemoryBackend::new());
+    let fib = Fib::new(ipv4(), config, policy, backend).await;
+    bench_sync(fib, ip("192.0.2.1")).await;
+}
+
+/// Needs CAP_NET_ADMIN, the routes go to table 4242 through 127.0.0.2.
+#[tokio::test]
+#[ignore]
+async fn bench_sync_netlink_1m_routes() {
+    let (config, policy) = bench_config();
+    let backend = Box::new(NetlinkBackend::new().unwrap());
+    let fib = Fib::new(ipv4(), config, policy, backend).await;
+    bench_sync(fib, ip("127.0.0.2")).await;
+}
@@ -1,33 +1,209 @@
 use ipnet::IpNet;
 use num_traits::FromPrimitive;
-use std::net::IpAddr;
-
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 use tonic::{transport::Server, Request, Response, Status};
 
 use ubgp::config_server::{Config, ConfigServer};
 use ubgp::state_server::{State, StateServer};
-use ubgp::{NeighborEntry, NeighborReply, NeighborRequest, RibEntry, RibReply, RibRequest};
+use ubgp::{
+    AdjRibInRequest, ClearDampingReply, ClearMaxPrefixReply, ClearMaxPrefixRequest, DampingEntry,
+    DampingReply, DampingRequest, MemoryEntry, MemoryReply, MemoryRequest, NeighborEntry,
+    NeighborReply, NeighborRequest, PathEntry, RibEntry, RibReply, RibRequest,
+    SoftReconfigureReply, SoftReconfigureRequest,
+};
 
 pub mod ubgp {
     tonic::include_proto!("ubgp");
 }
 
 use crate::bgp;
+use crate::bgp::Flatten;
+use crate::config;
+use crate::neighbor;
+use crate::policy;
+use crate::rib;
 use crate::speaker;
+use crate::speaker::RibEvent;
 
 #[derive(Debug)]
 pub struct GrpcServer {
-    speaker: Arc<Mutex<speaker::BGPSpeaker>>,
+    speaker: Arc<speaker::BGPSpeaker>,
+    /// How long a neighbor session is given to hand over its Adj-RIBs-In.
+    adj_rib_timeout: Duration,
 }
 
 impl GrpcServer {
-    pub fn new(speaker: Arc<Mutex<speaker::BGPSpeaker>>) -> Self {
-        GrpcServer { speaker }
+    pub fn new(speaker: Arc<speaker::BGPSpeaker>, config: &config::ApiConfig) -> Self {
+        GrpcServer {
+            speaker,
+            adj_rib_timeout: Duration::from_millis(config.adj_rib_timeout),
+        }
+    }
+
+    /// Configured neighbor with the address `ip`.
+    fn neighbor(&self, ip: &str) -> Result<&Arc<neighbor::NeighborCell>, String> {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid IP: {}", e))?;
+        self.speaker
+            .neighbors
+            .iter()
+            .find(|n| n.remote_ip == Some(ip))
+            .ok_or(format!("Unknown neighbor {}", ip))
+    }
+}
+
+fn address_family(afi: u32, safi: u32) -> Result<bgp::AddressFamily, String> {
+    let afi: bgp::Afi = FromPrimitive::from_u32(afi).ok_or(format!("Invalid AFI: {}", afi))?;
+    let safi: bgp::Safi = FromPrimitive::from_u32(safi).ok_or(format!("Invalid SAFI: {}", safi))?;
+    Ok(bgp::AddressFamily { afi, safi })
+}
+
+fn prefix(prefix: &Option<String>) -> Result<Option<bgp::Nlri>, String> {
+    prefix
+        .as_ref()
+        .map(|p| {
+            p.parse::<IpNet>()
+                .map(bgp::Nlri::from)
+                .map_err(|e| format!("Invalid prefix: {}", e))
+        })
+        .transpose()
+}
+
+/// Prefix looked up in the RIB, and whether it was given as an address to find the longest match
+/// of.
+fn rib_lookup(prefix: &str) -> Result<(IpNet, bool), String> {
+    match prefix.parse::<IpNet>() {
+        Ok(net) => Ok((net, false)),
+        Err(_) => prefix
+            .parse::<IpAddr>()
+            .map(|addr| (IpNet::from(addr), true))
+            .map_err(|e| format!("Invalid prefix or address: {}", e)),
     }
 }
 
+/// Routes of `rib` selected by a lookup of `net`. Without modifier this is the entry of `net`, or
+/// the longest match of an `address`. `longer` and `shorter` add the more and less specific
+/// prefixes.
+fn rib_routes(
+    rib: &rib::Rib,
+    net: IpNet,
+    address: bool,
+    longer: bool,
+    shorter: bool,
+) -> Vec<(&bgp::Nlri, &Vec<rib::RouteAttributes>)> {
+    let mut routes = vec![];
+    if shorter {
+        routes.extend(rib.less_specifics(&net));
+    }
+    if longer {
+        let exact = |n: &bgp::Nlri| n.net.trunc() == net.trunc();
+        routes.extend(
+            rib.more_specifics(&net)
+                .filter(|(n, _)| !shorter || !exact(n)),
+        );
+    }
+    if !shorter && !longer {
+        let route = if address {
+            rib.longest_match(&net)
+        } else {
+            rib.get_key_value(&bgp::Nlri::from(net))
+        };
+        routes.extend(route);
+    }
+    routes
+}
+
+fn memory_entry(
+    table: &str,
+    af: &bgp::AddressFamily,
+    peer: Option<IpAddr>,
+    rib: &rib::Rib,
+) -> MemoryEntry {
+    let memory = rib::memory(rib);
+    MemoryEntry {
+        table: table.to_string(),
+        afi: af.afi as u32,
+        safi: af.safi as u32,
+        peer: peer.map(|ip| ip.to_string()),
+        prefixes: memory.prefixes as u64,
+        paths: memory.paths as u64,
+        attribute_sets: memory.attribute_sets as u64,
+        bytes: memory.bytes as u64,
+    }
+}
+
+fn path_entry(a: &rib::RouteAttributes, igp_metric: Option<u32>, best: bool) -> PathEntry {
+    PathEntry {
+        next_hop: a.attrs.next_hop.to_string(),
+        peer: a.peer_ip.to_string(),
+        peer_rid: a.peer_rid,
+        as_path: a.attrs.as_path.aspath_string(),
+        origin: format!("{:?}", a.attrs.origin),
+        local_pref: a.attrs.local_pref,
+        med: a.attrs.multi_exit_disc,
+        weight: a.weight,
+        igp_metric,
+        suppressed: a.suppressed,
+        best,
+        multipath: a.multipath,
+        originator_id: a
+            .attrs
+            .originator_id
+            .map(|id| Ipv4Addr::from(id).to_string()),
+        cluster_list: a
+            .attrs
+            .cluster_list
+            .iter()
+            .map(|&id| Ipv4Addr::from(id).to_string())
+            .collect(),
+    }
+}
+
+fn idle_hold(n: &neighbor::NeighborStatus) -> (Option<u32>, Option<u32>) {
+    if n.peer_damping.is_none() {
+        return (None, None);
+    }
+    let now = Instant::now();
+    let timer = n
+        .idle_hold_until
+        .map(|until| until.saturating_duration_since(now).as_secs() as u32);
+    (Some(n.current_idle_hold_time(now) as u32), timer)
+}
+
+fn neighbor_entry(n: &neighbor::NeighborCell) -> NeighborEntry {
+    let (ip, bfd_state) = (n.remote_ip, n.bfd_state());
+    let n = n.status();
+    let (idle_hold_time, idle_hold_timer) = idle_hold(&n);
+    NeighborEntry {
+        ip: ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string()),
+        port: n.remote_port.unwrap_or(179) as u32,
+        asn: n.remote_asn.unwrap_or(0) as u32,
+        routerid: n.remote_rid.unwrap_or(0),
+        state: format!("{:?}", n.state),
+        bfd_state: bfd_state.map(|s| format!("{:?}", s)),
+        idle_hold_time,
+        idle_hold_timer,
+    }
+}
+
+/// Adj-RIBs-In of a neighbor, or `None` when its session does not answer in time.
+pub(crate) async fn adj_ribs_in(
+    n: &neighbor::NeighborCell,
+    timeout: Duration,
+) -> Option<neighbor::AdjRibsIn> {
+    let (reply, rx) = oneshot::channel();
+    if !n.status().send(neighbor::Event::AdjRibIn(reply)) {
+        return None;
+    }
+    tokio::time::timeout(timeout, rx).await.ok()?.ok()
+}
+
 #[tonic::async_trait]
 impl Config for GrpcServer {
     async fn get_neighbor_config(
@@ -36,53 +212,94 @@ impl Config for GrpcServer {
     ) -> Result<Response<NeighborReply>, Status> {
         log::debug!("Got a neighbor request: {:?}", request);
 
-        let mut entries = vec![];
-        let neighbors;
-        {
-            let s = self.speaker.lock().await;
-            neighbors = s.neighbors.clone();
+        let ip = request
+            .into_inner()
+            .ip
+            .map(|ip| ip.parse::<IpAddr>())
+            .transpose()
+            .map_err(|e| Status::invalid_argument(format!("Invalid IP: {}", e)))?;
+        let entries = self
+            .speaker
+            .neighbors
+            .iter()
+            .filter(|n| ip.is_none() || n.remote_ip == ip)
+            .map(|n| neighbor_entry(n))
+            .collect();
+
+        let reply = ubgp::NeighborReply { neighbors: entries };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn clear_damping(
+        &self,
+        request: Request<DampingRequest>,
+    ) -> Result<Response<ClearDampingReply>, Status> {
+        log::debug!("Got a clear damping request: {:?}", request);
+
+        let request = request.into_inner();
+        let af = address_family(request.afi, request.safi).map_err(Status::invalid_argument)?;
+        let nlri = prefix(&request.prefix).map_err(Status::invalid_argument)?;
+
+        let ribtx =
+            self.speaker.ribtx.get(&af).ok_or_else(|| {
+                Status::not_found(format!("Address family {:?} is not enabled", af))
+            })?;
+        ribtx
+            .send(RibEvent::ClearDamping(nlri))
+            .await
+            .map_err(|e| Status::internal(format!("Failed to clear damping: {}", e)))?;
+
+        Ok(Response::new(ClearDampingReply {}))
+    }
+
+    async fn clear_max_prefix(
+        &self,
+        request: Request<ClearMaxPrefixRequest>,
+    ) -> Result<Response<ClearMaxPrefixReply>, Status> {
+        log::debug!(
+            "Got a request to clear a maximum-prefix shutdown: {:?}",
+            request
+        );
+
+        let n = self
+            .neighbor(&request.get_ref().ip)
+            .map_err(Status::invalid_argument)?;
+        let (reply, rx) = oneshot::channel();
+        if !n.status().send(neighbor::Event::ClearMaxPrefix(reply)) || rx.await != Ok(true) {
+            return Err(Status::failed_precondition(
+                "The neighbor is not shut down by its maximum-prefix limit",
+            ));
         }
+        Ok(Response::new(ClearMaxPrefixReply {}))
+    }
 
-        match request.into_inner().ip {
-            None => {
-                for n in neighbors {
-                    let n = n.lock().await;
-                    let entry = NeighborEntry {
-                        ip: n
-                            .remote_ip
-                            .map(|ip| ip.to_string())
-                            .unwrap_or_else(|| "unknown".to_string()),
-                        port: n.remote_port.unwrap_or(179) as u32,
-                        asn: n.remote_asn.unwrap_or(0) as u32,
-                        routerid: n.remote_rid.unwrap_or(0),
-                        state: format!("{:?}", n.attributes.state),
-                    };
-                    entries.push(entry);
-                }
+    async fn soft_reconfigure_inbound(
+        &self,
+        request: Request<SoftReconfigureRequest>,
+    ) -> Result<Response<SoftReconfigureReply>, Status> {
+        log::debug!("Got a soft reconfiguration request: {:?}", request);
+
+        let n = self
+            .neighbor(&request.get_ref().ip)
+            .map_err(Status::invalid_argument)?;
+        let (reply, rx) = oneshot::channel();
+        if !n
+            .status()
+            .send(neighbor::Event::SoftReconfigureInbound(reply))
+        {
+            return Err(Status::unavailable("The neighbor session is not up"));
+        }
+        match rx.await {
+            Ok(Ok(())) => Ok(Response::new(SoftReconfigureReply {})),
+            Ok(Err(e @ neighbor::SoftReconfigureError::Disabled)) => {
+                Err(Status::failed_precondition(e.to_string()))
             }
-            Some(ip) => {
-                let ip: IpAddr = ip
-                    .parse()
-                    .map_err(|e| Status::invalid_argument(format!("Invalid IP: {}", e)))?;
-                for n in neighbors {
-                    let n = n.lock().await;
-                    if Some(ip) == n.remote_ip {
-                        let entry = NeighborEntry {
-                            ip: n.remote_ip.unwrap().to_string(),
-                            port: n.remote_port.unwrap_or(179) as u32,
-                            asn: n.remote_asn.unwrap_or(0) as u32,
-                            routerid: n.remote_rid.unwrap_or(0),
-                            state: format!("{:?}", n.attributes.state),
-                        };
-                        entries.push(entry);
-                    }
-                }
+            Ok(Err(e @ neighbor::SoftReconfigureError::RoutesChanged)) => {
+                Err(Status::aborted(e.to_string()))
             }
+            Err(_) => Err(Status::unavailable("The neighbor session went down")),
         }
-
-        let reply = ubgp::NeighborReply { neighbors: entries };
-
-        Ok(Response::new(reply))
     }
 }
 
@@ -102,21 +319,58 @@ impl State for GrpcServer {
 
         let af = bgp::AddressFamily { afi, safi };
 
-        let rib;
+        let aspath_regex = request
+            .get_ref()
+            .aspath_regex
+            .as_deref()
+            .map(policy::AsPathRegex::new)
+            .transpose()
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+
+        let lookup = request
+            .get_ref()
+            .prefix
+            .as_deref()
+            .map(rib_lookup)
+            .transpose()
+            .map_err(Status::invalid_argument)?;
+        let (longer, shorter) = (request.get_ref().longer, request.get_ref().shorter);
+
+        let config = &self.speaker.best_path;
+        if let (Some(ribtx), Some(next_hops)) =
+            (self.speaker.ribtx.get(&af), self.speaker.next_hops.get(&af))
         {
-            let s = self.speaker.lock().await;
-            rib = s.rib.get(&af);
-            match rib {
-                None => {}
-                Some(rib) => {
-                    let rib = rib.lock().await;
-                    for (n, _a) in rib.iter() {
-                        let n: IpNet = n.into();
-                        let nlri = n.to_string();
-                        let entry = RibEntry { nlri };
-                        entries.push(entry);
+            let rib = speaker::rib_snapshot(ribtx)
+                .await
+                .map_err(|e| Status::internal(format!("{:#}", e)))?;
+            let next_hops = next_hops.borrow().clone();
+            let routes = match lookup {
+                Some((net, address)) => rib_routes(&rib, net, address, longer, shorter),
+                None => rib.iter().collect(),
+            };
+            for (n, a) in routes {
+                if let Some(re) = &aspath_regex {
+                    if !a.iter().any(|a| re.is_match(&a.attrs.as_path)) {
+                        continue;
                     }
                 }
+                let selection = rib::select(a, config, &next_hops);
+                let best_index = selection.best.map(|(i, _)| i);
+                let mut paths: Vec<PathEntry> = a
+                    .iter()
+                    .enumerate()
+                    .map(|(i, a)| path_entry(a, selection.igp_metrics[i], Some(i) == best_index))
+                    .collect();
+                paths.sort_by_key(|p| !p.best);
+                let n: IpNet = n.into();
+                entries.push(RibEntry {
+                    nlri: n.to_string(),
+                    paths,
+                    decided_by: selection
+                        .best
+                        .and_then(|(_, step)| step)
+                        .map(|s| format!("{:?}", s)),
+                });
             }
         }
 
@@ -124,14 +378,120 @@ impl State for GrpcServer {
 
         Ok(Response::new(reply))
     }
+
+    async fn get_damping(
+        &self,
+        request: Request<DampingRequest>,
+    ) -> Result<Response<DampingReply>, Status> {
+        log::debug!("Got a damping request: {:?}", request);
+
+        let request = request.into_inner();
+        let af = address_family(request.afi, request.safi).map_err(Status::invalid_argument)?;
+        let nlri = prefix(&request.prefix).map_err(Status::invalid_argument)?;
+
+        let mut entries = vec![];
+        if let Some(ribtx) = self.speaker.ribtx.get(&af) {
+            let mut damping = speaker::damping_snapshot(ribtx)
+                .await
+                .map_err(|e| Status::internal(format!("{:#}", e)))?;
+            for (n, peer, info) in damping.entries(Instant::now()) {
+                if nlri.is_some_and(|nlri| nlri != n) {
+                    continue;
+                }
+                let net: IpNet = n.into();
+                entries.push(DampingEntry {
+                    nlri: net.to_string(),
+                    peer,
+                    penalty: info.penalty.round() as u32,
+                    flaps: info.flaps,
+                    suppressed: info.suppressed,
+                    reuse_in: damping.reuse_in(&info).map(|d| d.as_secs() as u32),
+                });
+            }
+        }
+
+        Ok(Response::new(DampingReply { entries }))
+    }
+
+    async fn get_memory(
+        &self,
+        request: Request<MemoryRequest>,
+    ) -> Result<Response<MemoryReply>, Status> {
+        log::debug!("Got a memory request: {:?}", request);
+
+        let mut tables = vec![];
+        for (af, ribtx) in &self.speaker.ribtx {
+            let rib = speaker::rib_snapshot(ribtx)
+                .await
+                .map_err(|e| Status::internal(format!("{:#}", e)))?;
+            tables.push(memory_entry("Loc-RIB", af, None, &rib));
+        }
+        for n in &self.speaker.neighbors {
+            let Some(adjrib) = adj_ribs_in(n, self.adj_rib_timeout).await else {
+                continue;
+            };
+            for (af, rib) in &adjrib.accepted {
+                tables.push(memory_entry("Adj-RIB-In", af, n.remote_ip, rib));
+            }
+            for (af, rib) in adjrib.received.iter().flatten() {
+                tables.push(memory_entry("Adj-RIB-In pre-policy", af, n.remote_ip, rib));
+            }
+        }
+
+        let (attribute_sets, attribute_bytes) = rib::interned();
+        Ok(Response::new(MemoryReply {
+            tables,
+            attribute_sets: attribute_sets as u64,
+            attribute_bytes: attribute_bytes as u64,
+        }))
+    }
+
+    async fn get_adj_rib_in(
+        &self,
+        request: Request<AdjRibInRequest>,
+    ) -> Result<Response<RibReply>, Status> {
+        log::debug!("Got an Adj-RIB-In request: {:?}", request);
+
+        let request = request.into_inner();
+        let af = address_family(request.afi, request.safi).map_err(Status::invalid_argument)?;
+        let n = self
+            .neighbor(&request.ip)
+            .map_err(Status::invalid_argument)?;
+        let adjrib = adj_ribs_in(n, self.adj_rib_timeout)
+            .await
+            .ok_or_else(|| Status::unavailable("The neighbor session is not up"))?;
+        let tables = if request.pre_policy {
+            adjrib.received.ok_or_else(|| {
+                Status::failed_precondition(
+                    "Soft-reconfiguration inbound is not enabled for the neighbor",
+                )
+            })?
+        } else {
+            adjrib.accepted
+        };
+
+        let mut entries = vec![];
+        if let Some(rib) = tables.get(&af) {
+            for (n, a) in rib.iter() {
+                let n: IpNet = n.into();
+                entries.push(RibEntry {
+                    nlri: n.to_string(),
+                    paths: a.iter().map(|a| path_entry(a, None, false)).collect(),
+                    decided_by: None,
+                });
+            }
+        }
+
+        Ok(Response::new(RibReply { nlris: entries }))
+    }
 }
 
-pub async fn grpc_server(speaker: Arc<Mutex<speaker::BGPSpeaker>>) {
+pub async fn grpc_server(speaker: Arc<speaker::BGPSpeaker>, config: config::ApiConfig) {
     let addr = "127.0.0.1:50051"
         .parse()
         .expect("Invalid gRPC server address");
-    let config_server = GrpcServer::new(speaker.clone());
-    let state_server = GrpcServer::new(speaker);
+    let config_server = GrpcServer::new(speaker.clone(), &config);
+    let state_server = GrpcServer::new(speaker, &config);
 
     if let Err(e) = Server::builder()
         .add_service(ConfigServer::new(config_server))
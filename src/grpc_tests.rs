@@ -0,0 +1,56 @@
+use crate::grpc::adj_ribs_in;
+use crate::neighbor::{BGPNeighbor, BGPState, Event, NeighborCell};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+fn neighbor_cell() -> (NeighborCell, mpsc::Receiver<Event>) {
+    let mut n = BGPNeighbor::new(
+        None,
+        None,
+        65000,
+        1,
+        Some("192.0.2.1".parse().unwrap()),
+        Some(179),
+        Some(65001),
+        3,
+        5,
+        BGPState::Established,
+        None,
+        HashMap::new(),
+        None,
+        false,
+    );
+    let (tx, rx) = mpsc::channel(8);
+    n.tx = Some(tx);
+    (NeighborCell::new(n), rx)
+}
+
+#[tokio::test]
+async fn test_adj_ribs_in_timeout() {
+    let (cell, _rx) = neighbor_cell();
+
+    // The session never answers
+    let start = Instant::now();
+    assert!(adj_ribs_in(&cell, Duration::from_millis(50))
+        .await
+        .is_none());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn test_adj_ribs_in_answered() {
+    let (cell, mut rx) = neighbor_cell();
+    tokio::spawn(async move {
+        while let Some(e) = rx.recv().await {
+            if let Event::AdjRibIn(reply) = e {
+                let _ = reply.send(Default::default());
+            }
+        }
+    });
+
+    let adjrib = adj_ribs_in(&cell, Duration::from_secs(5)).await.unwrap();
+    assert!(adjrib.accepted.is_empty());
+    assert!(adjrib.received.is_none());
+}
@@ -0,0 +1,166 @@
+use std::net::IpAddr;
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+use tokio::sync::{mpsc, watch, Mutex, MutexGuard};
+
+use super::session::BGPNeighbor;
+use super::types::{BGPState, Event};
+use crate::bfd;
+use crate::config;
+
+/// Neighbor state read by the speaker, the RIB managers and the API without locking the
+/// neighbor, which its session may hold while busy.
+#[derive(Debug, Clone, Default)]
+pub struct NeighborStatus {
+    pub state: BGPState,
+    pub remote_port: Option<u16>,
+    pub remote_asn: Option<u16>,
+    pub remote_rid: Option<u32>,
+    pub hold_time: u16,
+    pub connect_retry_counter: usize,
+    pub max_retry_count: Option<u16>,
+    pub keepalive_received_at: Option<Instant>,
+    pub bfd: bool,
+    pub max_prefix_shutdown: bool,
+    pub peer_damping: Option<config::PeerDamping>,
+    /// Idle hold time doubled by the last session failure, before any halving.
+    pub idle_hold_time: usize,
+    pub established_at: Option<Instant>,
+    pub idle_hold_until: Option<Instant>,
+    /// Mailbox of the session, while there is one.
+    pub tx: Option<mpsc::Sender<Event>>,
+}
+
+impl NeighborStatus {
+    fn new(n: &BGPNeighbor) -> Self {
+        NeighborStatus {
+            state: n.attributes.state,
+            remote_port: n.remote_port,
+            remote_asn: n.remote_asn,
+            remote_rid: n.remote_rid,
+            hold_time: n.attributes.hold_time,
+            connect_retry_counter: n.attributes.connect_retry_counter,
+            max_retry_count: n.max_retry_count,
+            keepalive_received_at: n.keepalive_received_at,
+            bfd: n.bfd,
+            max_prefix_shutdown: n.max_prefix_shutdown,
+            peer_damping: n.peer_damping.clone(),
+            idle_hold_time: n.attributes.idle_hold_time,
+            established_at: n.established_at,
+            idle_hold_until: n.idle_hold_until,
+            tx: n.tx.clone(),
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state == BGPState::Established
+    }
+
+    /// Whether the IdleHoldTimer is running, during which no connection is accepted.
+    pub fn is_idle_hold(&self, now: Instant) -> bool {
+        self.idle_hold_until.is_some_and(|until| until > now)
+    }
+
+    /// Idle hold time in seconds, see [`BGPNeighbor::idle_hold_time`].
+    pub fn current_idle_hold_time(&self, now: Instant) -> usize {
+        match &self.peer_damping {
+            Some(pd) => {
+                super::session::idle_hold_time(pd, self.idle_hold_time, self.established_at, now)
+            }
+            None => 0,
+        }
+    }
+
+    /// Whether connection attempts are over, having reached the maximum retry count.
+    pub fn connect_retries_exhausted(&self) -> bool {
+        self.max_retry_count
+            .is_some_and(|max| self.connect_retry_counter >= max as usize)
+    }
+
+    /// Hands `event` to the session, if there is one and its mailbox is not full.
+    pub fn send(&self, event: Event) -> bool {
+        self.tx
+            .as_ref()
+            .is_some_and(|tx| tx.try_send(event).is_ok())
+    }
+}
+
+/// A configured neighbor, whose state is shared by the tasks running its session. Every change
+/// made through [`NeighborCell::lock`] is published as a [`NeighborStatus`] snapshot when the
+/// lock is released. The API, BFD and the timers read the snapshot and hand events to the
+/// session rather than locking the neighbor.
+#[derive(Debug)]
+pub struct NeighborCell {
+    pub remote_ip: Option<IpAddr>,
+    neighbor: Mutex<BGPNeighbor>,
+    status: watch::Sender<NeighborStatus>,
+    rib_order: Mutex<()>,
+    /// State of the BFD session with the neighbor, owned by the BFD manager.
+    bfd_state: watch::Sender<Option<bfd::State>>,
+}
+
+impl NeighborCell {
+    pub fn new(neighbor: BGPNeighbor) -> Self {
+        let (status, _) = watch::channel(NeighborStatus::new(&neighbor));
+        NeighborCell {
+            remote_ip: neighbor.remote_ip,
+            neighbor: Mutex::new(neighbor),
+            status,
+            rib_order: Mutex::new(()),
+            bfd_state: watch::channel(None).0,
+        }
+    }
+
+    pub async fn lock(&self) -> NeighborGuard<'_> {
+        NeighborGuard {
+            neighbor: self.neighbor.lock().await,
+            status: &self.status,
+        }
+    }
+
+    /// Held from changing the Adj-RIBs-In until the changes are handed to the RIB, so that they
+    /// reach it in order without the neighbor being locked while the RIB catches up. Taken
+    /// before [`NeighborCell::lock`], never while holding it.
+    pub async fn lock_rib(&self) -> MutexGuard<'_, ()> {
+        self.rib_order.lock().await
+    }
+
+    pub fn set_bfd_state(&self, state: bfd::State) {
+        self.bfd_state.send_replace(Some(state));
+    }
+
+    pub fn bfd_state(&self) -> Option<bfd::State> {
+        *self.bfd_state.borrow()
+    }
+
+    /// Status as of the last release of the lock.
+    pub fn status(&self) -> NeighborStatus {
+        self.status.borrow().clone()
+    }
+}
+
+pub struct NeighborGuard<'a> {
+    neighbor: MutexGuard<'a, BGPNeighbor>,
+    status: &'a watch::Sender<NeighborStatus>,
+}
+
+impl Deref for NeighborGuard<'_> {
+    type Target = BGPNeighbor;
+
+    fn deref(&self) -> &BGPNeighbor {
+        &self.neighbor
+    }
+}
+
+impl DerefMut for NeighborGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BGPNeighbor {
+        &mut self.neighbor
+    }
+}
+
+impl Drop for NeighborGuard<'_> {
+    fn drop(&mut self) {
+        self.status
+            .send_replace(NeighborStatus::new(&self.neighbor));
+    }
+}
@@ -1,13 +1,9 @@
 use super::capabilities::Capabilities;
-use super::session::BGPNeighbor;
-use crate::bgp::{self, Message, Nlri};
-use crate::rib::RouteAttributes;
+use crate::bgp;
+use crate::error::BgpError;
 use anyhow::{anyhow, Context, Result};
 use futures::SinkExt;
-use std::collections::HashMap;
-use std::sync::Arc;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
 use tokio_util::codec::Framed;
 
@@ -34,89 +30,46 @@ pub async fn send_open(
     Ok(())
 }
 
-pub async fn send_update(
+pub async fn send_updates(
     server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
-    neighbor: Arc<Mutex<BGPNeighbor>>,
-    nlris: Vec<(Nlri, Option<RouteAttributes>)>,
+    messages: &[Vec<u8>],
 ) -> Result<()> {
-    let mut wd: Vec<Nlri> = vec![];
-    let mut updates: HashMap<RouteAttributes, Vec<Nlri>> = HashMap::new();
-    let router_id = {
-        let n = neighbor.lock().await;
-        n.remote_rid
-            .ok_or_else(|| anyhow!("Remote router ID not set"))?
-    };
-
-    for (n, a) in nlris {
-        match a {
-            None => wd.push(n),
-            Some(route_attributes) => {
-                if !route_attributes.is_from_neighbor(router_id) {
-                    updates.entry(route_attributes.clone()).or_default().push(n);
-                }
-            }
-        }
-    }
-
-    if updates.is_empty() && wd.is_empty() {
-        return Ok(());
-    }
-
-    let mut nlris = vec![];
-    let mut attributes = vec![];
-
-    for (mut ra, mut routes) in updates {
-        let should_send = {
-            let neighbor = neighbor.lock().await;
-            let local_asn = neighbor.local_asn;
-            let local_ip = neighbor
-                .local_ip
-                .ok_or_else(|| anyhow!("Local IP not set"))?;
-            let remote_asn = neighbor
-                .remote_asn
-                .ok_or_else(|| anyhow!("Remote ASN not set"))?;
-
-            if local_asn != remote_asn {
-                ra.next_hop = local_ip;
-                ra.prepend(local_asn, 1);
-                true
-            } else {
-                !ra.is_from_ibgp()
-            }
-        };
-
-        if !should_send {
-            continue;
-        }
-
-        let mut pa = Into::<Vec<bgp::PathAttribute>>::into(ra)
-            .into_iter()
-            .filter(|x| x.is_transitive())
-            .collect::<Vec<bgp::PathAttribute>>();
-        attributes.append(&mut pa);
-        nlris.append(&mut routes);
+    for message in messages {
+        server
+            .feed(message.clone())
+            .await
+            .context("Failed to send UPDATE message")?;
     }
+    server
+        .flush()
+        .await
+        .context("Failed to send UPDATE message")?;
+    Ok(())
+}
 
-    let body = bgp::BGPUpdateMessageBuilder::default()
-        .withdrawn_routes(wd.clone())
-        .path_attributes(attributes)
-        .nlri(nlris)
-        .build()
-        .map_err(|e| anyhow!("Failed to build UPDATE message: {}", e))?;
+pub async fn send_notification(
+    server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
+    code: bgp::ErrorCode,
+    subcode: u8,
+    data: Vec<u8>,
+) -> Result<()> {
+    let mut body = bgp::BGPNotificationMessage::new(code, subcode as usize)
+        .map_err(|e| anyhow!("Failed to create NOTIFICATION message: {}", e))?;
+    body.data = data;
 
-    log::info!("Sending UPDATE {:?}", body);
+    log::info!("Sending NOTIFICATION {:?}", body);
 
-    let message: Vec<u8> =
-        Message::new(bgp::MessageType::Update, bgp::BGPMessageBody::Update(body))
-            .context("Failed to create UPDATE message")?
-            .into();
+    let message: Vec<u8> = bgp::Message::new(
+        bgp::MessageType::Notification,
+        bgp::BGPMessageBody::Notification(body),
+    )
+    .context("Failed to create NOTIFICATION message")?
+    .into();
 
     server
         .send(message)
         .await
-        .context("Failed to send UPDATE message")?;
-
-    Ok(())
+        .context("Failed to send NOTIFICATION message")
 }
 
 pub async fn send_keepalive(
@@ -140,6 +93,8 @@ pub async fn send_keepalive(
         .context("Failed to send KEEPALIVE message")
 }
 
+/// Reads the next message. A message failing validation is answered with the matching
+/// NOTIFICATION before the error is returned.
 pub async fn read_message(
     server: &mut Framed<TcpStream, bgp::BGPMessageCodec>,
 ) -> Option<Result<bgp::Message, std::io::Error>> {
@@ -148,6 +103,12 @@ pub async fn read_message(
             Ok(message) => Some(Ok(message)),
             Err(e) => {
                 log::error!("Failed to parse BGP message: {}", e);
+                if let BgpError::Validation(v) = &e {
+                    let (code, subcode) = v.to_notification_codes();
+                    if let Err(e) = send_notification(server, code, subcode, vec![]).await {
+                        log::error!("{}", e);
+                    }
+                }
                 Some(Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
                     format!("BGP message parse error: {}", e),
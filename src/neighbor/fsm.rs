@@ -1,6 +1,6 @@
+use super::cell::NeighborCell;
 use super::connection;
 use super::message_handler;
-use super::session::BGPNeighbor;
 use super::timers;
 use super::types::{BGPState, Event};
 use crate::bgp;
@@ -10,9 +10,12 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
 use tokio_util::codec::Framed;
 
+/// Events a session mailbox holds. UPDATEs take most of it, a session falling this far behind
+/// is reset rather than buffering more of them.
+pub(super) const MAILBOX_SIZE: usize = 100;
+
 pub(super) fn calculate_retry_delay(
     base_retry_time: u16,
     retry_counter: usize,
@@ -36,7 +39,43 @@ pub(super) fn calculate_retry_delay(
     }
 }
 
-pub async fn init_peer(n: Arc<Mutex<BGPNeighbor>>) {
+/// Tears the session down with a Cease (Maximum Number of Prefixes Reached) NOTIFICATION. The
+/// neighbor is left to the FSM in Idle, which restarts it once the restart interval is over.
+async fn max_prefix_shutdown(
+    af: bgp::AddressFamily,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
+    server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
+) -> Result<()> {
+    let limit = {
+        let mut n = nb.lock().await;
+        let mp = n
+            .max_prefix
+            .get(&af)
+            .ok_or_else(|| anyhow!("No maximum-prefix configured for {:?}", af))?;
+        let (limit, restart_interval) = (mp.limit, mp.restart_interval);
+        n.max_prefix_shut_down(std::time::Instant::now(), restart_interval);
+        limit
+    };
+
+    // RFC 4486: AFI, SAFI and the prefix upper bound
+    let mut data = (af.afi as u16).to_be_bytes().to_vec();
+    data.push(af.safi as u8);
+    data.extend_from_slice(&limit.to_be_bytes());
+    connection::send_notification(
+        server,
+        bgp::ErrorCode::Cease,
+        bgp::CeaseSubCode::MaximumNumberOfPrefixesReached as u8,
+        data,
+    )
+    .await?;
+
+    let reason = format!("maximum-prefix limit {} reached for {:?}", limit, af);
+    message_handler::close_session(s, nb, &reason).await;
+    Ok(())
+}
+
+pub async fn init_peer(n: Arc<NeighborCell>) {
     {
         let mut n = n.lock().await;
         n.attributes.connect_retry_counter = 0;
@@ -45,16 +84,7 @@ pub async fn init_peer(n: Arc<Mutex<BGPNeighbor>>) {
     log::debug!("FSM init_peer: Idle to Active");
 }
 
-pub async fn connect(
-    speaker: Arc<Mutex<speaker::BGPSpeaker>>,
-    neighbor: Arc<Mutex<BGPNeighbor>>,
-) -> Result<()> {
-    {
-        let mut n = neighbor.lock().await;
-        let sp = speaker.lock().await;
-        n.ribtx = sp.ribtx.clone();
-        log::debug!("Neighbor ribtx set: {:?}", n.ribtx.keys());
-    }
+pub async fn connect(speaker: Arc<speaker::BGPSpeaker>, neighbor: Arc<NeighborCell>) -> Result<()> {
     let (remote_addr, _local_ip, _local_asn) = {
         let n = neighbor.lock().await;
         let remote_ip = n
@@ -119,13 +149,13 @@ pub async fn connect(
 }
 
 pub async fn fsm_tcp(
-    neighbor: Arc<Mutex<BGPNeighbor>>,
+    neighbor: Arc<NeighborCell>,
     stream: TcpStream,
-    speaker: Arc<Mutex<speaker::BGPSpeaker>>,
+    speaker: Arc<speaker::BGPSpeaker>,
 ) -> Result<()> {
     log::debug!("starting fsm_tcp for neighbor");
 
-    let (tx, mut rx) = mpsc::channel::<Event>(100);
+    let (tx, mut rx) = mpsc::channel::<Event>(MAILBOX_SIZE);
     let mut server = Framed::new(stream, bgp::BGPMessageCodec);
 
     let state = {
@@ -158,7 +188,7 @@ pub async fn fsm_tcp(
     let na = neighbor.clone();
     let (sender, receiver) = tokio::sync::oneshot::channel();
     let hold_task = tokio::spawn(async {
-        if let Err(e) = timers::timer_hold(na, receiver).await {
+        if let Err(e) = timers::timer_hold(na, tx, receiver).await {
             log::error!("Hold timer error: {}", e);
         }
     });
@@ -166,20 +196,75 @@ pub async fn fsm_tcp(
     let result = fsm_loop(&mut rx, &mut server, speaker.clone(), neighbor.clone()).await;
     let _ = sender.send(());
     let _ = tokio::join!(hold_task);
-    {
+    let idle = {
         let mut n = neighbor.lock().await;
         n.attributes.state = BGPState::Idle;
         n.tx = None;
+        // Already recorded as a failure by the maximum-prefix shutdown
+        let idle_hold = if n.max_prefix_shutdown {
+            None
+        } else {
+            n.session_failed(std::time::Instant::now())
+        };
+        (n.max_prefix_shutdown || idle_hold.is_some()).then(|| {
+            // The automatic start is left to the FSM in Idle, which runs the IdleHoldTimer
+            let (tx, rx) = mpsc::channel(MAILBOX_SIZE);
+            if let Some(idle_hold) = idle_hold {
+                log::info!("Damping peer oscillations: idle hold for {:?}", idle_hold);
+                let _ = tx.try_send(Event::AutomaticStartWithDampPeerOscillations);
+            }
+            n.tx = Some(tx);
+            rx
+        })
+    };
+    if let Some(mut rx) = idle {
+        tokio::spawn(async move {
+            if let Err(e) = fsm_idle(&mut rx, speaker, neighbor).await {
+                log::error!("FSM error: {}", e);
+            }
+        });
     }
 
     result
 }
 
+/// Runs the FSM of a neighbor left in Idle, delivering the IdleHoldTimer as an event so that a
+/// ManualStop or AutomaticStop can cancel it. Ends once the session is started again, the timer
+/// is cancelled or a new session takes over the mailbox. A neighbor shut down by its
+/// maximum-prefix limit stays here until the shutdown is lifted.
+pub async fn fsm_idle(
+    rx: &mut mpsc::Receiver<Event>,
+    speaker: Arc<speaker::BGPSpeaker>,
+    neighbor: Arc<NeighborCell>,
+) -> Result<()> {
+    loop {
+        let idle_hold_until = neighbor.status().idle_hold_until;
+        let e = tokio::select! {
+            e = rx.recv() => match e {
+                Some(e) => e,
+                None => return Ok(()),
+            },
+            _ = timers::timer_idle_hold(idle_hold_until) => Event::IdleHoldTimerExpires,
+        };
+        process_event(e, speaker.clone(), neighbor.clone(), None).await?;
+        let status = neighbor.status();
+        if status.idle_hold_until.is_none() && !status.max_prefix_shutdown {
+            break;
+        }
+    }
+    rx.close();
+    let mut n = neighbor.lock().await;
+    if n.tx.as_ref().is_some_and(|tx| tx.is_closed()) {
+        n.tx = None;
+    }
+    Ok(())
+}
+
 async fn fsm_loop(
     rx: &mut mpsc::Receiver<Event>,
     server: &mut Framed<TcpStream, bgp::BGPMessageCodec>,
-    speaker: Arc<Mutex<speaker::BGPSpeaker>>,
-    neighbor: Arc<Mutex<BGPNeighbor>>,
+    speaker: Arc<speaker::BGPSpeaker>,
+    neighbor: Arc<NeighborCell>,
 ) -> Result<()> {
     loop {
         tokio::select! {
@@ -188,6 +273,23 @@ async fn fsm_loop(
                     log::info!("TCP connection termination requested");
                     return Ok(());
                 }
+                if let Event::AdjRibIn(reply) = e {
+                    let _ = reply.send(neighbor.lock().await.adj_ribs_in());
+                    continue;
+                }
+                // Shut down neighbors are left to the FSM in Idle
+                if let Event::ClearMaxPrefix(reply) = e {
+                    let _ = reply.send(false);
+                    continue;
+                }
+                // Handing the whole table to the RIB would hold up the keepalives and the socket.
+                if let Event::SoftReconfigureInbound(reply) = e {
+                    let neighbor = neighbor.clone();
+                    tokio::spawn(async move {
+                        let _ = reply.send(message_handler::soft_reconfigure_inbound(&neighbor).await);
+                    });
+                    continue;
+                }
                 process_event(e, speaker.clone(), neighbor.clone(), Some(server)).await?;
             }
             Some(m) = connection::read_message(server) => {
@@ -219,8 +321,8 @@ async fn fsm_loop(
 
 pub async fn process_event(
     e: Event,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
     server: Option<&mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>>,
 ) -> Result<()> {
     let state = {
@@ -248,14 +350,14 @@ pub async fn process_event(
             }
             BGPState::Established => {
                 log::debug!("FSM ESTABLISHED: received {:?}", e);
-                process_event_established(e, nb, server).await
+                process_event_established(e, s, nb, server).await
             }
             _ => Ok(()),
         },
         None => {
             if let BGPState::Idle = state {
                 log::debug!("FSM IDLE: received {:?}", e);
-                process_event_idle(e, nb).await
+                process_event_idle(e, s, nb).await
             } else {
                 Ok(())
             }
@@ -263,7 +365,11 @@ pub async fn process_event(
     }
 }
 
-pub async fn process_event_idle(e: Event, nb: Arc<Mutex<BGPNeighbor>>) -> Result<()> {
+pub async fn process_event_idle(
+    e: Event,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
+) -> Result<()> {
     match e {
         Event::ManualStartWithPassiveTcpEstablishment => {
             log::debug!("FSM IDLE: {:?} to be implemented", e);
@@ -277,6 +383,42 @@ pub async fn process_event_idle(e: Event, nb: Arc<Mutex<BGPNeighbor>>) -> Result
         Event::AutomaticStart => {
             init_peer(nb).await;
         }
+        Event::AutomaticStartWithDampPeerOscillations => {
+            let n = nb.lock().await;
+            if n.max_prefix_shutdown {
+                log::debug!("FSM IDLE: shut down by maximum-prefix limit, start ignored");
+            } else if n.is_idle_hold(std::time::Instant::now()) {
+                log::debug!("FSM IDLE: IdleHoldTimer running, start deferred");
+            } else {
+                drop(n);
+                damped_start(s, nb).await;
+            }
+        }
+        Event::IdleHoldTimerExpires => {
+            if nb.lock().await.clear_max_prefix() {
+                log::info!(
+                    "Restart interval over, restarting session after maximum-prefix shutdown"
+                );
+            } else {
+                log::info!("IdleHoldTimer expired, restarting session");
+            }
+            damped_start(s, nb).await;
+        }
+        Event::ClearMaxPrefix(reply) => {
+            let cleared = nb.lock().await.clear_max_prefix();
+            let _ = reply.send(cleared);
+            if cleared {
+                log::info!("Restarting session after clearing the maximum-prefix shutdown");
+                damped_start(s, nb).await;
+            }
+        }
+        Event::ManualStop | Event::AutomaticStop => {
+            let mut n = nb.lock().await;
+            if n.idle_hold_until.take().is_some() {
+                log::info!("FSM IDLE: {:?}, IdleHoldTimer cancelled", e);
+            }
+            n.attributes.idle_hold_timer = 0;
+        }
         _ => {
             log::debug!("{:?}", e);
         }
@@ -284,10 +426,20 @@ pub async fn process_event_idle(e: Event, nb: Arc<Mutex<BGPNeighbor>>) -> Result
     Ok(())
 }
 
+/// Stops the IdleHoldTimer and connects to the neighbor again.
+async fn damped_start(s: Arc<speaker::BGPSpeaker>, nb: Arc<NeighborCell>) {
+    {
+        let mut n = nb.lock().await;
+        n.idle_hold_until = None;
+        n.attributes.idle_hold_timer = 0;
+    }
+    timers::idle_hold_restart(s, nb);
+}
+
 pub async fn process_event_connect(
     e: Event,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
     server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
 ) -> Result<()> {
     match e {
@@ -305,21 +457,11 @@ pub async fn process_event_connect(
             init_peer(nb).await;
         }
         Event::TcpConnectionValid => {
-            let asn;
-            let rid;
-            let hold;
-            let capabilities;
-            {
-                let s = s.lock().await;
-                asn = s.local_asn;
-                rid = s.router_id;
-                hold = s.hold_time;
-            }
-            {
+            let (local_asn, capabilities) = {
                 let n = nb.lock().await;
-                capabilities = n.capabilities_advertised.clone();
-            }
-            connection::send_open(server, asn, rid, hold, capabilities)
+                (n.open_asn(), n.capabilities_advertised.clone())
+            };
+            connection::send_open(server, local_asn, s.router_id, s.hold_time, capabilities)
                 .await
                 .context("Failed to send OPEN message in CONNECT state")?;
             {
@@ -337,8 +479,8 @@ pub async fn process_event_connect(
 
 pub async fn process_event_active(
     e: Event,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
     server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
 ) -> Result<()> {
     match e {
@@ -355,21 +497,11 @@ pub async fn process_event_active(
             log::debug!("FSM ACTIVE: {:?} to be implemented", e);
         }
         Event::TcpConnectionConfirmed => {
-            let asn;
-            let rid;
-            let hold;
-            let capabilities;
-            {
-                let s = s.lock().await;
-                asn = s.local_asn;
-                rid = s.router_id;
-                hold = s.hold_time;
-            }
-            {
+            let (local_asn, capabilities) = {
                 let n = nb.lock().await;
-                capabilities = n.capabilities_advertised.clone();
-            }
-            connection::send_open(server, asn, rid, hold, capabilities)
+                (n.open_asn(), n.capabilities_advertised.clone())
+            };
+            connection::send_open(server, local_asn, s.router_id, s.hold_time, capabilities)
                 .await
                 .context("Failed to send OPEN message in ACTIVE state")?;
             {
@@ -390,7 +522,7 @@ pub async fn process_event_active(
 
 pub async fn process_event_opensent(
     e: Event,
-    _nb: Arc<Mutex<BGPNeighbor>>,
+    _nb: Arc<NeighborCell>,
     _server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
 ) -> Result<()> {
     match e {
@@ -424,8 +556,8 @@ pub async fn process_event_opensent(
 
 pub async fn process_event_openconfirm(
     e: Event,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
     server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
 ) -> Result<()> {
     match e {
@@ -441,21 +573,11 @@ pub async fn process_event_openconfirm(
             log::debug!("FSM OPENCONFIRM: {:?} to be implemented", e);
         }
         Event::BGPOpen => {
-            let asn;
-            let rid;
-            let hold;
-            let capabilities;
-            {
-                let s = s.lock().await;
-                asn = s.local_asn;
-                rid = s.router_id;
-                hold = s.hold_time;
-            }
-            {
+            let (local_asn, capabilities) = {
                 let n = nb.lock().await;
-                capabilities = n.capabilities_advertised.clone();
-            }
-            connection::send_open(server, asn, rid, hold, capabilities)
+                (n.open_asn(), n.capabilities_advertised.clone())
+            };
+            connection::send_open(server, local_asn, s.router_id, s.hold_time, capabilities)
                 .await
                 .context("Failed to send OPEN message in OPENCONFIRM state")?;
         }
@@ -468,7 +590,8 @@ pub async fn process_event_openconfirm(
 
 pub async fn process_event_established(
     e: Event,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
     server: &mut Framed<tokio::net::TcpStream, bgp::BGPMessageCodec>,
 ) -> Result<()> {
     match e {
@@ -495,8 +618,27 @@ pub async fn process_event_established(
                 .await
                 .context("Failed to send keepalive in ESTABLISHED state")?;
         }
-        Event::RibUpdate(nlris) => {
-            let _ = connection::send_update(server, nb.clone(), nlris).await;
+        Event::Updates(messages) => {
+            connection::send_updates(server, &messages)
+                .await
+                .context("Failed to send UPDATE messages")?;
+        }
+        Event::MaxPrefixExceeded(af) => {
+            max_prefix_shutdown(af, s, nb, server).await?;
+        }
+        Event::BfdDown(diag) => {
+            let reason = format!("BFD session down ({:?})", diag);
+            message_handler::close_session(s, nb, &reason).await;
+        }
+        Event::UpdatesOverflow => {
+            connection::send_notification(
+                server,
+                bgp::ErrorCode::Cease,
+                bgp::CeaseSubCode::OutOfResources as u8,
+                vec![],
+            )
+            .await?;
+            message_handler::close_session(s, nb, "UPDATEs queued faster than they are sent").await;
         }
         _ => {
             log::debug!("FSM ESTABLISHED: {:?} looks like an error", e);
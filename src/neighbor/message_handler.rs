@@ -1,20 +1,21 @@
-use super::session::BGPNeighbor;
+use super::cell::NeighborCell;
+use super::session::PrefixLimit;
 use super::timers;
-use super::types::{BGPState, Event};
-use crate::bgp::{self, AddressFamily, Nlri};
+use super::types::{BGPState, Event, SoftReconfigureError};
+use crate::bgp::{self, AddressFamily, Flatten};
 use crate::rib::{RibUpdate, RouteAttributes};
 use crate::speaker::{self};
 use anyhow::{anyhow, Context, Result};
 use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc;
 
 use crate::rib;
 
 pub async fn process_message(
     m: bgp::Message,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) -> Result<()> {
     let state = {
         let nb = nb.lock().await;
@@ -51,8 +52,8 @@ pub async fn process_message(
 
 pub async fn process_message_opensent(
     m: bgp::Message,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) -> Result<()> {
     match m.body {
         bgp::BGPMessageBody::Keepalive(_body) => {
@@ -69,14 +70,12 @@ pub async fn process_message_opensent(
 
             match collision_detection(body.clone(), s).await {
                 true => {
-                    tx.send(Event::OpenCollisionDump)
-                        .await
+                    tx.try_send(Event::OpenCollisionDump)
                         .context("Failed to send OpenCollisionDump event")?;
                 }
                 false => match validate_open(body.clone(), nb.clone()).await {
                     false => {
-                        tx.send(Event::BGPOpenMsgErr)
-                            .await
+                        tx.try_send(Event::BGPOpenMsgErr)
                             .context("Failed to send BGPOpenMsgErr event")?;
                     }
                     true => {
@@ -110,8 +109,7 @@ pub async fn process_message_opensent(
                 n.tx.clone()
                     .ok_or_else(|| anyhow!("No tx channel available"))?
             };
-            tx.send(Event::NotifMsg)
-                .await
+            tx.try_send(Event::NotifMsg)
                 .context("failed to send notifmsg event")?;
             Ok(())
         }
@@ -120,8 +118,8 @@ pub async fn process_message_opensent(
 
 pub async fn process_message_active(
     m: bgp::Message,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) -> Result<()> {
     match m.body {
         bgp::BGPMessageBody::Open(body) => {
@@ -133,15 +131,13 @@ pub async fn process_message_active(
             };
             match collision_detection(body.clone(), s).await {
                 true => {
-                    tx.send(Event::OpenCollisionDump)
-                        .await
+                    tx.try_send(Event::OpenCollisionDump)
                         .context("failed to send OpenCollisionDump event")?;
                     Ok(())
                 }
                 false => match validate_open(body.clone(), nb.clone()).await {
                     false => {
-                        tx.send(Event::BGPOpenMsgErr)
-                            .await
+                        tx.try_send(Event::BGPOpenMsgErr)
                             .context("failed to send BGPOpenMsgErr event")?;
                         Ok(())
                     }
@@ -156,8 +152,7 @@ pub async fn process_message_active(
                             let _ = timers::timer_keepalive(nb, ta).await;
                         });
                         log::debug!("FSM ACTIVE: Active to OpenConfirm");
-                        tx.send(Event::BGPOpen)
-                            .await
+                        tx.try_send(Event::BGPOpen)
                             .context("failed to send BGPOpen event")?;
                         Ok(())
                     }
@@ -172,8 +167,7 @@ pub async fn process_message_active(
                     n.tx.clone()
                         .ok_or_else(|| anyhow!("No tx channel available"))?
             }
-            tx.send(Event::NotifMsg)
-                .await
+            tx.try_send(Event::NotifMsg)
                 .context("failed to send notifmsg event")?;
             Ok(())
         }
@@ -184,15 +178,15 @@ pub async fn process_message_active(
     }
 }
 
-pub async fn process_message_connect(_m: bgp::Message, _nb: Arc<Mutex<BGPNeighbor>>) -> Result<()> {
+pub async fn process_message_connect(_m: bgp::Message, _nb: Arc<NeighborCell>) -> Result<()> {
     log::debug!("FSM Shouldn't receive messages in Connect state");
     Ok(())
 }
 
 pub async fn process_message_openconfirm(
     m: bgp::Message,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) -> Result<()> {
     match m.body {
         bgp::BGPMessageBody::Keepalive(_body) => {
@@ -200,13 +194,14 @@ pub async fn process_message_openconfirm(
             {
                 let mut n = nb.lock().await;
                 n.attributes.state = BGPState::Established;
+                n.established_at = Some(std::time::Instant::now());
                 if let Some(remote_ip) = n.remote_ip {
                     log::info!("Established BGP neighborship with {}", remote_ip);
                 } else {
                     log::info!("Established BGP neighborship with unknown peer");
                 }
-                send_locrib(s.clone(), n.clone()).await;
             }
+            join_update_groups(s.clone(), nb.clone()).await;
             log::debug!("FSM OpenConfirm to Established");
             Ok(())
         }
@@ -216,8 +211,7 @@ pub async fn process_message_openconfirm(
                 n.tx.clone()
                     .context("Neighbor TX channel not initialized")?
             };
-            tx.send(Event::NotifMsg)
-                .await
+            tx.try_send(Event::NotifMsg)
                 .context("Failed to send notification event")?;
             Ok(())
         }
@@ -230,8 +224,8 @@ pub async fn process_message_openconfirm(
 
 pub async fn process_message_established(
     m: bgp::Message,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) -> Result<()> {
     match m.body {
         bgp::BGPMessageBody::Keepalive(_body) => {
@@ -253,21 +247,27 @@ pub async fn process_message_established(
     }
 }
 
-pub async fn process_message_idle(_m: bgp::Message, _nb: Arc<Mutex<BGPNeighbor>>) -> Result<()> {
+pub async fn process_message_idle(_m: bgp::Message, _nb: Arc<NeighborCell>) -> Result<()> {
     log::debug!("FSM Shouldn't receive messages in Idle state");
     Ok(())
 }
 
 pub async fn handle_notification(
     m: bgp::BGPNotificationMessage,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) {
     log::warn!(
         "Received NOTIFICATION message: Error Code: {:?}, Subcode: {}",
         m.error_code,
         m.error_subcode
     );
+    close_session(s, nb, &format!("NOTIFICATION: {:?}", m.error_code)).await;
+}
+
+/// Withdraws the routes learned from the neighbor, moves it to Idle and asks its FSM loop to
+/// drop the TCP connection.
+pub async fn close_session(s: Arc<speaker::BGPSpeaker>, nb: Arc<NeighborCell>, reason: &str) {
     let remote_rid;
     let remote_ip;
     let supported_families;
@@ -290,37 +290,46 @@ pub async fn handle_notification(
     }
 
     log::warn!(
-        "Closing BGP session with {} (RID: {}) due to NOTIFICATION: {:?}",
+        "Closing BGP session with {} (RID: {}) due to {}",
         remote_ip,
         remote_rid,
-        m.error_code
+        reason
     );
-    withdraw_neighbor_routes(s.clone(), remote_rid, remote_ip, supported_families).await;
-
     {
+        let _rib = nb.lock_rib().await;
+        withdraw_neighbor_routes(s.clone(), &nb, remote_rid, remote_ip, supported_families).await;
         let mut n = nb.lock().await;
         n.attributes.state = BGPState::Idle;
         n.adjrib.clear();
+        n.adjrib_received.clear();
         log::info!("Transitioned neighbor {} to IDLE state", remote_ip);
     }
     if let Some(tx) = nb.lock().await.tx.clone() {
-        if let Err(e) = tx.send(Event::TcpConnectionFails).await {
-            log::error!("Failed to send TcpConnectionFails event: {}", e);
+        match tx.try_send(Event::TcpConnectionFails) {
+            Ok(()) => {}
+            // The session may be the one closing itself, it is not waited for.
+            Err(mpsc::error::TrySendError::Full(e)) => {
+                tokio::spawn(async move {
+                    let _ = tx.send(e).await;
+                });
+            }
+            Err(e) => log::error!("Failed to send TcpConnectionFails event: {}", e),
         }
     }
 }
 async fn withdraw_neighbor_routes(
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: &Arc<NeighborCell>,
     remote_rid: u32,
     remote_ip: IpAddr,
     families: Vec<bgp::AddressFamily>,
 ) {
-    let speaker = s.lock().await;
-
     for af in families {
-        if let Some(rib_tx) = speaker.ribtx.get(&af) {
-            let mut attr = rib::RouteAttributes::default();
-            attr.peer_rid = remote_rid;
+        if let Some(rib_tx) = s.ribtx.get(&af) {
+            let attr = rib::RouteAttributes {
+                peer_rid: remote_rid,
+                ..Default::default()
+            };
             let msg = speaker::Update {
                 added: None,
                 withdrawn: Some(rib::RibUpdate {
@@ -336,53 +345,49 @@ async fn withdraw_neighbor_routes(
                 af.safi,
                 remote_ip
             );
-            if let Err(e) = rib_tx.send(speaker::RibEvent::UpdateRoutes(msg)).await {
+            if let Err(e) = rib_tx
+                .send(speaker::RibEvent::UpdateRoutes(Box::new(msg)))
+                .await
+            {
                 log::error!("Failed to send withdrawal message: {}", e);
             }
+            if let Err(e) = rib_tx.send(speaker::RibEvent::PeerDown(nb.clone())).await {
+                log::error!("Failed to send peer down event: {}", e);
+            }
         }
     }
 }
 
-pub async fn handle_keepalive(nb: Arc<Mutex<BGPNeighbor>>) {
+pub async fn handle_keepalive(nb: Arc<NeighborCell>) {
     let mut n = nb.lock().await;
-    n.attributes.keepalive_timer = 0;
+    n.keepalive_received_at = Some(std::time::Instant::now());
 }
 
+/// Whether another connection with the router sending `message` is already past its OPEN. The
+/// neighbors are only known through their status, so that a busy one does not hold this up.
 pub async fn collision_detection(
     message: bgp::BGPOpenMessage,
-    speaker: Arc<Mutex<speaker::BGPSpeaker>>,
+    speaker: Arc<speaker::BGPSpeaker>,
 ) -> bool {
-    let ns;
-    let rid;
-    {
-        let s = speaker.lock().await;
-        ns = s.neighbors.clone();
-        rid = s.router_id;
-    }
     log::debug!("Checking collision for bgp::BGPOpenMessage");
-    for n in ns {
-        let n = n.lock().await;
-        let tx = n.tx.clone();
-        match tx {
+    for n in &speaker.neighbors {
+        let status = n.status();
+        match status.tx {
             None => {}
-            Some(t) => match n.attributes.state {
-                BGPState::OpenConfirm => {
-                    if n.remote_rid == Some(message.router_id) {
-                        if n.remote_rid < Some(rid) {
-                            let _ = t.send(Event::OpenCollisionDump).await;
-                        }
-                        log::debug!("Collision detected!");
-                        return true;
+            Some(_) => match status.state {
+                BGPState::OpenConfirm if status.remote_rid == Some(message.router_id) => {
+                    if status.remote_rid < Some(speaker.router_id) {
+                        status.send(Event::OpenCollisionDump);
                     }
+                    log::debug!("Collision detected!");
+                    return true;
                 }
-                BGPState::OpenSent => {
-                    if n.remote_rid == Some(message.router_id) {
-                        if n.remote_rid < Some(rid) {
-                            let _ = t.send(Event::OpenCollisionDump).await;
-                        }
-                        log::debug!("Collision detected!");
-                        return true;
+                BGPState::OpenSent if status.remote_rid == Some(message.router_id) => {
+                    if status.remote_rid < Some(speaker.router_id) {
+                        status.send(Event::OpenCollisionDump);
                     }
+                    log::debug!("Collision detected!");
+                    return true;
                 }
                 _ => {}
             },
@@ -392,10 +397,7 @@ pub async fn collision_detection(
     false
 }
 
-pub async fn validate_open(
-    message: bgp::BGPOpenMessage,
-    neighbor: Arc<Mutex<BGPNeighbor>>,
-) -> bool {
+pub async fn validate_open(message: bgp::BGPOpenMessage, neighbor: Arc<NeighborCell>) -> bool {
     log::debug!("bgp::BGPOpenMessage validation in progress");
     let n = neighbor.lock().await;
     match n.remote_asn {
@@ -421,7 +423,7 @@ pub async fn validate_open(
     true
 }
 
-pub async fn update_from_open(message: bgp::BGPOpenMessage, neighbor: Arc<Mutex<BGPNeighbor>>) {
+pub async fn update_from_open(message: bgp::BGPOpenMessage, neighbor: Arc<NeighborCell>) {
     let mut n = neighbor.lock().await;
     n.attributes.hold_time = message.hold_time;
     n.remote_rid = Some(message.router_id);
@@ -429,56 +431,51 @@ pub async fn update_from_open(message: bgp::BGPOpenMessage, neighbor: Arc<Mutex<
     n.attributes.state = BGPState::OpenConfirm;
     let caps: bgp::BGPCapabilities = message.opt_params.into();
     n.capabilities_received = caps.into();
-    log::debug!("Neighbor updated from Open : {:?}", n);
+    log::debug!("Neighbor updated from Open : {:?}", *n);
 }
 
-pub async fn send_locrib(s: Arc<Mutex<speaker::BGPSpeaker>>, nb: BGPNeighbor) {
-    let adv = nb
-        .capabilities_advertised
-        .multiprotocol
-        .expect("BUG: Advertised capabilities should be set before BGP establishment");
-    let rec = nb
-        .capabilities_received
-        .multiprotocol
-        .expect("BUG: Received capabilities should be set after processing OPEN message");
+/// Has the RIB managers of the negotiated families advertise their Loc-RIB to a neighbor that
+/// just got established, through its update group.
+pub async fn join_update_groups(s: Arc<speaker::BGPSpeaker>, nb: Arc<NeighborCell>) {
+    let peers = {
+        let n = nb.lock().await;
+        let adv = n
+            .capabilities_advertised
+            .multiprotocol
+            .clone()
+            .expect("BUG: Advertised capabilities should be set before BGP establishment");
+        let rec = n
+            .capabilities_received
+            .multiprotocol
+            .clone()
+            .expect("BUG: Received capabilities should be set after processing OPEN message");
+        let mut peers = vec![];
+        for af in adv {
+            if !rec.contains(&af) {
+                continue;
+            }
+            match speaker::Peer::new(nb.clone(), &n, &af, &s.mrai) {
+                Ok(peer) => peers.push((af, peer)),
+                Err(e) => log::error!("Cannot advertise {:?} to {:?}: {:#}", af, n.remote_ip, e),
+            }
+        }
+        peers
+    };
 
-    for af in adv {
-        if rec.contains(&af) {
-            let s = s.lock().await;
-            let r = s
-                .rib
-                .get(&af)
-                .expect("BUG: RIB should exist for negotiated address family")
-                .lock()
-                .await;
-            let routes: Vec<(Nlri, Option<RouteAttributes>)> = r
-                .iter()
-                .map(|(n, a)| {
-                    (
-                        *n,
-                        Some(
-                            a.first()
-                                .expect("BUG: RIB entry should have at least one route attribute")
-                                .clone(),
-                        ),
-                    )
-                })
-                .collect();
-            let tx = nb
-                .tx
-                .clone()
-                .expect("BUG: Neighbor TX channel should be initialized before establishment");
-            tx.send(Event::RibUpdate(routes))
-                .await
-                .expect("BUG: Failed to send RIB update to established neighbor");
+    for (af, peer) in peers {
+        let Some(ribtx) = s.ribtx.get(&af) else {
+            continue;
+        };
+        if let Err(e) = ribtx.send(speaker::RibEvent::PeerUp(Box::new(peer))).await {
+            log::error!("Failed to send peer up event: {}", e);
         }
     }
 }
 
 pub async fn handle_update(
     m: bgp::BGPUpdateMessage,
-    s: Arc<Mutex<speaker::BGPSpeaker>>,
-    nb: Arc<Mutex<BGPNeighbor>>,
+    s: Arc<speaker::BGPSpeaker>,
+    nb: Arc<NeighborCell>,
 ) {
     let mut af = AddressFamily {
         afi: bgp::Afi::Ipv4,
@@ -512,19 +509,60 @@ pub async fn handle_update(
             withdrawn = m.withdrawn_routes;
         }
     }
-    let local_asn;
-    {
-        let s = s.lock().await;
-        local_asn = s.local_asn;
-    }
+    let local_asn = s.local_asn;
+    let (remote_ip, local_rid, cluster_id, allowas_in, local_as) = {
+        let nb = nb.lock().await;
+        (
+            nb.remote_ip,
+            nb.local_rid,
+            nb.cluster_id,
+            nb.allowas_in,
+            nb.local_as.as_ref().map(|l| l.asn),
+        )
+    };
+    // The local AS configured for the neighbor is prepended on receipt, so it is looked for in
+    // the path as received.
+    let local_as_looped = local_as.is_some_and(|asn| {
+        m.path_attributes.iter().any(|a| match &a.value {
+            bgp::PathAttributeValue::AsPath(as_path) => {
+                as_path
+                    .flatten_aspath()
+                    .into_iter()
+                    .filter(|a| *a == asn)
+                    .count()
+                    > allowas_in as usize
+            }
+            _ => false,
+        })
+    });
     let attributes =
         RouteAttributes::new(m.path_attributes.clone(), local_asn.into(), nb.clone(), nh).await;
+    // Paths that looped through the local AS, a route reflector or the confederation are treated
+    // as withdrawn
+    let looped = attributes.is_as_loop(local_asn, allowas_in)
+        || local_as_looped
+        || s.confederation
+            .as_ref()
+            .is_some_and(|c| attributes.is_as_loop(c.identifier, 0))
+        || attributes.is_reflection_loop(local_rid, cluster_id);
+    if !nlris.is_empty() && looped {
+        log::debug!(
+            "Ignoring {:?} from {:?}: AS_PATH {}, ORIGINATOR_ID {:?}, CLUSTER_LIST {:?}",
+            nlris,
+            remote_ip,
+            attributes.attrs.as_path.aspath_string(),
+            attributes.attrs.originator_id,
+            attributes.attrs.cluster_list
+        );
+        withdrawn.append(&mut nlris);
+    }
 
     let mut msg = speaker::Update {
         added: None,
         withdrawn: None,
         rid: 0,
     };
+    let _rib = nb.lock_rib().await;
 
     if !withdrawn.is_empty() {
         let updates = RibUpdate {
@@ -537,12 +575,74 @@ pub async fn handle_update(
             nb.adjrib_withdraw(af.clone(), updates.clone()).await;
         }
     }
+    let mut added = vec![];
+    // Set when the maximum-prefix limit closes the session, once this UPDATE's withdrawals are
+    // handed to the RIB.
+    let mut cease_tx = None;
     if !nlris.is_empty() {
-        let updates = RibUpdate { nlris, attributes };
-        msg.added = Some(updates.clone());
-        {
+        let updates = RibUpdate {
+            nlris,
+            attributes: attributes.clone(),
+        };
+        let (limit, warning_only, tx) = {
             let mut nb = nb.lock().await;
-            nb.adjrib_add(af.clone(), updates.clone()).await;
+            nb.adjrib_received_add(af.clone(), updates.clone());
+            // Prefixes rejected by the import policy replace any path previously accepted
+            // from the neighbor, so they are handed to the RIB as withdrawals.
+            let (accepted, mut rejected) = nb.import(&af, updates);
+            if !rejected.is_empty() {
+                log::debug!("Import policy rejected {:?} from {:?}", rejected, remote_ip);
+                nb.adjrib_reject(&af, &rejected);
+                msg.withdrawn
+                    .get_or_insert_with(|| RibUpdate {
+                        nlris: vec![],
+                        attributes: attributes.clone(),
+                    })
+                    .nlris
+                    .append(&mut rejected);
+            }
+            for updates in &accepted {
+                nb.adjrib_add(af.clone(), updates.clone()).await;
+            }
+            added = accepted;
+            let warning_only = nb
+                .max_prefix
+                .get(&af)
+                .map(|mp| mp.warning_only)
+                .unwrap_or(false);
+            (nb.check_max_prefix(&af), warning_only, nb.tx.clone())
+        };
+        match limit {
+            PrefixLimit::Within => {}
+            PrefixLimit::Warning { count, limit } => {
+                log::warn!(
+                    "{:?}: {} prefixes received for {:?}, limit is {}",
+                    remote_ip,
+                    count,
+                    af,
+                    limit
+                );
+            }
+            PrefixLimit::Exceeded { count, limit } if warning_only => {
+                log::warn!(
+                    "{:?}: {} prefixes received for {:?}, exceeding limit {}",
+                    remote_ip,
+                    count,
+                    af,
+                    limit
+                );
+            }
+            PrefixLimit::Exceeded { count, limit } => {
+                log::error!(
+                    "{:?}: {} prefixes received for {:?}, exceeding limit {}, closing session",
+                    remote_ip,
+                    count,
+                    af,
+                    limit
+                );
+                added.clear();
+                cease_tx = tx;
+            }
         }
     }
     {
@@ -550,8 +650,24 @@ pub async fn handle_update(
         msg.rid = nb
             .remote_rid
             .expect("BUG: Remote RID should be set after processing OPEN message");
+
         if let Some(tx) = nb.ribtx.get(&af) {
-            let _ = tx.send(speaker::RibEvent::UpdateRoutes(msg)).await;
+            let mut added = added.into_iter();
+            msg.added = added.next();
+            let rid = msg.rid;
+            let _ = tx
+                .send(speaker::RibEvent::UpdateRoutes(Box::new(msg)))
+                .await;
+            for update in added {
+                let msg = speaker::Update {
+                    added: Some(update),
+                    withdrawn: None,
+                    rid,
+                };
+                let _ = tx
+                    .send(speaker::RibEvent::UpdateRoutes(Box::new(msg)))
+                    .await;
+            }
         } else {
             log::warn!(
                 "No RIB TX channel found for AFI/SAFI {:?} from peer {:?}",
@@ -560,4 +676,74 @@ pub async fn handle_update(
             );
         }
     }
+    if let Some(tx) = cease_tx {
+        let _ = tx.try_send(Event::MaxPrefixExceeded(af));
+    }
+}
+
+/// Attempts at applying the import policies again before giving up, when the routes received
+/// keep changing meanwhile.
+const REIMPORT_ATTEMPTS: usize = 5;
+
+/// Applies the import policies again to the routes received from the neighbor and hands what
+/// changed to the RIB.
+pub async fn soft_reconfigure_inbound(nb: &Arc<NeighborCell>) -> Result<(), SoftReconfigureError> {
+    let mut stale: Option<Vec<AddressFamily>> = None;
+    for _ in 0..REIMPORT_ATTEMPTS {
+        let (rid, mut reimport) = {
+            let n = nb.lock().await;
+            if !n.soft_reconfiguration_inbound {
+                return Err(SoftReconfigureError::Disabled);
+            }
+            let Some(rid) = n.remote_rid else {
+                return Ok(());
+            };
+            (rid, n.reimport())
+        };
+        if let Some(stale) = &stale {
+            reimport.retain(stale);
+        }
+        // The policies run over the whole table without holding the neighbor up.
+        let reimported = reimport.run();
+        let _rib = nb.lock_rib().await;
+        let (changes, ribtx) = {
+            let mut n = nb.lock().await;
+            let (changes, families) = n.reimported(reimported);
+            stale = Some(families);
+            (changes, n.ribtx.clone())
+        };
+        for (af, added, withdrawn) in changes {
+            log::info!(
+                "Soft reconfiguration of {:?} from {:?}: {} updated, {} withdrawn",
+                af,
+                nb.remote_ip,
+                added.iter().map(|u| u.nlris.len()).sum::<usize>(),
+                withdrawn.as_ref().map(|u| u.nlris.len()).unwrap_or(0)
+            );
+            let Some(tx) = ribtx.get(&af) else {
+                continue;
+            };
+            let msgs = added
+                .into_iter()
+                .map(|update| speaker::Update {
+                    added: Some(update),
+                    withdrawn: None,
+                    rid,
+                })
+                .chain(withdrawn.map(|update| speaker::Update {
+                    added: None,
+                    withdrawn: Some(update),
+                    rid,
+                }));
+            for msg in msgs {
+                let _ = tx
+                    .send(speaker::RibEvent::UpdateRoutes(Box::new(msg)))
+                    .await;
+            }
+        }
+        if stale.as_ref().is_some_and(|stale| stale.is_empty()) {
+            return Ok(());
+        }
+    }
+    Err(SoftReconfigureError::RoutesChanged)
 }
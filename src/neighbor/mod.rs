@@ -1,4 +1,5 @@
 mod capabilities;
+mod cell;
 mod connection;
 mod fsm;
 mod message_handler;
@@ -8,8 +9,11 @@ mod types;
 
 #[cfg(test)]
 mod fsm_tests;
+#[cfg(test)]
+mod session_tests;
 
 pub use capabilities::Capabilities;
+pub use cell::{NeighborCell, NeighborStatus};
 pub use fsm::{connect, fsm_tcp};
-pub use session::BGPNeighbor;
-pub use types::{BGPState, Event};
+pub use session::{AdjRibsIn, BGPNeighbor};
+pub use types::{BGPState, Event, SoftReconfigureError};
@@ -1,11 +1,15 @@
 use super::capabilities::Capabilities;
 use super::types::BGPState;
 use crate::bgp::{self, AddressFamily};
+use crate::config;
+use crate::policy;
 use crate::rib::{self, RibUpdate};
 use crate::speaker;
 use derive_builder::Builder;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Default, Builder, Debug, Clone, Copy)]
 #[builder(default)]
@@ -46,12 +50,62 @@ pub struct BGPNeighbor {
     // connect_retry_time: Option<u16>,
     pub capabilities_advertised: Capabilities,
     pub capabilities_received: Capabilities,
-    pub adjrib: HashMap<bgp::AddressFamily, rib::Rib>,
+    /// Routes accepted by the import policy. Copied on write when the API holds a snapshot.
+    pub adjrib: HashMap<bgp::AddressFamily, Arc<rib::Rib>>,
+    /// Routes as received, before the import policy, kept with soft-reconfiguration inbound.
+    pub adjrib_received: HashMap<bgp::AddressFamily, Arc<rib::Rib>>,
+    pub soft_reconfiguration_inbound: bool,
     pub tx: Option<tokio::sync::mpsc::Sender<super::types::Event>>,
     pub ribtx: HashMap<bgp::AddressFamily, tokio::sync::mpsc::Sender<speaker::RibEvent>>,
     pub attributes: BGPSessionAttributes,
     pub max_retry_count: Option<u16>,
     pub exponential_backoff: bool,
+    pub bfd: bool,
+    /// When the last KEEPALIVE was received.
+    pub keepalive_received_at: Option<Instant>,
+    pub max_prefix: HashMap<bgp::AddressFamily, config::MaxPrefix>,
+    pub max_prefix_warned: HashSet<bgp::AddressFamily>,
+    /// Families over a warning-only limit, reported once until back under it.
+    pub max_prefix_exceeded: HashSet<bgp::AddressFamily>,
+    pub max_prefix_shutdown: bool,
+    pub peer_damping: Option<config::PeerDamping>,
+    pub established_at: Option<Instant>,
+    pub idle_hold_until: Option<Instant>,
+    pub policies: HashMap<bgp::AddressFamily, policy::NeighborPolicy>,
+    pub weight: u32,
+    /// MinRouteAdvertisementInterval in seconds, overriding the session type default.
+    pub mrai: Option<u16>,
+    pub route_reflector_client: bool,
+    /// In another member AS of the confederation.
+    pub confederation_peer: bool,
+    pub local_as: Option<config::LocalAs>,
+    pub as_override: bool,
+    pub allowas_in: u8,
+    /// Cluster of the route reflector, its router ID unless configured.
+    pub cluster_id: u32,
+}
+
+/// `time` halved once for every stable period since `established_at`, down to the configured
+/// idle hold time.
+pub(super) fn idle_hold_time(
+    pd: &config::PeerDamping,
+    time: usize,
+    established_at: Option<Instant>,
+    now: Instant,
+) -> usize {
+    let mut time = time;
+    if let Some(since) = established_at {
+        let periods = now.saturating_duration_since(since).as_secs() / pd.stable_time.max(1) as u64;
+        time = time.checked_shr(periods as u32).unwrap_or(0);
+    }
+    time.max(pd.idle_hold_time as usize)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PrefixLimit {
+    Within,
+    Warning { count: usize, limit: u32 },
+    Exceeded { count: usize, limit: u32 },
 }
 
 impl BGPNeighbor {
@@ -96,53 +150,365 @@ impl BGPNeighbor {
             capabilities_advertised,
             capabilities_received: Capabilities::default(),
             adjrib: HashMap::default(),
+            adjrib_received: HashMap::default(),
+            soft_reconfiguration_inbound: false,
             tx,
             ribtx,
             attributes,
             max_retry_count,
             exponential_backoff,
+            bfd: false,
+            keepalive_received_at: None,
+            max_prefix: HashMap::new(),
+            max_prefix_warned: HashSet::new(),
+            max_prefix_exceeded: HashSet::new(),
+            max_prefix_shutdown: false,
+            peer_damping: None,
+            established_at: None,
+            idle_hold_until: None,
+            policies: HashMap::new(),
+            weight: 0,
+            mrai: None,
+            route_reflector_client: false,
+            confederation_peer: false,
+            local_as: None,
+            as_override: false,
+            allowas_in: 0,
+            cluster_id: local_rid,
         }
     }
 
+    /// AS sent in the OPEN message, the local AS configured for the neighbor if any.
+    pub fn open_asn(&self) -> u16 {
+        self.local_as
+            .as_ref()
+            .map(|l| l.asn)
+            .unwrap_or(self.local_asn)
+    }
+
     pub async fn is_established(&self) -> bool {
         matches!(self.attributes.state, BGPState::Established)
     }
 
+    pub fn set_peer_damping(&mut self, peer_damping: Option<config::PeerDamping>) {
+        self.attributes.damp_peer_oscillations = peer_damping.is_some();
+        self.attributes.idle_hold_time = peer_damping
+            .as_ref()
+            .map(|pd| pd.idle_hold_time as usize)
+            .unwrap_or(0);
+        self.peer_damping = peer_damping;
+    }
+
+    /// Idle hold time in seconds, halved once for every stable period of the current session.
+    pub fn idle_hold_time(&self, now: Instant) -> usize {
+        match &self.peer_damping {
+            Some(pd) => {
+                idle_hold_time(pd, self.attributes.idle_hold_time, self.established_at, now)
+            }
+            None => 0,
+        }
+    }
+
+    /// Records a session failure and returns how long to stay Idle before the next automatic
+    /// start. The idle hold time used for the following failure is doubled.
+    pub fn session_failed(&mut self, now: Instant) -> Option<Duration> {
+        let pd = self.peer_damping.clone()?;
+        let idle_hold = self.idle_hold_time(now);
+        self.established_at = None;
+        self.attributes.idle_hold_time = (idle_hold * 2).min(pd.max_idle_hold_time as usize);
+        self.attributes.idle_hold_timer = idle_hold;
+        let idle_hold = Duration::from_secs(idle_hold as u64);
+        self.idle_hold_until = Some(now + idle_hold);
+        Some(idle_hold)
+    }
+
+    /// Whether the IdleHoldTimer is running, during which no connection is accepted.
+    pub fn is_idle_hold(&self, now: Instant) -> bool {
+        self.idle_hold_until.is_some_and(|until| until > now)
+    }
+
     pub async fn adjrib_add(&mut self, af: AddressFamily, routes: RibUpdate) {
         log::debug!("Adding routes to ajdrib {:?} : {:?}", af, routes);
-        match self.adjrib.get_mut(&af) {
-            None => {
-                let mut rib = rib::Rib::default();
-                for nlri in routes.nlris {
-                    rib.insert(nlri, vec![routes.attributes.clone()]);
-                }
-                self.adjrib.insert(af.clone(), rib);
+        table_add(&mut self.adjrib, af, routes);
+    }
+
+    /// Keeps the routes as received when soft-reconfiguration inbound is enabled.
+    pub fn adjrib_received_add(&mut self, af: AddressFamily, routes: RibUpdate) {
+        if self.soft_reconfiguration_inbound {
+            table_add(&mut self.adjrib_received, af, routes);
+        }
+    }
+
+    /// Compares the Adj-RIB-In size of `af` with the configured maximum-prefix limit. The
+    /// threshold warning, and going over a warning-only limit, are only reported once until the
+    /// count drops below them again.
+    pub fn check_max_prefix(&mut self, af: &AddressFamily) -> PrefixLimit {
+        let mp = match self.max_prefix.get(af) {
+            Some(mp) => mp,
+            None => return PrefixLimit::Within,
+        };
+        let count = self.adjrib.get(af).map(|r| r.len()).unwrap_or(0);
+        let limit = mp.limit;
+        let threshold = limit as u64 * mp.warning_threshold as u64 / 100;
+
+        if count > limit as usize {
+            if !mp.warning_only || self.max_prefix_exceeded.insert(af.clone()) {
+                PrefixLimit::Exceeded { count, limit }
+            } else {
+                PrefixLimit::Within
             }
-            Some(rib) => {
-                for nlri in routes.nlris {
-                    match rib.get_mut(&nlri) {
-                        None => {
-                            rib.insert(nlri, vec![routes.attributes.clone()]);
-                        }
-                        Some(attributes) => {
-                            attributes.clear();
-                            attributes.push(routes.attributes.clone());
-                        }
-                    }
-                }
+        } else if count as u64 >= threshold {
+            self.max_prefix_exceeded.remove(af);
+            if self.max_prefix_warned.insert(af.clone()) {
+                PrefixLimit::Warning { count, limit }
+            } else {
+                PrefixLimit::Within
             }
+        } else {
+            self.max_prefix_warned.remove(af);
+            self.max_prefix_exceeded.remove(af);
+            PrefixLimit::Within
+        }
+    }
+
+    /// Shuts the neighbor down for going over its maximum-prefix limit. The IdleHoldTimer then
+    /// restarts the session after `restart_interval` seconds, or after the damping idle hold
+    /// when longer. Without a restart interval, the shutdown lasts until cleared.
+    pub fn max_prefix_shut_down(&mut self, now: Instant, restart_interval: Option<u16>) {
+        self.max_prefix_shutdown = true;
+        let idle_hold = self.session_failed(now).unwrap_or_default();
+        self.idle_hold_until = restart_interval
+            .map(|interval| now + Duration::from_secs(interval as u64).max(idle_hold));
+    }
+
+    /// Lifts a maximum-prefix shutdown, and the restart pending on it, returning whether the
+    /// neighbor was shut down.
+    pub fn clear_max_prefix(&mut self) -> bool {
+        self.max_prefix_warned.clear();
+        self.max_prefix_exceeded.clear();
+        let shutdown = std::mem::take(&mut self.max_prefix_shutdown);
+        if shutdown {
+            self.idle_hold_until = None;
         }
+        shutdown
     }
 
     pub async fn adjrib_withdraw(&mut self, af: AddressFamily, routes: RibUpdate) {
         log::debug!("Removing routes from adjrib {:?} : {:?}", af, routes);
-        match self.adjrib.get_mut(&af) {
-            None => {}
-            Some(rib) => {
-                for nlri in routes.nlris {
-                    rib.remove(&nlri);
+        table_withdraw(&mut self.adjrib_received, &af, &routes.nlris);
+        table_withdraw(&mut self.adjrib, &af, &routes.nlris);
+    }
+
+    /// Removes routes rejected by the import policy, which stay in the pre-policy view.
+    pub fn adjrib_reject(&mut self, af: &AddressFamily, nlris: &[bgp::Nlri]) {
+        table_withdraw(&mut self.adjrib, af, nlris);
+    }
+
+    /// Routes accepted by the import policy of `af`, and those it rejected. Everything is
+    /// accepted without a policy.
+    pub fn import(
+        &self,
+        af: &AddressFamily,
+        update: RibUpdate,
+    ) -> (Vec<RibUpdate>, Vec<bgp::Nlri>) {
+        match self.policies.get(af).and_then(|p| p.import.as_ref()) {
+            Some(import) => import.apply_update(update),
+            None => (vec![update], vec![]),
+        }
+    }
+
+    /// Copies the routes as received and accepted, and the import policies, so that
+    /// [`Reimport::run`] runs the policies again without holding the neighbor.
+    pub fn reimport(&self) -> Reimport {
+        Reimport {
+            families: self
+                .adjrib_received
+                .iter()
+                .map(|(af, received)| ReimportFamily {
+                    af: af.clone(),
+                    received: received.clone(),
+                    previous: self.adjrib.get(af).cloned().unwrap_or_default(),
+                    import: self.policies.get(af).and_then(|p| p.import.clone()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces the accepted routes with those of a [`Reimport`]. Families whose routes as
+    /// received changed meanwhile are left as they are, and returned to be reimported again
+    /// along with the changes of the families replaced.
+    pub fn reimported(
+        &mut self,
+        reimported: Vec<Reimported>,
+    ) -> (Vec<ReimportChanges>, Vec<AddressFamily>) {
+        let mut changes = vec![];
+        let mut stale = vec![];
+        for r in reimported {
+            match self.adjrib_received.get(&r.af) {
+                Some(received) if Arc::ptr_eq(received, &r.received) => {
+                    self.adjrib.insert(r.af.clone(), r.accepted);
+                    changes.push((r.af, r.changed, r.withdrawn));
+                }
+                // Cleared with the session, there is nothing left to reimport
+                None => {}
+                Some(_) => {
+                    log::info!("Routes of {:?} changed during the reimport", r.af);
+                    stale.push(r.af);
+                }
+            }
+        }
+        (changes, stale)
+    }
+
+    pub fn adj_ribs_in(&self) -> AdjRibsIn {
+        AdjRibsIn {
+            accepted: self.adjrib.clone(),
+            received: self
+                .soft_reconfiguration_inbound
+                .then(|| self.adjrib_received.clone()),
+        }
+    }
+}
+
+/// Routes of a family whose accepted path changed, and the prefixes no longer accepted.
+pub type ReimportChanges = (AddressFamily, Vec<RibUpdate>, Option<RibUpdate>);
+
+/// Routes of a family as received and accepted, and its import policy.
+#[derive(Debug)]
+struct ReimportFamily {
+    af: AddressFamily,
+    received: Arc<rib::Rib>,
+    previous: Arc<rib::Rib>,
+    import: Option<Arc<policy::RouteMap>>,
+}
+
+/// Copy of what running the import policies of a neighbor again over the routes as received
+/// needs.
+#[derive(Debug)]
+pub struct Reimport {
+    families: Vec<ReimportFamily>,
+}
+
+/// Routes of a family accepted by its import policy run again, with the changes to hand to the
+/// RIB.
+#[derive(Debug)]
+pub struct Reimported {
+    af: AddressFamily,
+    /// Routes as received the policy ran over.
+    received: Arc<rib::Rib>,
+    accepted: Arc<rib::Rib>,
+    changed: Vec<RibUpdate>,
+    withdrawn: Option<RibUpdate>,
+}
+
+impl Reimport {
+    /// Keeps the families of `families` only.
+    pub fn retain(&mut self, families: &[AddressFamily]) {
+        self.families.retain(|f| families.contains(&f.af));
+    }
+
+    /// Runs the import policies over the routes as received.
+    pub fn run(self) -> Vec<Reimported> {
+        self.families
+            .into_iter()
+            .map(|f| {
+                let mut table = HashMap::new();
+                for update in group_by_attributes(f.received.iter()) {
+                    let accepted = match &f.import {
+                        Some(import) => import.apply_update(update).0,
+                        None => vec![update],
+                    };
+                    for accepted in accepted {
+                        table_add(&mut table, f.af.clone(), accepted);
+                    }
+                }
+                let accepted = table.remove(&f.af).unwrap_or_default();
+                let previous = f.previous;
+                let withdrawn = group_by_attributes(
+                    previous
+                        .iter()
+                        .filter(|(nlri, _)| accepted.get(nlri).is_none()),
+                )
+                .into_iter()
+                .reduce(|mut withdrawn, mut update| {
+                    withdrawn.nlris.append(&mut update.nlris);
+                    withdrawn
+                });
+                let changed = group_by_attributes(accepted.iter().filter(|(nlri, paths)| {
+                    match (previous.get(nlri).and_then(|p| p.first()), paths.first()) {
+                        (Some(before), Some(after)) => !before.is_same_path(after),
+                        _ => true,
+                    }
+                }));
+                Reimported {
+                    af: f.af,
+                    received: f.received,
+                    accepted,
+                    changed,
+                    withdrawn,
                 }
+            })
+            .collect()
+    }
+}
+
+/// Snapshot of the Adj-RIBs-In of a neighbor.
+#[derive(Debug, Clone, Default)]
+pub struct AdjRibsIn {
+    pub accepted: HashMap<bgp::AddressFamily, Arc<rib::Rib>>,
+    /// Only kept with soft-reconfiguration inbound.
+    pub received: Option<HashMap<bgp::AddressFamily, Arc<rib::Rib>>>,
+}
+
+fn table_add(
+    table: &mut HashMap<AddressFamily, Arc<rib::Rib>>,
+    af: AddressFamily,
+    mut routes: RibUpdate,
+) {
+    routes.attributes.intern();
+    let rib = Arc::make_mut(table.entry(af).or_default());
+    for nlri in routes.nlris {
+        match rib.get_mut(&nlri) {
+            None => {
+                rib.insert(nlri, vec![routes.attributes.clone()]);
+            }
+            Some(attributes) => {
+                attributes.clear();
+                attributes.push(routes.attributes.clone());
             }
         }
     }
 }
+
+/// Routes of an Adj-RIB-In grouped by their interned attribute set.
+fn group_by_attributes<'a>(
+    routes: impl Iterator<Item = (&'a bgp::Nlri, &'a Vec<rib::RouteAttributes>)>,
+) -> Vec<RibUpdate> {
+    let mut groups: HashMap<*const rib::AttributeSet, RibUpdate> = HashMap::new();
+    for (nlri, paths) in routes {
+        for path in paths {
+            groups
+                .entry(Arc::as_ptr(&path.attrs))
+                .or_insert_with(|| RibUpdate {
+                    nlris: vec![],
+                    attributes: path.clone(),
+                })
+                .nlris
+                .push(*nlri);
+        }
+    }
+    groups.into_values().collect()
+}
+
+fn table_withdraw(
+    table: &mut HashMap<AddressFamily, Arc<rib::Rib>>,
+    af: &AddressFamily,
+    nlris: &[bgp::Nlri],
+) {
+    if let Some(rib) = table.get_mut(af) {
+        let rib = Arc::make_mut(rib);
+        for nlri in nlris {
+            rib.remove(nlri);
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use super::cell::NeighborCell;
+use super::fsm::{fsm_idle, MAILBOX_SIZE};
+use super::message_handler::soft_reconfigure_inbound;
+use super::session::{BGPNeighbor, PrefixLimit};
+use super::types::{BGPState, Event, SoftReconfigureError};
+use crate::bgp::{ASPATHSegment, ASPATHSegmentType, Flatten, PathAttribute};
+use crate::bgp::{AddressFamily, Afi, Nlri, Safi};
+use crate::config::{self, LocalAs, MaxPrefix, PeerDamping, PolicyAction};
+use crate::policy::{self, Policies};
+use crate::rib::{RibUpdate, RouteAttributes};
+use crate::speaker::{BGPSpeaker, RibEvent};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+fn neighbor(limit: u32) -> BGPNeighbor {
+    let mut n = BGPNeighbor::new(
+        None,
+        None,
+        65000,
+        1,
+        None,
+        Some(179),
+        Some(65001),
+        3,
+        5,
+        BGPState::Established,
+        None,
+        HashMap::new(),
+        None,
+        false,
+    );
+    n.max_prefix.insert(
+        ipv4(),
+        MaxPrefix {
+            afi: Afi::Ipv4,
+            safi: Safi::NLRIUnicast,
+            limit,
+            warning_threshold: 75,
+            warning_only: false,
+            restart_interval: None,
+        },
+    );
+    n
+}
+
+fn ipv4() -> AddressFamily {
+    AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::NLRIUnicast,
+    }
+}
+
+fn update(range: std::ops::Range<u8>) -> RibUpdate {
+    RibUpdate {
+        nlris: range
+            .map(|i| Nlri {
+                net: format!("10.0.{}.0/24", i).parse().unwrap(),
+            })
+            .collect(),
+        attributes: RouteAttributes::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_max_prefix_warning_then_exceeded() {
+    let mut n = neighbor(4);
+
+    n.adjrib_add(ipv4(), update(0..2)).await;
+    assert_eq!(n.check_max_prefix(&ipv4()), PrefixLimit::Within);
+
+    n.adjrib_add(ipv4(), update(2..3)).await;
+    assert_eq!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Warning { count: 3, limit: 4 }
+    );
+    assert_eq!(n.check_max_prefix(&ipv4()), PrefixLimit::Within);
+
+    n.adjrib_add(ipv4(), update(3..5)).await;
+    assert_eq!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Exceeded { count: 5, limit: 4 }
+    );
+}
+
+#[tokio::test]
+async fn test_max_prefix_warning_rearmed_below_threshold() {
+    let mut n = neighbor(4);
+
+    n.adjrib_add(ipv4(), update(0..3)).await;
+    assert!(matches!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Warning { .. }
+    ));
+
+    n.adjrib_withdraw(ipv4(), update(0..2)).await;
+    assert_eq!(n.check_max_prefix(&ipv4()), PrefixLimit::Within);
+
+    n.adjrib_add(ipv4(), update(0..2)).await;
+    assert!(matches!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Warning { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_max_prefix_warning_only_exceeded_once() {
+    let mut n = neighbor(2);
+    n.max_prefix.get_mut(&ipv4()).unwrap().warning_only = true;
+
+    n.adjrib_add(ipv4(), update(0..3)).await;
+    assert_eq!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Exceeded { count: 3, limit: 2 }
+    );
+    n.adjrib_add(ipv4(), update(3..4)).await;
+    assert_eq!(n.check_max_prefix(&ipv4()), PrefixLimit::Within);
+
+    n.adjrib_withdraw(ipv4(), update(0..3)).await;
+    assert_eq!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Warning { count: 1, limit: 2 }
+    );
+    n.adjrib_add(ipv4(), update(0..3)).await;
+    assert_eq!(
+        n.check_max_prefix(&ipv4()),
+        PrefixLimit::Exceeded { count: 4, limit: 2 }
+    );
+}
+
+#[test]
+fn test_clear_max_prefix() {
+    let mut n = neighbor(2);
+    assert!(!n.clear_max_prefix());
+
+    n.max_prefix_shutdown = true;
+    n.max_prefix_warned.insert(ipv4());
+    assert!(n.clear_max_prefix());
+    assert!(!n.max_prefix_shutdown);
+    assert!(n.max_prefix_warned.is_empty());
+}
+
+#[test]
+fn test_max_prefix_restart_backs_off() {
+    let mut n = damped_neighbor();
+    let now = Instant::now();
+
+    // Restarted after the restart interval, or the idle hold when longer
+    n.max_prefix_shut_down(now, Some(7));
+    assert_eq!(n.idle_hold_until, Some(now + Duration::from_secs(7)));
+    n.max_prefix_shut_down(now, Some(7));
+    assert_eq!(n.idle_hold_until, Some(now + Duration::from_secs(10)));
+
+    // Held down until cleared, which also cancels the restart
+    n.max_prefix_shut_down(now, None);
+    assert_eq!(n.idle_hold_until, None);
+    n.max_prefix_shut_down(now, Some(7));
+    assert!(n.clear_max_prefix());
+    assert_eq!(n.idle_hold_until, None);
+}
+
+/// Idle neighbor whose connections are refused.
+fn refused_neighbor() -> BGPNeighbor {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let mut n = neighbor(1);
+    n.remote_ip = Some("127.0.0.1".parse().unwrap());
+    n.remote_port = Some(port);
+    n.attributes.state = BGPState::Idle;
+    n
+}
+
+#[tokio::test]
+async fn test_max_prefix_restart_retries() {
+    let mut n = refused_neighbor();
+    n.max_prefix_shut_down(Instant::now(), Some(0));
+    let (tx, mut rx) = mpsc::channel(MAILBOX_SIZE);
+    n.tx = Some(tx);
+    let cell = Arc::new(NeighborCell::new(n));
+    let speaker = Arc::new(BGPSpeaker::new(65000, 1, 180, vec![], 179, vec![]));
+
+    // No automatic start while shut down, the IdleHoldTimer restarts the session
+    assert!(cell
+        .status()
+        .send(Event::AutomaticStartWithDampPeerOscillations));
+    fsm_idle(&mut rx, speaker, cell.clone()).await.unwrap();
+    assert!(!cell.status().max_prefix_shutdown);
+
+    // Connecting is retried while refused
+    tokio::time::timeout(Duration::from_secs(30), async {
+        while cell.lock().await.attributes.connect_retry_counter < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(cell.status().state, BGPState::Idle);
+}
+
+#[tokio::test]
+async fn test_clear_max_prefix_event() {
+    let mut n = refused_neighbor();
+    n.max_prefix_shut_down(Instant::now(), None);
+    let (tx, mut rx) = mpsc::channel(MAILBOX_SIZE);
+    n.tx = Some(tx);
+    let cell = Arc::new(NeighborCell::new(n));
+    let speaker = Arc::new(BGPSpeaker::new(65000, 1, 180, vec![], 179, vec![]));
+
+    let (reply, cleared) = oneshot::channel();
+    assert!(cell.status().send(Event::ClearMaxPrefix(reply)));
+    fsm_idle(&mut rx, speaker, cell.clone()).await.unwrap();
+    assert_eq!(cleared.await, Ok(true));
+    assert!(!cell.status().max_prefix_shutdown);
+}
+
+#[tokio::test]
+async fn test_max_prefix_unconfigured_family() {
+    let mut n = neighbor(1);
+    let ipv6 = AddressFamily {
+        afi: Afi::Ipv6,
+        safi: Safi::NLRIUnicast,
+    };
+
+    assert_eq!(n.check_max_prefix(&ipv6), PrefixLimit::Within);
+}
+
+fn damped_neighbor() -> BGPNeighbor {
+    let mut n = neighbor(1);
+    n.set_peer_damping(Some(PeerDamping {
+        idle_hold_time: 5,
+        max_idle_hold_time: 60,
+        stable_time: 100,
+    }));
+    n
+}
+
+#[test]
+fn test_idle_hold_time_grows_until_max() {
+    let mut n = damped_neighbor();
+    let now = Instant::now();
+
+    let holds: Vec<u64> = (0..6)
+        .map(|_| n.session_failed(now).unwrap().as_secs())
+        .collect();
+    assert_eq!(holds, vec![5, 10, 20, 40, 60, 60]);
+    assert!(n.is_idle_hold(now));
+    assert!(!n.is_idle_hold(now + Duration::from_secs(61)));
+}
+
+#[test]
+fn test_idle_hold_time_decays_when_stable() {
+    let mut n = damped_neighbor();
+    let now = Instant::now();
+
+    for _ in 0..3 {
+        n.session_failed(now);
+    }
+    assert_eq!(n.idle_hold_time(now), 40);
+
+    n.established_at = Some(now);
+    assert_eq!(n.idle_hold_time(now + Duration::from_secs(99)), 40);
+    assert_eq!(n.idle_hold_time(now + Duration::from_secs(100)), 20);
+    assert_eq!(n.idle_hold_time(now + Duration::from_secs(1000)), 5);
+
+    let later = now + Duration::from_secs(250);
+    assert_eq!(n.session_failed(later), Some(Duration::from_secs(10)));
+    assert_eq!(n.idle_hold_time(later), 20);
+}
+
+#[test]
+fn test_idle_hold_disabled() {
+    let mut n = neighbor(1);
+
+    assert_eq!(n.session_failed(Instant::now()), None);
+    assert!(!n.is_idle_hold(Instant::now()));
+}
+
+#[tokio::test]
+async fn test_status_published_on_unlock() {
+    let cell = NeighborCell::new(damped_neighbor());
+    let now = Instant::now();
+
+    {
+        let mut n = cell.lock().await;
+        n.attributes.state = BGPState::Idle;
+        n.session_failed(now);
+        n.session_failed(now);
+        // Not visible until the lock is released
+        assert!(cell.status().is_established());
+    }
+
+    let status = cell.status();
+    assert!(!status.is_established());
+    assert!(status.is_idle_hold(now));
+    assert_eq!(
+        status.current_idle_hold_time(now),
+        cell.lock().await.idle_hold_time(now)
+    );
+    // No session to hand events to
+    assert!(!status.send(Event::ManualStop));
+}
+
+#[tokio::test]
+async fn test_manual_stop_cancels_idle_hold() {
+    let mut n = damped_neighbor();
+    n.attributes.state = BGPState::Idle;
+    n.session_failed(Instant::now());
+    let (tx, mut rx) = mpsc::channel(MAILBOX_SIZE);
+    n.tx = Some(tx);
+    let cell = Arc::new(NeighborCell::new(n));
+    let speaker = Arc::new(BGPSpeaker::new(65000, 1, 180, vec![], 179, vec![]));
+
+    // The automatic start waits for the IdleHoldTimer, which the ManualStop cancels
+    let status = cell.status();
+    assert!(status.send(Event::AutomaticStartWithDampPeerOscillations));
+    assert!(status.send(Event::ManualStop));
+    fsm_idle(&mut rx, speaker, cell.clone()).await.unwrap();
+
+    let status = cell.status();
+    assert_eq!(status.state, BGPState::Idle);
+    assert_eq!(status.idle_hold_until, None);
+    assert!(status.tx.is_none());
+}
+
+/// Import policy accepting the /24s of 10.0.0.0/22 but `denied`.
+fn import_policy(denied: &str) -> policy::NeighborPolicy {
+    let entry = |action, prefix: &str| config::PrefixListEntry {
+        action,
+        prefix: prefix.to_string(),
+        ge: Some(24),
+        le: Some(24),
+    };
+    let prefix_lists = vec![config::PrefixList {
+        name: "ACCEPTED".to_string(),
+        entries: vec![
+            entry(PolicyAction::Deny, denied),
+            entry(PolicyAction::Permit, "10.0.0.0/22"),
+        ],
+    }];
+    let route_maps = vec![config::RouteMap {
+        name: "IMPORT".to_string(),
+        entries: vec![config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                prefix_list: Some("ACCEPTED".to_string()),
+                ..Default::default()
+            },
+            set: Default::default(),
+        }],
+    }];
+    let policies = Policies::new(&prefix_lists, &[], &route_maps).unwrap();
+    policy::NeighborPolicy {
+        import: Some(policies.route_map("IMPORT").unwrap()),
+        export: None,
+    }
+}
+
+/// Hands `routes` to the Adj-RIBs-In the way an UPDATE does.
+async fn receive(n: &mut BGPNeighbor, routes: RibUpdate) {
+    n.adjrib_received_add(ipv4(), routes.clone());
+    let (accepted, rejected) = n.import(&ipv4(), routes);
+    n.adjrib_reject(&ipv4(), &rejected);
+    for routes in accepted {
+        n.adjrib_add(ipv4(), routes).await;
+    }
+}
+
+fn nlris(rib: &crate::rib::Rib) -> Vec<String> {
+    let mut nlris: Vec<String> = rib.iter().map(|(n, _)| n.net.to_string()).collect();
+    nlris.sort();
+    nlris
+}
+
+#[tokio::test]
+async fn test_soft_reconfiguration_keeps_received_routes() {
+    let mut n = neighbor(100);
+    n.soft_reconfiguration_inbound = true;
+    n.policies.insert(ipv4(), import_policy("10.0.0.0/23"));
+
+    receive(&mut n, update(0..6)).await;
+    let adjrib = n.adj_ribs_in();
+    assert_eq!(
+        nlris(&adjrib.accepted[&ipv4()]),
+        ["10.0.2.0/24", "10.0.3.0/24"]
+    );
+    assert_eq!(adjrib.received.unwrap()[&ipv4()].len(), 6);
+
+    n.adjrib_withdraw(ipv4(), update(3..5)).await;
+    let adjrib = n.adj_ribs_in();
+    assert_eq!(nlris(&adjrib.accepted[&ipv4()]), ["10.0.2.0/24"]);
+    assert_eq!(adjrib.received.unwrap()[&ipv4()].len(), 4);
+}
+
+#[tokio::test]
+async fn test_soft_reconfiguration_reimport() {
+    let mut n = neighbor(100);
+    n.soft_reconfiguration_inbound = true;
+    n.policies.insert(ipv4(), import_policy("10.0.0.0/23"));
+    receive(&mut n, update(0..4)).await;
+
+    n.policies.insert(ipv4(), import_policy("10.0.3.0/24"));
+    let (changes, stale) = n.reimported(n.reimport().run());
+    assert!(stale.is_empty());
+    assert_eq!(changes.len(), 1);
+    let (af, changed, withdrawn) = &changes[0];
+    assert_eq!(*af, ipv4());
+    // 10.0.2.0/24 was already accepted with the same path
+    let mut changed: Vec<Nlri> = changed.iter().flat_map(|u| u.nlris.clone()).collect();
+    changed.sort_by_key(|n| n.net.to_string());
+    assert_eq!(changed, update(0..2).nlris);
+    assert_eq!(withdrawn.as_ref().unwrap().nlris, update(3..4).nlris);
+    assert_eq!(
+        nlris(&n.adjrib[&ipv4()]),
+        ["10.0.0.0/24", "10.0.1.0/24", "10.0.2.0/24"]
+    );
+}
+
+#[tokio::test]
+async fn test_reimport_retried_when_routes_changed() {
+    let mut n = neighbor(100);
+    n.soft_reconfiguration_inbound = true;
+    receive(&mut n, update(0..4)).await;
+
+    n.policies.insert(ipv4(), import_policy("10.0.3.0/24"));
+    let reimport = n.reimport();
+    n.adjrib_withdraw(ipv4(), update(0..1)).await;
+    let (changes, stale) = n.reimported(reimport.run());
+    assert!(changes.is_empty());
+    assert_eq!(stale, [ipv4()]);
+    assert_eq!(n.adjrib[&ipv4()].len(), 3);
+
+    let mut reimport = n.reimport();
+    reimport.retain(&stale);
+    let (changes, stale) = n.reimported(reimport.run());
+    assert!(stale.is_empty());
+    assert_eq!(changes[0].2.as_ref().unwrap().nlris, update(3..4).nlris);
+    assert_eq!(nlris(&n.adjrib[&ipv4()]), ["10.0.1.0/24", "10.0.2.0/24"]);
+}
+
+#[tokio::test]
+async fn test_soft_reconfiguration_does_not_hold_neighbor() {
+    let (ribtx, mut ribrx) = mpsc::channel(1);
+    let mut n = neighbor(100);
+    n.ribtx.insert(ipv4(), ribtx);
+    n.remote_rid = Some(2);
+    n.soft_reconfiguration_inbound = true;
+    n.policies.insert(ipv4(), import_policy("10.0.3.0/24"));
+    receive(&mut n, update(0..4)).await;
+    n.policies.insert(ipv4(), import_policy("10.0.0.0/23"));
+    let cell = Arc::new(NeighborCell::new(n));
+
+    let reconfigure = tokio::spawn({
+        let cell = cell.clone();
+        async move { soft_reconfigure_inbound(&cell).await }
+    });
+    // The RIB takes one change at a time, the neighbor is not locked meanwhile
+    let first = ribrx.recv().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), cell.lock())
+        .await
+        .unwrap();
+    let second = ribrx.recv().await.unwrap();
+    assert_eq!(reconfigure.await.unwrap(), Ok(()));
+
+    let (RibEvent::UpdateRoutes(first), RibEvent::UpdateRoutes(second)) = (first, second) else {
+        panic!("Routes expected");
+    };
+    assert_eq!(first.added.unwrap().nlris, update(3..4).nlris);
+    let mut withdrawn = second.withdrawn.unwrap().nlris;
+    withdrawn.sort_by_key(|n| n.net.to_string());
+    assert_eq!(withdrawn, update(0..2).nlris);
+}
+
+#[tokio::test]
+async fn test_soft_reconfiguration_disabled() {
+    let mut n = neighbor(100);
+    n.policies.insert(ipv4(), import_policy("10.0.0.0/23"));
+    receive(&mut n, update(0..4)).await;
+
+    let adjrib = n.adj_ribs_in();
+    assert_eq!(adjrib.accepted[&ipv4()].len(), 2);
+    assert!(adjrib.received.is_none());
+    assert!(n.reimported(n.reimport().run()).0.is_empty());
+    assert_eq!(
+        soft_reconfigure_inbound(&Arc::new(NeighborCell::new(n))).await,
+        Err(SoftReconfigureError::Disabled)
+    );
+}
+
+#[tokio::test]
+async fn test_local_as() {
+    let mut n = neighbor(100);
+    assert_eq!(n.open_asn(), 65000);
+    n.remote_ip = Some("192.0.2.1".parse().unwrap());
+    n.remote_rid = Some(2);
+    n.local_as = Some(LocalAs {
+        asn: 64512,
+        no_prepend: false,
+        replace_as: false,
+    });
+    assert_eq!(n.open_asn(), 64512);
+
+    let received = vec![PathAttribute::aspath(vec![ASPATHSegment {
+        segment_type: ASPATHSegmentType::AsSequence,
+        as_list: vec![65001],
+    }])];
+    let cell = Arc::new(NeighborCell::new(n));
+    let ra = RouteAttributes::new(received.clone(), 65000, cell.clone(), None).await;
+    assert_eq!(ra.attrs.as_path.aspath_string(), "64512 65001");
+
+    cell.lock().await.local_as.as_mut().unwrap().no_prepend = true;
+    let ra = RouteAttributes::new(received, 65000, cell, None).await;
+    assert_eq!(ra.attrs.as_path.aspath_string(), "65001");
+}
+
+#[tokio::test]
+async fn test_local_pref_ignored_from_ebgp() {
+    let mut n = neighbor(100);
+    n.remote_ip = Some("192.0.2.1".parse().unwrap());
+    n.remote_rid = Some(2);
+    let received = vec![
+        PathAttribute::aspath(vec![ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsSequence,
+            as_list: vec![65001],
+        }]),
+        PathAttribute::local_pref(500),
+    ];
+    let cell = Arc::new(NeighborCell::new(n));
+    let ra = RouteAttributes::new(received.clone(), 65000, cell.clone(), None).await;
+    assert_eq!(ra.attrs.local_pref, None);
+
+    cell.lock().await.remote_asn = Some(65000);
+    let ra = RouteAttributes::new(received.clone(), 65000, cell.clone(), None).await;
+    assert_eq!(ra.attrs.local_pref, Some(500));
+
+    // Kept from another member AS of the confederation
+    {
+        let mut n = cell.lock().await;
+        n.remote_asn = Some(65001);
+        n.confederation_peer = true;
+    }
+    let ra = RouteAttributes::new(received, 65000, cell, None).await;
+    assert_eq!(ra.attrs.local_pref, Some(500));
+}
@@ -1,25 +1,48 @@
-use super::session::BGPNeighbor;
+use super::cell::NeighborCell;
 use super::types::Event;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
 use tokio::time::Duration;
 
 use crate::neighbor::BGPState;
+use crate::speaker;
 
+/// Runs the IdleHoldTimer of a damped neighbor until `until`. Never expires when the timer is
+/// not running.
+pub async fn timer_idle_hold(until: Option<std::time::Instant>) {
+    match until {
+        Some(until) => tokio::time::sleep_until(until.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reconnects a neighbor once its IdleHoldTimer is over. Connection attempts are retried until
+/// one succeeds or `max_retry_count` is reached.
+pub fn idle_hold_restart(s: Arc<speaker::BGPSpeaker>, nb: Arc<NeighborCell>) {
+    tokio::spawn(async move {
+        loop {
+            match super::fsm::connect(s.clone(), nb.clone()).await {
+                Ok(()) => return,
+                Err(e) => log::error!("Failed to restart session: {}", e),
+            }
+            let n = nb.status();
+            if n.connect_retries_exhausted() || n.state != BGPState::Idle {
+                return;
+            }
+        }
+    });
+}
+
+/// Sends the session `tx` a KeepaliveTimerExpires every third of the negotiated hold time.
 pub async fn timer_hold(
-    n: Arc<Mutex<BGPNeighbor>>,
+    n: Arc<NeighborCell>,
+    tx: mpsc::Sender<Event>,
     mut receiver: tokio::sync::oneshot::Receiver<()>,
 ) -> Result<()> {
     loop {
-        let (hold_time, tx) = {
-            let n = n.lock().await;
-            (n.attributes.hold_time, n.tx.clone())
-        };
-
-        let tx = tx.ok_or_else(|| anyhow!("Timer channel not available for neighbor"))?;
-
+        let hold_time = n.status().hold_time;
         tokio::select! {
             _ = tokio::time::sleep(Duration::from_secs(hold_time as u64 / 3)) => {
                 tx.send(Event::KeepaliveTimerExpires)
@@ -34,30 +57,30 @@ pub async fn timer_hold(
     }
 }
 
-pub async fn timer_keepalive(n: Arc<Mutex<BGPNeighbor>>, tx: mpsc::Sender<Event>) -> Result<()> {
+/// Sends the session `tx` a KeepaliveTimerExpires every second once no KEEPALIVE was received
+/// for the hold time.
+pub async fn timer_keepalive(n: Arc<NeighborCell>, tx: mpsc::Sender<Event>) -> Result<()> {
     log::debug!("FSM Starting TimerKeepalive");
+    let started = Instant::now();
 
     loop {
         tokio::time::sleep(Duration::from_secs(1)).await;
 
-        let (keepalive_timer, state, hold_time) = {
-            let mut n = n.lock().await;
-            n.attributes.keepalive_timer += 1;
-            (
-                n.attributes.keepalive_timer,
-                n.attributes.state,
-                n.attributes.hold_time as usize,
-            )
-        };
-
-        if state == BGPState::Idle {
+        let status = n.status();
+        if status.state == BGPState::Idle {
             log::info!("FSM TimerKeepalive exiting due to Idle state");
             return Ok(());
         }
 
-        log::trace!("FSM TimerKeepalive incremented to {}", keepalive_timer);
+        // A KEEPALIVE from a previous session does not count
+        let since = status
+            .keepalive_received_at
+            .filter(|at| *at > started)
+            .unwrap_or(started);
+        let keepalive_timer = since.elapsed().as_secs();
+        log::trace!("FSM TimerKeepalive at {}", keepalive_timer);
 
-        if keepalive_timer > hold_time {
+        if keepalive_timer > status.hold_time as u64 {
             tx.send(Event::KeepaliveTimerExpires)
                 .await
                 .context("Failed to send KeepaliveTimerExpires event")?;
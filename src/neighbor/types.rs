@@ -1,5 +1,10 @@
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use super::session::AdjRibsIn;
+use crate::bfd;
 use crate::bgp::{self};
-use crate::rib;
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum BGPState {
@@ -42,7 +47,28 @@ pub enum Event {
     KeepAliveMsg,
     UpdateMsg,
     UpdateMsgErr,
-    RibUpdate(Vec<(bgp::Nlri, Option<rib::RouteAttributes>)>),
+    /// UPDATEs encoded by the update group of the neighbor.
+    Updates(Arc<Vec<Vec<u8>>>),
+    /// UPDATEs were left out as the mailbox was full, the session is reset to resync.
+    UpdatesOverflow,
+    MaxPrefixExceeded(bgp::AddressFamily),
+    /// Asks for a snapshot of the Adj-RIBs-In.
+    AdjRibIn(oneshot::Sender<AdjRibsIn>),
+    /// Lifts a maximum-prefix shutdown and restarts the session, replies false when the neighbor
+    /// was not shut down.
+    ClearMaxPrefix(oneshot::Sender<bool>),
+    /// The BFD session with the neighbor went down.
+    BfdDown(bfd::Diagnostic),
+    /// Applies the import policies again to the routes received.
+    SoftReconfigureInbound(oneshot::Sender<Result<(), SoftReconfigureError>>),
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoftReconfigureError {
+    #[error("Soft-reconfiguration inbound is not enabled for the neighbor")]
+    Disabled,
+    #[error("The routes received kept changing while the import policies ran")]
+    RoutesChanged,
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,625 @@
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::bgp::{self, Flatten};
+use crate::config::{self, PolicyAction};
+use crate::rib::{RibUpdate, RouteAttributes, DEFAULT_LOCAL_PREF};
+
+pub const COMMUNITY_NO_EXPORT: u32 = 0xFFFF_FF01;
+pub const COMMUNITY_NO_ADVERTISE: u32 = 0xFFFF_FF02;
+pub const COMMUNITY_NO_EXPORT_SUBCONFED: u32 = 0xFFFF_FF03;
+
+/// Parses `ASN:VALUE` or the name of a well-known community (RFC 1997).
+pub fn parse_community(s: &str) -> Result<u32> {
+    match s.to_lowercase().as_str() {
+        "no-export" => return Ok(COMMUNITY_NO_EXPORT),
+        "no-advertise" => return Ok(COMMUNITY_NO_ADVERTISE),
+        "no-export-subconfed" => return Ok(COMMUNITY_NO_EXPORT_SUBCONFED),
+        _ => {}
+    }
+    let (asn, value) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid community {}, expected ASN:VALUE", s))?;
+    let asn: u16 = asn
+        .parse()
+        .with_context(|| format!("Invalid community ASN in {}", s))?;
+    let value: u16 = value
+        .parse()
+        .with_context(|| format!("Invalid community value in {}", s))?;
+    Ok(((asn as u32) << 16) | value as u32)
+}
+
+fn parse_origin(s: &str) -> Result<bgp::OriginType> {
+    match s.to_lowercase().as_str() {
+        "igp" => Ok(bgp::OriginType::Igp),
+        "egp" => Ok(bgp::OriginType::Egp),
+        "incomplete" => Ok(bgp::OriginType::Incomplete),
+        _ => Err(anyhow!("Invalid origin {}", s)),
+    }
+}
+
+/// Accepts a prefix, or an address taken as a host prefix.
+fn parse_net(s: &str) -> Result<IpNet> {
+    if let Ok(net) = s.parse::<IpNet>() {
+        return Ok(net);
+    }
+    let ip: IpAddr = s
+        .parse()
+        .with_context(|| format!("Invalid address or prefix {}", s))?;
+    Ok(IpNet::from(ip))
+}
+
+#[derive(Debug)]
+struct PrefixListEntry {
+    action: PolicyAction,
+    prefix: IpNet,
+    ge: u8,
+    le: u8,
+}
+
+impl PrefixListEntry {
+    fn new(config: &config::PrefixListEntry) -> Result<Self> {
+        let prefix: IpNet = config
+            .prefix
+            .parse()
+            .with_context(|| format!("Invalid prefix {}", config.prefix))?;
+        let prefix = prefix.trunc();
+        let (ge, le) = match (config.ge, config.le) {
+            (None, None) => (prefix.prefix_len(), prefix.prefix_len()),
+            (Some(ge), None) => (ge, prefix.max_prefix_len()),
+            (None, Some(le)) => (prefix.prefix_len(), le),
+            (Some(ge), Some(le)) => (ge, le),
+        };
+        if prefix.prefix_len() > ge || ge > le || le > prefix.max_prefix_len() {
+            return Err(anyhow!(
+                "Invalid length range ge {} le {} for {}",
+                ge,
+                le,
+                prefix
+            ));
+        }
+        Ok(PrefixListEntry {
+            action: config.action,
+            prefix,
+            ge,
+            le,
+        })
+    }
+
+    fn matches(&self, net: &IpNet) -> bool {
+        self.prefix.contains(net) && (self.ge..=self.le).contains(&net.prefix_len())
+    }
+}
+
+/// Ordered prefix list. The first matching entry decides and unmatched prefixes are denied.
+#[derive(Debug)]
+pub struct PrefixList {
+    pub name: String,
+    entries: Vec<PrefixListEntry>,
+}
+
+impl PrefixList {
+    pub fn new(config: &config::PrefixList) -> Result<Self> {
+        let entries = config
+            .entries
+            .iter()
+            .map(PrefixListEntry::new)
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Invalid prefix list {}", config.name))?;
+        Ok(PrefixList {
+            name: config.name.clone(),
+            entries,
+        })
+    }
+
+    pub fn permits(&self, net: &IpNet) -> bool {
+        self.entries
+            .iter()
+            .find(|e| e.matches(net))
+            .is_some_and(|e| e.action == PolicyAction::Permit)
+    }
+}
+
+/// Regular expression over AS numbers, e.g. `65000-65099`, matching whole ASNs only.
+fn asn_range_regex(lo: u32, hi: u32) -> String {
+    fn same_len(lo: &[u8], hi: &[u8]) -> Vec<String> {
+        let (l, h) = (lo[0] as char, hi[0] as char);
+        if lo.len() == 1 {
+            return vec![if l == h {
+                l.to_string()
+            } else {
+                format!("[{}-{}]", l, h)
+            }];
+        }
+        let rest = lo.len() - 1;
+        if lo[1..].iter().all(|&d| d == b'0') && hi[1..].iter().all(|&d| d == b'9') {
+            return vec![format!("[{}-{}][0-9]{{{}}}", l, h, rest)];
+        }
+        if l == h {
+            return same_len(&lo[1..], &hi[1..])
+                .into_iter()
+                .map(|p| format!("{}{}", l, p))
+                .collect();
+        }
+        let nines = vec![b'9'; rest];
+        let zeros = vec![b'0'; rest];
+        let mut parts: Vec<String> = same_len(&lo[1..], &nines)
+            .into_iter()
+            .map(|p| format!("{}{}", l, p))
+            .collect();
+        if hi[0] - lo[0] > 1 {
+            parts.push(format!(
+                "[{}-{}][0-9]{{{}}}",
+                (lo[0] + 1) as char,
+                (hi[0] - 1) as char,
+                rest
+            ));
+        }
+        parts.extend(
+            same_len(&zeros, &hi[1..])
+                .into_iter()
+                .map(|p| format!("{}{}", h, p)),
+        );
+        parts
+    }
+
+    let mut parts = vec![];
+    let mut start = lo;
+    while start <= hi {
+        let digits = start.to_string().len() as u32;
+        let end = hi.min(10u32.pow(digits) - 1);
+        parts.extend(same_len(
+            start.to_string().as_bytes(),
+            end.to_string().as_bytes(),
+        ));
+        start = end + 1;
+    }
+    format!(r"\b(?:{})\b", parts.join("|"))
+}
+
+/// Cisco/Juniper style AS path regular expression, matched against the textual AS path as
+/// produced by `Flatten::aspath_string`.
+///
+/// `_` matches a delimiter (start, end, space, comma or brace), `N-M` matches any ASN in the
+/// range and a brace following a delimiter is a literal AS_SET brace rather than a repetition.
+#[derive(Debug, Clone)]
+pub struct AsPathRegex {
+    regex: Regex,
+}
+
+impl AsPathRegex {
+    pub fn new(expression: &str) -> Result<Self> {
+        let translated = AsPathRegex::translate(expression)?;
+        let regex = Regex::new(&translated)
+            .with_context(|| format!("Invalid AS path regular expression {}", expression))?;
+        Ok(AsPathRegex { regex })
+    }
+
+    fn translate(expression: &str) -> Result<String> {
+        let chars: Vec<char> = expression.chars().collect();
+        let mut out = String::new();
+        let mut in_class = false;
+        let mut in_set = false;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if in_class {
+                out.push(c);
+                if c == '\\' && i + 1 < chars.len() {
+                    out.push(chars[i + 1]);
+                    i += 1;
+                } else if c == ']' {
+                    in_class = false;
+                }
+                i += 1;
+                continue;
+            }
+            match c {
+                '\\' => {
+                    out.push(c);
+                    if let Some(next) = chars.get(i + 1) {
+                        out.push(*next);
+                        i += 1;
+                    }
+                }
+                '[' => {
+                    in_class = true;
+                    out.push(c);
+                }
+                '_' => out.push_str(r"(?:^|$|[ ,{}])"),
+                '{' => {
+                    let literal = match out.chars().last() {
+                        None => true,
+                        Some(prev) => "^|( ".contains(prev) || out.ends_with(r"[ ,{}])"),
+                    };
+                    if literal {
+                        in_set = true;
+                        out.push_str(r"\{");
+                    } else {
+                        // Repetition quantifier, copied as is
+                        let end = chars[i..]
+                            .iter()
+                            .position(|&c| c == '}')
+                            .ok_or_else(|| anyhow!("Unbalanced brace in {}", expression))?;
+                        out.extend(&chars[i..=i + end]);
+                        i += end;
+                    }
+                }
+                '}' if in_set => {
+                    in_set = false;
+                    out.push_str(r"\}");
+                }
+                ',' if in_set => out.push(','),
+                '0'..='9' => {
+                    let start = i;
+                    while i + 1 < chars.len() && chars[i + 1].is_ascii_digit() {
+                        i += 1;
+                    }
+                    let lo: String = chars[start..=i].iter().collect();
+                    let hi_len = chars[i + 1..]
+                        .iter()
+                        .skip(1)
+                        .take_while(|c| c.is_ascii_digit())
+                        .count();
+                    if chars.get(i + 1) == Some(&'-') && hi_len > 0 {
+                        let hi: String = chars[i + 2..i + 2 + hi_len].iter().collect();
+                        let (lo, hi): (u32, u32) = (lo.parse()?, hi.parse()?);
+                        if lo > hi || hi > u16::MAX as u32 {
+                            return Err(anyhow!("Invalid ASN range {}-{}", lo, hi));
+                        }
+                        out.push_str(&asn_range_regex(lo, hi));
+                        i += 1 + hi_len;
+                    } else {
+                        out.push_str(&lo);
+                    }
+                }
+                _ => out.push(c),
+            }
+            i += 1;
+        }
+        Ok(out)
+    }
+
+    pub fn is_match(&self, as_path: &bgp::Aspath) -> bool {
+        self.regex.is_match(&as_path.aspath_string())
+    }
+}
+
+#[derive(Debug)]
+struct AsPathListEntry {
+    action: PolicyAction,
+    regex: AsPathRegex,
+}
+
+/// Ordered AS path access list. The first matching expression decides and unmatched paths are
+/// denied.
+#[derive(Debug)]
+pub struct AsPathList {
+    entries: Vec<AsPathListEntry>,
+}
+
+impl AsPathList {
+    pub fn new(config: &config::AsPathList) -> Result<Self> {
+        let entries = config
+            .entries
+            .iter()
+            .map(|e| {
+                Ok(AsPathListEntry {
+                    action: e.action,
+                    regex: AsPathRegex::new(&e.regex)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Invalid AS path list {}", config.name))?;
+        Ok(AsPathList { entries })
+    }
+
+    pub fn permits(&self, as_path: &bgp::Aspath) -> bool {
+        let as_path = as_path.aspath_string();
+        self.entries
+            .iter()
+            .find(|e| e.regex.regex.is_match(&as_path))
+            .is_some_and(|e| e.action == PolicyAction::Permit)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Match {
+    prefix_list: Option<Arc<PrefixList>>,
+    as_path_list: Option<Arc<AsPathList>>,
+    next_hop: Option<IpNet>,
+    as_path_contains: Option<u16>,
+    origin: Option<bgp::OriginType>,
+    med: Option<u32>,
+    local_pref: Option<u32>,
+    community: Option<u32>,
+}
+
+impl Match {
+    fn new(config: &config::RouteMapMatch, policies: &Policies) -> Result<Self> {
+        let prefix_list = match &config.prefix_list {
+            None => None,
+            Some(name) => Some(policies.prefix_list(name)?),
+        };
+        let as_path_list = match &config.as_path_list {
+            None => None,
+            Some(name) => Some(
+                policies
+                    .as_path_lists
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Unknown AS path list {}", name))?,
+            ),
+        };
+        Ok(Match {
+            prefix_list,
+            as_path_list,
+            next_hop: config.next_hop.as_deref().map(parse_net).transpose()?,
+            as_path_contains: config.as_path_contains,
+            origin: config.origin.as_deref().map(parse_origin).transpose()?,
+            med: config.med,
+            local_pref: config.local_pref,
+            community: config
+                .community
+                .as_deref()
+                .map(parse_community)
+                .transpose()?,
+        })
+    }
+
+    fn matches(&self, nlri: &bgp::Nlri, attributes: &RouteAttributes) -> bool {
+        if let Some(pl) = &self.prefix_list {
+            if !pl.permits(&nlri.net) {
+                log::debug!("Prefix list {} does not permit {}", pl.name, nlri.net);
+                return false;
+            }
+        }
+        if let Some(apl) = &self.as_path_list {
+            if !apl.permits(&attributes.attrs.as_path) {
+                return false;
+            }
+        }
+        if let Some(net) = &self.next_hop {
+            if !net.contains(&attributes.attrs.next_hop) {
+                return false;
+            }
+        }
+        if let Some(asn) = self.as_path_contains {
+            if !attributes.attrs.as_path.flatten_aspath().contains(&asn) {
+                return false;
+            }
+        }
+        if let Some(origin) = &self.origin {
+            if *origin != attributes.attrs.origin {
+                return false;
+            }
+        }
+        if self.med.is_some() && self.med != attributes.attrs.multi_exit_disc {
+            return false;
+        }
+        if self
+            .local_pref
+            .is_some_and(|lp| lp != attributes.attrs.local_pref.unwrap_or(DEFAULT_LOCAL_PREF))
+        {
+            return false;
+        }
+        if let Some(community) = self.community {
+            if !attributes.attrs.communities.contains(&community) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct Set {
+    local_pref: Option<u32>,
+    med: Option<u32>,
+    next_hop: Option<IpAddr>,
+    community: Option<Vec<u32>>,
+    community_additive: bool,
+    as_path_prepend: Vec<u16>,
+    weight: Option<u32>,
+}
+
+impl Set {
+    fn new(config: &config::RouteMapSet) -> Result<Self> {
+        let community = match &config.community {
+            None => None,
+            Some(c) => Some(
+                c.iter()
+                    .map(|c| parse_community(c))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+        };
+        Ok(Set {
+            local_pref: config.local_pref,
+            med: config.med,
+            next_hop: config.next_hop,
+            community,
+            community_additive: config.community_additive,
+            as_path_prepend: config.as_path_prepend.clone(),
+            weight: config.weight,
+        })
+    }
+
+    fn apply(&self, attributes: &mut RouteAttributes) {
+        if let Some(lp) = self.local_pref {
+            attributes.attrs_mut().local_pref = Some(lp);
+        }
+        if let Some(med) = self.med {
+            attributes.attrs_mut().multi_exit_disc = Some(med);
+        }
+        if let Some(nh) = self.next_hop {
+            attributes.attrs_mut().next_hop = nh;
+        }
+        if let Some(communities) = &self.community {
+            let attrs = attributes.attrs_mut();
+            if !self.community_additive {
+                attrs.communities.clear();
+            }
+            for c in communities {
+                if !attrs.communities.contains(c) {
+                    attrs.communities.push(*c);
+                }
+            }
+        }
+        for asn in self.as_path_prepend.iter().rev() {
+            attributes.prepend(*asn, 1);
+        }
+        if let Some(weight) = self.weight {
+            attributes.weight = weight;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RouteMapEntry {
+    action: PolicyAction,
+    match_: Match,
+    set: Set,
+}
+
+#[derive(Debug)]
+pub struct RouteMap {
+    pub name: String,
+    entries: Vec<RouteMapEntry>,
+}
+
+impl RouteMap {
+    pub fn new(config: &config::RouteMap, policies: &Policies) -> Result<Self> {
+        let entries = config
+            .entries
+            .iter()
+            .map(|e| {
+                Ok(RouteMapEntry {
+                    action: e.action,
+                    match_: Match::new(&e.match_, policies)?,
+                    set: Set::new(&e.set)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Invalid route map {}", config.name))?;
+        Ok(RouteMap {
+            name: config.name.clone(),
+            entries,
+        })
+    }
+
+    /// Returns the route as modified by the first matching entry, or `None` when it is rejected.
+    pub fn apply(&self, nlri: &bgp::Nlri, attributes: &RouteAttributes) -> Option<RouteAttributes> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|e| e.match_.matches(nlri, attributes))?;
+        match entry.action {
+            PolicyAction::Deny => None,
+            PolicyAction::Permit => {
+                let mut attributes = attributes.clone();
+                entry.set.apply(&mut attributes);
+                Some(attributes)
+            }
+        }
+    }
+
+    /// Applies the route map to every prefix of an update. Accepted prefixes are grouped by
+    /// resulting attributes, rejected ones are returned separately.
+    pub fn apply_update(&self, update: RibUpdate) -> (Vec<RibUpdate>, Vec<bgp::Nlri>) {
+        let mut accepted: Vec<RibUpdate> = vec![];
+        let mut rejected = vec![];
+        for nlri in update.nlris {
+            match self.apply(&nlri, &update.attributes) {
+                None => rejected.push(nlri),
+                Some(attributes) => {
+                    match accepted
+                        .iter_mut()
+                        .find(|u| u.attributes.is_same_path(&attributes))
+                    {
+                        Some(u) => u.nlris.push(nlri),
+                        None => accepted.push(RibUpdate {
+                            nlris: vec![nlri],
+                            attributes,
+                        }),
+                    }
+                }
+            }
+        }
+        (accepted, rejected)
+    }
+}
+
+/// Import and export route maps of a neighbor for one address family.
+#[derive(Debug, Clone, Default)]
+pub struct NeighborPolicy {
+    pub import: Option<Arc<RouteMap>>,
+    pub export: Option<Arc<RouteMap>>,
+}
+
+/// Every prefix list, AS path list and route map defined in the configuration, by name.
+#[derive(Debug, Default)]
+pub struct Policies {
+    prefix_lists: HashMap<String, Arc<PrefixList>>,
+    as_path_lists: HashMap<String, Arc<AsPathList>>,
+    route_maps: HashMap<String, Arc<RouteMap>>,
+}
+
+impl Policies {
+    pub fn new(
+        prefix_lists: &[config::PrefixList],
+        as_path_lists: &[config::AsPathList],
+        route_maps: &[config::RouteMap],
+    ) -> Result<Self> {
+        let mut policies = Policies::default();
+        for pl in prefix_lists {
+            policies
+                .prefix_lists
+                .insert(pl.name.clone(), Arc::new(PrefixList::new(pl)?));
+        }
+        for apl in as_path_lists {
+            policies
+                .as_path_lists
+                .insert(apl.name.clone(), Arc::new(AsPathList::new(apl)?));
+        }
+        for rm in route_maps {
+            let route_map = RouteMap::new(rm, &policies)?;
+            policies
+                .route_maps
+                .insert(rm.name.clone(), Arc::new(route_map));
+        }
+        Ok(policies)
+    }
+
+    pub fn prefix_list(&self, name: &str) -> Result<Arc<PrefixList>> {
+        self.prefix_lists
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown prefix list {}", name))
+    }
+
+    pub fn route_map(&self, name: &str) -> Result<Arc<RouteMap>> {
+        self.route_maps
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown route map {}", name))
+    }
+
+    pub fn neighbor_policy(&self, config: &config::NeighborPolicy) -> Result<NeighborPolicy> {
+        Ok(NeighborPolicy {
+            import: config
+                .import
+                .as_deref()
+                .map(|name| self.route_map(name))
+                .transpose()?,
+            export: config
+                .export
+                .as_deref()
+                .map(|name| self.route_map(name))
+                .transpose()?,
+        })
+    }
+}
@@ -0,0 +1,368 @@
+use std::sync::Arc;
+
+use crate::bgp::{ASPATHSegment, ASPATHSegmentType, Aspath, Nlri, OriginType};
+use crate::config::{self, PolicyAction};
+use crate::policy::*;
+use crate::rib::{AttributeSet, RibUpdate, RouteAttributes};
+
+fn nlri(s: &str) -> Nlri {
+    Nlri {
+        net: s.parse().unwrap(),
+    }
+}
+
+fn prefix_list_entry(
+    action: PolicyAction,
+    prefix: &str,
+    ge: Option<u8>,
+    le: Option<u8>,
+) -> config::PrefixListEntry {
+    config::PrefixListEntry {
+        action,
+        prefix: prefix.to_string(),
+        ge,
+        le,
+    }
+}
+
+fn policies(route_map: Vec<config::RouteMapEntry>) -> Policies {
+    let prefix_lists = vec![config::PrefixList {
+        name: "CUSTOMERS".to_string(),
+        entries: vec![
+            prefix_list_entry(PolicyAction::Deny, "10.1.0.0/16", Some(16), Some(32)),
+            prefix_list_entry(PolicyAction::Permit, "10.0.0.0/8", Some(16), Some(24)),
+        ],
+    }];
+    let route_maps = vec![config::RouteMap {
+        name: "IMPORT".to_string(),
+        entries: route_map,
+    }];
+    Policies::new(&prefix_lists, &[], &route_maps).unwrap()
+}
+
+fn attributes() -> RouteAttributes {
+    RouteAttributes {
+        attrs: Arc::new(AttributeSet {
+            next_hop: "192.0.2.1".parse().unwrap(),
+            as_path: vec![ASPATHSegment {
+                segment_type: ASPATHSegmentType::AsSequence,
+                as_list: vec![65001, 65002],
+            }],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_prefix_list_length_range() {
+    let pl = config::PrefixList {
+        name: "PL".to_string(),
+        entries: vec![
+            prefix_list_entry(PolicyAction::Permit, "192.0.2.0/24", None, None),
+            prefix_list_entry(PolicyAction::Permit, "10.0.0.0/8", Some(16), None),
+            prefix_list_entry(PolicyAction::Permit, "172.16.0.0/12", None, Some(16)),
+        ],
+    };
+    let pl = PrefixList::new(&pl).unwrap();
+
+    assert!(pl.permits(&"192.0.2.0/24".parse().unwrap()));
+    assert!(!pl.permits(&"192.0.2.0/25".parse().unwrap()));
+    assert!(!pl.permits(&"10.0.0.0/8".parse().unwrap()));
+    assert!(pl.permits(&"10.0.0.0/16".parse().unwrap()));
+    assert!(pl.permits(&"10.0.0.1/32".parse().unwrap()));
+    assert!(pl.permits(&"172.16.0.0/12".parse().unwrap()));
+    assert!(!pl.permits(&"172.16.0.0/20".parse().unwrap()));
+    assert!(!pl.permits(&"2001:db8::/32".parse().unwrap()));
+}
+
+#[test]
+fn test_prefix_list_invalid_range() {
+    let pl = config::PrefixList {
+        name: "PL".to_string(),
+        entries: vec![prefix_list_entry(
+            PolicyAction::Permit,
+            "10.0.0.0/16",
+            Some(8),
+            None,
+        )],
+    };
+    assert!(PrefixList::new(&pl).is_err());
+}
+
+#[test]
+fn test_parse_community() {
+    assert_eq!(parse_community("65000:100").unwrap(), 0xFDE8_0064);
+    assert_eq!(parse_community("no-export").unwrap(), COMMUNITY_NO_EXPORT);
+    assert!(parse_community("65000").is_err());
+    assert!(parse_community("70000:1").is_err());
+}
+
+#[test]
+fn test_route_map_first_match_and_set() {
+    let p = policies(vec![
+        config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                prefix_list: Some("CUSTOMERS".to_string()),
+                as_path_contains: Some(65002),
+                ..Default::default()
+            },
+            set: config::RouteMapSet {
+                local_pref: Some(200),
+                community: Some(vec!["65000:1".to_string()]),
+                as_path_prepend: vec![65001, 65001],
+                ..Default::default()
+            },
+        },
+        config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                origin: Some("igp".to_string()),
+                ..Default::default()
+            },
+            set: config::RouteMapSet {
+                med: Some(50),
+                ..Default::default()
+            },
+        },
+    ]);
+    let rm = p.route_map("IMPORT").unwrap();
+
+    let a = rm.apply(&nlri("10.2.0.0/16"), &attributes()).unwrap();
+    assert_eq!(a.attrs.local_pref, Some(200));
+    assert_eq!(a.attrs.multi_exit_disc, None);
+    assert_eq!(a.attrs.communities, vec![0xFDE8_0001]);
+    assert_eq!(a.attrs.as_path[0].as_list, vec![65001, 65001, 65001, 65002]);
+
+    // Denied by the prefix list, falls through to the second entry
+    let a = rm.apply(&nlri("10.1.0.0/16"), &attributes()).unwrap();
+    assert_eq!(a.attrs.local_pref, None);
+    assert_eq!(a.attrs.multi_exit_disc, Some(50));
+
+    let mut incomplete = attributes();
+    incomplete.attrs_mut().origin = OriginType::Incomplete;
+    assert!(rm.apply(&nlri("10.1.0.0/16"), &incomplete).is_none());
+}
+
+#[test]
+fn test_route_map_local_pref_default() {
+    let p = policies(vec![config::RouteMapEntry {
+        action: PolicyAction::Permit,
+        match_: config::RouteMapMatch {
+            local_pref: Some(100),
+            ..Default::default()
+        },
+        set: Default::default(),
+    }]);
+    let rm = p.route_map("IMPORT").unwrap();
+
+    // A path without LOCAL_PREF has the default one
+    let mut a = attributes();
+    assert!(rm.apply(&nlri("10.2.0.0/16"), &a).is_some());
+    a.attrs_mut().local_pref = Some(200);
+    assert!(rm.apply(&nlri("10.2.0.0/16"), &a).is_none());
+}
+
+#[test]
+fn test_route_map_deny() {
+    let p = policies(vec![
+        config::RouteMapEntry {
+            action: PolicyAction::Deny,
+            match_: config::RouteMapMatch {
+                next_hop: Some("192.0.2.0/24".to_string()),
+                ..Default::default()
+            },
+            set: Default::default(),
+        },
+        config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: Default::default(),
+            set: Default::default(),
+        },
+    ]);
+    let rm = p.route_map("IMPORT").unwrap();
+
+    assert!(rm.apply(&nlri("10.2.0.0/16"), &attributes()).is_none());
+
+    let mut other = attributes();
+    other.attrs_mut().next_hop = "198.51.100.1".parse().unwrap();
+    assert!(rm
+        .apply(&nlri("10.2.0.0/16"), &other)
+        .is_some_and(|ra| ra.is_same_path(&other)));
+}
+
+#[test]
+fn test_route_map_apply_update() {
+    let p = policies(vec![
+        config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                prefix_list: Some("CUSTOMERS".to_string()),
+                ..Default::default()
+            },
+            set: config::RouteMapSet {
+                local_pref: Some(200),
+                ..Default::default()
+            },
+        },
+        config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                community: Some("no-export".to_string()),
+                ..Default::default()
+            },
+            set: Default::default(),
+        },
+    ]);
+    let rm = p.route_map("IMPORT").unwrap();
+    let mut a = attributes();
+    a.attrs_mut().communities = vec![COMMUNITY_NO_EXPORT];
+
+    let (accepted, rejected) = rm.apply_update(RibUpdate {
+        nlris: vec![
+            nlri("10.2.0.0/16"),
+            nlri("10.1.0.0/16"),
+            nlri("10.3.0.0/16"),
+        ],
+        attributes: a,
+    });
+    assert!(rejected.is_empty());
+    assert_eq!(accepted.len(), 2);
+    assert_eq!(
+        accepted[0].nlris,
+        vec![nlri("10.2.0.0/16"), nlri("10.3.0.0/16")]
+    );
+    assert_eq!(accepted[1].nlris, vec![nlri("10.1.0.0/16")]);
+
+    let (accepted, rejected) = rm.apply_update(RibUpdate {
+        nlris: vec![nlri("10.1.0.0/16")],
+        attributes: attributes(),
+    });
+    assert!(accepted.is_empty());
+    assert_eq!(rejected, vec![nlri("10.1.0.0/16")]);
+}
+
+#[test]
+fn test_policies_unknown_references() {
+    let route_maps = vec![config::RouteMap {
+        name: "IMPORT".to_string(),
+        entries: vec![config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                prefix_list: Some("MISSING".to_string()),
+                ..Default::default()
+            },
+            set: Default::default(),
+        }],
+    }];
+    assert!(Policies::new(&[], &[], &route_maps).is_err());
+    assert!(Policies::default().route_map("IMPORT").is_err());
+}
+
+fn aspath(sequence: Vec<u16>, set: Vec<u16>) -> Aspath {
+    let mut aspath = vec![ASPATHSegment {
+        segment_type: ASPATHSegmentType::AsSequence,
+        as_list: sequence,
+    }];
+    if !set.is_empty() {
+        aspath.push(ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsSet,
+            as_list: set,
+        });
+    }
+    aspath
+}
+
+#[test]
+fn test_aspath_regex_anchors_and_delimiters() {
+    let path = aspath(vec![65001, 650, 65002], vec![]);
+
+    assert!(AsPathRegex::new("^65001_").unwrap().is_match(&path));
+    assert!(!AsPathRegex::new("^650_").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("_650_").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("_65002$").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("^65001_650_65002$")
+        .unwrap()
+        .is_match(&path));
+    assert!(AsPathRegex::new("^$").unwrap().is_match(&Aspath::new()));
+    assert!(AsPathRegex::new(".*").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("^6500[0-2]_").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("^([0-9]+_){3}$").is_ok());
+}
+
+#[test]
+fn test_aspath_regex_asn_range() {
+    let path = aspath(vec![64999, 65050], vec![]);
+
+    assert!(AsPathRegex::new("_65000-65099$").unwrap().is_match(&path));
+    assert!(!AsPathRegex::new("^65000-65099_").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("^9-64999_").unwrap().is_match(&path));
+    assert!(!AsPathRegex::new("_650-651_").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("_65100-65000_").is_err());
+    assert!(AsPathRegex::new("_1-70000_").is_err());
+
+    for (lo, hi) in [(0, 9), (7, 123), (99, 1000), (1234, 56789)] {
+        let re = AsPathRegex::new(&format!("^{}-{}$", lo, hi)).unwrap();
+        for asn in [
+            0u16, 5, 7, 9, 10, 99, 100, 123, 124, 999, 1000, 1233, 1234, 56789, 56790,
+        ] {
+            let expected = (lo..=hi).contains(&asn);
+            assert_eq!(
+                re.is_match(&aspath(vec![asn], vec![])),
+                expected,
+                "{} in {}-{}",
+                asn,
+                lo,
+                hi
+            );
+        }
+    }
+}
+
+#[test]
+fn test_aspath_regex_as_set() {
+    let path = aspath(vec![65001], vec![65003, 65004]);
+
+    assert!(AsPathRegex::new("_{65003,65004}$").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("_65004_").unwrap().is_match(&path));
+    assert!(!AsPathRegex::new("^{").unwrap().is_match(&path));
+    assert!(AsPathRegex::new("_{.*}$").unwrap().is_match(&path));
+}
+
+#[test]
+fn test_route_map_as_path_list() {
+    let as_path_lists = vec![config::AsPathList {
+        name: "FROM-65002".to_string(),
+        entries: vec![
+            config::AsPathListEntry {
+                action: PolicyAction::Deny,
+                regex: "_65003_".to_string(),
+            },
+            config::AsPathListEntry {
+                action: PolicyAction::Permit,
+                regex: "_65002$".to_string(),
+            },
+        ],
+    }];
+    let route_maps = vec![config::RouteMap {
+        name: "IMPORT".to_string(),
+        entries: vec![config::RouteMapEntry {
+            action: PolicyAction::Permit,
+            match_: config::RouteMapMatch {
+                as_path_list: Some("FROM-65002".to_string()),
+                ..Default::default()
+            },
+            set: Default::default(),
+        }],
+    }];
+    let p = Policies::new(&[], &as_path_lists, &route_maps).unwrap();
+    let rm = p.route_map("IMPORT").unwrap();
+
+    let mut a = attributes();
+    assert!(rm.apply(&nlri("10.2.0.0/16"), &a).is_some());
+    a.attrs_mut().as_path = aspath(vec![65001, 65003, 65002], vec![]);
+    assert!(rm.apply(&nlri("10.2.0.0/16"), &a).is_none());
+    a.attrs_mut().as_path = aspath(vec![65002, 65001], vec![]);
+    assert!(rm.apply(&nlri("10.2.0.0/16"), &a).is_none());
+}
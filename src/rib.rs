@@ -1,28 +1,151 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::net::IpAddr;
-use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, LazyLock};
 use std::time::Instant;
-use tokio::sync::Mutex;
 
 use crate::bgp::Flatten;
 use crate::bgp::{self, PathAttribute};
+use crate::config;
+use crate::fib;
 use crate::neighbor;
+use crate::trie;
 
-#[derive(Debug, Eq, Clone)]
-pub struct RouteAttributes {
-    as_path: bgp::Aspath,
-    origin: bgp::OriginType,
+/// LOCAL_PREF assumed for paths that do not carry one.
+pub const DEFAULT_LOCAL_PREF: u32 = 100;
+
+/// Path attributes of a route. They are hash-consed, routes with the same attributes share a
+/// single copy.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct AttributeSet {
+    pub as_path: bgp::Aspath,
+    pub origin: bgp::OriginType,
     pub next_hop: IpAddr,
-    local_pref: Option<u32>,
-    multi_exit_disc: Option<u32>,
-    path_type: PathType,
-    peer_type: PeeringType,
-    recv_time: Instant,
+    pub local_pref: Option<u32>,
+    pub multi_exit_disc: Option<u32>,
+    pub communities: Vec<u32>,
+    /// Router ID of the speaker that brought the path into the AS, set by route reflectors.
+    pub originator_id: Option<u32>,
+    /// Clusters the path was reflected through, the last one first.
+    pub cluster_list: Vec<u32>,
+}
+
+impl Default for AttributeSet {
+    fn default() -> Self {
+        AttributeSet {
+            as_path: Vec::new(),
+            origin: bgp::OriginType::Igp,
+            next_hop: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            local_pref: None,
+            multi_exit_disc: None,
+            communities: Vec::new(),
+            originator_id: None,
+            cluster_list: Vec::new(),
+        }
+    }
+}
+
+impl AttributeSet {
+    /// Bytes used by these attributes, on the heap included.
+    pub fn size(&self) -> usize {
+        size_of::<Self>()
+            + self.as_path.capacity() * size_of::<bgp::ASPATHSegment>()
+            + self
+                .as_path
+                .iter()
+                .map(|s| s.as_list.capacity() * size_of::<u16>())
+                .sum::<usize>()
+            + (self.communities.capacity() + self.cluster_list.capacity()) * size_of::<u32>()
+    }
+}
+
+/// Sets in the interner that trigger dropping those no route uses anymore.
+const INTERNER_MIN_PURGE: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Interner {
+    sets: HashSet<Arc<AttributeSet>>,
+    purge_at: usize,
+}
+
+/// Distinct attribute sets of the routes of every RIB.
+static ATTRIBUTE_SETS: LazyLock<std::sync::Mutex<Interner>> =
+    LazyLock::new(|| std::sync::Mutex::new(Interner::default()));
+
+/// Shared copy of `attributes`.
+pub fn intern(attributes: Arc<AttributeSet>) -> Arc<AttributeSet> {
+    let mut interner = ATTRIBUTE_SETS
+        .lock()
+        .expect("BUG: Attribute interner lock poisoned");
+    if let Some(shared) = interner.sets.get(&attributes) {
+        return shared.clone();
+    }
+    // The interner holding the only reference means no route uses the set anymore.
+    if interner.sets.len() >= interner.purge_at {
+        interner.sets.retain(|a| Arc::strong_count(a) > 1);
+        interner.purge_at = (interner.sets.len() * 2).max(INTERNER_MIN_PURGE);
+    }
+    interner.sets.insert(attributes.clone());
+    attributes
+}
+
+/// Number of distinct attribute sets in use and the bytes they use.
+pub fn interned() -> (usize, usize) {
+    let mut interner = ATTRIBUTE_SETS
+        .lock()
+        .expect("BUG: Attribute interner lock poisoned");
+    interner.sets.retain(|a| Arc::strong_count(a) > 1);
+    let bytes = interner.sets.iter().map(|a| a.size()).sum();
+    (interner.sets.len(), bytes)
+}
+
+/// Memory used by a RIB.
+#[derive(Debug, Default, PartialEq)]
+pub struct RibMemory {
+    pub prefixes: usize,
+    pub paths: usize,
+    /// Distinct attribute sets of the paths.
+    pub attribute_sets: usize,
+    /// Estimate including the attribute sets, even when other RIBs share them.
+    pub bytes: usize,
+}
+
+pub fn memory(rib: &Rib) -> RibMemory {
+    let mut sets = HashSet::new();
+    let mut memory = RibMemory {
+        prefixes: rib.len(),
+        bytes: rib.size(),
+        ..Default::default()
+    };
+    for attributes in rib.values() {
+        memory.paths += attributes.len();
+        memory.bytes += attributes.capacity() * size_of::<RouteAttributes>();
+        for a in attributes {
+            if sets.insert(Arc::as_ptr(&a.attrs)) {
+                memory.bytes += a.attrs.size();
+            }
+        }
+    }
+    memory.attribute_sets = sets.len();
+    memory
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteAttributes {
+    pub attrs: Arc<AttributeSet>,
+    pub weight: u32,
+    pub path_type: PathType,
+    pub peer_type: PeeringType,
+    pub recv_time: Instant,
     pub peer_rid: u32,
-    peer_ip: IpAddr,
+    pub peer_ip: IpAddr,
+    pub suppressed: bool,
+    /// Part of the equal-cost paths installed in the FIB for the prefix.
+    pub multipath: bool,
+    /// Learned from an iBGP neighbor that is a route reflector client.
+    pub from_client: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -34,28 +157,110 @@ pub struct RibUpdate {
 impl Default for RouteAttributes {
     fn default() -> Self {
         RouteAttributes {
-            as_path: Vec::new(),
-            origin: bgp::OriginType::Igp,
-            next_hop: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-            local_pref: None,
-            multi_exit_disc: None,
+            attrs: Arc::new(AttributeSet::default()),
+            weight: 0,
             path_type: PathType::External,
             peer_type: PeeringType::Ebgp,
             recv_time: Instant::now(),
             peer_rid: 0,
             peer_ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            suppressed: false,
+            multipath: false,
+            from_client: false,
         }
     }
 }
 
 impl RouteAttributes {
-    pub fn prepend(&mut self, asn: u16, times: u8) -> bgp::Aspath {
-        let sequence = bgp::ASPATHSegment {
-            segment_type: bgp::ASPATHSegmentType::AsSequence,
-            as_list: vec![asn; times.into()],
+    /// Path of a prefix the speaker `router_id` originates. Its next hop is left unspecified,
+    /// every neighbor gets the local address of its session instead.
+    pub fn local(router_id: u32, afi: &bgp::Afi) -> Self {
+        let unspecified = match afi {
+            bgp::Afi::Ipv4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bgp::Afi::Ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
         };
-        self.as_path.insert(0, sequence);
-        self.as_path.clone()
+        RouteAttributes {
+            attrs: Arc::new(AttributeSet {
+                next_hop: unspecified,
+                ..Default::default()
+            }),
+            path_type: PathType::Local,
+            // Advertised to the iBGP neighbors whether they are route reflector clients or not
+            peer_type: PeeringType::Ebgp,
+            peer_rid: router_id,
+            peer_ip: unspecified,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.path_type == PathType::Local
+    }
+
+    /// Attributes of this route only, copied first when other routes share them.
+    pub fn attrs_mut(&mut self) -> &mut AttributeSet {
+        Arc::make_mut(&mut self.attrs)
+    }
+
+    /// Shares the attributes with the other routes that have the same.
+    pub fn intern(&mut self) {
+        self.attrs = intern(self.attrs.clone());
+    }
+
+    pub fn prepend(&mut self, asn: u16, times: u8) -> bgp::Aspath {
+        self.prepend_segment(bgp::ASPATHSegmentType::AsSequence, asn, times)
+    }
+
+    /// Prepends the member AS of the confederation, when advertising to another member AS.
+    pub fn prepend_confed(&mut self, asn: u16) -> bgp::Aspath {
+        self.prepend_segment(bgp::ASPATHSegmentType::AsConfedSequence, asn, 1)
+    }
+
+    fn prepend_segment(
+        &mut self,
+        segment_type: bgp::ASPATHSegmentType,
+        asn: u16,
+        times: u8,
+    ) -> bgp::Aspath {
+        let times = times as usize;
+        let attrs = self.attrs_mut();
+        match attrs.as_path.first_mut() {
+            Some(segment)
+                if segment.segment_type == segment_type
+                    && segment.as_list.len() + times <= u8::MAX as usize =>
+            {
+                segment
+                    .as_list
+                    .splice(0..0, std::iter::repeat_n(asn, times));
+            }
+            _ => {
+                let sequence = bgp::ASPATHSegment {
+                    segment_type,
+                    as_list: vec![asn; times],
+                };
+                attrs.as_path.insert(0, sequence);
+            }
+        }
+        attrs.as_path.clone()
+    }
+
+    /// Replaces every occurrence of `asn` in the AS path with `by`.
+    pub fn replace_as(&mut self, asn: u16, by: u16) {
+        if !self.attrs.as_path.flatten_aspath().contains(&asn) {
+            return;
+        }
+        for segment in self.attrs_mut().as_path.iter_mut() {
+            for a in segment.as_list.iter_mut().filter(|a| **a == asn) {
+                *a = by;
+            }
+        }
+    }
+
+    /// Removes the confederation segments from a path leaving the confederation.
+    pub fn strip_confed(&mut self) {
+        if self.attrs.as_path.iter().any(|s| s.is_confed()) {
+            self.attrs_mut().as_path.retain(|s| !s.is_confed());
+        }
     }
 
     pub fn is_from_ibgp(&self) -> bool {
@@ -65,6 +270,25 @@ impl RouteAttributes {
         false
     }
 
+    /// Router ID of the speaker that brought the path into the AS.
+    pub fn originator_id(&self) -> u32 {
+        self.attrs.originator_id.unwrap_or(self.peer_rid)
+    }
+
+    /// Reflects an iBGP path to another iBGP neighbor (RFC 4456).
+    pub fn reflect(&mut self, cluster_id: u32) {
+        let originator_id = self.originator_id();
+        let attrs = self.attrs_mut();
+        attrs.originator_id = Some(originator_id);
+        attrs.cluster_list.insert(0, cluster_id);
+    }
+
+    /// Whether the path was reflected back to the speaker that originated it, or went through
+    /// the cluster already.
+    pub fn is_reflection_loop(&self, router_id: u32, cluster_id: u32) -> bool {
+        self.attrs.originator_id == Some(router_id) || self.attrs.cluster_list.contains(&cluster_id)
+    }
+
     pub fn is_from_neighbor(&self, n: u32) -> bool {
         if self.peer_rid == n {
             return true;
@@ -72,18 +296,30 @@ impl RouteAttributes {
         false
     }
 
-    pub async fn is_valid(&self, asn: u16) -> bool {
-        if self.as_path.flatten_aspath().contains(&asn) {
-            return false;
-        }
-        true
+    /// Whether both describe the same path from the same peer, attribute for attribute.
+    pub fn is_same_path(&self, other: &Self) -> bool {
+        self.peer_rid == other.peer_rid
+            && self.peer_ip == other.peer_ip
+            && self.attrs == other.attrs
+    }
+
+    /// Whether `asn` appears in the AS path more than `allowed` times.
+    pub fn is_as_loop(&self, asn: u16, allowed: u8) -> bool {
+        let count = self
+            .attrs
+            .as_path
+            .flatten_aspath()
+            .into_iter()
+            .filter(|a| *a == asn)
+            .count();
+        count > allowed as usize
     }
 
     pub async fn new(
         src: Vec<bgp::PathAttribute>,
         local_asn: u32,
-        // s: Arc<Mutex<speaker::BGPSpeaker>>,
-        nb: Arc<Mutex<neighbor::BGPNeighbor>>,
+        // s: Arc<speaker::BGPSpeaker>,
+        nb: Arc<neighbor::NeighborCell>,
         nh: Option<IpAddr>,
     ) -> RouteAttributes {
         let mut multi_exit_disc = None;
@@ -91,6 +327,9 @@ impl RouteAttributes {
         let mut next_hop = Some(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
         let mut as_path: Vec<bgp::ASPATHSegment> = vec![];
         let mut origin = bgp::OriginType::Igp;
+        let mut communities = vec![];
+        let mut originator_id = None;
+        let mut cluster_list = vec![];
         for p in src {
             match p.value {
                 bgp::PathAttributeValue::Origin(o) => {
@@ -108,6 +347,15 @@ impl RouteAttributes {
                 bgp::PathAttributeValue::LocalPref(l) => {
                     local_pref = Some(l);
                 }
+                bgp::PathAttributeValue::Community(c) => {
+                    communities = c;
+                }
+                bgp::PathAttributeValue::OriginatorId(o) => {
+                    originator_id = Some(o.into());
+                }
+                bgp::PathAttributeValue::ClusterList(c) => {
+                    cluster_list = c;
+                }
                 bgp::PathAttributeValue::AtomicAggregate => {}
                 bgp::PathAttributeValue::Aggregator(_) => {}
                 _ => {}
@@ -122,6 +370,10 @@ impl RouteAttributes {
         let remote_asn;
         let peer_rid;
         let peer_ip;
+        let weight;
+        let client;
+        let confed;
+        let local_as;
         {
             let nb = nb.lock().await;
             remote_asn = nb
@@ -133,6 +385,10 @@ impl RouteAttributes {
             peer_ip = nb
                 .remote_ip
                 .expect("BUG: Remote IP should be set after BGP session establishment");
+            weight = nb.weight;
+            client = nb.route_reflector_client;
+            confed = nb.confederation_peer;
+            local_as = nb.local_as.clone();
         }
 
         let peer_type;
@@ -141,31 +397,54 @@ impl RouteAttributes {
         if local_asn == remote_asn as u32 {
             peer_type = PeeringType::Ibgp;
             path_type = PathType::Internal;
+        } else if confed {
+            peer_type = PeeringType::Confed;
+            path_type = PathType::Internal;
         } else {
             peer_type = PeeringType::Ebgp;
             path_type = PathType::External;
         }
 
-        // This is not the right way to determine path_type. We should only set to internal if the
-        // route is locally generated by the router
+        // A LOCAL_PREF from an external peer is ignored (RFC 4271 section 5.1.5), paths from
+        // other member ASes of the confederation keep it.
+        if peer_type == PeeringType::Ebgp {
+            local_pref = None;
+        }
 
-        RouteAttributes {
-            next_hop,
-            multi_exit_disc,
-            local_pref,
-            as_path,
-            origin,
+        let from_client = client && peer_type == PeeringType::Ibgp;
+        let mut ra = RouteAttributes {
+            attrs: Arc::new(AttributeSet {
+                next_hop,
+                multi_exit_disc,
+                local_pref,
+                as_path,
+                origin,
+                communities,
+                originator_id,
+                cluster_list,
+            }),
+            weight,
             path_type,
             peer_type,
             peer_rid,
             peer_ip,
             recv_time: Instant::now(),
+            suppressed: false,
+            multipath: false,
+            from_client,
+        };
+        // The neighbor sees the local AS configured for it in the path, as if it were a hop
+        if let Some(local_as) = local_as.filter(|l| !l.no_prepend) {
+            ra.prepend(local_as.asn, 1);
         }
+        ra.intern();
+        ra
     }
 }
 
 impl From<RouteAttributes> for Vec<PathAttribute> {
     fn from(val: RouteAttributes) -> Self {
+        let val = Arc::unwrap_or_clone(val.attrs);
         let mut ret = vec![];
         match val.next_hop {
             IpAddr::V6(_ip6) => {
@@ -183,6 +462,15 @@ impl From<RouteAttributes> for Vec<PathAttribute> {
         if let Some(med) = val.multi_exit_disc {
             ret.push(PathAttribute::med(med));
         }
+        if !val.communities.is_empty() {
+            ret.push(PathAttribute::communities(val.communities));
+        }
+        if let Some(originator_id) = val.originator_id {
+            ret.push(PathAttribute::originator_id(originator_id.into()));
+        }
+        if !val.cluster_list.is_empty() {
+            ret.push(PathAttribute::cluster_list(val.cluster_list));
+        }
         ret
     }
 }
@@ -193,96 +481,305 @@ pub enum PathType {
     Internal,
     // Aggregate,
     // Redist,
-    // Local,
+    /// Originated by the speaker.
+    Local,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Ord, Hash)]
 pub enum PeeringType {
     Ibgp,
+    /// eBGP between member ASes of a confederation, internal to the decision process.
+    Confed,
     Ebgp,
 }
 
-impl PartialEq for RouteAttributes {
-    fn eq(&self, other: &Self) -> bool {
-        let slen: usize = self.as_path.iter().map(|x| x.len()).sum();
-        let olen: usize = other.as_path.iter().map(|x| x.len()).sum();
-        self.local_pref == other.local_pref
-            && self.multi_exit_disc == other.multi_exit_disc
-            && self.origin == other.origin
-            && slen == olen
-    }
+/// Step of the decision process that made one path preferred over another.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum DecisionStep {
+    Weight,
+    LocalPref,
+    LocallyOriginated,
+    AsPathLength,
+    Origin,
+    Med,
+    PeerType,
+    IgpMetric,
+    OldestPath,
+    RouterId,
+    ClusterListLength,
+    PeerAddress,
 }
 
-impl Hash for RouteAttributes {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let len: usize = self.as_path.iter().map(|x| x.len()).sum();
-        self.local_pref.hash(state);
-        self.multi_exit_disc.hash(state);
-        self.origin.hash(state);
-        len.hash(state);
+impl RouteAttributes {
+    /// AS the path was received from, the first AS of the path once the confederation segments
+    /// are skipped (RFC 5065 section 5.3). Paths with an empty AS_PATH come from the local AS.
+    pub fn neighbor_as(&self) -> Option<u16> {
+        let segment = self.attrs.as_path.iter().find(|segment| {
+            !matches!(
+                segment.segment_type,
+                bgp::ASPATHSegmentType::AsConfedSequence | bgp::ASPATHSegmentType::AsConfedSet
+            )
+        })?;
+        match segment.segment_type {
+            bgp::ASPATHSegmentType::AsSequence => segment.as_list.first().copied(),
+            _ => None,
+        }
     }
-}
 
-impl PartialOrd for RouteAttributes {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    pub fn as_path_len(&self) -> usize {
+        self.attrs.as_path.iter().map(|x| x.len()).sum()
     }
-}
 
-impl Ord for RouteAttributes {
-    fn cmp(&self, other: &Self) -> Ordering {
-        let lp = self.local_pref.cmp(&other.local_pref);
-        if lp != Ordering::Equal {
-            return lp;
+    /// Compares two paths following RFC 4271 section 9.1.2.2 and returns the step that decided.
+    /// `Ordering::Greater` means `self` is preferred. The IGP metric to a next hop is only
+    /// looked up when the earlier steps tie.
+    pub fn compare<F>(
+        &self,
+        other: &Self,
+        config: &config::BestPathConfig,
+        igp_metric: &F,
+    ) -> (Ordering, DecisionStep)
+    where
+        F: Fn(&IpAddr) -> u32,
+    {
+        let weight = self.weight.cmp(&other.weight);
+        if weight != Ordering::Equal {
+            return (weight, DecisionStep::Weight);
         }
 
-        // If a path was created locally such as network or aggregate, it is preferred over a path learned via BGP.
+        let lp = self
+            .attrs
+            .local_pref
+            .unwrap_or(DEFAULT_LOCAL_PREF)
+            .cmp(&other.attrs.local_pref.unwrap_or(DEFAULT_LOCAL_PREF));
+        if lp != Ordering::Equal {
+            return (lp, DecisionStep::LocalPref);
+        }
 
-        let pt = self.path_type.cmp(&other.path_type);
-        if pt != Ordering::Equal {
-            return pt;
+        let local = self.is_local().cmp(&other.is_local());
+        if local != Ordering::Equal {
+            return (local, DecisionStep::LocallyOriginated);
         }
 
-        let slen: usize = self.as_path.iter().map(|x| x.len()).sum();
-        let olen: usize = other.as_path.iter().map(|x| x.len()).sum();
-        let path_len = slen.cmp(&olen).reverse();
+        let path_len = self.as_path_len().cmp(&other.as_path_len()).reverse();
         if path_len != Ordering::Equal {
-            return path_len;
+            return (path_len, DecisionStep::AsPathLength);
         }
 
-        let otype = self.origin.cmp(&other.origin);
+        let otype = self.attrs.origin.cmp(&other.attrs.origin).reverse();
         if otype != Ordering::Equal {
-            return otype;
+            return (otype, DecisionStep::Origin);
         }
 
-        let med = self.multi_exit_disc.cmp(&other.multi_exit_disc);
-        if med != Ordering::Equal {
-            return med;
+        // A missing MED is the best possible value.
+        if config.always_compare_med || self.neighbor_as() == other.neighbor_as() {
+            let med = self
+                .attrs
+                .multi_exit_disc
+                .unwrap_or(0)
+                .cmp(&other.attrs.multi_exit_disc.unwrap_or(0))
+                .reverse();
+            if med != Ordering::Equal {
+                return (med, DecisionStep::Med);
+            }
         }
 
-        let peer = self.peer_type.cmp(&other.peer_type);
+        // Paths from other member ASes of the confederation are internal.
+        let external = |p: &Self| p.peer_type == PeeringType::Ebgp;
+        let peer = external(self).cmp(&external(other));
         if peer != Ordering::Equal {
-            return peer;
+            return (peer, DecisionStep::PeerType);
         }
 
-        if self.peer_type == PeeringType::Ibgp {
-            // check igp of path and return the lowest
+        let igp = igp_metric(&self.attrs.next_hop)
+            .cmp(&igp_metric(&other.attrs.next_hop))
+            .reverse();
+        if igp != Ordering::Equal {
+            return (igp, DecisionStep::IgpMetric);
         }
 
-        if self.peer_type == PeeringType::Ebgp {
-            let r_time = self.recv_time.cmp(&other.recv_time);
+        if self.peer_type == PeeringType::Ebgp && other.peer_type == PeeringType::Ebgp {
+            let r_time = self.recv_time.cmp(&other.recv_time).reverse();
             if r_time != Ordering::Equal {
-                return r_time;
+                return (r_time, DecisionStep::OldestPath);
             }
         }
 
-        let rid = self.peer_rid.cmp(&other.peer_rid);
+        // The ORIGINATOR_ID stands for the router ID of reflected paths.
+        let rid = self.originator_id().cmp(&other.originator_id()).reverse();
         if rid != Ordering::Equal {
-            return rid;
+            return (rid, DecisionStep::RouterId);
+        }
+
+        let clusters = self
+            .attrs
+            .cluster_list
+            .len()
+            .cmp(&other.attrs.cluster_list.len())
+            .reverse();
+        if clusters != Ordering::Equal {
+            return (clusters, DecisionStep::ClusterListLength);
+        }
+
+        (
+            self.peer_ip.cmp(&other.peer_ip).reverse(),
+            DecisionStep::PeerAddress,
+        )
+    }
+}
+
+/// Index of the preferred path among `paths`, compared one after the other in order.
+fn preferred<F>(
+    paths: &[&RouteAttributes],
+    candidates: impl Iterator<Item = usize>,
+    config: &config::BestPathConfig,
+    igp_metric: &F,
+) -> Option<usize>
+where
+    F: Fn(&IpAddr) -> u32,
+{
+    candidates.reduce(
+        |best, i| match paths[i].compare(paths[best], config, igp_metric).0 {
+            Ordering::Greater => i,
+            _ => best,
+        },
+    )
+}
+
+/// Selects the best of `paths` and returns its index along with the step that made it win over
+/// the runner-up, `None` when there was no other path to compare with.
+///
+/// MED is not comparable between paths from different neighbor ASes, so the outcome depends on
+/// the order paths are compared in. With `deterministic_med` the paths are first grouped by
+/// neighbor AS and only the best of every group compete with each other.
+pub fn best_path<F>(
+    paths: &[&RouteAttributes],
+    config: &config::BestPathConfig,
+    igp_metric: F,
+) -> Option<(usize, Option<DecisionStep>)>
+where
+    F: Fn(&IpAddr) -> u32,
+{
+    let select = |excluded: Option<usize>| {
+        let candidates = (0..paths.len()).filter(|i| Some(*i) != excluded);
+        if !config.deterministic_med {
+            return preferred(paths, candidates, config, &igp_metric);
+        }
+        let mut groups: Vec<(Option<u16>, Vec<usize>)> = vec![];
+        for i in candidates {
+            let neighbor_as = paths[i].neighbor_as();
+            match groups.iter_mut().find(|(asn, _)| *asn == neighbor_as) {
+                Some((_, group)) => group.push(i),
+                None => groups.push((neighbor_as, vec![i])),
+            }
         }
+        let winners: Vec<usize> = groups
+            .into_iter()
+            .filter_map(|(_, group)| preferred(paths, group.into_iter(), config, &igp_metric))
+            .collect();
+        preferred(paths, winners.into_iter(), config, &igp_metric)
+    };
+
+    let best = select(None)?;
+    let step = select(Some(best))
+        .map(|runner_up| paths[best].compare(paths[runner_up], config, &igp_metric).1);
+    Some((best, step))
+}
 
-        self.peer_ip.cmp(&other.peer_ip)
+/// Indexes of the paths installed along with the best one, the best first. They must tie with it
+/// down to the IGP metric, and have the same AS path unless `multipath_relax` is set, in which
+/// case an AS path of the same length from another neighbor AS is enough.
+pub fn multipath<F>(
+    paths: &[&RouteAttributes],
+    best: usize,
+    config: &config::BestPathConfig,
+    igp_metric: F,
+) -> Vec<usize>
+where
+    F: Fn(&IpAddr) -> u32,
+{
+    let best_path = paths[best];
+    let maximum_paths = match best_path.peer_type {
+        PeeringType::Ebgp => config.maximum_paths_ebgp,
+        PeeringType::Ibgp | PeeringType::Confed => config.maximum_paths_ibgp,
+    };
+    let mut selected = vec![best];
+    for (i, path) in paths.iter().enumerate() {
+        if selected.len() >= maximum_paths {
+            break;
+        }
+        if i == best
+            || selected
+                .iter()
+                .any(|s| paths[*s].attrs.next_hop == path.attrs.next_hop)
+        {
+            continue;
+        }
+        if !config.multipath_relax && path.attrs.as_path != best_path.attrs.as_path {
+            continue;
+        }
+        let (_, step) = path.compare(best_path, config, &igp_metric);
+        if step > DecisionStep::IgpMetric {
+            selected.push(i);
+        }
+    }
+    selected
+}
+
+/// Outcome of the decision process over the paths of a prefix, as indexes into them.
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Best path and the step that preferred it over the runner-up.
+    pub best: Option<(usize, Option<DecisionStep>)>,
+    /// Paths to install in the FIB, the best first.
+    pub multipath: Vec<usize>,
+    /// IGP metric to the next hop of every path, `None` when it is unreachable.
+    pub igp_metrics: Vec<Option<u32>>,
+}
+
+/// Runs the decision process over the paths that are neither suppressed nor have an unreachable
+/// next hop.
+pub fn select(
+    attributes: &[RouteAttributes],
+    config: &config::BestPathConfig,
+    next_hops: &fib::NextHops,
+) -> Selection {
+    // Locally originated paths need no next hop.
+    let igp_metrics: Vec<Option<u32>> = attributes
+        .iter()
+        .map(|a| {
+            if a.is_local() {
+                Some(0)
+            } else {
+                next_hops.get(&a.attrs.next_hop).map(|r| r.metric)
+            }
+        })
+        .collect();
+    let metrics: HashMap<IpAddr, u32> = attributes
+        .iter()
+        .zip(&igp_metrics)
+        .filter_map(|(a, m)| m.map(|m| (a.attrs.next_hop, m)))
+        .collect();
+    let igp_metric = |nh: &IpAddr| metrics.get(nh).copied().unwrap_or(0);
+
+    let (indexes, candidates): (Vec<usize>, Vec<&RouteAttributes>) = attributes
+        .iter()
+        .enumerate()
+        .filter(|(i, a)| !a.suppressed && igp_metrics[*i].is_some())
+        .unzip();
+    let best = best_path(&candidates, config, igp_metric);
+    let multipath = best
+        .map(|(best, _)| multipath(&candidates, best, config, igp_metric))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|i| indexes[i])
+        .collect();
+    Selection {
+        best: best.map(|(best, step)| (indexes[best], step)),
+        multipath,
+        igp_metrics,
     }
 }
 
-pub type Rib = HashMap<bgp::Nlri, Vec<RouteAttributes>>;
+/// Paths of every prefix, for the Loc-RIB and the Adj-RIBs.
+pub type Rib = trie::PrefixMap<bgp::Nlri, Vec<RouteAttributes>>;
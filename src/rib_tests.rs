@@ -0,0 +1,446 @@
+use std::cmp::Ordering;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::bgp::{ASPATHSegment, ASPATHSegmentType, Afi, Flatten, Nlri, OriginType};
+use crate::config::BestPathConfig;
+use crate::fib::{desired_routes, NextHops};
+use crate::rib::*;
+
+/// Paths are received at the same time unless a test says otherwise.
+fn recv_time() -> Instant {
+    static RECV_TIME: OnceLock<Instant> = OnceLock::new();
+    *RECV_TIME.get_or_init(Instant::now)
+}
+
+fn path(asn: u16, rid: u32) -> RouteAttributes {
+    RouteAttributes {
+        recv_time: recv_time(),
+        attrs: Arc::new(AttributeSet {
+            as_path: vec![ASPATHSegment {
+                segment_type: ASPATHSegmentType::AsSequence,
+                as_list: vec![asn],
+            }],
+            ..Default::default()
+        }),
+        peer_rid: rid,
+        peer_ip: IpAddr::from([192, 0, 2, rid as u8]),
+        ..Default::default()
+    }
+}
+
+fn no_metric(_: &IpAddr) -> u32 {
+    0
+}
+
+fn compare(a: &RouteAttributes, b: &RouteAttributes) -> (Ordering, DecisionStep) {
+    a.compare(b, &BestPathConfig::default(), &no_metric)
+}
+
+#[test]
+fn test_weight_before_local_pref() {
+    let mut a = path(65001, 1);
+    a.weight = 10;
+    let mut b = path(65001, 2);
+    b.attrs_mut().local_pref = Some(500);
+    assert_eq!(compare(&a, &b), (Ordering::Greater, DecisionStep::Weight));
+}
+
+#[test]
+fn test_missing_local_pref_is_default() {
+    let a = path(65001, 2);
+    let mut b = path(65001, 1);
+    b.attrs_mut().local_pref = Some(DEFAULT_LOCAL_PREF);
+    assert_eq!(compare(&a, &b).1, DecisionStep::RouterId);
+
+    b.attrs_mut().local_pref = Some(90);
+    assert_eq!(
+        compare(&a, &b),
+        (Ordering::Greater, DecisionStep::LocalPref)
+    );
+}
+
+#[test]
+fn test_locally_originated_preferred() {
+    let a = RouteAttributes::local(1, &Afi::Ipv4);
+    let b = path(65001, 2);
+    assert_eq!(
+        compare(&a, &b),
+        (Ordering::Greater, DecisionStep::LocallyOriginated)
+    );
+
+    // Selected without a next hop to resolve, and left out of the FIB
+    let nlri = Nlri {
+        net: "10.0.0.0/24".parse().unwrap(),
+    };
+    let selection = select(
+        &[a.clone(), b],
+        &BestPathConfig::default(),
+        &NextHops::default(),
+    );
+    assert_eq!(selection.best, Some((0, None)));
+    let mut local = a;
+    local.multipath = true;
+    let mut rib = Rib::default();
+    rib.insert(nlri, vec![local]);
+    assert!(desired_routes(&rib).is_empty());
+}
+
+#[test]
+fn test_shorter_as_path_and_lower_origin() {
+    let a = path(65001, 2);
+    let mut b = path(65001, 1);
+    b.prepend(65001, 1);
+    assert_eq!(
+        compare(&a, &b),
+        (Ordering::Greater, DecisionStep::AsPathLength)
+    );
+
+    let mut b = path(65001, 1);
+    b.attrs_mut().origin = OriginType::Incomplete;
+    assert_eq!(compare(&a, &b), (Ordering::Greater, DecisionStep::Origin));
+}
+
+#[test]
+fn test_med_only_compared_within_neighbor_as() {
+    let mut a = path(65001, 2);
+    a.attrs_mut().multi_exit_disc = Some(10);
+    let mut b = path(65001, 1);
+    b.attrs_mut().multi_exit_disc = Some(20);
+    assert_eq!(compare(&a, &b), (Ordering::Greater, DecisionStep::Med));
+
+    // A missing MED is the lowest
+    b.attrs_mut().multi_exit_disc = None;
+    assert_eq!(compare(&a, &b), (Ordering::Less, DecisionStep::Med));
+
+    let mut b = path(65002, 1);
+    b.attrs_mut().multi_exit_disc = Some(20);
+    assert_eq!(compare(&a, &b), (Ordering::Less, DecisionStep::RouterId));
+
+    let config = BestPathConfig {
+        always_compare_med: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        a.compare(&b, &config, &no_metric),
+        (Ordering::Greater, DecisionStep::Med)
+    );
+}
+
+#[test]
+fn test_med_neighbor_as_skips_confed_segments() {
+    let confed = |member: u16, asn: u16, med: u32| {
+        let mut p = path(asn, member as u32);
+        let attrs = p.attrs_mut();
+        attrs.as_path.insert(
+            0,
+            ASPATHSegment {
+                segment_type: ASPATHSegmentType::AsConfedSequence,
+                as_list: vec![member],
+            },
+        );
+        attrs.multi_exit_disc = Some(med);
+        p
+    };
+    // Through different members of the confederation from the same neighbor AS
+    let a = confed(2, 65001, 10);
+    let b = confed(1, 65001, 20);
+    assert_eq!(compare(&a, &b), (Ordering::Greater, DecisionStep::Med));
+
+    // From different neighbor ASes, the MED must not be compared
+    let b = confed(1, 65002, 20);
+    assert_eq!(compare(&a, &b), (Ordering::Less, DecisionStep::RouterId));
+}
+
+#[test]
+fn test_ebgp_then_igp_metric() {
+    let a = path(65001, 2);
+    let mut b = path(65001, 1);
+    b.peer_type = PeeringType::Ibgp;
+    assert_eq!(compare(&a, &b), (Ordering::Greater, DecisionStep::PeerType));
+
+    let mut a = path(65001, 2);
+    a.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 1]);
+    let mut b = path(65001, 1);
+    b.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 2]);
+    let metric = |nh: &IpAddr| if *nh == a.attrs.next_hop { 5 } else { 10 };
+    assert_eq!(
+        a.compare(&b, &BestPathConfig::default(), &metric),
+        (Ordering::Greater, DecisionStep::IgpMetric)
+    );
+}
+
+#[test]
+fn test_oldest_ebgp_path() {
+    let mut a = path(65001, 2);
+    let mut b = path(65001, 1);
+    b.recv_time = a.recv_time + Duration::from_secs(1);
+    assert_eq!(
+        compare(&a, &b),
+        (Ordering::Greater, DecisionStep::OldestPath)
+    );
+
+    // Not for iBGP paths
+    a.peer_type = PeeringType::Ibgp;
+    b.peer_type = PeeringType::Ibgp;
+    assert_eq!(compare(&a, &b), (Ordering::Less, DecisionStep::RouterId));
+}
+
+#[test]
+fn test_cluster_list_and_peer_address() {
+    let mut a = path(65001, 1);
+    let mut b = path(65001, 1);
+    b.attrs_mut().cluster_list = vec![1];
+    assert_eq!(
+        compare(&a, &b),
+        (Ordering::Greater, DecisionStep::ClusterListLength)
+    );
+
+    b.attrs_mut().cluster_list.clear();
+    a.peer_ip = IpAddr::from([192, 0, 2, 10]);
+    b.peer_ip = IpAddr::from([192, 0, 2, 20]);
+    assert_eq!(
+        compare(&a, &b),
+        (Ordering::Greater, DecisionStep::PeerAddress)
+    );
+}
+
+#[test]
+fn test_originator_id_replaces_router_id() {
+    // Reflected by RID 1 for the router 5, against a path learned from RID 3
+    let mut a = path(65001, 1);
+    a.attrs_mut().originator_id = Some(5);
+    let b = path(65001, 3);
+    assert_eq!(compare(&a, &b), (Ordering::Less, DecisionStep::RouterId));
+}
+
+#[test]
+fn test_confederation_paths_are_internal() {
+    let mut confed = path(65001, 1);
+    confed.peer_type = PeeringType::Confed;
+    let mut ibgp = path(65001, 2);
+    ibgp.peer_type = PeeringType::Ibgp;
+    assert_eq!(
+        compare(&path(65001, 3), &confed),
+        (Ordering::Greater, DecisionStep::PeerType)
+    );
+    assert_eq!(
+        compare(&confed, &ibgp),
+        (Ordering::Greater, DecisionStep::RouterId)
+    );
+
+    // Member ASes are prepended in a confederation segment, not counted in the path length
+    confed.prepend_confed(65010);
+    confed.prepend_confed(65011);
+    assert_eq!(confed.attrs.as_path.aspath_string(), "(65011 65010) 65001");
+    assert_eq!(confed.as_path_len(), 1);
+    confed.strip_confed();
+    confed.prepend(64999, 1);
+    assert_eq!(confed.attrs.as_path.aspath_string(), "64999 65001");
+}
+
+#[test]
+fn test_as_loop_allowance_and_override() {
+    let mut a = path(65001, 1);
+    a.prepend(65000, 2);
+    assert!(a.is_as_loop(65000, 0));
+    assert!(a.is_as_loop(65000, 1));
+    assert!(!a.is_as_loop(65000, 2));
+    assert!(!a.is_as_loop(65002, 0));
+
+    a.replace_as(65000, 65100);
+    assert_eq!(a.attrs.as_path.aspath_string(), "65100 65100 65001");
+}
+
+#[test]
+fn test_reflection() {
+    let mut a = path(65001, 1);
+    a.reflect(10);
+    assert_eq!(a.attrs.originator_id, Some(1));
+    assert_eq!(a.attrs.cluster_list, vec![10]);
+    a.reflect(20);
+    assert_eq!(a.attrs.originator_id, Some(1));
+    assert_eq!(a.attrs.cluster_list, vec![20, 10]);
+
+    assert!(a.is_reflection_loop(1, 30));
+    assert!(a.is_reflection_loop(2, 10));
+    assert!(!a.is_reflection_loop(2, 30));
+    assert!(!path(65001, 1).is_reflection_loop(1, 1));
+}
+
+#[test]
+fn test_best_path_reports_deciding_step() {
+    let config = BestPathConfig::default();
+    assert_eq!(best_path(&[], &config, no_metric), None);
+
+    let a = path(65001, 1);
+    assert_eq!(best_path(&[&a], &config, no_metric), Some((0, None)));
+
+    let mut b = path(65002, 2);
+    b.attrs_mut().local_pref = Some(200);
+    let c = path(65003, 3);
+    assert_eq!(
+        best_path(&[&a, &b, &c], &config, no_metric),
+        Some((1, Some(DecisionStep::LocalPref)))
+    );
+}
+
+#[test]
+fn test_deterministic_med() {
+    let mut p1 = path(65001, 1);
+    p1.attrs_mut().multi_exit_disc = Some(200);
+    let mut p2 = path(65002, 2);
+    p2.attrs_mut().multi_exit_disc = Some(100);
+    let mut p3 = path(65001, 3);
+    p3.attrs_mut().multi_exit_disc = Some(100);
+
+    let best = |paths: &[&RouteAttributes], config: &BestPathConfig| {
+        best_path(paths, config, no_metric).map(|(i, step)| (paths[i].peer_rid, step))
+    };
+
+    // Compared in order, the result depends on the order paths were received in
+    let config = BestPathConfig::default();
+    assert_eq!(best(&[&p1, &p2, &p3], &config).map(|b| b.0), Some(3));
+    assert_eq!(best(&[&p1, &p3, &p2], &config).map(|b| b.0), Some(2));
+
+    let config = BestPathConfig {
+        deterministic_med: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        best(&[&p1, &p2, &p3], &config),
+        Some((2, Some(DecisionStep::RouterId)))
+    );
+    assert_eq!(
+        best(&[&p1, &p3, &p2], &config),
+        Some((2, Some(DecisionStep::RouterId)))
+    );
+}
+
+#[test]
+fn test_multipath_requires_same_as_path() {
+    let config = BestPathConfig {
+        maximum_paths_ebgp: 4,
+        ..Default::default()
+    };
+    let mut a = path(65001, 1);
+    a.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 1]);
+    let mut b = path(65001, 2);
+    b.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 2]);
+    let mut c = path(65002, 3);
+    c.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 3]);
+    let mut d = path(65001, 4);
+    d.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 4]);
+    d.attrs_mut().multi_exit_disc = Some(10);
+
+    let paths = [&a, &b, &c, &d];
+    assert_eq!(multipath(&paths, 0, &config, no_metric), vec![0, 1]);
+
+    let config = BestPathConfig {
+        multipath_relax: true,
+        ..config
+    };
+    assert_eq!(multipath(&paths, 0, &config, no_metric), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_multipath_limits() {
+    let mut a = path(65001, 1);
+    a.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 1]);
+    let mut b = path(65001, 2);
+    b.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 2]);
+    let mut c = path(65001, 3);
+    c.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 2]);
+    let mut d = path(65001, 4);
+    d.attrs_mut().next_hop = IpAddr::from([10, 0, 0, 4]);
+    let paths = [&a, &b, &c, &d];
+
+    // A single path unless configured otherwise
+    let config = BestPathConfig::default();
+    assert_eq!(multipath(&paths, 0, &config, no_metric), vec![0]);
+
+    // Paths through the same next hop are only installed once
+    let config = BestPathConfig {
+        maximum_paths_ebgp: 3,
+        ..Default::default()
+    };
+    assert_eq!(multipath(&paths, 0, &config, no_metric), vec![0, 1, 3]);
+
+    // The iBGP limit applies to iBGP best paths, which also need the same IGP metric
+    let config = BestPathConfig {
+        maximum_paths_ebgp: 1,
+        maximum_paths_ibgp: 4,
+        ..Default::default()
+    };
+    let paths: Vec<RouteAttributes> = paths
+        .iter()
+        .map(|p| RouteAttributes {
+            peer_type: PeeringType::Ibgp,
+            ..(*p).clone()
+        })
+        .collect();
+    let paths: Vec<&RouteAttributes> = paths.iter().collect();
+    let metric = |nh: &IpAddr| {
+        if *nh == IpAddr::from([10, 0, 0, 4]) {
+            20
+        } else {
+            10
+        }
+    };
+    assert_eq!(multipath(&paths, 0, &config, metric), vec![0, 1]);
+}
+
+#[test]
+fn test_intern_shares_attributes() {
+    let mut a = path(64900, 1);
+    let mut b = path(64900, 2);
+    assert!(!Arc::ptr_eq(&a.attrs, &b.attrs));
+    a.intern();
+    b.intern();
+    assert!(Arc::ptr_eq(&a.attrs, &b.attrs));
+
+    // Changing a shared set only changes the route's own copy
+    b.attrs_mut().local_pref = Some(200);
+    assert_eq!(a.attrs.local_pref, None);
+    b.intern();
+    assert!(!Arc::ptr_eq(&a.attrs, &b.attrs));
+
+    let mut c = path(64900, 3);
+    c.attrs_mut().local_pref = Some(200);
+    c.intern();
+    assert!(Arc::ptr_eq(&b.attrs, &c.attrs));
+}
+
+#[test]
+fn test_rib_memory() {
+    let mut rib = Rib::default();
+    let empty = memory(&rib);
+    assert_eq!(
+        (empty.prefixes, empty.paths, empty.attribute_sets),
+        (0, 0, 0)
+    );
+
+    let mut a = path(64901, 1);
+    let mut b = path(64902, 2);
+    a.intern();
+    b.intern();
+    for net in ["10.0.0.0/8", "10.1.0.0/16", "2001:db8::/32"] {
+        let nlri = crate::bgp::Nlri {
+            net: net.parse().unwrap(),
+        };
+        rib.insert(nlri, vec![a.clone(), b.clone()]);
+    }
+    let mut c = path(64901, 3);
+    c.intern();
+    rib.insert(
+        crate::bgp::Nlri {
+            net: "192.0.2.0/24".parse().unwrap(),
+        },
+        vec![c],
+    );
+
+    let used = memory(&rib);
+    assert_eq!((used.prefixes, used.paths, used.attribute_sets), (4, 7, 2));
+    assert!(used.bytes > empty.bytes + 7 * std::mem::size_of::<RouteAttributes>());
+}
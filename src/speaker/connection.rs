@@ -2,43 +2,49 @@ use anyhow::{Context, Result};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
 
 use crate::neighbor;
 
 use super::types::BGPSpeaker;
 
-pub async fn add_incoming(speaker: Arc<Mutex<BGPSpeaker>>, socket: TcpStream, addr: SocketAddr) {
+pub async fn add_incoming(speaker: Arc<BGPSpeaker>, socket: TcpStream, addr: SocketAddr) {
     log::info!("New incoming connection from {}", addr);
 
     let remote_ip = addr.ip();
     let remote_port = addr.port();
-    let matched_neighbor = {
-        let s = speaker.lock().await;
-        s.neighbors
-            .iter()
-            .find(|n| {
-                if let Ok(neighbor) = n.try_lock() {
-                    neighbor.remote_ip == Some(remote_ip)
-                } else {
-                    false
-                }
-            })
-            .cloned()
-    };
+    let matched_neighbor = speaker
+        .neighbors
+        .iter()
+        .find(|n| n.remote_ip == Some(remote_ip))
+        .cloned();
 
     match matched_neighbor {
         Some(existing_neighbor) => {
             let should_accept = {
-                let n = existing_neighbor.lock().await;
+                // Decided from the status, so that a busy session does not hold up the listener.
+                let n = existing_neighbor.status();
 
                 log::info!(
                     "Found configured neighbor for {} (ASN: {:?}, State: {:?})",
                     remote_ip,
                     n.remote_asn,
-                    n.attributes.state
+                    n.state
                 );
-                match n.attributes.state {
+                if n.max_prefix_shutdown {
+                    log::warn!(
+                        "Rejecting connection from {} - shut down by maximum-prefix limit",
+                        remote_ip
+                    );
+                    return;
+                }
+                if n.is_idle_hold(std::time::Instant::now()) {
+                    log::warn!(
+                        "Rejecting connection from {} - IdleHoldTimer running",
+                        remote_ip
+                    );
+                    return;
+                }
+                match n.state {
                     neighbor::BGPState::Idle
                     | neighbor::BGPState::Active
                     | neighbor::BGPState::Connect => true,
@@ -48,7 +54,7 @@ pub async fn add_incoming(speaker: Arc<Mutex<BGPSpeaker>>, socket: TcpStream, ad
                         log::warn!(
                             "Rejecting connection from {} - already in state {:?}",
                             remote_ip,
-                            n.attributes.state
+                            n.state
                         );
                         false
                     }
@@ -89,16 +95,8 @@ pub async fn add_incoming(speaker: Arc<Mutex<BGPSpeaker>>, socket: TcpStream, ad
         }
     }
 }
-pub async fn listen(speaker: Arc<Mutex<BGPSpeaker>>) -> Result<()> {
-    let local_ips;
-    let local_port;
-    {
-        let s = speaker.lock().await;
-        local_ips = s.local_ips.clone();
-        local_port = s.local_port;
-    }
-
-    let socket_addr = format!("{}:{}", local_ips[0], local_port);
+pub async fn listen(speaker: Arc<BGPSpeaker>) -> Result<()> {
+    let socket_addr = format!("{}:{}", speaker.local_ips[0], speaker.local_port);
     let listener = TcpListener::bind(&socket_addr)
         .await
         .context(format!("Failed to bind BGP listener to {}", socket_addr))?;
@@ -111,13 +109,8 @@ pub async fn listen(speaker: Arc<Mutex<BGPSpeaker>>) -> Result<()> {
         add_incoming(speaker.clone(), socket, addr).await;
     }
 }
-pub async fn connect_mgr(speaker: Arc<Mutex<BGPSpeaker>>) {
-    let neighbors;
-    {
-        let s = speaker.lock().await;
-        neighbors = s.neighbors.clone();
-    }
-    for neighbor in neighbors {
+pub async fn connect_mgr(speaker: Arc<BGPSpeaker>) {
+    for neighbor in speaker.neighbors.clone() {
         let speaker = speaker.clone();
         tokio::spawn(async move { neighbor::connect(speaker, neighbor).await });
     }
@@ -1,13 +1,39 @@
+use netlink_packet_route::link::LinkMessage;
+use netlink_packet_route::route::RouteMessage;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use super::update_group::Peer;
+
+use crate::bgp;
+use crate::damping;
+use crate::neighbor;
 use crate::rib;
 
 #[derive(Debug)]
 pub enum RibEvent {
-    UpdateRoutes(Update),
+    UpdateRoutes(Box<Update>),
+    /// Forgets the damping history of a prefix, or of every prefix when `None`.
+    ClearDamping(Option<bgp::Nlri>),
+    /// The resolution of these next hops through the kernel routing table changed.
+    NextHopsChanged(Vec<IpAddr>),
+    /// Asks for the Loc-RIB as of the end of the current batch.
+    Snapshot(oneshot::Sender<Arc<rib::Rib>>),
+    /// Asks for a copy of the damping state.
+    DampingSnapshot(oneshot::Sender<damping::Damping>),
+    /// A session got established, the neighbor is to be sent the Loc-RIB.
+    PeerUp(Box<Peer>),
+    /// A session went down, nothing more is to be advertised to the neighbor.
+    PeerDown(Arc<neighbor::NeighborCell>),
 }
 
 #[derive(Debug)]
 pub enum FibEvent {
-    RibUpdated,
+    RouteAdded(RouteMessage),
+    RouteDeleted(RouteMessage),
+    LinkChanged(LinkMessage),
+    LinkRemoved(LinkMessage),
 }
 
 #[derive(Debug)]
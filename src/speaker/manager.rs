@@ -1,87 +1,239 @@
 use anyhow::{Context, Result};
+use futures::stream::StreamExt;
+use rtnetlink::packet_core::NetlinkPayload;
+use rtnetlink::packet_route::RouteNetlinkMessage;
+use rtnetlink::{new_multicast_connection, MulticastGroup};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
 use tokio::time::Duration;
 
 use crate::bgp::{self};
+use crate::config;
+use crate::damping;
 use crate::fib::{self};
-use crate::neighbor;
 use crate::rib::{self};
 
 use super::events::{FibEvent, RibEvent};
-pub async fn best_reachable(
-    fib: Arc<Mutex<fib::Fib>>,
-    attributes: Vec<rib::RouteAttributes>,
+use super::update_group::UpdateGroups;
+
+/// Interval at which suppressed paths are checked for reuse.
+const DAMPING_REUSE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Most events the RIB manager applies before running the decision process on the prefixes they
+/// touched.
+const MAX_BATCH: usize = 1024;
+
+/// Runs the decision process over the paths that are neither suppressed nor unreachable, marks
+/// the paths to install in the FIB and moves the best one to the front of `attributes`.
+fn best_reachable(
+    next_hops: &fib::NextHops,
+    attributes: &mut Vec<rib::RouteAttributes>,
+    config: &config::BestPathConfig,
 ) -> Option<rib::RouteAttributes> {
-    let fib = fib.lock().await;
-    attributes
+    let selected = rib::select(attributes, config, next_hops).multipath;
+    for (i, a) in attributes.iter_mut().enumerate() {
+        a.multipath = selected.contains(&i);
+    }
+    let best = attributes.remove(*selected.first()?);
+    attributes.insert(0, best.clone());
+    Some(best)
+}
+
+/// Best path selected by the last run of the decision process.
+pub(super) fn current_best(attributes: &[rib::RouteAttributes]) -> Option<rib::RouteAttributes> {
+    attributes.first().filter(|a| a.multipath).cloned()
+}
+
+/// Next hops of the paths installed in the FIB, sorted. Locally originated paths are not.
+fn installed_next_hops(attributes: &[rib::RouteAttributes]) -> Vec<IpAddr> {
+    let mut next_hops: Vec<IpAddr> = attributes
         .iter()
-        .find(|a| fib.has_route(a.next_hop))
-        .cloned()
+        .filter(|a| a.multipath && !a.is_local())
+        .map(|a| a.attrs.next_hop)
+        .collect();
+    next_hops.sort();
+    next_hops
 }
-async fn loc_rib_added(
-    rib: Arc<Mutex<rib::Rib>>,
-    fib: Arc<Mutex<fib::Fib>>,
-    asn: u16,
-    routes: rib::RibUpdate,
-) -> Vec<(bgp::Nlri, Option<rib::RouteAttributes>)> {
-    let mut modified = vec![];
-    let mut rib = rib.lock().await;
 
-    for nlri in routes.nlris {
-        match rib.get_mut(&nlri) {
-            None => {
-                if routes.attributes.is_valid(asn).await {
-                    rib.insert(nlri, vec![routes.attributes.clone()]);
-                    {
-                        let fib = fib.lock().await;
-                        if fib.has_route(routes.attributes.clone().next_hop) {
-                            modified.push((nlri, Some(routes.attributes.clone())));
-                        }
-                    }
+/// Next hops to resolve for the paths, sorted and without duplicates.
+fn path_next_hops(attributes: &[rib::RouteAttributes]) -> Vec<IpAddr> {
+    let mut next_hops: Vec<IpAddr> = attributes
+        .iter()
+        .filter(|a| !a.is_local())
+        .map(|a| a.attrs.next_hop)
+        .collect();
+    next_hops.sort();
+    next_hops.dedup();
+    next_hops
+}
+
+/// Prefixes of the Loc-RIB by the next hops of their paths, so that a change in the resolution
+/// of a next hop only touches the prefixes using it.
+#[derive(Debug, Default)]
+struct NextHopIndex(HashMap<IpAddr, HashSet<bgp::Nlri>>);
+
+impl NextHopIndex {
+    /// Moves `nlri` from the `previous` next hops of its paths to the `current` ones. Returns
+    /// whether one of these is new to the Loc-RIB.
+    fn update(&mut self, nlri: bgp::Nlri, previous: &[IpAddr], current: &[IpAddr]) -> bool {
+        let mut added = false;
+        for next_hop in previous.iter().filter(|nh| !current.contains(nh)) {
+            if let Some(prefixes) = self.0.get_mut(next_hop) {
+                prefixes.remove(&nlri);
+                if prefixes.is_empty() {
+                    self.0.remove(next_hop);
                 }
             }
-            Some(all_attributes) => {
-                if routes.attributes.is_valid(asn).await {
-                    let previous_best = best_reachable(fib.clone(), all_attributes.to_vec()).await;
-
-                    all_attributes.push(routes.attributes.clone());
-                    all_attributes.sort();
-                    all_attributes.reverse();
-
-                    {
-                        let fib = fib.lock().await;
-                        if fib.has_route(routes.attributes.clone().next_hop) {
-                            match previous_best {
-                                None => {
-                                    modified.push((nlri, Some(routes.attributes.clone())));
-                                }
-                                Some(best) => {
-                                    if routes.attributes > best {
-                                        modified.push((nlri, Some(routes.attributes.clone())));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+        }
+        for next_hop in current.iter().filter(|nh| !previous.contains(nh)) {
+            let prefixes = self.0.entry(*next_hop).or_default();
+            added |= prefixes.is_empty();
+            prefixes.insert(nlri);
+        }
+        added
+    }
+
+    /// Prefixes with a path through one of `next_hops`.
+    fn prefixes<'a>(&'a self, next_hops: &'a [IpAddr]) -> impl Iterator<Item = &'a bgp::Nlri> {
+        next_hops.iter().filter_map(|nh| self.0.get(nh)).flatten()
+    }
+}
+
+/// Outcome of a Loc-RIB change.
+#[derive(Debug, Default)]
+struct RibChanges {
+    /// Prefixes whose best path changed, to advertise to the neighbors.
+    best: Vec<(bgp::Nlri, Option<rib::RouteAttributes>)>,
+    /// Whether the paths to install in the FIB changed.
+    fib: bool,
+    /// Whether paths have next hops the FIB is to track from its next sync on, these are
+    /// unreachable until it does.
+    track: bool,
+}
+
+fn best_changed(
+    previous: &Option<rib::RouteAttributes>,
+    current: &Option<rib::RouteAttributes>,
+) -> bool {
+    match (previous, current) {
+        (None, None) => false,
+        (Some(p), Some(c)) => !p.is_same_path(c),
+        _ => true,
+    }
+}
+
+/// State of a prefix before its paths changed.
+#[derive(Debug)]
+struct Touched {
+    nlri: bgp::Nlri,
+    best: Option<rib::RouteAttributes>,
+    installed_next_hops: Vec<IpAddr>,
+    path_next_hops: Vec<IpAddr>,
+}
+
+/// Prefixes whose paths changed during a batch of events, with their state before the batch.
+#[derive(Debug, Default)]
+struct Batch {
+    touched: Vec<Touched>,
+    seen: HashSet<bgp::Nlri>,
+}
+
+impl Batch {
+    /// Records the state of `nlri` before its paths change, unless they already did in the batch.
+    fn touch(&mut self, nlri: bgp::Nlri, attributes: &[rib::RouteAttributes]) {
+        if self.seen.insert(nlri) {
+            self.touched.push(Touched {
+                nlri,
+                best: current_best(attributes),
+                installed_next_hops: installed_next_hops(attributes),
+                path_next_hops: path_next_hops(attributes),
+            });
+        }
+    }
+
+    /// Runs the decision process once for every touched prefix, drops those left without paths
+    /// and keeps `index` in line with the next hops of their paths.
+    fn decide(
+        self,
+        rib: &mut rib::Rib,
+        next_hops: &fib::NextHops,
+        config: &config::BestPathConfig,
+        index: &mut NextHopIndex,
+    ) -> RibChanges {
+        let mut changes = RibChanges::default();
+        for previous in self.touched {
+            let nlri = previous.nlri;
+            let Some(all_attributes) = rib.get_mut(&nlri) else {
+                index.update(nlri, &previous.path_next_hops, &[]);
+                continue;
+            };
+            let current = path_next_hops(all_attributes);
+            changes.track |= index.update(nlri, &previous.path_next_hops, &current)
+                || current.iter().any(|nh| !next_hops.tracks(nh));
+            let best = best_reachable(next_hops, all_attributes, config);
+            changes.fib |= installed_next_hops(all_attributes) != previous.installed_next_hops;
+            if all_attributes.is_empty() {
+                rib.remove(&nlri);
+            }
+            if best_changed(&previous.best, &best) {
+                changes.best.push((nlri, best));
             }
         }
+        changes
+    }
+}
+
+async fn loc_rib_added(
+    rib: &mut rib::Rib,
+    damping: &mut damping::Damping,
+    batch: &mut Batch,
+    mut routes: rib::RibUpdate,
+) {
+    // Policies may have changed the attributes the Adj-RIB-In shares.
+    routes.attributes.intern();
+
+    let now = Instant::now();
+    let peer_rid = routes.attributes.peer_rid;
+
+    for nlri in routes.nlris {
+        let all_attributes = rib.get_or_insert_with(nlri, Vec::new);
+        batch.touch(nlri, all_attributes);
+
+        // A new advertisement from the same peer implicitly withdraws the previous one.
+        let mut attributes = routes.attributes.clone();
+        let changed = match all_attributes
+            .iter()
+            .position(|a| a.is_from_neighbor(peer_rid))
+        {
+            Some(i) => !all_attributes.remove(i).is_same_path(&attributes),
+            None => false,
+        };
+        attributes.suppressed = damping.advertised(nlri, peer_rid, changed, now);
+
+        all_attributes.push(attributes);
     }
-    modified
 }
-async fn loc_rib_withdraw(
-    rib: Arc<Mutex<rib::Rib>>,
-    fib: Arc<Mutex<fib::Fib>>,
+
+fn loc_rib_withdraw(
+    rib: &mut rib::Rib,
+    damping: &mut damping::Damping,
+    batch: &mut Batch,
     routes: rib::RibUpdate,
-) -> Vec<(bgp::Nlri, Option<rib::RouteAttributes>)> {
-    let mut modified = vec![];
-    let mut rib = rib.lock().await;
-    let mut to_withdraw: Vec<bgp::Nlri> = vec![];
-    if routes.nlris.is_empty() {
-        let peer_rid = routes.attributes.peer_rid;
+) {
+    let now = Instant::now();
+    let peer_rid = routes.attributes.peer_rid;
+
+    // Routes flushed because the session went down are not flaps of the prefix itself, only
+    // explicit withdrawals are penalized.
+    let explicit = !routes.nlris.is_empty();
+    let to_withdraw: Vec<bgp::Nlri> = if explicit {
+        routes.nlris
+    } else {
         log::info!("Withdrawing all routes from peer RID {}", peer_rid);
-        to_withdraw = rib
+        let to_withdraw: Vec<bgp::Nlri> = rib
             .iter()
             .filter_map(|(prefix, attrs)| {
                 if attrs.iter().any(|attr| attr.peer_rid == peer_rid) {
@@ -96,146 +248,393 @@ async fn loc_rib_withdraw(
             to_withdraw.len(),
             peer_rid
         );
-    } else {
-        to_withdraw = routes.nlris;
-    }
+        to_withdraw
+    };
 
     for nlri in to_withdraw {
-        match rib.get_mut(&nlri) {
-            None => {}
-            Some(all_attributes) => {
-                let previous_best = best_reachable(fib.clone(), all_attributes.to_vec()).await;
-
-                all_attributes.retain(|a| !a.is_from_neighbor(routes.attributes.peer_rid));
-                if all_attributes.is_empty() {
-                    rib.remove(&nlri);
-                }
+        if let Some(all_attributes) = rib.get_mut(&nlri) {
+            batch.touch(nlri, all_attributes);
 
-                match previous_best {
-                    None => {}
-                    Some(best) => {
-                        if best.peer_rid == routes.attributes.peer_rid {
-                            modified.push((nlri, None));
-                        }
-                    }
+            let count = all_attributes.len();
+            all_attributes.retain(|a| !a.is_from_neighbor(peer_rid));
+            if explicit && all_attributes.len() != count {
+                damping.withdrawn(nlri, peer_rid, now);
+            }
+        }
+    }
+}
+
+/// Lifts the suppression of the given paths.
+fn loc_rib_unsuppress(rib: &mut rib::Rib, batch: &mut Batch, paths: Vec<(bgp::Nlri, u32)>) {
+    for (nlri, peer_rid) in paths {
+        if let Some(all_attributes) = rib.get_mut(&nlri) {
+            batch.touch(nlri, all_attributes);
+            all_attributes
+                .iter_mut()
+                .filter(|a| a.is_from_neighbor(peer_rid))
+                .for_each(|a| a.suppressed = false);
+        }
+    }
+}
+
+/// Marks for the decision process the prefixes with a path through one of `next_hops`, whose
+/// resolution changed.
+fn loc_rib_next_hops(
+    rib: &rib::Rib,
+    index: &NextHopIndex,
+    batch: &mut Batch,
+    next_hops: Vec<IpAddr>,
+) {
+    for nlri in index.prefixes(&next_hops) {
+        if let Some(all_attributes) = rib.get(nlri) {
+            batch.touch(*nlri, all_attributes);
+        }
+    }
+}
+
+/// Latest Loc-RIB the FIB is to be brought in line with. Only the last one is kept: the FIB
+/// manager syncs once however many batches the RIB manager went through meanwhile, and the RIB
+/// manager never waits on it. The RIB manager resolves next hops through the snapshot the FIB
+/// manager publishes instead of the FIB, which the FIB manager alone owns.
+#[derive(Debug, Default)]
+pub struct FibSync {
+    rib: std::sync::Mutex<Option<Arc<rib::Rib>>>,
+    notify: Notify,
+}
+
+impl FibSync {
+    fn request(&self, rib: Arc<rib::Rib>) {
+        *self.rib.lock().expect("BUG: FIB sync lock poisoned") = Some(rib);
+        self.notify.notify_one();
+    }
+
+    /// Takes back the request the FIB manager did not get to yet, so that the next change does
+    /// not copy the Loc-RIB. Returns whether there was one.
+    pub(super) fn cancel(&self) -> bool {
+        self.rib
+            .lock()
+            .expect("BUG: FIB sync lock poisoned")
+            .take()
+            .is_some()
+    }
+
+    async fn next(&self) -> Arc<rib::Rib> {
+        loop {
+            if let Some(rib) = self.rib.lock().expect("BUG: FIB sync lock poisoned").take() {
+                return rib;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Loc-RIB of the RIB manager behind `tx`. Reading it does not hold the RIB manager up, which
+/// copies the whole Loc-RIB on its next change if the snapshot is still in use by then, so it is
+/// to be dropped as soon as possible.
+pub async fn rib_snapshot(tx: &mpsc::Sender<RibEvent>) -> Result<Arc<rib::Rib>> {
+    let (reply, rx) = oneshot::channel();
+    tx.send(RibEvent::Snapshot(reply))
+        .await
+        .context("RIB manager stopped")?;
+    rx.await.context("RIB manager stopped")
+}
+
+pub async fn damping_snapshot(tx: &mpsc::Sender<RibEvent>) -> Result<damping::Damping> {
+    let (reply, rx) = oneshot::channel();
+    tx.send(RibEvent::DampingSnapshot(reply))
+        .await
+        .context("RIB manager stopped")?;
+    rx.await.context("RIB manager stopped")
+}
+
+/// State owned by the RIB manager of an address family.
+struct RibManager {
+    /// Copied on write while a snapshot is in use. [`Arc::make_mut`] then clones the whole
+    /// Loc-RIB, every prefix and path, on this task before applying the change: a change during
+    /// a gRPC read or a FIB sync over a full table costs a full copy. A FIB sync request not
+    /// taken yet is cancelled before each change for that reason.
+    rib: Arc<rib::Rib>,
+    /// Resolution of the next hops, as published by the FIB manager.
+    resolutions: watch::Receiver<Arc<fib::NextHops>>,
+    damping: damping::Damping,
+    next_hops: NextHopIndex,
+    groups: UpdateGroups,
+    config: config::BestPathConfig,
+    fib_sync: Arc<FibSync>,
+}
+
+impl RibManager {
+    async fn process(&mut self, events: Vec<RibEvent>) {
+        let pending = self.fib_sync.cancel();
+        let mut batch = Batch::default();
+        let mut snapshots = vec![];
+        for event in events {
+            match event {
+                RibEvent::Snapshot(reply) => snapshots.push(reply),
+                RibEvent::DampingSnapshot(reply) => {
+                    let _ = reply.send(self.damping.clone());
+                }
+                RibEvent::PeerUp(peer) => {
+                    // Joins once the batch so far is advertised, which the Loc-RIB includes.
+                    self.decide(std::mem::take(&mut batch), false).await;
+                    self.groups.join(*peer, &self.rib);
+                }
+                RibEvent::PeerDown(cell) => self.groups.leave(&cell),
+                event => {
+                    let rib = Arc::make_mut(&mut self.rib);
+                    process_rib_event(event, rib, &mut self.damping, &self.next_hops, &mut batch)
+                        .await;
                 }
             }
         }
+        self.decide(batch, pending).await;
+        for reply in snapshots {
+            let _ = reply.send(self.rib.clone());
+        }
+    }
+
+    /// Lifts the suppression of the damped paths due for reuse.
+    async fn reuse(&mut self) {
+        let reused = self.damping.reuse(Instant::now());
+        if reused.is_empty() {
+            return;
+        }
+        let pending = self.fib_sync.cancel();
+        let mut batch = Batch::default();
+        loc_rib_unsuppress(Arc::make_mut(&mut self.rib), &mut batch, reused);
+        self.decide(batch, pending).await;
+    }
+
+    /// Runs the decision process over the prefixes touched by `batch` and hands the changes to
+    /// the FIB manager and the neighbors. `pending` is whether a FIB sync was still to be done.
+    async fn decide(&mut self, batch: Batch, pending: bool) {
+        let changes = if batch.touched.is_empty() {
+            RibChanges::default()
+        } else {
+            let resolutions = self.resolutions.borrow().clone();
+            batch.decide(
+                Arc::make_mut(&mut self.rib),
+                &resolutions,
+                &self.config,
+                &mut self.next_hops,
+            )
+        };
+        if pending || changes.fib || changes.track || !changes.best.is_empty() {
+            self.fib_sync.request(self.rib.clone());
+        }
+        log::debug!(
+            "The following have modified best route and need to be propagated {:?}",
+            changes.best
+        );
+        self.groups
+            .advertise(&changes.best, tokio::time::Instant::now());
     }
-    modified
 }
 
 pub async fn rib_mgr(
-    rib: Arc<Mutex<rib::Rib>>,
-    fib: Arc<Mutex<fib::Fib>>,
-    neighbors: Vec<Arc<Mutex<neighbor::BGPNeighbor>>>,
-    asn: u16,
-    mut rx: tokio::sync::mpsc::Receiver<RibEvent>,
-    tx: tokio::sync::mpsc::Sender<FibEvent>,
+    resolutions: watch::Receiver<Arc<fib::NextHops>>,
+    damping: damping::Damping,
+    config: config::BestPathConfig,
+    mut rx: mpsc::Receiver<RibEvent>,
+    fib_sync: Arc<FibSync>,
 ) {
+    let mut manager = RibManager {
+        rib: Arc::new(rib::Rib::default()),
+        resolutions,
+        damping,
+        next_hops: NextHopIndex::default(),
+        groups: UpdateGroups::default(),
+        config,
+        fib_sync,
+    };
+    let mut reuse_timer = tokio::time::interval(DAMPING_REUSE_INTERVAL);
+
     loop {
-        match rx.recv().await {
-            Some(e) => {
-                log::debug!("Rib Manager got {:?}", e);
-
-                if let Err(e) =
-                    process_rib_event(e, rib.clone(), fib.clone(), neighbors.clone(), asn, &tx)
-                        .await
-                {
-                    log::error!("Error processing RIB event: {}", e);
+        let flush_at = manager.groups.next_flush();
+        tokio::select! {
+            e = rx.recv() => match e {
+                Some(e) => {
+                    // Whatever queued up meanwhile is applied along, so that the decision process
+                    // runs once per prefix and the FIB and neighbors are told once.
+                    let mut events = vec![e];
+                    while events.len() < MAX_BATCH {
+                        match rx.try_recv() {
+                            Ok(e) => events.push(e),
+                            Err(_) => break,
+                        }
+                    }
+                    log::debug!("Rib Manager got {} events", events.len());
+                    manager.process(events).await;
                 }
-            }
-            None => {
-                log::info!("RIB manager channel closed, exiting");
-                break;
+                None => {
+                    log::info!("RIB manager channel closed, exiting");
+                    break;
+                }
+            },
+            _ = reuse_timer.tick() => manager.reuse().await,
+            _ = tokio::time::sleep_until(flush_at.unwrap_or_else(tokio::time::Instant::now)),
+                if flush_at.is_some() =>
+            {
+                manager.groups.flush(tokio::time::Instant::now());
             }
         }
-
-        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
 
+/// Applies `event` to the Loc-RIB, leaving the decision process to the end of the batch.
 async fn process_rib_event(
     event: RibEvent,
-    rib: Arc<Mutex<rib::Rib>>,
-    fib: Arc<Mutex<fib::Fib>>,
-    neighbors: Vec<Arc<Mutex<neighbor::BGPNeighbor>>>,
-    asn: u16,
-    tx: &tokio::sync::mpsc::Sender<FibEvent>,
-) -> Result<()> {
+    rib: &mut rib::Rib,
+    damping: &mut damping::Damping,
+    next_hop_index: &NextHopIndex,
+    batch: &mut Batch,
+) {
+    log::debug!("Rib Manager got {:?}", event);
+
     match event {
         RibEvent::UpdateRoutes(msg) => {
-            let mut modified = vec![];
-
             if let Some(routes) = msg.added {
                 log::debug!("Adding routes {:?} from {:?}", routes, msg.rid);
-                let mut added = loc_rib_added(rib.clone(), fib.clone(), asn, routes).await;
-                modified.append(&mut added);
+                loc_rib_added(rib, damping, batch, routes).await;
             }
 
             if let Some(routes) = msg.withdrawn {
-                let mut withdraw = loc_rib_withdraw(rib.clone(), fib.clone(), routes).await;
-                modified.append(&mut withdraw);
-            }
-
-            log::debug!(
-                "The following have modified best route and need to be propagated {:?}",
-                modified
-            );
-
-            if !modified.is_empty() {
-                tx.send(FibEvent::RibUpdated)
-                    .await
-                    .context("Failed to send FIB update event")?;
-
-                for n in &neighbors {
-                    let n = n.lock().await;
-                    if n.is_established().await {
-                        if let Some(tx) = &n.tx {
-                            if let Err(e) =
-                                tx.send(neighbor::Event::RibUpdate(modified.clone())).await
-                            {
-                                log::error!("Failed to send RIB update to neighbor: {}", e);
-                            }
-                        }
-                    }
-                }
+                loc_rib_withdraw(rib, damping, batch, routes);
             }
         }
+        RibEvent::NextHopsChanged(next_hops) => {
+            log::debug!("Next hops {:?} changed", next_hops);
+            loc_rib_next_hops(rib, next_hop_index, batch, next_hops);
+        }
+        RibEvent::ClearDamping(nlri) => {
+            log::info!("Clearing damping history for {:?}", nlri);
+            let cleared = damping.clear(nlri);
+            loc_rib_unsuppress(rib, batch, cleared);
+        }
+        // Answered by the RIB manager once the batch is applied.
+        RibEvent::Snapshot(_)
+        | RibEvent::DampingSnapshot(_)
+        | RibEvent::PeerUp(_)
+        | RibEvent::PeerDown(_) => {}
     }
-
-    Ok(())
 }
+
+/// Installs the best paths of the Loc-RIB and follows the changes of the kernel routing table,
+/// publishing the resolution of the next hops through `resolutions` and telling the RIB manager
+/// about those that changed.
 pub async fn fib_mgr(
-    fib: Arc<Mutex<fib::Fib>>,
-    rib: Arc<Mutex<rib::Rib>>,
-    mut rx: tokio::sync::mpsc::Receiver<FibEvent>,
+    mut fib: fib::Fib,
+    fib_sync: Arc<FibSync>,
+    stale_routes_time: Duration,
+    resolutions: watch::Sender<Arc<fib::NextHops>>,
+    ribtx: mpsc::Sender<RibEvent>,
+    mut rx: mpsc::Receiver<FibEvent>,
 ) {
     log::debug!("starting fib manager");
-    {
-        let mut fib = fib.lock().await;
-        fib.refresh().await;
-    }
+    fib.refresh().await;
+    resolutions.send_replace(Arc::new(fib.next_hops()));
+
+    let stale_sweep = tokio::time::sleep(stale_routes_time);
+    tokio::pin!(stale_sweep);
+    let mut swept = false;
 
     loop {
-        match tokio::time::timeout(Duration::from_secs(30), rx.recv()).await {
-            Ok(Some(e)) => match e {
-                FibEvent::RibUpdated => {
-                    log::debug!("Fib Manager: Got RIB update event");
-                    let mut fib = fib.lock().await;
-                    fib.refresh().await;
-                    fib.sync(rib.clone()).await;
+        let changed = tokio::select! {
+            rib = fib_sync.next() => {
+                log::debug!("Fib Manager: Got RIB update");
+                sync_fib(&mut fib, rib).await
+            }
+            e = rx.recv() => match e {
+                Some(FibEvent::RouteAdded(msg)) => match fib.route_added(msg).await {
+                    Some(prefix) => fib.update_next_hops(Some(&prefix)),
+                    None => continue,
+                },
+                Some(FibEvent::RouteDeleted(msg)) => match fib.route_deleted(msg) {
+                    Some(prefix) => fib.update_next_hops(Some(&prefix)),
+                    None => continue,
+                },
+                Some(FibEvent::LinkChanged(link)) => {
+                    fib.link_changed(link, false).await;
+                    fib.update_next_hops(None)
+                }
+                Some(FibEvent::LinkRemoved(link)) => {
+                    fib.link_changed(link, true).await;
+                    fib.update_next_hops(None)
+                }
+                None => {
+                    log::info!("FIB manager channel closed, exiting");
+                    break;
                 }
             },
-            Ok(None) => {
-                log::info!("FIB manager channel closed, exiting");
-                break;
+            _ = &mut stale_sweep, if !swept => {
+                swept = true;
+                log::info!("Removing BGP routes left over by a previous run");
+                fib.sweep_stale();
+                match rib_snapshot(&ribtx).await {
+                    Ok(rib) => sync_fib(&mut fib, rib).await,
+                    Err(e) => {
+                        log::error!("Failed to read the Loc-RIB: {}", e);
+                        continue;
+                    }
+                }
             }
-            Err(_) => {
-                log::trace!("FIB periodic refresh");
-                let mut fib = fib.lock().await;
-                fib.refresh().await;
+        };
+
+        if !changed.is_empty() {
+            resolutions.send_replace(Arc::new(fib.next_hops()));
+            if let Err(e) = ribtx.send(RibEvent::NextHopsChanged(changed)).await {
+                log::error!("Failed to send next hop changes to the RIB manager: {}", e);
             }
         }
     }
 }
+
+/// Brings the kernel routes in line with the Loc-RIB, and the tracked next hops in line with
+/// those of its paths. Returns the next hops whose resolution changed, those tracked from now on
+/// included.
+async fn sync_fib(fib: &mut fib::Fib, rib: Arc<rib::Rib>) -> Vec<IpAddr> {
+    let (desired, in_use) = {
+        let in_use: HashSet<IpAddr> = rib.values().flat_map(|a| path_next_hops(a)).collect();
+        (fib::desired_routes(&rib), in_use)
+    };
+    // Released before the kernel is synced, so the RIB manager need not copy the Loc-RIB.
+    drop(rib);
+    let mut changed = fib.track_next_hops(&in_use);
+    changed.extend(fib.sync(desired).await);
+    changed
+}
+
+/// Forwards the kernel route notifications of `af`, and link changes, to the FIB manager.
+pub async fn kernel_monitor(
+    af: bgp::AddressFamily,
+    tx: tokio::sync::mpsc::Sender<FibEvent>,
+) -> Result<()> {
+    let route_group = match af.afi {
+        bgp::Afi::Ipv4 => MulticastGroup::Ipv4Route,
+        bgp::Afi::Ipv6 => MulticastGroup::Ipv6Route,
+    };
+    let (connection, _, mut messages) =
+        new_multicast_connection(&[route_group, MulticastGroup::Link])
+            .context("Failed to subscribe to netlink notifications")?;
+    tokio::spawn(connection);
+
+    while let Some((msg, _)) = messages.next().await {
+        let event = match msg.payload {
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(r)) => {
+                FibEvent::RouteAdded(r)
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRoute(r)) => {
+                FibEvent::RouteDeleted(r)
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewLink(l)) => {
+                FibEvent::LinkChanged(l)
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelLink(l)) => {
+                FibEvent::LinkRemoved(l)
+            }
+            _ => continue,
+        };
+        tx.send(event).await.context("FIB manager channel closed")?;
+    }
+    Ok(())
+}
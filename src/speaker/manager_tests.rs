@@ -0,0 +1,227 @@
+use netlink_packet_route::route::RouteProtocol;
+use rtnetlink::RouteMessageBuilder;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+use crate::bgp::{ASPATHSegment, ASPATHSegmentType, AddressFamily, Afi, Nlri, Safi};
+use crate::config::{BestPathConfig, FibConfig};
+use crate::damping::Damping;
+use crate::fib::{Fib, FibEntry, InstallPolicy, MemoryBackend, RouteParams};
+use crate::rib::{AttributeSet, Rib, RibUpdate, RouteAttributes};
+
+use super::events::{FibEvent, RibEvent, Update};
+use super::manager::{fib_mgr, rib_mgr, rib_snapshot, FibSync};
+
+fn nlri(s: &str) -> Nlri {
+    Nlri {
+        net: s.parse().unwrap(),
+    }
+}
+
+fn peer(rid: u32) -> RouteAttributes {
+    RouteAttributes {
+        attrs: Arc::new(AttributeSet {
+            as_path: vec![ASPATHSegment {
+                segment_type: ASPATHSegmentType::AsSequence,
+                as_list: vec![65000 + rid as u16],
+            }],
+            next_hop: IpAddr::from([192, 0, 2, rid as u8]),
+            ..Default::default()
+        }),
+        peer_rid: rid,
+        peer_ip: IpAddr::from([192, 0, 2, rid as u8]),
+        ..Default::default()
+    }
+}
+
+fn added(rid: u32, nlris: Vec<Nlri>) -> RibEvent {
+    RibEvent::UpdateRoutes(Box::new(Update {
+        added: Some(RibUpdate {
+            nlris,
+            attributes: peer(rid),
+        }),
+        withdrawn: None,
+        rid,
+    }))
+}
+
+fn withdrawn(rid: u32, nlris: Vec<Nlri>) -> RibEvent {
+    RibEvent::UpdateRoutes(Box::new(Update {
+        added: None,
+        withdrawn: Some(RibUpdate {
+            nlris,
+            attributes: peer(rid),
+        }),
+        rid,
+    }))
+}
+
+/// Starts a RIB manager without neighbors once `events` are queued, so that they make up its
+/// first batch. Returns its mailbox and the slot it asks the FIB manager to sync through.
+async fn start(events: Vec<RibEvent>) -> (mpsc::Sender<RibEvent>, Arc<FibSync>) {
+    let af = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::NLRIUnicast,
+    };
+    let backend = Box::new(MemoryBackend::new());
+    let fib = Fib::new(af, FibConfig::default(), InstallPolicy::default(), backend).await;
+    let (rib_tx, rib_rx) = mpsc::channel(100);
+    for e in events {
+        rib_tx.send(e).await.unwrap();
+    }
+    let fib_sync = Arc::new(FibSync::default());
+    let (_, resolutions) = watch::channel(Arc::new(fib.next_hops()));
+    tokio::spawn(rib_mgr(
+        resolutions,
+        Damping::new(None),
+        BestPathConfig::default(),
+        rib_rx,
+        fib_sync.clone(),
+    ));
+    (rib_tx, fib_sync)
+}
+
+#[tokio::test]
+async fn test_rib_mgr_batches_events() {
+    let events = vec![
+        added(1, vec![nlri("10.0.0.0/24"), nlri("10.0.1.0/24")]),
+        added(2, vec![nlri("10.0.0.0/24")]),
+        withdrawn(1, vec![nlri("10.0.0.0/24")]),
+        added(3, vec![nlri("10.0.2.0/24")]),
+        withdrawn(3, vec![nlri("10.0.2.0/24")]),
+    ];
+    let (tx, fib_sync) = start(events).await;
+    let rib = rib_snapshot(&tx).await.unwrap();
+
+    assert!(fib_sync.cancel());
+    assert_eq!(rib.len(), 2);
+    let paths = rib.get(&nlri("10.0.0.0/24")).unwrap();
+    assert_eq!(paths.len(), 1);
+    assert_eq!(paths[0].peer_rid, 2);
+    assert!(paths[0].multipath);
+    assert_eq!(rib.get(&nlri("10.0.1.0/24")).unwrap()[0].peer_rid, 1);
+    // Prefixes added and withdrawn within the batch leave nothing behind
+    assert!(rib.get(&nlri("10.0.2.0/24")).is_none());
+}
+
+#[tokio::test]
+async fn test_rib_mgr_unchanged_batch_not_propagated() {
+    let (tx, fib_sync) = start(vec![added(1, vec![nlri("10.0.0.0/24")])]).await;
+    rib_snapshot(&tx).await.unwrap();
+    assert!(fib_sync.cancel());
+
+    // Readvertising the same path changes neither the best path nor the FIB
+    tx.send(added(1, vec![nlri("10.0.0.0/24")])).await.unwrap();
+    rib_snapshot(&tx).await.unwrap();
+    assert!(!fib_sync.cancel());
+}
+
+#[tokio::test]
+async fn test_rib_snapshot_unchanged_by_later_events() {
+    let (tx, _fib_sync) = start(vec![added(1, vec![nlri("10.0.0.0/24")])]).await;
+    let before = rib_snapshot(&tx).await.unwrap();
+
+    tx.send(withdrawn(1, vec![nlri("10.0.0.0/24")]))
+        .await
+        .unwrap();
+    let after = rib_snapshot(&tx).await.unwrap();
+
+    assert_eq!(before.len(), 1);
+    assert!(after.is_empty());
+}
+
+/// Loc-RIB once the best path of `prefix` is `installed` or not, after the FIB manager caught up.
+async fn wait_installed(tx: &mpsc::Sender<RibEvent>, prefix: Nlri, installed: bool) -> Arc<Rib> {
+    for _ in 0..100 {
+        let rib = rib_snapshot(tx).await.unwrap();
+        if rib.get(&prefix).is_some_and(|a| a[0].multipath) == installed {
+            return rib;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{:?} installed is not {}", prefix, installed);
+}
+
+#[tokio::test]
+async fn test_next_hops_resolved_by_fib_manager() {
+    let af = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::NLRIUnicast,
+    };
+    let params = RouteParams {
+        protocol: RouteProtocol::Kernel,
+        ..Default::default()
+    };
+    let connected = FibEntry::new("192.0.2.0/24".parse().unwrap(), vec![], &params);
+    let backend = Box::new(MemoryBackend::with_routes(vec![connected]));
+    let fib = Fib::new(af, FibConfig::default(), InstallPolicy::default(), backend).await;
+    let (resolutions_tx, resolutions) = watch::channel(Arc::new(fib.next_hops()));
+    let (rib_tx, rib_rx) = mpsc::channel(100);
+    let (fib_tx, fib_rx) = mpsc::channel(100);
+    let fib_sync = Arc::new(FibSync::default());
+    tokio::spawn(rib_mgr(
+        resolutions,
+        Damping::new(None),
+        BestPathConfig::default(),
+        rib_rx,
+        fib_sync.clone(),
+    ));
+    tokio::spawn(fib_mgr(
+        fib,
+        fib_sync,
+        Duration::from_secs(3600),
+        resolutions_tx,
+        rib_tx.clone(),
+        fib_rx,
+    ));
+
+    // The next hop is unreachable until the FIB manager tracks it
+    let prefix = nlri("10.0.0.0/24");
+    rib_tx.send(added(1, vec![prefix])).await.unwrap();
+    wait_installed(&rib_tx, prefix, true).await;
+
+    let msg = RouteMessageBuilder::<Ipv4Addr>::new()
+        .destination_prefix(Ipv4Addr::new(192, 0, 2, 0), 24)
+        .build();
+    fib_tx.send(FibEvent::RouteDeleted(msg)).await.unwrap();
+    let rib = wait_installed(&rib_tx, prefix, false).await;
+    assert_eq!(rib.get(&prefix).unwrap().len(), 1);
+}
+
+/// Feeds a full table of single prefix UPDATEs from two peers to the RIB manager through a
+/// channel of the size the speaker uses. The timings are printed, run with
+/// `cargo test --release -- --ignored --nocapture bench_`.
+#[tokio::test]
+#[ignore]
+async fn bench_rib_mgr_100k_updates() {
+    const UPDATES: u32 = 100_000;
+    let (rib_tx, _fib_sync) = start(vec![]).await;
+
+    let start = Instant::now();
+    for rid in [1, 2] {
+        for i in 0..UPDATES {
+            let net = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + (i << 8)));
+            let prefix = Nlri {
+                net: ipnet::IpNet::new(net, 24).unwrap(),
+            };
+            rib_tx.send(added(rid, vec![prefix])).await.unwrap();
+        }
+        println!(
+            "{:?} to queue {} UPDATEs from peer {}",
+            start.elapsed(),
+            UPDATES,
+            rid
+        );
+    }
+    let rib = rib_snapshot(&rib_tx).await.unwrap();
+    let elapsed = start.elapsed();
+    println!(
+        "{:?} to process {} UPDATEs, {:.0} per second",
+        elapsed,
+        2 * UPDATES,
+        2.0 * UPDATES as f64 / elapsed.as_secs_f64()
+    );
+    assert_eq!(rib.len(), UPDATES as usize);
+}
@@ -1,7 +1,14 @@
 mod connection;
 mod events;
 mod manager;
+#[cfg(test)]
+mod manager_tests;
 mod types;
+mod update_group;
+#[cfg(test)]
+mod update_group_tests;
 
 pub use events::{RibEvent, Update};
+pub use manager::{damping_snapshot, rib_snapshot};
 pub use types::BGPSpeaker;
+pub use update_group::Peer;
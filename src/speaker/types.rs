@@ -1,31 +1,47 @@
+use anyhow::{Context, Result};
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::time::Duration;
 
+use crate::bfd;
 use crate::bgp;
 use crate::config;
+use crate::damping;
 use crate::fib;
 use crate::neighbor;
+use crate::policy;
 use crate::rib;
 
 use super::connection;
-use super::events::{FibEvent, RibEvent};
+use super::events::{FibEvent, RibEvent, Update};
 use super::manager;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 #[derive(Builder, Debug)]
 #[builder(setter(into))]
 pub struct BGPSpeaker {
     pub local_asn: u16,
     pub router_id: u32,
+    /// Cluster of the route reflector, the router ID unless configured.
+    pub cluster_id: u32,
+    pub confederation: Option<config::Confederation>,
     pub hold_time: u16,
     pub local_ips: Vec<IpAddr>,
     pub local_port: u16,
     pub families: Vec<bgp::AddressFamily>,
-    pub rib: HashMap<bgp::AddressFamily, Arc<Mutex<rib::Rib>>>,
-    pub fib: HashMap<bgp::AddressFamily, Arc<Mutex<fib::Fib>>>,
+    /// Resolution of the next hops of every address family, as published by its FIB manager.
+    pub next_hops: HashMap<bgp::AddressFamily, watch::Receiver<Arc<fib::NextHops>>>,
     pub ribtx: HashMap<bgp::AddressFamily, tokio::sync::mpsc::Sender<RibEvent>>,
-    pub neighbors: Vec<Arc<Mutex<neighbor::BGPNeighbor>>>,
+    pub neighbors: Vec<Arc<neighbor::NeighborCell>>,
+    /// Prefixes originated by the speaker.
+    pub networks: Vec<bgp::Nlri>,
+    pub bfd: config::BfdConfig,
+    pub damping_config: Option<config::DampingConfig>,
+    pub policies: Arc<policy::Policies>,
+    pub best_path: config::BestPathConfig,
+    pub mrai: config::MraiConfig,
+    pub fib_config: config::FibConfig,
+    pub fib_policies: HashMap<bgp::AddressFamily, fib::InstallPolicy>,
 }
 
 impl BGPSpeaker {
@@ -40,23 +56,28 @@ impl BGPSpeaker {
         BGPSpeakerBuilder::default()
             .local_asn(local_asn)
             .router_id(router_id)
+            .cluster_id(router_id)
+            .confederation(None)
             .hold_time(hold_time)
             .local_ips(local_ips)
             .local_port(local_port)
             .families(families)
-            .rib(HashMap::new())
-            .fib(HashMap::new())
+            .next_hops(HashMap::new())
             .ribtx(HashMap::new())
             .neighbors(vec![])
+            .networks(vec![])
+            .bfd(config::BfdConfig::default())
+            .damping_config(None)
+            .policies(Arc::new(policy::Policies::default()))
+            .best_path(config::BestPathConfig::default())
+            .mrai(config::MraiConfig::default())
+            .fib_config(config::FibConfig::default())
+            .fib_policies(HashMap::new())
             .build()
             .expect("BUG: Failed to build BGPSpeaker with valid parameters")
     }
-    pub async fn add_neighbor(
-        &mut self,
-        config: config::Neighbor,
-        ribtx: HashMap<bgp::AddressFamily, tokio::sync::mpsc::Sender<RibEvent>>,
-    ) {
-        let n = Arc::new(Mutex::new(neighbor::BGPNeighbor::new(
+    pub async fn add_neighbor(&mut self, config: config::Neighbor) -> Result<()> {
+        let mut n = neighbor::BGPNeighbor::new(
             None,
             None,
             self.local_asn,
@@ -77,38 +98,126 @@ impl BGPSpeaker {
                 .expect("Connect retry time not configured for neighbor"),
             neighbor::BGPState::Idle,
             config.families,
-            ribtx,
+            HashMap::new(),
             config.max_retry_count,
             config.exponential_backoff,
-        )));
-        self.neighbors.push(n);
-    }
-    pub async fn start(speaker: Arc<Mutex<BGPSpeaker>>) {
+        );
+        n.bfd = config.bfd;
+        n.max_prefix = config
+            .max_prefix
+            .into_iter()
+            .map(|mp| (mp.family(), mp))
+            .collect();
+        n.set_peer_damping(config.damp_peer_oscillations);
+        n.weight = config.weight;
+        n.mrai = config.mrai;
+        n.soft_reconfiguration_inbound = config.soft_reconfiguration_inbound;
+        n.route_reflector_client = config.route_reflector_client;
+        n.local_as = config.local_as;
+        n.as_override = config.as_override;
+        n.allowas_in = config.allowas_in;
+        n.cluster_id = self.cluster_id;
+        if let Some(confed) = self
+            .confederation
+            .as_ref()
+            .filter(|_| config.asn != self.local_asn)
         {
-            let mut speaker = speaker.lock().await;
-            for af in speaker.families.clone() {
-                let rib = Arc::new(Mutex::new(HashMap::new()));
-                let fib = Arc::new(Mutex::new(fib::Fib::new(af.clone()).await));
-                let (rib_tx, rib_rx) = mpsc::channel::<RibEvent>(100);
-                let (fib_tx, fib_rx) = mpsc::channel::<FibEvent>(100);
-                speaker.rib.insert(af.clone(), rib.clone());
-                speaker.ribtx.insert(af.clone(), rib_tx);
-                speaker.fib.insert(af, fib.clone());
-                let r1 = rib.clone();
-                let f1 = fib.clone();
-                let asn = speaker.local_asn;
-                let neighbors = speaker.neighbors.clone();
+            if confed.members.contains(&config.asn) {
+                n.confederation_peer = true;
+            } else {
+                // The confederation is a single AS to the outside
+                n.local_asn = confed.identifier;
+            }
+        }
+        for p in &config.policies {
+            n.policies
+                .insert(p.family(), self.policies.neighbor_policy(p)?);
+        }
+        self.neighbors
+            .push(Arc::new(neighbor::NeighborCell::new(n)));
+        Ok(())
+    }
+    /// Spawns the RIB and FIB managers of every address family, then the tasks establishing the
+    /// sessions. The speaker is read-only from then on: the RIBs live in their managers and the
+    /// neighbors in their cells.
+    pub async fn start(mut self) -> Result<Arc<Self>> {
+        let mut backends = fib::backends(&self.fib_config, &self.families)
+            .context("Failed to create the FIB backends")?;
+        for af in self.families.clone() {
+            let backend = backends
+                .remove(&af)
+                .expect("BUG: No FIB backend for address family");
+            let policy = self.fib_policies.get(&af).cloned().unwrap_or_default();
+            let fib = fib::Fib::new(af.clone(), self.fib_config.clone(), policy, backend).await;
+            let (resolutions_tx, resolutions) = watch::channel(Arc::new(fib.next_hops()));
+            let damping = damping::Damping::new(self.damping_config.clone());
+            let fib_sync = Arc::new(manager::FibSync::default());
+            let (rib_tx, rib_rx) = mpsc::channel::<RibEvent>(100);
+            let (fib_tx, fib_rx) = mpsc::channel::<FibEvent>(100);
+            self.ribtx.insert(af.clone(), rib_tx.clone());
+            self.next_hops.insert(af.clone(), resolutions.clone());
+            let s1 = fib_sync.clone();
+            let best_path = self.best_path.clone();
+            let monitor_tx = fib_tx.clone();
+            tokio::spawn(async move {
+                manager::rib_mgr(resolutions, damping, best_path, rib_rx, s1).await
+            });
+            let networks: Vec<bgp::Nlri> = self
+                .networks
+                .iter()
+                .filter(|n| n.afi() == af.afi)
+                .copied()
+                .collect();
+            if !networks.is_empty() {
+                let update = Update {
+                    added: Some(rib::RibUpdate {
+                        nlris: networks,
+                        attributes: rib::RouteAttributes::local(self.router_id, &af.afi),
+                    }),
+                    withdrawn: None,
+                    rid: self.router_id,
+                };
+                rib_tx
+                    .send(RibEvent::UpdateRoutes(Box::new(update)))
+                    .await
+                    .context("RIB manager stopped")?;
+            }
+            if self.fib_config.backend == config::FibBackendType::Netlink {
                 tokio::spawn(async move {
-                    manager::rib_mgr(r1, f1, neighbors, asn, rib_rx, fib_tx).await
+                    if let Err(e) = manager::kernel_monitor(af, monitor_tx).await {
+                        log::error!("Kernel route monitor failed: {:#}", e);
+                    }
                 });
-                tokio::spawn(async move { manager::fib_mgr(fib, rib, fib_rx).await });
             }
+            let stale_routes_time = Duration::from_secs(self.fib_config.stale_routes_time as u64);
+            tokio::spawn(async move {
+                manager::fib_mgr(
+                    fib,
+                    fib_sync,
+                    stale_routes_time,
+                    resolutions_tx,
+                    rib_tx,
+                    fib_rx,
+                )
+                .await
+            });
+        }
+        for n in &self.neighbors {
+            n.lock().await.ribtx = self.ribtx.clone();
         }
 
+        let speaker = Arc::new(self);
         let s1 = speaker.clone();
         let s2 = speaker.clone();
+        let s3 = speaker.clone();
 
+        tokio::spawn(async move {
+            if let Err(e) = bfd::bfd_mgr(s3).await {
+                log::error!("BFD manager failed: {}", e);
+            }
+        });
         tokio::spawn(async move { connection::connect_mgr(s1).await });
         tokio::spawn(async move { connection::listen(s2).await });
+        Ok(speaker)
     }
 }
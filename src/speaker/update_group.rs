@@ -0,0 +1,554 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{Duration, Instant};
+
+use crate::bgp::{self, Nlri};
+use crate::config;
+use crate::neighbor;
+use crate::policy;
+use crate::rib;
+
+use super::manager::current_best;
+
+/// Path attributes of an UPDATE, shared by every Adj-RIB-Out the path is advertised in.
+type Attributes = Arc<Vec<bgp::PathAttribute>>;
+
+/// UPDATE header and the withdrawn routes and path attributes lengths.
+const UPDATE_OVERHEAD: usize = bgp::MIN_MESSAGE_LENGTH + 4;
+
+/// What the UPDATEs sent to a neighbor depend on. Neighbors sharing it share an update group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GroupKey {
+    local_asn: u16,
+    ibgp: bool,
+    /// The neighbors are route reflector clients.
+    client: bool,
+    /// The neighbors are in another member AS of the confederation.
+    confed: bool,
+    cluster_id: u32,
+    /// Next hop of the locally originated paths.
+    local_ip: IpAddr,
+    /// Next hop of the paths sent over eBGP.
+    next_hop_self: Option<IpAddr>,
+    local_as: Option<config::LocalAs>,
+    /// AS of the neighbors, replaced with the local AS in the paths sent to them.
+    as_override: Option<u16>,
+    export: Option<String>,
+    mrai: Duration,
+}
+
+/// A neighbor whose session got established, joining the update group of an address family.
+#[derive(Debug)]
+pub struct Peer {
+    cell: Arc<neighbor::NeighborCell>,
+    rid: u32,
+    key: GroupKey,
+    export: Option<Arc<policy::RouteMap>>,
+    jitter: u8,
+}
+
+impl Peer {
+    pub fn new(
+        cell: Arc<neighbor::NeighborCell>,
+        n: &neighbor::BGPNeighbor,
+        af: &bgp::AddressFamily,
+        mrai: &config::MraiConfig,
+    ) -> Result<Self> {
+        let rid = n
+            .remote_rid
+            .ok_or_else(|| anyhow!("Remote router ID not set"))?;
+        let local_ip = n.local_ip.ok_or_else(|| anyhow!("Local IP not set"))?;
+        let remote_asn = n.remote_asn.ok_or_else(|| anyhow!("Remote ASN not set"))?;
+        let ibgp = n.local_asn == remote_asn;
+        let confed = n.confederation_peer;
+        let export = n.policies.get(af).and_then(|p| p.export.clone());
+        let default_mrai = if ibgp { mrai.ibgp } else { mrai.ebgp };
+        Ok(Peer {
+            cell,
+            rid,
+            key: GroupKey {
+                local_asn: n.local_asn,
+                ibgp,
+                client: ibgp && n.route_reflector_client,
+                confed,
+                cluster_id: n.cluster_id,
+                local_ip,
+                next_hop_self: (!ibgp && !confed).then_some(local_ip),
+                local_as: n.local_as.clone(),
+                as_override: n.as_override.then_some(remote_asn),
+                export: export.as_ref().map(|e| e.name.clone()),
+                mrai: Duration::from_secs(n.mrai.unwrap_or(default_mrai) as u64),
+            },
+            export,
+            jitter: mrai.jitter,
+        })
+    }
+}
+
+/// Path exported to an update group, with the router ID of the peer it was learned from.
+#[derive(Debug, Clone)]
+struct Export {
+    attributes: Attributes,
+    peer_rid: u32,
+}
+
+/// Changes to advertise to a neighbor, announcements grouped by path attributes.
+#[derive(Debug, Default)]
+struct Changes {
+    withdrawn: Vec<Nlri>,
+    announced: Vec<(Attributes, Vec<Nlri>)>,
+    /// Position in `announced` of the announcements with the attributes.
+    groups: HashMap<*const Vec<bgp::PathAttribute>, usize>,
+}
+
+impl PartialEq for Changes {
+    fn eq(&self, other: &Self) -> bool {
+        self.withdrawn == other.withdrawn && self.announced == other.announced
+    }
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.withdrawn.is_empty() && self.announced.is_empty()
+    }
+
+    fn announce(&mut self, nlri: Nlri, attributes: &Attributes) {
+        match self.groups.get(&Arc::as_ptr(attributes)) {
+            Some(&i) => self.announced[i].1.push(nlri),
+            None => {
+                self.groups
+                    .insert(Arc::as_ptr(attributes), self.announced.len());
+                self.announced.push((attributes.clone(), vec![nlri]));
+            }
+        }
+    }
+
+    /// UPDATE messages carrying the changes, withdrawals first, none over the maximum message
+    /// length.
+    fn encode(&self) -> Result<Vec<Vec<u8>>> {
+        let mut messages = vec![];
+        for withdrawn in chunks(&self.withdrawn, 0) {
+            messages.push(update(withdrawn, vec![], vec![])?);
+        }
+        for (attributes, nlris) in &self.announced {
+            let attributes_len = attributes
+                .iter()
+                .map(|a| Vec::<u8>::from(a.clone()).len())
+                .sum();
+            for nlris in chunks(nlris, attributes_len) {
+                messages.push(update(vec![], attributes.to_vec(), nlris)?);
+            }
+        }
+        Ok(messages)
+    }
+}
+
+/// Splits `nlris` so that each UPDATE with `attributes_len` bytes of path attributes fits the
+/// maximum message length.
+fn chunks(nlris: &[Nlri], attributes_len: usize) -> Vec<Vec<Nlri>> {
+    let room = bgp::MAX_MESSAGE_LENGTH - UPDATE_OVERHEAD - attributes_len;
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    let mut len = 0;
+    for nlri in nlris {
+        let nlri_len = 1 + (nlri.net.prefix_len() as usize).div_ceil(8);
+        if len + nlri_len > room {
+            chunks.push(std::mem::take(&mut chunk));
+            len = 0;
+        }
+        chunk.push(*nlri);
+        len += nlri_len;
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+fn update(
+    withdrawn: Vec<Nlri>,
+    attributes: Vec<bgp::PathAttribute>,
+    nlris: Vec<Nlri>,
+) -> Result<Vec<u8>> {
+    let body = bgp::BGPUpdateMessageBuilder::default()
+        .withdrawn_routes(withdrawn)
+        .path_attributes(attributes)
+        .nlri(nlris)
+        .build()
+        .map_err(|e| anyhow!("Failed to build UPDATE message: {}", e))?;
+    log::debug!("Encoding UPDATE {:?}", body);
+    Ok(
+        bgp::Message::new(bgp::MessageType::Update, bgp::BGPMessageBody::Update(body))
+            .context("Failed to create UPDATE message")?
+            .into(),
+    )
+}
+
+#[derive(Debug)]
+struct Member {
+    cell: Arc<neighbor::NeighborCell>,
+    rid: u32,
+    /// Paths advertised to the neighbor.
+    adj_rib_out: HashMap<Nlri, Attributes>,
+    /// UPDATEs were left out as the session mailbox was full. The Adj-RIB-Out is not what the
+    /// neighbor got anymore, nothing is sent until the session is reset and joins again.
+    overflowed: bool,
+}
+
+impl Member {
+    /// Applies the changes exported to the group to the Adj-RIB-Out, leaving out the paths
+    /// learned from the neighbor itself, and returns those to advertise.
+    fn diff(&mut self, exports: &[(Nlri, Option<Export>)]) -> Changes {
+        let mut changes = Changes::default();
+        for (nlri, export) in exports {
+            let attributes = export
+                .as_ref()
+                .filter(|e| e.peer_rid != self.rid)
+                .map(|e| &e.attributes);
+            match attributes {
+                Some(attributes) => {
+                    if self.adj_rib_out.get(nlri) != Some(attributes) {
+                        self.adj_rib_out.insert(*nlri, attributes.clone());
+                        changes.announce(*nlri, attributes);
+                    }
+                }
+                // Only what was advertised is withdrawn.
+                None => {
+                    if self.adj_rib_out.remove(nlri).is_some() {
+                        changes.withdrawn.push(*nlri);
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    /// Hands `messages` to the session without waiting on it, so that a slow neighbor holds up
+    /// neither the RIB manager nor the rest of the group.
+    fn send(&mut self, messages: Arc<Vec<Vec<u8>>>) {
+        let Some(tx) = self.cell.status().tx else {
+            log::debug!("Session with {:?} closed", self.cell.remote_ip);
+            return;
+        };
+        match tx.try_send(neighbor::Event::Updates(messages)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!(
+                    "{:?} is not keeping up with its UPDATEs, resetting the session",
+                    self.cell.remote_ip
+                );
+                self.overflowed = true;
+                tokio::spawn(async move {
+                    let _ = tx.send(neighbor::Event::UpdatesOverflow).await;
+                });
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::debug!("Session with {:?} closed", self.cell.remote_ip);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UpdateGroup {
+    key: GroupKey,
+    export: Option<Arc<policy::RouteMap>>,
+    /// Paths exported to the group, before leaving out those of each member.
+    exported: HashMap<Nlri, Export>,
+    members: Vec<Member>,
+    jitter: u8,
+    /// Prefixes whose announcement waits for the MRAI timer. Only their latest path is sent.
+    pending: HashSet<Nlri>,
+    /// When the MRAI timer expires, announcements go out right away after that.
+    mrai_expiry: Option<Instant>,
+}
+
+impl UpdateGroup {
+    /// Runs the best path of `nlri` through the export rules of the group.
+    fn export(&self, nlri: &Nlri, best: &rib::RouteAttributes) -> Option<rib::RouteAttributes> {
+        // Paths learned over iBGP are only reflected from or to route reflector clients.
+        let reflected = self.key.ibgp && best.is_from_ibgp();
+        if reflected && !best.from_client && !self.key.client {
+            return None;
+        }
+        let external = !self.key.ibgp && !self.key.confed;
+        let mut ra = best.clone();
+        // The MED received from a neighboring AS is not sent to another, only one set by the
+        // export policy is.
+        if external && ra.attrs.multi_exit_disc.is_some() {
+            ra.attrs_mut().multi_exit_disc = None;
+        }
+        if let Some(export) = &self.export {
+            match export.apply(nlri, &ra) {
+                Some(exported) => ra = exported,
+                None => {
+                    log::debug!("Export policy {} rejected {:?}", export.name, nlri);
+                    return None;
+                }
+            }
+        }
+        // RFC 1997 well-known communities, possibly set by the export policy. NO_EXPORT keeps
+        // the path within the confederation, NO_EXPORT_SUBCONFED within the member AS.
+        let communities = &ra.attrs.communities;
+        if communities.contains(&policy::COMMUNITY_NO_ADVERTISE)
+            || (!self.key.ibgp
+                && !self.key.confed
+                && communities.contains(&policy::COMMUNITY_NO_EXPORT))
+            || (!self.key.ibgp && communities.contains(&policy::COMMUNITY_NO_EXPORT_SUBCONFED))
+        {
+            log::debug!("Not advertising {:?} to the group: {:?}", nlri, communities);
+            return None;
+        }
+        if best.is_local() && ra.attrs.next_hop == best.attrs.next_hop {
+            ra.attrs_mut().next_hop = self.key.local_ip;
+        }
+        if let Some(local_ip) = self.key.next_hop_self {
+            // A next hop set by the export policy takes precedence over next-hop-self
+            if ra.attrs.next_hop == best.attrs.next_hop {
+                ra.attrs_mut().next_hop = local_ip;
+            }
+            // Non-transitive attributes and LOCAL_PREF stay in the AS or the confederation
+            let attrs = ra.attrs_mut();
+            attrs.local_pref = None;
+            attrs.originator_id = None;
+            attrs.cluster_list.clear();
+            ra.strip_confed();
+            if let Some(neighbor_asn) = self.key.as_override {
+                ra.replace_as(neighbor_asn, self.key.local_asn);
+            }
+            if !self.key.local_as.as_ref().is_some_and(|l| l.replace_as) {
+                ra.prepend(self.key.local_asn, 1);
+            }
+            if let Some(local_as) = &self.key.local_as {
+                ra.prepend(local_as.asn, 1);
+            }
+        }
+        if self.key.confed {
+            ra.prepend_confed(self.key.local_asn);
+        }
+        if reflected {
+            ra.reflect(self.key.cluster_id);
+        }
+        Some(ra)
+    }
+
+    /// Updates the paths exported to the group with new best paths, returns those that changed.
+    fn update(
+        &mut self,
+        best: &[(Nlri, Option<rib::RouteAttributes>)],
+    ) -> Vec<(Nlri, Option<Export>)> {
+        let mut changes = vec![];
+        // Encoded attributes by interned set, which is kept so that its address is not reused.
+        let mut encoded: HashMap<*const rib::AttributeSet, (Arc<rib::AttributeSet>, Attributes)> =
+            HashMap::new();
+        for (nlri, best) in best {
+            let export = best
+                .as_ref()
+                .and_then(|best| self.export(nlri, best))
+                .map(|mut ra| {
+                    let peer_rid = ra.peer_rid;
+                    ra.intern();
+                    let attributes = match encoded.get(&Arc::as_ptr(&ra.attrs)) {
+                        Some((_, a)) => a.clone(),
+                        None => {
+                            let set = ra.attrs.clone();
+                            let a = Arc::new(Vec::from(ra));
+                            encoded.insert(Arc::as_ptr(&set), (set, a.clone()));
+                            a
+                        }
+                    };
+                    Export {
+                        attributes,
+                        peer_rid,
+                    }
+                });
+            let unchanged = match (self.exported.get(nlri), &export) {
+                (None, None) => true,
+                (Some(p), Some(e)) => p.peer_rid == e.peer_rid && p.attributes == e.attributes,
+                _ => false,
+            };
+            if unchanged {
+                continue;
+            }
+            match &export {
+                Some(e) => self.exported.insert(*nlri, e.clone()),
+                None => self.exported.remove(nlri),
+            };
+            changes.push((*nlri, export));
+        }
+        changes
+    }
+
+    /// Sends the changes to the members, withdrawals right away and announcements once the MRAI
+    /// timer expired.
+    fn advertise(&mut self, best: &[(Nlri, Option<rib::RouteAttributes>)], now: Instant) {
+        let exports = self.update(best);
+        let (announced, withdrawn): (Vec<_>, Vec<_>) =
+            exports.into_iter().partition(|(_, e)| e.is_some());
+        for (nlri, _) in &withdrawn {
+            self.pending.remove(nlri);
+        }
+        self.pending
+            .extend(announced.into_iter().map(|(nlri, _)| nlri));
+
+        if self.mrai_expiry.is_some_and(|expiry| expiry > now) {
+            if !withdrawn.is_empty() {
+                self.send(&withdrawn);
+            }
+        } else {
+            self.flush(withdrawn, now);
+        }
+    }
+
+    /// Sends `withdrawn` along with the pending announcements and restarts the MRAI timer if
+    /// there were any.
+    fn flush(&mut self, mut exports: Vec<(Nlri, Option<Export>)>, now: Instant) {
+        if !self.pending.is_empty() {
+            self.mrai_expiry = Some(now + self.mrai());
+        }
+        exports.extend(
+            self.pending
+                .drain()
+                .map(|nlri| (nlri, self.exported.get(&nlri).cloned())),
+        );
+        if !exports.is_empty() {
+            self.send(&exports);
+        }
+    }
+
+    /// MRAI shortened by the random jitter.
+    fn mrai(&self) -> Duration {
+        let jitter = fastrand::u32(0..=self.jitter as u32);
+        self.key.mrai * (100 - jitter) / 100
+    }
+
+    /// Sends the changes to every established member. Members getting the same changes, all of
+    /// them unless a path came from one of them, share the encoded UPDATEs.
+    fn send(&mut self, exports: &[(Nlri, Option<Export>)]) {
+        let mut encoded: Vec<(Changes, Arc<Vec<Vec<u8>>>)> = vec![];
+        for member in &mut self.members {
+            if member.overflowed || !member.cell.status().is_established() {
+                continue;
+            }
+            let changes = member.diff(exports);
+            if changes.is_empty() {
+                continue;
+            }
+            let messages = match encoded.iter().find(|(c, _)| *c == changes) {
+                Some((_, messages)) => messages.clone(),
+                None => match changes.encode() {
+                    Ok(messages) => {
+                        let messages = Arc::new(messages);
+                        encoded.push((changes, messages.clone()));
+                        messages
+                    }
+                    Err(e) => {
+                        log::error!("Failed to encode UPDATEs: {:#}", e);
+                        continue;
+                    }
+                },
+            };
+            member.send(messages);
+        }
+    }
+}
+
+/// Update groups of an address family, owned by its RIB manager.
+#[derive(Debug, Default)]
+pub(super) struct UpdateGroups {
+    groups: Vec<UpdateGroup>,
+}
+
+impl UpdateGroups {
+    /// Adds `peer` to its update group, creating it from the Loc-RIB if needed, and sends it the
+    /// paths exported to the group.
+    pub(super) fn join(&mut self, peer: Peer, rib: &rib::Rib) {
+        self.leave(&peer.cell);
+        let i = match self.groups.iter().position(|g| g.key == peer.key) {
+            Some(i) => i,
+            None => {
+                let mut group = UpdateGroup {
+                    key: peer.key.clone(),
+                    export: peer.export.clone(),
+                    exported: HashMap::new(),
+                    members: vec![],
+                    jitter: peer.jitter,
+                    pending: HashSet::new(),
+                    mrai_expiry: None,
+                };
+                let best: Vec<_> = rib
+                    .iter()
+                    .map(|(nlri, paths)| (*nlri, current_best(paths)))
+                    .collect();
+                group.update(&best);
+                self.groups.push(group);
+                self.groups.len() - 1
+            }
+        };
+        let group = &mut self.groups[i];
+        log::info!(
+            "{:?} joins an update group of {} members",
+            peer.cell.remote_ip,
+            group.members.len()
+        );
+        let mut member = Member {
+            cell: peer.cell,
+            rid: peer.rid,
+            adj_rib_out: HashMap::new(),
+            overflowed: false,
+        };
+        let exports: Vec<_> = group
+            .exported
+            .iter()
+            .map(|(nlri, e)| (*nlri, Some(e.clone())))
+            .collect();
+        let changes = member.diff(&exports);
+        if !changes.is_empty() {
+            match changes.encode() {
+                Ok(messages) => member.send(Arc::new(messages)),
+                Err(e) => log::error!("Failed to encode UPDATEs: {:#}", e),
+            }
+        }
+        group.members.push(member);
+    }
+
+    /// Removes the neighbor from its update group, dropping the group once empty.
+    pub(super) fn leave(&mut self, cell: &Arc<neighbor::NeighborCell>) {
+        for group in &mut self.groups {
+            group.members.retain(|m| !Arc::ptr_eq(&m.cell, cell));
+        }
+        self.groups.retain(|g| !g.members.is_empty());
+    }
+
+    /// Advertises best path changes to every update group.
+    pub(super) fn advertise(
+        &mut self,
+        best: &[(Nlri, Option<rib::RouteAttributes>)],
+        now: Instant,
+    ) {
+        for group in &mut self.groups {
+            group.advertise(best, now);
+        }
+    }
+
+    /// Next expiry of an MRAI timer with announcements waiting for it.
+    pub(super) fn next_flush(&self) -> Option<Instant> {
+        self.groups
+            .iter()
+            .filter(|g| !g.pending.is_empty())
+            .filter_map(|g| g.mrai_expiry)
+            .min()
+    }
+
+    /// Sends the announcements whose MRAI timer expired.
+    pub(super) fn flush(&mut self, now: Instant) {
+        for group in &mut self.groups {
+            if group.mrai_expiry.is_some_and(|expiry| expiry <= now) {
+                group.flush(vec![], now);
+            }
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+use crate::bgp::{
+    ASPATHSegment, ASPATHSegmentType, AddressFamily, Afi, BGPUpdateMessage, Flatten, Nlri,
+    PathAttributeValue, Safi, MAX_MESSAGE_LENGTH, MIN_MESSAGE_LENGTH,
+};
+use crate::config::{self, LocalAs, MraiConfig, PolicyAction};
+use crate::neighbor::{BGPNeighbor, BGPState, Event, NeighborCell};
+use crate::policy::{NeighborPolicy, Policies, COMMUNITY_NO_ADVERTISE, COMMUNITY_NO_EXPORT};
+use crate::rib::{AttributeSet, PathType, PeeringType, Rib, RouteAttributes};
+
+use super::update_group::{Peer, UpdateGroups};
+
+const LOCAL_ASN: u16 = 65000;
+
+/// Capacity of the session mailboxes.
+const MAILBOX_SIZE: usize = 100;
+
+fn nlri(s: &str) -> Nlri {
+    Nlri {
+        net: s.parse().unwrap(),
+    }
+}
+
+fn ipv4() -> AddressFamily {
+    AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::NLRIUnicast,
+    }
+}
+
+fn path(rid: u32) -> RouteAttributes {
+    RouteAttributes {
+        attrs: Arc::new(AttributeSet {
+            as_path: vec![ASPATHSegment {
+                segment_type: ASPATHSegmentType::AsSequence,
+                as_list: vec![LOCAL_ASN + rid as u16],
+            }],
+            next_hop: IpAddr::from([192, 0, 2, rid as u8]),
+            ..Default::default()
+        }),
+        peer_rid: rid,
+        peer_ip: IpAddr::from([192, 0, 2, rid as u8]),
+        multipath: true,
+        ..Default::default()
+    }
+}
+
+/// Established neighbor with router ID `rid` in `remote_asn`, the mailbox of its session and the
+/// peer joining the update groups. `configure` sets the rest of the neighbor up.
+async fn neighbor(
+    rid: u32,
+    remote_asn: u16,
+    configure: impl FnOnce(&mut BGPNeighbor),
+    mrai: u16,
+) -> (Arc<NeighborCell>, mpsc::Receiver<Event>, Peer) {
+    let (tx, rx) = mpsc::channel(MAILBOX_SIZE);
+    let mut n = BGPNeighbor::new(
+        Some(IpAddr::from([192, 0, 2, 100])),
+        Some(179),
+        LOCAL_ASN,
+        100,
+        Some(IpAddr::from([192, 0, 2, rid as u8])),
+        Some(179),
+        Some(remote_asn),
+        3,
+        5,
+        BGPState::Established,
+        None,
+        HashMap::new(),
+        None,
+        false,
+    );
+    n.remote_rid = Some(rid);
+    n.tx = Some(tx);
+    configure(&mut n);
+    let cell = Arc::new(NeighborCell::new(n));
+    let mrai = MraiConfig {
+        ebgp: mrai,
+        ibgp: mrai,
+        jitter: 0,
+    };
+    let peer = Peer::new(cell.clone(), &*cell.lock().await, &ipv4(), &mrai).unwrap();
+    (cell, rx, peer)
+}
+
+/// Established eBGP neighbor with an MRAI of `mrai` seconds.
+async fn member_with_mrai(rid: u32, mrai: u16) -> (Arc<NeighborCell>, mpsc::Receiver<Event>, Peer) {
+    neighbor(rid, LOCAL_ASN + rid as u16, |_| {}, mrai).await
+}
+
+async fn member(rid: u32) -> (Arc<NeighborCell>, mpsc::Receiver<Event>, Peer) {
+    member_with_mrai(rid, 0).await
+}
+
+/// UPDATE batches sent to a neighbor.
+fn received(rx: &mut mpsc::Receiver<Event>) -> Vec<Arc<Vec<Vec<u8>>>> {
+    let mut batches = vec![];
+    while let Ok(e) = rx.try_recv() {
+        if let Event::Updates(messages) = e {
+            batches.push(messages);
+        }
+    }
+    batches
+}
+
+fn decode(message: &[u8]) -> BGPUpdateMessage {
+    BGPUpdateMessage::try_from(message[1..].to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn test_update_group_encodes_once() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member(1).await;
+    let (_c2, mut rx2, p2) = member(2).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(3)))], Instant::now());
+
+    let (b1, b2) = (received(&mut rx1), received(&mut rx2));
+    assert_eq!(b1.len(), 1);
+    assert!(Arc::ptr_eq(&b1[0], &b2[0]));
+    let update = decode(&b1[0][0]);
+    assert_eq!(update.nlri, vec![nlri("10.0.0.0/24")]);
+    assert!(update.withdrawn_routes.is_empty());
+}
+
+#[tokio::test]
+async fn test_adj_rib_out_suppresses_duplicates() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member(1).await;
+    groups.join(p1, &Rib::default());
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(3)))], Instant::now());
+    assert_eq!(received(&mut rx1).len(), 1);
+
+    // The MED is not sent to eBGP neighbors, the UPDATE would be the same
+    let mut changed = path(3);
+    changed.attrs_mut().multi_exit_disc = Some(10);
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(changed))], Instant::now());
+    assert!(received(&mut rx1).is_empty());
+
+    // Nothing is withdrawn that was not advertised
+    groups.advertise(&[(nlri("10.0.1.0/24"), None)], Instant::now());
+    assert!(received(&mut rx1).is_empty());
+}
+
+#[tokio::test]
+async fn test_adj_rib_out_implicit_withdrawal() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member(1).await;
+    let (_c2, mut rx2, p2) = member(2).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(2)))], Instant::now());
+    assert_eq!(received(&mut rx1).len(), 1);
+    assert!(received(&mut rx2).is_empty());
+
+    // The best path now comes from neighbor 1, which is not sent its own path back
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(1)))], Instant::now());
+    let b1 = received(&mut rx1);
+    assert_eq!(b1.len(), 1);
+    let update = decode(&b1[0][0]);
+    assert_eq!(update.withdrawn_routes, vec![nlri("10.0.0.0/24")]);
+    assert!(update.nlri.is_empty());
+    let b2 = received(&mut rx2);
+    assert_eq!(decode(&b2[0][0]).nlri, vec![nlri("10.0.0.0/24")]);
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), None)], Instant::now());
+    assert!(received(&mut rx1).is_empty());
+    assert_eq!(
+        decode(&received(&mut rx2)[0][0]).withdrawn_routes,
+        vec![nlri("10.0.0.0/24")]
+    );
+}
+
+#[tokio::test]
+async fn test_join_sends_loc_rib_within_message_length() {
+    let mut rib = Rib::default();
+    for i in 0..2000u32 {
+        let net = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + (i << 8)));
+        let prefix = Nlri {
+            net: ipnet::IpNet::new(net, 24).unwrap(),
+        };
+        rib.insert(prefix, vec![path(3)]);
+    }
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member(1).await;
+    groups.join(p1, &rib);
+
+    let batches = received(&mut rx1);
+    assert_eq!(batches.len(), 1);
+    assert!(batches[0].len() > 1);
+    let mut announced = 0;
+    for message in batches[0].iter() {
+        // The codec adds the marker and the length
+        assert!(message.len() + MIN_MESSAGE_LENGTH - 1 <= MAX_MESSAGE_LENGTH);
+        announced += decode(message).nlri.len();
+    }
+    assert_eq!(announced, 2000);
+}
+
+#[tokio::test]
+async fn test_leave_stops_advertisements() {
+    let mut groups = UpdateGroups::default();
+    let (c1, mut rx1, p1) = member(1).await;
+    groups.join(p1, &Rib::default());
+    groups.leave(&c1);
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(3)))], Instant::now());
+    assert!(received(&mut rx1).is_empty());
+}
+
+#[tokio::test]
+async fn test_mrai_coalesces_announcements() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member_with_mrai(1, 30).await;
+    groups.join(p1, &Rib::default());
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(3)))], at(0));
+    assert_eq!(received(&mut rx1).len(), 1);
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(4)))], at(1));
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(5)))], at(2));
+    assert!(received(&mut rx1).is_empty());
+    assert_eq!(groups.next_flush(), Some(at(30)));
+
+    groups.flush(at(29));
+    assert!(received(&mut rx1).is_empty());
+    groups.flush(at(30));
+    let batches = received(&mut rx1);
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].len(), 1);
+    let update = decode(&batches[0][0]);
+    assert_eq!(update.nlri, vec![nlri("10.0.0.0/24")]);
+    let as_path = update
+        .path_attributes
+        .iter()
+        .find_map(|a| match &a.value {
+            PathAttributeValue::AsPath(p) => Some(p.aspath_string()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(as_path, "65000 65005");
+    assert_eq!(groups.next_flush(), None);
+}
+
+#[tokio::test]
+async fn test_mrai_does_not_delay_withdrawals() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member_with_mrai(1, 30).await;
+    groups.join(p1, &Rib::default());
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(path(3)))], at(0));
+    received(&mut rx1);
+    groups.advertise(
+        &[
+            (nlri("10.0.1.0/24"), Some(path(3))),
+            (nlri("10.0.2.0/24"), Some(path(3))),
+        ],
+        at(1),
+    );
+    groups.advertise(
+        &[(nlri("10.0.0.0/24"), None), (nlri("10.0.2.0/24"), None)],
+        at(2),
+    );
+
+    // Only what was advertised is withdrawn, right away
+    let batches = received(&mut rx1);
+    assert_eq!(batches.len(), 1);
+    let update = decode(&batches[0][0]);
+    assert_eq!(update.withdrawn_routes, vec![nlri("10.0.0.0/24")]);
+    assert!(update.nlri.is_empty());
+
+    groups.flush(at(30));
+    let batches = received(&mut rx1);
+    assert_eq!(decode(&batches[0][0]).nlri, vec![nlri("10.0.1.0/24")]);
+}
+
+/// Path learned from the iBGP neighbor `rid`.
+fn ibgp_path(rid: u32, from_client: bool) -> RouteAttributes {
+    RouteAttributes {
+        attrs: Arc::new(AttributeSet {
+            next_hop: IpAddr::from([192, 0, 2, rid as u8]),
+            ..Default::default()
+        }),
+        peer_rid: rid,
+        peer_ip: IpAddr::from([192, 0, 2, rid as u8]),
+        peer_type: PeeringType::Ibgp,
+        path_type: PathType::Internal,
+        from_client,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_route_reflection_rules() {
+    let mut groups = UpdateGroups::default();
+    let client = |n: &mut BGPNeighbor| n.route_reflector_client = true;
+    let (_c1, mut rx1, p1) = neighbor(1, LOCAL_ASN, client, 0).await;
+    let (_c2, mut rx2, p2) = neighbor(2, LOCAL_ASN, client, 0).await;
+    let (_c3, mut rx3, p3) = neighbor(3, LOCAL_ASN, |_| {}, 0).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+    groups.join(p3, &Rib::default());
+
+    // From a client to the other clients and the non-clients
+    groups.advertise(
+        &[(nlri("10.0.0.0/24"), Some(ibgp_path(1, true)))],
+        Instant::now(),
+    );
+    assert!(received(&mut rx1).is_empty());
+    let update = decode(&received(&mut rx2)[0][0]);
+    assert_eq!(update.nlri, vec![nlri("10.0.0.0/24")]);
+    let reflected: Vec<_> = update
+        .path_attributes
+        .iter()
+        .filter_map(|a| match &a.value {
+            PathAttributeValue::OriginatorId(id) => Some(format!("originator {}", id)),
+            PathAttributeValue::ClusterList(c) => Some(format!("clusters {:?}", c)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(reflected, ["originator 0.0.0.1", "clusters [100]"]);
+    assert_eq!(received(&mut rx3).len(), 1);
+
+    // From a non-client to the clients only
+    groups.advertise(
+        &[(nlri("10.0.1.0/24"), Some(ibgp_path(4, false)))],
+        Instant::now(),
+    );
+    assert_eq!(
+        decode(&received(&mut rx1)[0][0]).nlri,
+        vec![nlri("10.0.1.0/24")]
+    );
+    assert_eq!(received(&mut rx2).len(), 1);
+    assert!(received(&mut rx3).is_empty());
+}
+
+fn as_path(update: &BGPUpdateMessage) -> String {
+    update
+        .path_attributes
+        .iter()
+        .find_map(|a| match &a.value {
+            PathAttributeValue::AsPath(p) => Some(p.aspath_string()),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_confederation_export() {
+    const CONFED_ID: u16 = 64999;
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = neighbor(1, 65100, |n| n.confederation_peer = true, 0).await;
+    let (_c2, mut rx2, p2) = neighbor(2, 65002, |n| n.local_asn = CONFED_ID, 0).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+
+    // Learned from the member AS 65004, which got it from AS 65003
+    let mut best = path(4);
+    best.peer_type = PeeringType::Confed;
+    best.path_type = PathType::Internal;
+    best.attrs_mut().local_pref = Some(200);
+    best.attrs_mut().as_path = vec![
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsConfedSequence,
+            as_list: vec![65004],
+        },
+        ASPATHSegment {
+            segment_type: ASPATHSegmentType::AsSequence,
+            as_list: vec![65003],
+        },
+    ];
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(best))], Instant::now());
+
+    // Within the confederation the next hop and LOCAL_PREF are kept
+    let update = decode(&received(&mut rx1)[0][0]);
+    assert_eq!(as_path(&update), "(65000 65004) 65003");
+    assert!(update
+        .path_attributes
+        .iter()
+        .any(|a| a.value == PathAttributeValue::NextHop([192, 0, 2, 4].into())));
+    let local_pref = update
+        .path_attributes
+        .iter()
+        .find(|a| a.value == PathAttributeValue::LocalPref(200))
+        .unwrap();
+    // Sent well-known, not optional non-transitive
+    assert!(!local_pref.optional && local_pref.transitive);
+
+    // Outside it the confederation is a single AS
+    let update = decode(&received(&mut rx2)[0][0]);
+    assert_eq!(as_path(&update), "64999 65003");
+    assert!(update
+        .path_attributes
+        .iter()
+        .any(|a| a.value == PathAttributeValue::NextHop([192, 0, 2, 100].into())));
+    assert!(!update
+        .path_attributes
+        .iter()
+        .any(|a| matches!(a.value, PathAttributeValue::LocalPref(_))));
+}
+
+#[tokio::test]
+async fn test_local_as_and_as_override() {
+    let local_as = |replace_as| {
+        move |n: &mut BGPNeighbor| {
+            n.local_as = Some(LocalAs {
+                asn: 64512,
+                no_prepend: false,
+                replace_as,
+            })
+        }
+    };
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = neighbor(1, 65001, local_as(false), 0).await;
+    let (_c2, mut rx2, p2) = neighbor(2, 65001, local_as(true), 0).await;
+    let (_c3, mut rx3, p3) = neighbor(3, 65002, |n| n.as_override = true, 0).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+    groups.join(p3, &Rib::default());
+
+    let mut best = path(4);
+    best.attrs_mut().as_path = vec![ASPATHSegment {
+        segment_type: ASPATHSegmentType::AsSequence,
+        as_list: vec![65002, 65004],
+    }];
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(best))], Instant::now());
+
+    let update = decode(&received(&mut rx1)[0][0]);
+    assert_eq!(as_path(&update), "64512 65000 65002 65004");
+    let update = decode(&received(&mut rx2)[0][0]);
+    assert_eq!(as_path(&update), "64512 65002 65004");
+    let update = decode(&received(&mut rx3)[0][0]);
+    assert_eq!(as_path(&update), "65000 65000 65004");
+}
+
+fn med(update: &BGPUpdateMessage) -> Option<u32> {
+    update.path_attributes.iter().find_map(|a| match a.value {
+        PathAttributeValue::MultiExitDisc(med) => Some(med),
+        _ => None,
+    })
+}
+
+#[tokio::test]
+async fn test_ebgp_med_set_by_export_policy() {
+    let policies = Policies::new(
+        &[],
+        &[],
+        &[config::RouteMap {
+            name: "EXPORT".to_string(),
+            entries: vec![config::RouteMapEntry {
+                action: PolicyAction::Permit,
+                match_: Default::default(),
+                set: config::RouteMapSet {
+                    med: Some(50),
+                    ..Default::default()
+                },
+            }],
+        }],
+    )
+    .unwrap();
+    let export = policies.route_map("EXPORT").unwrap();
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member(1).await;
+    let (_c2, mut rx2, p2) = neighbor(
+        2,
+        65002,
+        |n| {
+            n.policies.insert(
+                ipv4(),
+                NeighborPolicy {
+                    import: None,
+                    export: Some(export),
+                },
+            );
+        },
+        0,
+    )
+    .await;
+    let (_c3, mut rx3, p3) = neighbor(3, LOCAL_ASN, |_| {}, 0).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+    groups.join(p3, &Rib::default());
+
+    let mut best = path(4);
+    best.attrs_mut().multi_exit_disc = Some(10);
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(best))], Instant::now());
+
+    // The received MED goes no further than the AS
+    assert_eq!(med(&decode(&received(&mut rx1)[0][0])), None);
+    assert_eq!(med(&decode(&received(&mut rx2)[0][0])), Some(50));
+    assert_eq!(med(&decode(&received(&mut rx3)[0][0])), Some(10));
+}
+
+#[tokio::test]
+async fn test_locally_originated_export() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = neighbor(1, LOCAL_ASN, |_| {}, 0).await;
+    let (_c2, mut rx2, p2) = member(2).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+
+    let local = RouteAttributes::local(100, &Afi::Ipv4);
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(local))], Instant::now());
+
+    // The next hop is the local address of the session, even over iBGP
+    let local_ip = PathAttributeValue::NextHop([192, 0, 2, 100].into());
+    let update = decode(&received(&mut rx1)[0][0]);
+    assert_eq!(as_path(&update), "");
+    assert!(update.path_attributes.iter().any(|a| a.value == local_ip));
+    let update = decode(&received(&mut rx2)[0][0]);
+    assert_eq!(as_path(&update), "65000");
+    assert!(update.path_attributes.iter().any(|a| a.value == local_ip));
+}
+
+#[tokio::test]
+async fn test_no_export_stays_in_confederation() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = neighbor(1, LOCAL_ASN, |_| {}, 0).await;
+    let (_c2, mut rx2, p2) = neighbor(2, 65100, |n| n.confederation_peer = true, 0).await;
+    let (_c3, mut rx3, p3) = member(3).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+    groups.join(p3, &Rib::default());
+
+    let mut best = path(4);
+    best.attrs_mut().communities = vec![COMMUNITY_NO_EXPORT];
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(best))], Instant::now());
+
+    assert_eq!(received(&mut rx1).len(), 1);
+    assert_eq!(received(&mut rx2).len(), 1);
+    assert!(received(&mut rx3).is_empty());
+}
+
+#[tokio::test]
+async fn test_no_advertise_not_sent() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = neighbor(1, LOCAL_ASN, |_| {}, 0).await;
+    let (_c2, mut rx2, p2) = neighbor(2, 65100, |n| n.confederation_peer = true, 0).await;
+    let (_c3, mut rx3, p3) = member(3).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+    groups.join(p3, &Rib::default());
+
+    let mut best = path(4);
+    best.attrs_mut().communities = vec![COMMUNITY_NO_ADVERTISE];
+    groups.advertise(&[(nlri("10.0.0.0/24"), Some(best))], Instant::now());
+
+    assert!(received(&mut rx1).is_empty());
+    assert!(received(&mut rx2).is_empty());
+    assert!(received(&mut rx3).is_empty());
+}
+
+#[tokio::test]
+async fn test_mailbox_overflow_resets_session() {
+    let mut groups = UpdateGroups::default();
+    let (_c1, mut rx1, p1) = member(1).await;
+    let (_c2, mut rx2, p2) = member(2).await;
+    groups.join(p1, &Rib::default());
+    groups.join(p2, &Rib::default());
+
+    // Neighbor 2 keeps up, neighbor 1 does not read its mailbox
+    for i in 0..=MAILBOX_SIZE as u32 {
+        let net = Ipv4Addr::from(0x0a00_0000 + (i << 8));
+        let prefix = nlri(&format!("{}/24", net));
+        groups.advertise(&[(prefix, Some(path(3)))], Instant::now());
+        assert_eq!(received(&mut rx2).len(), 1);
+    }
+    groups.advertise(&[(nlri("10.1.0.0/24"), Some(path(3)))], Instant::now());
+    assert_eq!(received(&mut rx2).len(), 1);
+
+    assert_eq!(received(&mut rx1).len(), MAILBOX_SIZE);
+    // The reset waits for room in the mailbox, nothing more is sent meanwhile
+    tokio::task::yield_now().await;
+    assert!(matches!(rx1.try_recv(), Ok(Event::UpdatesOverflow)));
+    assert!(rx1.try_recv().is_err());
+}
@@ -0,0 +1,330 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Bits of the network address of `net`, left aligned so that both families branch the same way.
+fn bits(net: &IpNet) -> u128 {
+    match net.network() {
+        IpAddr::V4(addr) => (u32::from(addr) as u128) << 96,
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+/// Length of the longest prefix `a` and `b` have in common, at most the length of the shortest.
+fn common_len(a: &IpNet, b: &IpNet) -> u8 {
+    let len = (bits(a) ^ bits(b)).leading_zeros() as u8;
+    len.min(a.prefix_len()).min(b.prefix_len())
+}
+
+/// Child `net` goes to below a node of length `len`, shorter than `net`.
+fn branch(net: &IpNet, len: u8) -> usize {
+    ((bits(net) >> (127 - len)) & 1) as usize
+}
+
+fn truncate(net: &IpNet, len: u8) -> IpNet {
+    IpNet::new(net.addr(), len)
+        .expect("BUG: Truncated prefix longer than the original")
+        .trunc()
+}
+
+#[derive(Debug, Clone)]
+struct Node<K, V> {
+    net: IpNet,
+    /// None for the nodes only there to branch.
+    entry: Option<(K, V)>,
+    children: [Option<Box<Node<K, V>>>; 2],
+}
+
+impl<K, V> Node<K, V> {
+    fn new(net: IpNet, entry: Option<(K, V)>) -> Box<Self> {
+        Box::new(Node {
+            net,
+            entry,
+            children: [None, None],
+        })
+    }
+
+    /// Whether `net` is this prefix or one of its more specifics.
+    fn covers(&self, net: &IpNet) -> bool {
+        common_len(&self.net, net) == self.net.prefix_len()
+    }
+}
+
+fn insert<K, V>(slot: &mut Option<Box<Node<K, V>>>, net: IpNet, key: K, value: V) -> Option<V> {
+    let Some(node) = slot.as_mut() else {
+        *slot = Some(Node::new(net, Some((key, value))));
+        return None;
+    };
+    let node_len = node.net.prefix_len();
+    let common = common_len(&node.net, &net);
+    if common == node_len && node_len == net.prefix_len() {
+        return node.entry.replace((key, value)).map(|(_, v)| v);
+    }
+    if common == node_len {
+        return insert(&mut node.children[branch(&net, node_len)], net, key, value);
+    }
+
+    // The new prefix covers the node, or both hang below a new branching node.
+    let old = slot.take().expect("BUG: Node vanished during insertion");
+    let mut parent = if common == net.prefix_len() {
+        Node::new(net, Some((key, value)))
+    } else {
+        let mut parent = Node::new(truncate(&net, common), None);
+        parent.children[branch(&net, common)] = Some(Node::new(net, Some((key, value))));
+        parent
+    };
+    let old_branch = branch(&old.net, common);
+    parent.children[old_branch] = Some(old);
+    *slot = Some(parent);
+    None
+}
+
+fn remove<K, V>(slot: &mut Option<Box<Node<K, V>>>, net: &IpNet) -> Option<(K, V)> {
+    let node = slot.as_mut()?;
+    if !node.covers(net) {
+        return None;
+    }
+    let node_len = node.net.prefix_len();
+    let removed = if node_len == net.prefix_len() {
+        node.entry.take()?
+    } else {
+        remove(&mut node.children[branch(net, node_len)], net)?
+    };
+
+    // A node without entry is only kept to branch.
+    if node.entry.is_none() && node.children.iter().any(Option::is_none) {
+        let [zero, one] = std::mem::take(&mut node.children);
+        *slot = zero.or(one);
+    }
+    Some(removed)
+}
+
+/// Map from IP prefixes, of both families, supporting exact, longest-match, less-specific and
+/// more-specific lookups. It is a path-compressed binary trie, iterated in prefix order, every
+/// prefix followed by its more specifics.
+#[derive(Debug, Clone)]
+pub struct PrefixMap<K, V> {
+    v4: Option<Box<Node<K, V>>>,
+    v6: Option<Box<Node<K, V>>>,
+    len: usize,
+}
+
+impl<K, V> Default for PrefixMap<K, V> {
+    fn default() -> Self {
+        PrefixMap {
+            v4: None,
+            v6: None,
+            len: 0,
+        }
+    }
+}
+
+impl<K: Copy + Into<IpNet>, V> PrefixMap<K, V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn root(&self, net: &IpNet) -> &Option<Box<Node<K, V>>> {
+        match net {
+            IpNet::V4(_) => &self.v4,
+            IpNet::V6(_) => &self.v6,
+        }
+    }
+
+    fn root_mut(&mut self, net: &IpNet) -> &mut Option<Box<Node<K, V>>> {
+        match net {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
+        }
+    }
+
+    fn find(&self, net: &IpNet) -> Option<&Node<K, V>> {
+        let mut node = self.root(net).as_deref()?;
+        loop {
+            let node_len = node.net.prefix_len();
+            if !node.covers(net) {
+                return None;
+            }
+            if node_len == net.prefix_len() {
+                return Some(node);
+            }
+            node = node.children[branch(net, node_len)].as_deref()?;
+        }
+    }
+
+    fn find_mut(&mut self, net: &IpNet) -> Option<&mut Node<K, V>> {
+        let mut node = self.root_mut(net).as_deref_mut()?;
+        loop {
+            let node_len = node.net.prefix_len();
+            if !node.covers(net) {
+                return None;
+            }
+            if node_len == net.prefix_len() {
+                return Some(node);
+            }
+            node = node.children[branch(net, node_len)].as_deref_mut()?;
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let net = (*key).into().trunc();
+        self.find(&net)?.entry.as_ref().map(|(_, v)| v)
+    }
+
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        let net = (*key).into().trunc();
+        self.find(&net)?.entry.as_ref().map(|(k, v)| (k, v))
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let net = (*key).into().trunc();
+        self.find_mut(&net)?.entry.as_mut().map(|(_, v)| v)
+    }
+
+    /// Value of `key`, inserted first with `default` when there is none.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, default: F) -> &mut V {
+        if self.get(&key).is_none() {
+            self.insert(key, default());
+        }
+        self.get_mut(&key)
+            .expect("BUG: Prefix missing right after insertion")
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let net = key.into().trunc();
+        let previous = insert(self.root_mut(&net), net, key, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let net = (*key).into().trunc();
+        let (_, value) = remove(self.root_mut(&net), &net)?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Bytes used by the trie and its nodes, not counting what the values hold on the heap.
+    pub fn size(&self) -> usize {
+        let mut nodes = 0;
+        let mut stack: Vec<&Node<K, V>> =
+            self.v4.iter().chain(self.v6.iter()).map(|n| &**n).collect();
+        while let Some(node) = stack.pop() {
+            nodes += 1;
+            stack.extend(node.children.iter().flatten().map(|n| &**n));
+        }
+        size_of::<Self>() + nodes * size_of::<Node<K, V>>()
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            stack: self.v6.iter().chain(self.v4.iter()).map(|n| &**n).collect(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            stack: self
+                .v6
+                .iter_mut()
+                .chain(self.v4.iter_mut())
+                .map(|n| &mut **n)
+                .collect(),
+        }
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    /// Entry of the longest prefix covering `net`, `net` itself when it is in the map. An
+    /// address is looked up as a host prefix.
+    pub fn longest_match(&self, net: &IpNet) -> Option<(&K, &V)> {
+        self.less_specifics(net).pop()
+    }
+
+    /// Entries of `net` and of the prefixes covering it, the shortest first.
+    pub fn less_specifics(&self, net: &IpNet) -> Vec<(&K, &V)> {
+        let net = net.trunc();
+        let mut found = vec![];
+        let mut node = self.root(&net).as_deref();
+        while let Some(n) = node.filter(|n| n.covers(&net)) {
+            found.extend(n.entry.as_ref().map(|(k, v)| (k, v)));
+            if n.net.prefix_len() == net.prefix_len() {
+                break;
+            }
+            node = n.children[branch(&net, n.net.prefix_len())].as_deref();
+        }
+        found
+    }
+
+    /// Entries of `net` and of the prefixes it covers, in prefix order.
+    pub fn more_specifics(&self, net: &IpNet) -> Iter<'_, K, V> {
+        let net = net.trunc();
+        let mut node = self.root(&net).as_deref();
+        while let Some(n) = node {
+            if n.net.prefix_len() >= net.prefix_len() {
+                break;
+            }
+            if !n.covers(&net) {
+                node = None;
+                break;
+            }
+            node = n.children[branch(&net, n.net.prefix_len())].as_deref();
+        }
+        Iter {
+            stack: node
+                .filter(|n| common_len(&net, &n.net) == net.prefix_len())
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    stack: Vec<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            self.stack
+                .extend(node.children.iter().rev().flatten().map(|n| &**n));
+            if let Some((k, v)) = &node.entry {
+                return Some((k, v));
+            }
+        }
+        None
+    }
+}
+
+pub struct IterMut<'a, K, V> {
+    stack: Vec<&'a mut Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            let Node {
+                entry, children, ..
+            } = node;
+            let [zero, one] = children;
+            self.stack.extend(one.as_deref_mut());
+            self.stack.extend(zero.as_deref_mut());
+            if let Some((k, v)) = entry {
+                return Some((&*k, v));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,167 @@
+use ipnet::IpNet;
+use std::collections::HashMap;
+
+use crate::trie::*;
+
+fn net(s: &str) -> IpNet {
+    s.parse().unwrap()
+}
+
+fn map(prefixes: &[&str]) -> PrefixMap<IpNet, usize> {
+    let mut map = PrefixMap::default();
+    for (i, p) in prefixes.iter().enumerate() {
+        map.insert(net(p), i);
+    }
+    map
+}
+
+fn keys<'a>(entries: impl IntoIterator<Item = (&'a IpNet, &'a usize)>) -> Vec<String> {
+    entries.into_iter().map(|(k, _)| k.to_string()).collect()
+}
+
+#[test]
+fn test_insert_get_remove() {
+    let mut m = map(&["10.0.0.0/8", "10.1.0.0/16", "10.2.0.0/16", "::/0"]);
+    assert_eq!(m.len(), 4);
+    assert_eq!(m.get(&net("10.1.0.0/16")), Some(&1));
+    assert_eq!(m.get(&net("10.0.0.0/16")), None);
+    assert_eq!(m.get(&net("0.0.0.0/0")), None);
+    assert_eq!(m.get(&net("::/0")), Some(&3));
+
+    assert_eq!(m.insert(net("10.1.0.0/16"), 10), Some(1));
+    assert_eq!(m.len(), 4);
+    *m.get_or_insert_with(net("10.3.0.0/16"), || 0) += 1;
+    assert_eq!(m.get(&net("10.3.0.0/16")), Some(&1));
+
+    // Removing the covering prefix leaves the branching node behind
+    assert_eq!(m.remove(&net("10.0.0.0/8")), Some(0));
+    assert_eq!(m.remove(&net("10.0.0.0/8")), None);
+    assert_eq!(m.get(&net("10.1.0.0/16")), Some(&10));
+    assert_eq!(m.remove(&net("10.1.0.0/16")), Some(10));
+    assert_eq!(m.remove(&net("10.2.0.0/16")), Some(2));
+    assert_eq!(m.get(&net("10.3.0.0/16")), Some(&1));
+    assert_eq!(m.len(), 2);
+}
+
+#[test]
+fn test_iteration_order() {
+    let m = map(&[
+        "10.2.0.0/16",
+        "2001:db8::/32",
+        "10.0.0.0/8",
+        "192.0.2.0/24",
+        "10.1.0.0/16",
+        "10.1.0.0/24",
+    ]);
+    assert_eq!(
+        keys(m.iter()),
+        vec![
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "10.1.0.0/24",
+            "10.2.0.0/16",
+            "192.0.2.0/24",
+            "2001:db8::/32"
+        ]
+    );
+}
+
+#[test]
+fn test_longest_match() {
+    let m = map(&["0.0.0.0/0", "10.0.0.0/8", "10.1.0.0/16", "10.1.2.0/24"]);
+    let lookup = |s: &str| m.longest_match(&net(s)).map(|(k, _)| k.to_string());
+    assert_eq!(lookup("10.1.2.3/32"), Some("10.1.2.0/24".to_string()));
+    assert_eq!(lookup("10.1.3.3/32"), Some("10.1.0.0/16".to_string()));
+    assert_eq!(lookup("10.1.0.0/16"), Some("10.1.0.0/16".to_string()));
+    assert_eq!(lookup("11.0.0.1/32"), Some("0.0.0.0/0".to_string()));
+    assert_eq!(lookup("2001:db8::1/128"), None);
+}
+
+#[test]
+fn test_less_and_more_specifics() {
+    let m = map(&[
+        "10.0.0.0/8",
+        "10.1.0.0/16",
+        "10.1.2.0/24",
+        "10.1.3.0/24",
+        "10.2.0.0/16",
+        "11.0.0.0/8",
+    ]);
+    assert_eq!(
+        keys(m.less_specifics(&net("10.1.2.128/25"))),
+        vec!["10.0.0.0/8", "10.1.0.0/16", "10.1.2.0/24"]
+    );
+    assert_eq!(
+        keys(m.more_specifics(&net("10.1.0.0/16"))),
+        vec!["10.1.0.0/16", "10.1.2.0/24", "10.1.3.0/24"]
+    );
+    // The prefix itself does not need to be in the map
+    assert_eq!(
+        keys(m.more_specifics(&net("10.1.0.0/20"))),
+        vec!["10.1.2.0/24", "10.1.3.0/24"]
+    );
+    assert_eq!(
+        keys(m.more_specifics(&net("10.0.0.0/7"))),
+        vec![
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "10.1.2.0/24",
+            "10.1.3.0/24",
+            "10.2.0.0/16",
+            "11.0.0.0/8"
+        ]
+    );
+    assert!(keys(m.more_specifics(&net("12.0.0.0/8"))).is_empty());
+}
+
+#[test]
+fn test_iter_mut() {
+    let mut m = map(&["10.0.0.0/8", "10.1.0.0/16", "2001:db8::/32"]);
+    for (_, v) in m.iter_mut() {
+        *v += 100;
+    }
+    let values: Vec<usize> = m.values().copied().collect();
+    assert_eq!(values, vec![100, 101, 102]);
+}
+
+#[test]
+fn test_against_hash_map() {
+    // Pseudo-random prefixes, most of them overlapping
+    let mut seed: u64 = 42;
+    let mut random = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as u32
+    };
+    let mut m = PrefixMap::default();
+    let mut model = HashMap::new();
+    for i in 0..5000 {
+        let addr = std::net::Ipv4Addr::from(random() & 0x0fff_ff00);
+        let p: IpNet = ipnet::Ipv4Net::new(addr, (random() % 25) as u8)
+            .unwrap()
+            .trunc()
+            .into();
+        if random() % 3 == 0 {
+            assert_eq!(m.remove(&p), model.remove(&p));
+        } else {
+            assert_eq!(m.insert(p, i), model.insert(p, i));
+        }
+    }
+    assert_eq!(m.len(), model.len());
+    let mut expected: Vec<(IpNet, usize)> = model.iter().map(|(k, v)| (*k, *v)).collect();
+    expected.sort();
+    let mut found: Vec<(IpNet, usize)> = m.iter().map(|(k, v)| (*k, *v)).collect();
+    found.sort();
+    assert_eq!(found, expected);
+
+    for _ in 0..500 {
+        let addr = std::net::Ipv4Addr::from(random() & 0x0fff_ffff);
+        let host = IpNet::from(std::net::IpAddr::V4(addr));
+        let covering = model
+            .keys()
+            .filter(|p| p.contains(&host))
+            .max_by_key(|p| p.prefix_len());
+        assert_eq!(m.longest_match(&host).map(|(k, _)| k), covering);
+    }
+}
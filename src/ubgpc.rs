@@ -1,6 +1,11 @@
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
-use ubgp::{config_client::ConfigClient, state_client::StateClient, NeighborRequest, RibRequest};
+use std::net::Ipv4Addr;
+use ubgp::{
+    config_client::ConfigClient, state_client::StateClient, AdjRibInRequest, ClearMaxPrefixRequest,
+    DampingRequest, MemoryRequest, NeighborRequest, PathEntry, RibReply, RibRequest,
+    SoftReconfigureRequest,
+};
 
 pub mod ubgp {
     tonic::include_proto!("ubgp");
@@ -23,6 +28,11 @@ struct Opt {
 enum Commands {
     Rib(RibArgs),
     Neighbors(NeighborsArgs),
+    /// Routes received from a neighbor and soft reconfiguration
+    Neighbor(NeighborArgs),
+    Damping(DampingArgs),
+    /// Memory used by the Loc-RIBs and Adj-RIBs-In
+    Memory,
 }
 
 #[derive(Args)]
@@ -31,6 +41,29 @@ struct RibArgs {
     afi: u32,
     #[arg(short, long, default_value_t = 1)]
     safi: u32,
+    /// AS path regular expression, e.g. "^65001_" or "_65000-65099$"
+    #[arg(long)]
+    aspath_regex: Option<String>,
+    /// Prefix to show, or address to show the longest matching prefix of
+    prefix: Option<String>,
+    /// Also show the prefixes more specific than the prefix
+    #[arg(long)]
+    longer: bool,
+    /// Also show the prefixes less specific than the prefix
+    #[arg(long)]
+    shorter: bool,
+}
+
+#[derive(Args)]
+struct DampingArgs {
+    #[arg(short, long, default_value_t = 1)]
+    afi: u32,
+    #[arg(short, long, default_value_t = 1)]
+    safi: u32,
+    #[arg(short, long)]
+    prefix: Option<String>,
+    #[arg(long)]
+    clear: bool,
 }
 
 #[derive(Args)]
@@ -39,6 +72,88 @@ struct NeighborsArgs {
     address: Option<String>,
 }
 
+#[derive(Args)]
+struct NeighborArgs {
+    ip: String,
+    #[command(subcommand)]
+    command: NeighborCommands,
+}
+
+#[derive(Subcommand)]
+enum NeighborCommands {
+    /// Routes as received, before the import policy (needs soft-reconfiguration inbound)
+    ReceivedRoutes(FamilyArgs),
+    /// Routes accepted by the import policy
+    AcceptedRoutes(FamilyArgs),
+    /// Applies the import policy again to the routes received
+    SoftIn,
+    /// Lifts a shutdown by the maximum-prefix limit and reconnects
+    ClearMaxPrefix,
+}
+
+#[derive(Args)]
+struct FamilyArgs {
+    #[arg(short, long, default_value_t = 1)]
+    afi: u32,
+    #[arg(short, long, default_value_t = 1)]
+    safi: u32,
+}
+
+/// One line per path: `*` valid or `s` suppressed, then `>` for the best path and `=` for the
+/// other multipaths. Next hops are only resolved in the Loc-RIB.
+fn path_line(p: &PathEntry, loc_rib: bool) -> String {
+    let mut line = format!(
+        "  {}{} {} from {} ({})",
+        if p.suppressed { 's' } else { '*' },
+        if p.best {
+            '>'
+        } else if p.multipath {
+            '='
+        } else {
+            ' '
+        },
+        p.next_hop,
+        p.peer,
+        Ipv4Addr::from(p.peer_rid)
+    );
+    match p.igp_metric {
+        Some(metric) => line.push_str(&format!(" metric {}", metric)),
+        None if loc_rib => line.push_str(" unreachable"),
+        None => {}
+    }
+    if let Some(lp) = p.local_pref {
+        line.push_str(&format!(" local-pref {}", lp));
+    }
+    if let Some(med) = p.med {
+        line.push_str(&format!(" med {}", med));
+    }
+    if p.weight != 0 {
+        line.push_str(&format!(" weight {}", p.weight));
+    }
+    if let Some(originator_id) = &p.originator_id {
+        line.push_str(&format!(" originator {}", originator_id));
+    }
+    if !p.cluster_list.is_empty() {
+        line.push_str(&format!(" cluster-list {}", p.cluster_list.join(" ")));
+    }
+    line.push_str(&format!(" path {} {}", p.as_path, p.origin));
+    line
+}
+
+/// Prints the paths of every prefix, best first, along with the step of the decision process
+/// that selected the best path.
+fn print_rib(reply: &RibReply, loc_rib: bool) {
+    for entry in &reply.nlris {
+        match &entry.decided_by {
+            Some(step) => println!("{} (best path by {})", entry.nlri, step),
+            None => println!("{}", entry.nlri),
+        }
+        for p in &entry.paths {
+            println!("{}", path_line(p, loc_rib));
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::parse();
@@ -59,9 +174,13 @@ async fn main() -> Result<()> {
             let request = tonic::Request::new(RibRequest {
                 afi: args.afi,
                 safi: args.safi,
+                aspath_regex: args.aspath_regex,
+                prefix: args.prefix,
+                longer: args.longer,
+                shorter: args.shorter,
             });
             let response = client.get_rib(request).await?;
-            println!("{:?}", response.get_ref());
+            print_rib(response.get_ref(), true);
         }
         Commands::Neighbors(args) => {
             let mut client = ConfigClient::connect(server_url).await?;
@@ -69,6 +188,56 @@ async fn main() -> Result<()> {
             let response = client.get_neighbor_config(request).await?;
             println!("{:?}", response.get_ref());
         }
+        Commands::Neighbor(args) => match args.command {
+            NeighborCommands::ReceivedRoutes(ref family)
+            | NeighborCommands::AcceptedRoutes(ref family) => {
+                let pre_policy = matches!(args.command, NeighborCommands::ReceivedRoutes(_));
+                let mut client = StateClient::connect(server_url).await?;
+                let request = tonic::Request::new(AdjRibInRequest {
+                    ip: args.ip,
+                    afi: family.afi,
+                    safi: family.safi,
+                    pre_policy,
+                });
+                let response = client.get_adj_rib_in(request).await?;
+                print_rib(response.get_ref(), false);
+            }
+            NeighborCommands::SoftIn => {
+                let mut client = ConfigClient::connect(server_url).await?;
+                let request = tonic::Request::new(SoftReconfigureRequest { ip: args.ip });
+                let response = client.soft_reconfigure_inbound(request).await?;
+                println!("{:?}", response.get_ref());
+            }
+            NeighborCommands::ClearMaxPrefix => {
+                let mut client = ConfigClient::connect(server_url).await?;
+                let request = tonic::Request::new(ClearMaxPrefixRequest { ip: args.ip });
+                let response = client.clear_max_prefix(request).await?;
+                println!("{:?}", response.get_ref());
+            }
+        },
+        Commands::Damping(args) => {
+            let request = tonic::Request::new(DampingRequest {
+                afi: args.afi,
+                safi: args.safi,
+                prefix: args.prefix,
+            });
+            if args.clear {
+                let mut client = ConfigClient::connect(server_url).await?;
+                let response = client.clear_damping(request).await?;
+                println!("{:?}", response.get_ref());
+            } else {
+                let mut client = StateClient::connect(server_url).await?;
+                let response = client.get_damping(request).await?;
+                println!("{:?}", response.get_ref());
+            }
+        }
+        Commands::Memory => {
+            let mut client = StateClient::connect(server_url).await?;
+            let response = client
+                .get_memory(tonic::Request::new(MemoryRequest {}))
+                .await?;
+            println!("{:?}", response.get_ref());
+        }
     }
 
     Ok(())
@@ -1,22 +1,36 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
 #[macro_use]
 extern crate derive_builder;
 
+mod bfd;
 mod bgp;
 mod config;
+mod damping;
+#[cfg(test)]
+mod damping_tests;
 mod error;
 mod fib;
+#[cfg(test)]
+mod fib_tests;
 mod grpc;
+#[cfg(test)]
+mod grpc_tests;
 mod neighbor;
+mod policy;
+#[cfg(test)]
+mod policy_tests;
 mod rib;
+#[cfg(test)]
+mod rib_tests;
 mod speaker;
+mod trie;
+#[cfg(test)]
+mod trie_tests;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -39,16 +53,43 @@ async fn main() -> Result<()> {
     let local_ips = config.localips.context("Local IPs not configured")?;
     let port = config.port.context("Port not configured")?;
 
-    let speaker = Arc::new(Mutex::new(speaker::BGPSpeaker::new(
+    let mut speaker = speaker::BGPSpeaker::new(
         config.asn,
         u32::from(config.rid),
         hold_time,
         local_ips,
         port,
         families,
-    )));
+    );
+    if let Some(cluster_id) = config.cluster_id {
+        speaker.cluster_id = u32::from(cluster_id);
+    }
+    speaker.confederation = config.confederation.clone();
+    speaker.bfd = config.bfd.clone();
+    speaker.damping_config = config.damping.clone();
+    speaker.best_path = config.best_path.clone();
+    speaker.mrai = config.mrai.clone();
+    speaker.fib_config = config.fib.clone();
+    speaker.networks = config
+        .networks
+        .iter()
+        .map(|n| n.parse::<ipnet::IpNet>().map(bgp::Nlri::from))
+        .collect::<Result<_, _>>()
+        .context("Invalid network")?;
+    speaker.policies = Arc::new(
+        policy::Policies::new(
+            &config.prefix_lists,
+            &config.as_path_lists,
+            &config.route_maps,
+        )
+        .context("Invalid routing policy")?,
+    );
+    for af in speaker.families.clone() {
+        let policy = fib::InstallPolicy::new(&config.fib, &af, &speaker.policies)
+            .context("Invalid FIB configuration")?;
+        speaker.fib_policies.insert(af, policy);
+    }
     if let Some(neighbors) = config.neighbors {
-        let mut speaker = speaker.lock().await;
         for mut n in neighbors {
             let families = config.families.clone();
             n.families = match n.families {
@@ -59,16 +100,22 @@ async fn main() -> Result<()> {
                 Some(i) => Some(i),
                 None => Some(speaker.hold_time),
             };
-            speaker.add_neighbor(n, HashMap::new()).await;
+            let ip = n.ip.clone();
+            speaker
+                .add_neighbor(n)
+                .await
+                .with_context(|| format!("Invalid configuration for neighbor {}", ip))?;
         }
     } else {
         log::info!("No neighbors configured, BGP speaker will accept incoming connections only");
     }
 
-    let s1 = speaker.clone();
-
-    tokio::spawn(async move { speaker::BGPSpeaker::start(speaker).await });
-    tokio::spawn(async move { grpc::grpc_server(s1).await });
+    let speaker = speaker
+        .start()
+        .await
+        .context("Failed to start the BGP speaker")?;
+    let api = config.api.clone();
+    tokio::spawn(async move { grpc::grpc_server(speaker, api).await });
 
     loop {
         sleep(Duration::from_secs(1)).await;